@@ -0,0 +1,310 @@
+use crate::commands::Flags;
+use crate::db::core;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+//
+// ─── Users ───────────────────────────────────────────────────────────────────────
+//
+
+/// An ACL user: who may log in, which commands they may run and which key
+/// prefixes they may touch.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    nopass: bool,
+    /// SHA-256 of each accepted password
+    passwords: BTreeSet<[u8; 32]>,
+    all_commands: bool,
+    allowed: BTreeSet<String>,
+    denied: BTreeSet<String>,
+    /// `*`, an exact key, or a subtree such as `billing:*`
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    /// A user with no passwords, no commands and no keys; disabled.
+    pub fn new(name: &str) -> Self {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            all_commands: false,
+            allowed: BTreeSet::new(),
+            denied: BTreeSet::new(),
+            key_patterns: Vec::new(),
+        }
+    }
+
+    /// Apply rules such as `on`, `>secret`, `#<sha256>`, `+@all`, `+@read`,
+    /// `-drop`, `~billing:*`.
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_ascii_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" | "+@all" => {
+                self.all_commands = true;
+                self.allowed.clear();
+                self.denied.clear();
+            }
+            "nocommands" | "-@all" => {
+                self.all_commands = false;
+                self.allowed.clear();
+                self.denied.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_string()],
+            "resetkeys" => self.key_patterns.clear(),
+            "reset" => *self = User::new(&self.name),
+            _ => {
+                if let Some(pass) = rule.strip_prefix('>') {
+                    self.passwords.insert(hash(pass));
+                    self.nopass = false;
+                } else if let Some(pass) = rule.strip_prefix('<') {
+                    self.passwords.remove(&hash(pass));
+                } else if let Some(hex) = rule.strip_prefix('#') {
+                    let hash =
+                        parse_hash(hex).ok_or_else(|| format!("Bad password hash in '{rule}'"))?;
+                    self.passwords.insert(hash);
+                    self.nopass = false;
+                } else if let Some(cmd) = lower.strip_prefix('+') {
+                    check_category(cmd)?;
+                    self.denied.remove(cmd);
+                    self.allowed.insert(cmd.to_string());
+                } else if let Some(cmd) = lower.strip_prefix('-') {
+                    check_category(cmd)?;
+                    self.allowed.remove(cmd);
+                    self.denied.insert(cmd.to_string());
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.key_patterns.push(pattern.to_string());
+                } else {
+                    return Err(format!("Error in ACL rule '{rule}'"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash(password)))
+    }
+
+    /// Whether the user may run `command`, which falls in `categories`. A
+    /// rule naming the command wins over one naming a category, which wins
+    /// over `+@all` / `-@all`.
+    pub fn can_run<'a>(&self, command: &str, categories: impl Iterator<Item = &'a str>) -> bool {
+        if self.denied.contains(command) {
+            return false;
+        }
+        if self.allowed.contains(command) {
+            return true;
+        }
+        let mut allowed = self.all_commands;
+        for category in categories {
+            let rule = format!("@{category}");
+            if self.denied.contains(&rule) {
+                return false;
+            }
+            allowed |= self.allowed.contains(&rule);
+        }
+        allowed
+    }
+
+    /// Whether `key` falls under one of the user's key patterns. A subtree
+    /// pattern `a:b:*` also covers `a:b` itself, since that key is the subtree.
+    pub fn can_access(&self, key: &str) -> bool {
+        self.key_patterns
+            .iter()
+            .any(|pattern| core::matches_pattern(pattern, key))
+    }
+
+    /// Whether the user may touch every key (`allkeys` or `~*`), as commands
+    /// on the whole keyspace such as DROP require.
+    pub fn can_access_all(&self) -> bool {
+        self.key_patterns.iter().any(|pattern| pattern == "*")
+    }
+
+    /// Rules that recreate this user, as shown by ACL LIST.
+    pub fn describe(&self) -> String {
+        let mut out = format!(
+            "user {} {}",
+            self.name,
+            if self.enabled { "on" } else { "off" }
+        );
+        if self.nopass {
+            out.push_str(" nopass");
+        }
+        for hash in &self.passwords {
+            out.push_str(" #");
+            out.extend(hash.iter().map(|b| format!("{b:02x}")));
+        }
+        for pattern in &self.key_patterns {
+            out.push_str(" ~");
+            out.push_str(pattern);
+        }
+        out.push_str(if self.all_commands {
+            " +@all"
+        } else {
+            " -@all"
+        });
+        for cmd in &self.allowed {
+            out.push_str(" +");
+            out.push_str(cmd);
+        }
+        for cmd in &self.denied {
+            out.push_str(" -");
+            out.push_str(cmd);
+        }
+        out
+    }
+}
+
+/// Only the categories of the command classification table exist.
+fn check_category(rule: &str) -> Result<(), String> {
+    match rule.strip_prefix('@') {
+        Some(category) if !Flags::CATEGORIES.contains(&category) => {
+            Err(format!("Unknown command category '{category}'"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn hash(password: &str) -> [u8; 32] {
+    Sha256::digest(password.as_bytes()).into()
+}
+
+/// 64 hex digits, as printed by ACL LIST.
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+//
+// ─── User Registry ───────────────────────────────────────────────────────────────
+//
+
+pub const DEFAULT_USER: &str = "default";
+
+/// All configured users. Sessions store only the user name and look the user
+/// up per command, so ACL changes take effect immediately.
+///
+/// # Panics
+///
+/// Every method panics if the user table's lock is poisoned. That needs a
+/// panic while the lock is held, and the critical sections here only clone,
+/// insert or remove map entries.
+#[derive(Debug)]
+pub struct Acl {
+    users: RwLock<HashMap<String, Arc<User>>>,
+}
+
+impl Acl {
+    /// The `default` user can do everything; with `requirepass` it needs that
+    /// password, otherwise connections are logged in as it automatically.
+    pub fn new(requirepass: Option<&str>) -> Self {
+        let mut default = User::new(DEFAULT_USER);
+        for rule in ["on", "allkeys", "+@all"] {
+            let _ = default.apply(rule);
+        }
+        match requirepass {
+            Some(pass) => {
+                default.passwords.insert(hash(pass));
+            }
+            None => default.nopass = true,
+        }
+        let mut users = HashMap::new();
+        users.insert(DEFAULT_USER.to_string(), Arc::new(default));
+        Acl {
+            users: RwLock::new(users),
+        }
+    }
+
+    /// Read `user <name> <rules...>` lines. Users defined here replace any
+    /// existing user of the same name, including `default`.
+    pub fn load_file(&self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read ACL file {}: {}", path.display(), e))?;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (Some("user"), Some(name)) = (words.next(), words.next()) else {
+                return Err(format!(
+                    "ACL file line {}: expected 'user <name> ...'",
+                    n + 1
+                ));
+            };
+            let mut user = User::new(name);
+            for rule in words {
+                user.apply(rule)
+                    .map_err(|e| format!("ACL file line {}: {}", n + 1, e))?;
+            }
+            self.users
+                .write()
+                .unwrap()
+                .insert(name.to_string(), Arc::new(user));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<User>> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    /// The user to start new connections as, if no AUTH is needed.
+    pub fn implicit_user(&self) -> Option<&'static str> {
+        self.get(DEFAULT_USER)
+            .filter(|u| u.enabled && u.nopass)
+            .map(|_| DEFAULT_USER)
+    }
+
+    /// The user, if `password` is right and the user is enabled.
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<Arc<User>> {
+        self.get(name).filter(|u| u.check_password(password))
+    }
+
+    /// Create or modify a user by applying `rules` in order.
+    pub fn set_user(&self, name: &str, rules: &[&str]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = match users.get(name) {
+            Some(user) => (**user).clone(),
+            None => User::new(name),
+        };
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        users.insert(name.to_string(), Arc::new(user));
+        Ok(())
+    }
+
+    pub fn del_user(&self, name: &str) -> bool {
+        self.users.write().unwrap().remove(name).is_some()
+    }
+
+    /// All users, sorted by name.
+    pub fn users(&self) -> Vec<Arc<User>> {
+        let mut users: Vec<_> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+}
@@ -0,0 +1,79 @@
+use super::AllocStats;
+use std::ffi::{c_void, CStr};
+use std::ptr;
+use std::sync::OnceLock;
+
+/// Read a mallctl value, e.g. a `size_t` statistic as `usize`.
+fn read<T: Copy + Default>(name: &CStr) -> T {
+    let mut value = T::default();
+    let mut len = std::mem::size_of::<T>();
+    let rc = unsafe {
+        jemalloc_sys::mallctl(
+            name.as_ptr(),
+            &mut value as *mut T as *mut c_void,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if rc == 0 {
+        value
+    } else {
+        T::default()
+    }
+}
+
+/// jemalloc caches its statistics; bumping the epoch refreshes them.
+fn refresh() {
+    let mut epoch: u64 = 1;
+    unsafe {
+        jemalloc_sys::mallctl(
+            c"epoch".as_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut epoch as *mut u64 as *mut c_void,
+            std::mem::size_of::<u64>(),
+        );
+    }
+}
+
+/// Whether jemalloc is the global allocator. Linking it isn't enough: a
+/// binary embedding the library may choose its own, which leaves jemalloc's
+/// figures meaningless. Checked once, by seeing whether an allocation moves
+/// jemalloc's per-thread counter.
+pub fn in_use() -> bool {
+    static IN_USE: OnceLock<bool> = OnceLock::new();
+    *IN_USE.get_or_init(|| {
+        let before: u64 = read(c"thread.allocated");
+        let probe = std::hint::black_box(vec![0u8; 64]);
+        let after: u64 = read(c"thread.allocated");
+        drop(probe);
+        after > before
+    })
+}
+
+/// Fresh snapshot of allocator statistics, `None` unless jemalloc is the
+/// global allocator.
+pub fn stats() -> Option<AllocStats> {
+    if !in_use() {
+        return None;
+    }
+    refresh();
+    Some(AllocStats {
+        allocated: read(c"stats.allocated"),
+        active: read(c"stats.active"),
+        resident: read(c"stats.resident"),
+        mapped: read(c"stats.mapped"),
+        metadata: read(c"stats.metadata"),
+        retained: read(c"stats.retained"),
+    })
+}
+
+/// Bytes jemalloc really reserves for a `size` byte request (its size class).
+#[inline]
+pub fn alloc_size(size: usize) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    unsafe { jemalloc_sys::nallocx(size, 0) }
+}
@@ -0,0 +1,99 @@
+/// Allocator-level memory figures, straight from jemalloc.
+#[derive(Debug, Clone, Default)]
+pub struct AllocStats {
+    /// Bytes handed out to the application
+    pub allocated: usize,
+    /// Bytes in active pages (allocated + page-level fragmentation)
+    pub active: usize,
+    /// Bytes physically resident, close to RSS
+    pub resident: usize,
+    /// Bytes in mapped extents
+    pub mapped: usize,
+    /// Bytes used by jemalloc's own bookkeeping
+    pub metadata: usize,
+    /// Bytes retained but not mapped
+    pub retained: usize,
+}
+
+impl AllocStats {
+    /// resident / allocated, 1.0 being perfect.
+    pub fn fragmentation_ratio(&self) -> f64 {
+        if self.allocated == 0 {
+            return 0.0;
+        }
+        self.resident as f64 / self.allocated as f64
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+mod jemalloc;
+#[cfg(feature = "jemalloc")]
+pub use jemalloc::{alloc_size, in_use, stats};
+
+/// Whether jemalloc is the global allocator; never without the `jemalloc`
+/// feature.
+#[cfg(not(feature = "jemalloc"))]
+pub fn in_use() -> bool {
+    false
+}
+
+/// Without jemalloc there are no allocator figures to report.
+#[cfg(not(feature = "jemalloc"))]
+pub fn stats() -> Option<AllocStats> {
+    None
+}
+
+/// Without jemalloc's size classes to ask, a request is assumed to be
+/// rounded up to the 16-byte granularity of common system allocators.
+#[cfg(not(feature = "jemalloc"))]
+#[inline]
+pub fn alloc_size(size: usize) -> usize {
+    size.next_multiple_of(16)
+}
+
+/// Name of the allocator serving this process, as INFO reports it.
+pub fn name() -> &'static str {
+    if in_use() {
+        "jemalloc"
+    } else {
+        "libc"
+    }
+}
+
+/// 1536 -> "1.50K", Redis style.
+pub fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = u;
+    }
+    format!("{value:.2}{unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{human_bytes, in_use, name, stats};
+
+    #[test]
+    fn no_figures_are_reported_for_another_global_allocator() {
+        // Unit tests run on the system allocator even when jemalloc is linked
+        assert!(!in_use());
+        assert_eq!(name(), "libc");
+        assert!(stats().is_none());
+    }
+
+    #[test]
+    fn human_bytes_uses_binary_units() {
+        assert_eq!(human_bytes(512), "512B");
+        assert_eq!(human_bytes(1536), "1.50K");
+        assert_eq!(human_bytes(3 << 30), "3.00G");
+    }
+}
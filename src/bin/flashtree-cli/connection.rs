@@ -0,0 +1,239 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// One reply as the server typed it in RESP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Array(Vec<Reply>),
+    Nil,
+}
+
+/// Where the server listens.
+#[derive(Debug, Clone)]
+pub enum Target {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Tcp(addr) => f.write_str(addr),
+            #[cfg(unix)]
+            Target::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A server and how to log in to it.
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub target: Target,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(target: &Target) -> io::Result<Self> {
+        Ok(match target {
+            Target::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            Target::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Tcp(s) => Stream::Tcp(s.try_clone()?),
+            #[cfg(unix)]
+            Stream::Unix(s) => Stream::Unix(s.try_clone()?),
+        })
+    }
+
+    fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(on),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_nonblocking(on),
+        }
+    }
+
+    /// Tell the server nothing more is coming; it answers what it has and hangs up.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(Shutdown::Write),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// A logged-in connection speaking RESP (`HELLO 2`). Requests are still
+/// plain lines; only replies are framed.
+#[derive(Debug)]
+pub struct Connection {
+    server: Server,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+impl Connection {
+    pub fn open(server: &Server) -> io::Result<Self> {
+        let stream = Stream::connect(&server.target)?;
+        let mut connection = Connection {
+            server: server.clone(),
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        };
+        connection.login()?;
+        Ok(connection)
+    }
+
+    fn login(&mut self) -> io::Result<()> {
+        if let Reply::Error(e) = self.call("HELLO 2")? {
+            return Err(io::Error::other(e));
+        }
+        let auth = match (&self.server.user, &self.server.pass) {
+            (Some(user), Some(pass)) => format!("AUTH {user} {pass}"),
+            (None, Some(pass)) => format!("AUTH {pass}"),
+            _ => return Ok(()),
+        };
+        match self.call(&auth)? {
+            Reply::Error(e) => Err(io::Error::new(ErrorKind::PermissionDenied, e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Connect again, e.g. after the server closed an idle connection.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        *self = Connection::open(&self.server)?;
+        Ok(())
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Send one request line and wait for its reply.
+    pub fn call(&mut self, line: &str) -> io::Result<Reply> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.read_reply()
+    }
+
+    pub fn read_reply(&mut self) -> io::Result<Reply> {
+        read_reply(&mut self.reader)
+    }
+
+    /// Whether the server hung up, or sent something nobody asked for such
+    /// as its idle timeout notice, since the last reply.
+    pub fn closed(&mut self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+        let stream = self.reader.get_mut();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let pending = stream.read(&mut [0; 1]);
+        let _ = stream.set_nonblocking(false);
+        !matches!(pending, Err(e) if e.kind() == ErrorKind::WouldBlock)
+    }
+
+    /// The two directions, for writing requests while replies are read.
+    pub fn halves(&mut self) -> (&mut BufReader<Stream>, &mut BufWriter<Stream>) {
+        (&mut self.reader, &mut self.writer)
+    }
+}
+
+/// Read one RESP reply. Also accepts bare `\n` line endings, which is how
+/// the server rejects a connection before it has a session.
+pub fn read_reply(reader: &mut impl BufRead) -> io::Result<Reply> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Server closed the connection",
+        ));
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected reply from server: {line:?}"),
+        )
+    };
+    let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+    Ok(match kind {
+        "+" => Reply::Status(rest.to_string()),
+        "-" => Reply::Error(rest.to_string()),
+        ":" => Reply::Integer(rest.parse().map_err(|_| invalid())?),
+        "$" if rest == "-1" => Reply::Nil,
+        "$" => {
+            let len: usize = rest.parse().map_err(|_| invalid())?;
+            let mut data = vec![0; len + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(len);
+            Reply::Bulk(String::from_utf8(data).map_err(|_| invalid())?)
+        }
+        "*" if rest == "-1" => Reply::Nil,
+        "*" => {
+            let len: usize = rest.parse().map_err(|_| invalid())?;
+            let items = (0..len)
+                .map(|_| read_reply(reader))
+                .collect::<io::Result<_>>()?;
+            Reply::Array(items)
+        }
+        _ => return Err(invalid()),
+    })
+}
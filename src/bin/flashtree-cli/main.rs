@@ -0,0 +1,175 @@
+//! Command-line client for FlashTree.
+//!
+//! ```text
+//! flashtree-cli                          interactive, with history and Tab completion
+//! flashtree-cli GET users:42             run one command and exit
+//! flashtree-cli --pipe < commands.txt    bulk load, one request per line
+//! ```
+
+mod connection;
+mod pipe;
+mod render;
+mod repl;
+
+use connection::{Connection, Reply, Server, Target};
+use std::io::IsTerminal;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: flashtree-cli [options] [command [arg ...]]
+
+Options:
+  -h, --host <host>      Server host (default 127.0.0.1)
+  -p, --port <port>      Server port (default 2002)
+  -s, --socket <path>    Connect to a Unix socket instead
+      --user <name>      ACL user to log in as
+  -a, --pass <password>  Password, sent with AUTH
+      --pipe             Send every line of stdin, then report the replies
+      --raw              Bare replies, the default when stdout is not a terminal
+      --no-raw           Formatted replies even when stdout is not a terminal
+      --help             Show this help";
+
+/// What to connect to and what to do there, from the command line.
+#[derive(Debug)]
+struct Options {
+    server: Server,
+    pipe: bool,
+    raw: bool,
+    /// A command to run once instead of starting a prompt
+    command: Vec<String>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut host = "127.0.0.1".to_string();
+        let mut port = 2002u16;
+        let mut socket = None;
+        let mut user = None;
+        let mut pass = None;
+        let mut pipe = false;
+        let mut raw = !std::io::stdout().is_terminal();
+        let mut command = Vec::new();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {flag}"))
+            };
+            match flag.as_str() {
+                "-h" | "--host" => host = value()?,
+                "-p" | "--port" => {
+                    let value = value()?;
+                    port = value
+                        .parse()
+                        .map_err(|_| format!("{flag} expects a port number, got {value:?}"))?;
+                }
+                "-s" | "--socket" => socket = Some(value()?),
+                "--user" => user = Some(value()?),
+                "-a" | "--pass" => pass = Some(value()?),
+                "--pipe" => pipe = true,
+                "--raw" => raw = true,
+                "--no-raw" => raw = false,
+                "--help" => return Err(USAGE.to_string()),
+                _ if flag.starts_with('-') => return Err(format!("Unknown option: {flag}")),
+                _ => {
+                    command.push(flag);
+                    command.extend(args);
+                    break;
+                }
+            }
+        }
+        if user.is_some() && pass.is_none() {
+            return Err("--user needs --pass".to_string());
+        }
+        if pipe && !command.is_empty() {
+            return Err("--pipe reads commands from stdin; don't pass one as well".to_string());
+        }
+        let target = match socket {
+            #[cfg(unix)]
+            Some(path) => Target::Unix(path.into()),
+            #[cfg(not(unix))]
+            Some(_) => return Err("Unix sockets are not supported on this platform".to_string()),
+            None => Target::Tcp(format!("{host}:{port}")),
+        };
+        Ok(Options {
+            server: Server { target, user, pass },
+            pipe,
+            raw,
+            command,
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(usage) if usage == USAGE => {
+            println!("{usage}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    let mut connection = match Connection::open(&options.server) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Could not connect to {}: {e}", options.server.target);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if options.pipe {
+        return match pipe::run(&mut connection) {
+            Ok(summary) => {
+                println!("errors: {}, replies: {}", summary.errors, summary.replies);
+                if summary.replies < summary.sent {
+                    eprintln!(
+                        "{} of {} requests got no reply",
+                        summary.sent - summary.replies,
+                        summary.sent
+                    );
+                }
+                if summary.errors > 0 || summary.replies < summary.sent {
+                    ExitCode::FAILURE
+                } else {
+                    ExitCode::SUCCESS
+                }
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if !options.command.is_empty() {
+        // Errors go to stderr and the exit status, so scripts can check them
+        return match connection.call(&options.command.join(" ")) {
+            Ok(Reply::Error(e)) => {
+                eprintln!("(error) {e}");
+                ExitCode::FAILURE
+            }
+            Ok(reply) if options.raw => {
+                println!("{}", render::raw(&reply));
+                ExitCode::SUCCESS
+            }
+            Ok(reply) => {
+                println!("{}", render::pretty(&reply));
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    match repl::run(connection, options.raw) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use crate::connection::{read_reply, Connection, Reply};
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::thread;
+
+/// Counts from a `--pipe` run.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub sent: usize,
+    pub replies: usize,
+    pub errors: usize,
+}
+
+/// Send every line of stdin as a request without waiting for replies, then
+/// count the replies. Writing and reading run side by side so neither the
+/// socket nor the server's output buffer fills up, and the write half is
+/// shut once stdin ends so the server hangs up after its last reply.
+pub fn run(connection: &mut Connection) -> io::Result<Summary> {
+    let (reader, writer) = connection.halves();
+    thread::scope(|scope| {
+        let sender = scope.spawn(move || -> io::Result<usize> {
+            let mut sent = 0;
+            for line in io::stdin().lock().lines() {
+                let line = line?;
+                let line = line.trim_end_matches('\r');
+                if line.trim().is_empty() {
+                    continue;
+                }
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+                sent += 1;
+            }
+            writer.flush()?;
+            writer.get_ref().shutdown_write()?;
+            Ok(sent)
+        });
+
+        let mut summary = Summary::default();
+        loop {
+            match read_reply(reader) {
+                Ok(Reply::Error(e)) => {
+                    eprintln!("{e}");
+                    summary.errors += 1;
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            summary.replies += 1;
+        }
+        summary.sent = sender.join().expect("stdin reader panicked")?;
+        Ok(summary)
+    })
+}
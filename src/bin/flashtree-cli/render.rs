@@ -0,0 +1,47 @@
+use crate::connection::Reply;
+
+/// Format a reply for a person at a terminal: typed, quoted and with arrays
+/// numbered, e.g. the list from LRANGE as
+///
+/// ```text
+/// 1) "a"
+/// 2) "b"
+/// ```
+pub fn pretty(reply: &Reply) -> String {
+    match reply {
+        Reply::Status(s) => s.clone(),
+        Reply::Error(e) => format!("(error) {e}"),
+        Reply::Integer(n) => format!("(integer) {n}"),
+        // Blocks of text such as INFO read better as they are
+        Reply::Bulk(s) if s.contains('\n') => s.trim_end().to_string(),
+        Reply::Bulk(s) => format!("{s:?}"),
+        Reply::Nil => "(nil)".to_string(),
+        Reply::Array(items) if items.is_empty() => "(empty array)".to_string(),
+        Reply::Array(items) => {
+            let width = items.len().to_string().len();
+            let mut out = String::new();
+            for (i, item) in items.iter().enumerate() {
+                let prefix = format!("{:>width$}) ", i + 1);
+                let indent = " ".repeat(prefix.len());
+                for (n, line) in pretty(item).lines().enumerate() {
+                    out.push_str(if n == 0 { &prefix } else { &indent });
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+            out.pop();
+            out
+        }
+    }
+}
+
+/// Format a reply for another program: bare values one per line, nested
+/// arrays flattened, nil as an empty line.
+pub fn raw(reply: &Reply) -> String {
+    match reply {
+        Reply::Status(s) | Reply::Bulk(s) | Reply::Error(s) => s.clone(),
+        Reply::Integer(n) => n.to_string(),
+        Reply::Nil => String::new(),
+        Reply::Array(items) => items.iter().map(raw).collect::<Vec<_>>().join("\n"),
+    }
+}
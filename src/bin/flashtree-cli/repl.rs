@@ -0,0 +1,197 @@
+use crate::connection::{Connection, Reply, Server};
+use crate::render;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+use std::cell::RefCell;
+use std::io;
+use std::path::PathBuf;
+
+/// Tab completion: command names for the first word, key segments from the
+/// live trie for the rest, e.g. `GET users:4<Tab>` offers `41`, `42`.
+struct Completion {
+    /// Lower-case names from COMMAND, the server's own command table
+    commands: Vec<String>,
+    /// A connection of its own, opened on first use, so CHILDREN is never
+    /// queued inside the user's MULTI
+    lookups: RefCell<Option<Connection>>,
+    server: Server,
+}
+
+impl Completion {
+    fn new(connection: &mut Connection) -> Self {
+        let mut commands: Vec<String> = match connection.call("COMMAND") {
+            Ok(Reply::Array(entries)) => entries
+                .into_iter()
+                .filter_map(|entry| match entry {
+                    Reply::Array(mut fields) if !fields.is_empty() => match fields.swap_remove(0) {
+                        Reply::Bulk(name) => Some(name),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        commands.sort();
+        Completion {
+            commands,
+            lookups: RefCell::new(None),
+            server: connection.server().clone(),
+        }
+    }
+
+    /// Segments directly beneath `key`, or none if they can't be looked up.
+    fn children(&self, key: &str) -> Vec<String> {
+        let mut lookups = self.lookups.borrow_mut();
+        if lookups.as_mut().is_none_or(|c| c.closed()) {
+            *lookups = Connection::open(&self.server).ok();
+        }
+        let Some(connection) = lookups.as_mut() else {
+            return Vec::new();
+        };
+        match connection.call(&format!("CHILDREN {key}")) {
+            Ok(Reply::Array(names)) => names
+                .into_iter()
+                .filter_map(|name| match name {
+                    Reply::Bulk(name) => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let head = &line[..pos];
+        let start = head.rfind(' ').map_or(0, |i| i + 1);
+        let word = &head[start..];
+        if head[..start].trim().is_empty() {
+            // Answer in the case the user is typing in
+            let upper = word.chars().any(|c| c.is_ascii_uppercase());
+            let word = word.to_ascii_lowercase();
+            let names = self
+                .commands
+                .iter()
+                .filter(|name| name.starts_with(&word))
+                .map(|name| match upper {
+                    true => name.to_ascii_uppercase(),
+                    false => name.clone(),
+                })
+                .collect();
+            return Ok((start, names));
+        }
+        let (parent, partial, at) = match word.rfind(':') {
+            Some(i) => (&word[..i], &word[i + 1..], start + i + 1),
+            None => ("", word, start),
+        };
+        let mut names = self.children(parent);
+        names.retain(|name| name.starts_with(partial));
+        Ok((at, names))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+/// Commands after which the server pushes messages until unsubscribed.
+fn subscribes(line: &str) -> bool {
+    let name = line.split(' ').next().unwrap_or_default();
+    ["subscribe", "psubscribe", "watchtree"]
+        .iter()
+        .any(|s| name.eq_ignore_ascii_case(s))
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".flashtree_cli_history"))
+}
+
+/// Read commands from the terminal until EOF (Ctrl-D) or QUIT.
+pub fn run(mut connection: Connection, raw: bool) -> io::Result<()> {
+    let render = if raw { render::raw } else { render::pretty };
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .build();
+    let mut editor: Editor<Completion, DefaultHistory> =
+        Editor::with_config(config).map_err(io::Error::other)?;
+    editor.set_helper(Some(Completion::new(&mut connection)));
+    let history = history_path();
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+    let prompt = format!("{}> ", connection.server().target);
+
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        // Passwords stay out of the history file
+        if !line
+            .get(..5)
+            .is_some_and(|s| s.eq_ignore_ascii_case("auth "))
+        {
+            let _ = editor.add_history_entry(line);
+        }
+        if line.eq_ignore_ascii_case("quit") || line.eq_ignore_ascii_case("exit") {
+            break;
+        }
+        if connection.closed() {
+            if let Err(e) = connection.reconnect() {
+                eprintln!("Could not connect to {}: {e}", connection.server().target);
+                continue;
+            }
+        }
+        let reply = match connection.call(line) {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
+            }
+        };
+        println!("{}", render(&reply));
+        if subscribes(line) && !matches!(reply, Reply::Error(_)) {
+            println!("Reading messages... (press Ctrl-C to quit)");
+            loop {
+                match connection.read_reply() {
+                    Ok(message) => println!("{}", render(&message)),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(ref path) = history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
@@ -0,0 +1,357 @@
+use super::lists::parse_ends;
+use super::replication::LogGuard;
+use super::streams;
+use super::{CommandError, CommandResult, Reply, Session};
+use crate::db::{core, Database, End, Keyspace, StreamId};
+use crate::server::Shared;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// The reply handed to a waiter once it has been served.
+type Served = Result<Reply, CommandError>;
+
+/// What to do for a waiter once its key has something for it.
+#[derive(Debug)]
+pub(super) enum Op {
+    /// BLPOP / BRPOP
+    Pop(End),
+    /// BLMOVE
+    Move { dst: String, from: End, to: End },
+    /// XREAD BLOCK: entries after the given ID of each stream
+    Read {
+        after: Vec<(String, StreamId)>,
+        count: Option<usize>,
+    },
+    /// XREADGROUP BLOCK with `>`: entries new to the group
+    ReadGroup {
+        group: String,
+        consumer: String,
+        count: Option<usize>,
+        noack: bool,
+    },
+}
+
+impl Op {
+    /// Try to serve this op from `key` at `now_ms`; `None` if there is
+    /// nothing for it yet.
+    fn try_serve(
+        &self,
+        key: &str,
+        database: &Database,
+        now_ms: u64,
+    ) -> Result<Option<Reply>, CommandError> {
+        let pair =
+            |item: String| Reply::Array(vec![Reply::Bulk(key.to_string()), Reply::Bulk(item)]);
+        Ok(match *self {
+            Op::Pop(end) => database.pop(key, end)?.map(pair),
+            Op::Move { ref dst, from, to } => {
+                database.pop_push(key, dst, from, to)?.map(Reply::Bulk)
+            }
+            Op::Read { ref after, count } => {
+                let Some(&(_, id)) = after.iter().find(|(k, _)| k == key) else {
+                    return Ok(None);
+                };
+                streams::read_after(database, key, id, count)?
+            }
+            Op::ReadGroup {
+                ref group,
+                ref consumer,
+                count,
+                noack,
+            } => streams::read_new(database, key, group, consumer, count, noack, now_ms)?,
+        })
+    }
+
+    /// The write a replica should make once this op was served from `key`
+    /// at `now_ms`.
+    fn replicated(&self, key: &str, now_ms: u64) -> Option<Vec<String>> {
+        let args: Vec<&str> = match *self {
+            Op::Pop(End::Left) => vec!["LPOP", key],
+            Op::Pop(End::Right) => vec!["RPOP", key],
+            Op::Move { ref dst, from, to } => vec!["LMOVE", key, dst, from.name(), to.name()],
+            Op::Read { .. } => return None,
+            Op::ReadGroup {
+                ref group,
+                ref consumer,
+                count,
+                noack,
+            } => {
+                let count = count.map(|n| n.to_string());
+                let time = now_ms.to_string();
+                let mut args = vec!["XREADGROUP", "GROUP", group, consumer];
+                if let Some(ref count) = count {
+                    args.extend(["COUNT", count]);
+                }
+                if noack {
+                    args.push("NOACK");
+                }
+                args.extend(["TIME", &time, "STREAMS", key, ">"]);
+                return Some(args.into_iter().map(str::to_string).collect());
+            }
+        };
+        Some(args.into_iter().map(str::to_string).collect())
+    }
+}
+
+/// One blocked connection, queued on every key it waits for.
+#[derive(Debug)]
+struct Waiter {
+    op: Op,
+    /// Taken by whoever finishes the wait first: a write serving it, or the
+    /// connection giving up.
+    tx: Mutex<Option<oneshot::Sender<Served>>>,
+}
+
+//
+// ─── Registry ────────────────────────────────────────────────────────────────────
+//
+
+/// Connections blocked on list or stream keys, oldest first per key.
+#[derive(Debug, Default)]
+pub struct Blocking {
+    queues: Mutex<HashMap<String, VecDeque<Arc<Waiter>>>>,
+    blocked: AtomicUsize,
+}
+
+impl Blocking {
+    pub fn new() -> Self {
+        Blocking::default()
+    }
+
+    /// Serve the waiters on `key` in the order they blocked, for as long as
+    /// it has something for them. Called after anything pushes onto a list or
+    /// appends to a stream at `key`; what serving changed goes to `log`. A
+    /// BLMOVE served here pushes onto its destination, whose waiters are
+    /// served next.
+    pub fn serve(&self, key: &str, database: &Database, mut log: Option<&mut LogGuard>) {
+        let Ok(mut queues) = self.queues.lock() else {
+            return;
+        };
+        let mut pushed = vec![key.to_string()];
+        while let Some(key) = pushed.pop() {
+            let Some(queue) = queues.get_mut(&key) else {
+                continue;
+            };
+            Self::serve_queue(queue, &key, database, log.as_deref_mut(), &mut pushed);
+            if queue.is_empty() {
+                queues.remove(&key);
+            }
+        }
+    }
+
+    /// `serve` for the one queue of `key`, noting in `pushed` the keys that
+    /// serving pushed onto.
+    fn serve_queue(
+        queue: &mut VecDeque<Arc<Waiter>>,
+        key: &str,
+        database: &Database,
+        mut log: Option<&mut LogGuard>,
+        pushed: &mut Vec<String>,
+    ) {
+        // Stream readers don't consume entries, so one that cannot be served
+        // doesn't mean the ones behind it can't be either.
+        queue.retain(|waiter| {
+            let Ok(mut slot) = waiter.tx.lock() else {
+                return false;
+            };
+            // Already served through another key, or gave up
+            let Some(tx) = slot.take() else {
+                return false;
+            };
+            let now_ms = core::now_ms();
+            let served = match waiter.op.try_serve(key, database, now_ms) {
+                Ok(Some(reply)) => {
+                    let replicated = waiter.op.replicated(key, now_ms);
+                    if let (Some(log), Some(args)) = (log.as_deref_mut(), replicated) {
+                        log.feed(&args.iter().map(String::as_str).collect::<Vec<_>>());
+                    }
+                    if let Op::Move { ref dst, .. } = waiter.op {
+                        pushed.push(dst.clone());
+                    }
+                    Ok(reply)
+                }
+                Ok(None) => {
+                    *slot = Some(tx);
+                    return true;
+                }
+                Err(e) => Err(e),
+            };
+            let _ = tx.send(served);
+            false
+        });
+    }
+
+    /// Connections currently blocked.
+    pub fn blocked_clients(&self) -> usize {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    /// Take `waiter` out of the queues for `keys`.
+    fn forget(&self, waiter: &Arc<Waiter>, keys: &[String]) {
+        self.blocked.fetch_sub(1, Ordering::Relaxed);
+        let Ok(mut queues) = self.queues.lock() else {
+            return;
+        };
+        if let Ok(mut tx) = waiter.tx.lock() {
+            tx.take();
+        }
+        for key in keys {
+            if let Some(queue) = queues.get_mut(key) {
+                queue.retain(|w| !Arc::ptr_eq(w, waiter));
+                if queue.is_empty() {
+                    queues.remove(key);
+                }
+            }
+        }
+    }
+}
+
+//
+// ─── Blocked Connections ─────────────────────────────────────────────────────────
+//
+
+/// Left in `Session::blocked` by a blocking command that found nothing to
+/// pop or read. The connection waits on it instead of sending the command's reply.
+#[derive(Debug)]
+pub struct Blocked {
+    waiter: Arc<Waiter>,
+    rx: oneshot::Receiver<Served>,
+    keys: Vec<String>,
+    /// `None` blocks until served
+    timeout: Option<Duration>,
+}
+
+impl Blocked {
+    /// Resolves once the waiter has been served.
+    pub async fn served(&mut self) -> Option<Served> {
+        (&mut self.rx).await.ok()
+    }
+
+    /// How long to wait; `None` waits until served.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Stop waiting and return the reply: the served one, even if it came at
+    /// the last moment, nil otherwise.
+    pub fn finish(mut self, blocking: &Blocking, served: Option<Served>) -> Reply {
+        blocking.forget(&self.waiter, &self.keys);
+        match served.or_else(|| self.rx.try_recv().ok()) {
+            Some(Ok(reply)) => reply,
+            Some(Err(e)) => e.into(),
+            None => Reply::Nil,
+        }
+    }
+}
+
+//
+// ─── Commands ────────────────────────────────────────────────────────────────────
+//
+
+/// Seconds to block, fractions allowed; 0 blocks indefinitely.
+fn parse_timeout(arg: &str) -> Result<Option<Duration>, CommandError> {
+    let invalid = || CommandError::syntax("timeout is not a float or out of range");
+    let secs: f64 = arg.parse().map_err(|_| invalid())?;
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .map_err(|_| invalid())
+}
+
+/// BLPOP / BRPOP key [key ...] timeout
+pub fn handle_bpop(
+    args: &[&str],
+    shared: &Shared,
+    session: &mut Session,
+    end: End,
+) -> CommandResult {
+    if args.len() < 3 {
+        return Err(CommandError::syntax(format!(
+            "Usage: {} key [key ...] timeout",
+            args[0].to_ascii_uppercase()
+        )));
+    }
+    let timeout = parse_timeout(args[args.len() - 1])?;
+    let keys = &args[1..args.len() - 1];
+    let op = Op::Pop(end);
+    block(shared, session, keys, timeout, op, |op| {
+        for key in keys {
+            if let Some(reply) = op.try_serve(key, &shared.database, core::now_ms())? {
+                return Ok(Some(reply));
+            }
+        }
+        Ok(None)
+    })
+}
+
+/// BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout
+pub fn handle_blmove(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    if args.len() != 6 {
+        return Err(CommandError::syntax(
+            "Usage: BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout",
+        ));
+    }
+    let (from, to) = parse_ends(args[3], args[4])?;
+    let timeout = parse_timeout(args[5])?;
+    let op = Op::Move {
+        dst: args[2].to_string(),
+        from,
+        to,
+    };
+    block(shared, session, &args[1..2], timeout, op, |op| {
+        op.try_serve(args[1], &shared.database, core::now_ms())
+    })
+}
+
+/// Reply with whatever `try_now` finds. With nothing there, queue the session
+/// on every key and leave it blocked. Trying and queueing happen under the
+/// registry lock, so a write in between cannot be missed. Sessions without a
+/// connection to park never block.
+pub(super) fn block(
+    shared: &Shared,
+    session: &mut Session,
+    keys: &[&str],
+    timeout: Option<Duration>,
+    op: Op,
+    try_now: impl FnOnce(&Op) -> Result<Option<Reply>, CommandError>,
+) -> CommandResult {
+    let mut queues = shared
+        .blocking
+        .queues
+        .lock()
+        .map_err(|_| CommandError::Internal("blocking registry poisoned".to_string()))?;
+    if let Some(reply) = try_now(&op)? {
+        return Ok(reply);
+    }
+    if !session.subscriptions.can_push() {
+        return Ok(Reply::Nil);
+    }
+    let (tx, rx) = oneshot::channel();
+    let waiter = Arc::new(Waiter {
+        op,
+        tx: Mutex::new(Some(tx)),
+    });
+    let mut unique: Vec<String> = Vec::with_capacity(keys.len());
+    for key in keys {
+        if !unique.iter().any(|k| k == key) {
+            queues
+                .entry(key.to_string())
+                .or_default()
+                .push_back(Arc::clone(&waiter));
+            unique.push(key.to_string());
+        }
+    }
+    shared.blocking.blocked.fetch_add(1, Ordering::Relaxed);
+    session.blocked = Some(Blocked {
+        waiter,
+        rx,
+        keys: unique,
+        timeout,
+    });
+    Ok(Reply::Nil)
+}
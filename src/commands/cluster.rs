@@ -0,0 +1,669 @@
+use super::replication::LogGuard;
+use super::{key_args, spans_keyspace, Command, CommandError, CommandResult, Reply, Session};
+use crate::config::Config;
+use crate::db::{snapshot, Keyspace};
+use crate::server::Shared;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// Keys are spread over this many slots, and slots over the nodes.
+pub const SLOTS: u16 = 16384;
+
+/// How long MIGRATE waits on the target before giving up. Writes to a
+/// subtree in flight get TRYAGAIN meanwhile, so this is kept short.
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster uses for slots.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The first segment of `key`: `tenant` for `tenant:42:name`.
+fn top_segment(key: &str) -> &str {
+    key.split(':').next().unwrap_or(key)
+}
+
+/// The slot `key` lives in. Only the top-level segment is hashed, so a
+/// whole `tenant:*` subtree stays on one node and prefix scans stay local.
+pub fn key_slot(key: &str) -> u16 {
+    crc16(top_segment(key).as_bytes()) % SLOTS
+}
+
+//
+// ─── Topology ────────────────────────────────────────────────────────────────────
+//
+
+/// Which node serves each slot, and the slots being moved between nodes.
+/// Nodes are named by the `host:port` clients reach them at.
+#[derive(Debug)]
+struct Topology {
+    owners: Vec<Option<Arc<str>>>,
+    /// Slots this node is handing over, with the node receiving them
+    migrating: HashMap<u16, Arc<str>>,
+    /// Slots this node is taking over, with the node they come from
+    importing: HashMap<u16, Arc<str>>,
+    /// Top-level segments MIGRATE is sending to another node right now
+    in_flight: HashSet<String>,
+}
+
+impl Topology {
+    /// TRYAGAIN for a write to a subtree that is on its way to another
+    /// node: it would be deleted here once the transfer is done.
+    fn not_in_flight(&self, command: Command, keys: &[&str]) -> Result<(), CommandError> {
+        if command.flags().write
+            && keys
+                .iter()
+                .any(|key| self.in_flight.contains(top_segment(key)))
+        {
+            return Err(CommandError::TryAgain);
+        }
+        Ok(())
+    }
+
+    /// Runs of consecutive slots with the same owner: (first, last, owner).
+    fn ranges(&self) -> Vec<(u16, u16, Arc<str>)> {
+        let mut ranges: Vec<(u16, u16, Arc<str>)> = Vec::new();
+        for (slot, owner) in self.owners.iter().enumerate() {
+            let Some(owner) = owner else {
+                continue;
+            };
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, last, node)) if *last + 1 == slot && node == owner => *last = slot,
+                _ => ranges.push((slot, slot, Arc::clone(owner))),
+            }
+        }
+        ranges
+    }
+
+    /// Every node mentioned anywhere, sorted.
+    fn nodes(&self, myself: &Arc<str>) -> Vec<Arc<str>> {
+        let mut nodes: Vec<Arc<str>> = self
+            .owners
+            .iter()
+            .flatten()
+            .chain(self.migrating.values())
+            .chain(self.importing.values())
+            .chain([myself])
+            .cloned()
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+}
+
+/// Cluster mode: this node's name and its view of the slot map. Without
+/// `--cluster-enabled` every key is served locally.
+#[derive(Debug)]
+pub struct Cluster {
+    myself: Option<Arc<str>>,
+    topology: RwLock<Topology>,
+}
+
+impl Cluster {
+    pub fn new(config: &Config) -> Self {
+        let myself = config
+            .cluster_enabled
+            .then(|| config.cluster_announce.as_ref().or(config.bind.as_ref()))
+            .flatten()
+            .map(|addr| Arc::from(addr.as_str()));
+        Cluster {
+            myself,
+            topology: RwLock::new(Topology {
+                owners: vec![None; SLOTS as usize],
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+                in_flight: HashSet::new(),
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.myself.is_some()
+    }
+
+    fn topology(&self) -> Result<RwLockReadGuard<'_, Topology>, CommandError> {
+        self.topology
+            .read()
+            .map_err(|_| CommandError::Internal("cluster state poisoned".to_string()))
+    }
+}
+
+//
+// ─── Routing ─────────────────────────────────────────────────────────────────────
+//
+
+/// Check that this node serves the keys of a command, or tell the client
+/// where to go: MOVED for a slot owned elsewhere, ASK for a key whose
+/// subtree has already been migrated out of a slot that is moving.
+///
+/// Whether a subtree is still here is only settled while holding the
+/// replication log, which MIGRATE takes to delete it, so for a slot that is
+/// moving the log is taken into `log` if the command does not hold it yet.
+pub(super) fn route<'a>(
+    command: Command,
+    args: &[&str],
+    shared: &'a Shared,
+    session: &mut Session,
+    log: &mut Option<LogGuard<'a>>,
+) -> Result<(), CommandError> {
+    let keys = key_args(command, args);
+    let Some(ref myself) = shared.cluster.myself else {
+        // MIGRATE works without cluster mode too
+        if keys.is_empty() || !command.flags().write {
+            return Ok(());
+        }
+        return shared.cluster.topology()?.not_in_flight(command, &keys);
+    };
+    if command == Command::Asking {
+        return Ok(());
+    }
+    // ASKING covers only the command right after it
+    let asking = std::mem::take(&mut session.asking);
+    let Some(first) = keys.first() else {
+        if spans_slots(command, args) {
+            return Err(CommandError::syntax(format!(
+                "'{}' covers every slot, which cluster mode does not allow",
+                command.name()
+            )));
+        }
+        return Ok(());
+    };
+    let slot = key_slot(first);
+    if keys.iter().any(|key| key_slot(key) != slot) {
+        return Err(CommandError::CrossSlot);
+    }
+    loop {
+        let topology = shared.cluster.topology()?;
+        return match topology.owners[slot as usize] {
+            Some(ref owner) if owner == myself => {
+                topology.not_in_flight(command, &keys)?;
+                // MIGRATE itself answers NOKEY for what has gone already
+                let Some(target) = topology
+                    .migrating
+                    .get(&slot)
+                    .filter(|_| command != Command::Migrate)
+                else {
+                    return Ok(());
+                };
+                if log.is_none() {
+                    // The log comes before the topology, as in MIGRATE
+                    drop(topology);
+                    *log = Some(shared.replication.lock());
+                    continue;
+                }
+                let mut here = 0;
+                for key in &keys {
+                    if shared.database.version(top_segment(key))?.is_some() {
+                        here += 1;
+                    }
+                }
+                match here {
+                    0 => Err(CommandError::Ask(slot, target.to_string())),
+                    n if n == keys.len() => Ok(()),
+                    _ => Err(CommandError::TryAgain),
+                }
+            }
+            _ if asking && topology.importing.contains_key(&slot) => Ok(()),
+            Some(ref owner) => Err(CommandError::Moved(slot, owner.to_string())),
+            None => Err(CommandError::ClusterDown),
+        };
+    }
+}
+
+/// Keyless commands over the whole keyspace. On a node they would see or
+/// change only its own slots, so cluster mode refuses them. MEMORY is left
+/// alone, as it reports on the node itself.
+fn spans_slots(command: Command, args: &[&str]) -> bool {
+    spans_keyspace(command, args) && command != Command::Memory
+}
+
+/// Check that this node owns the slots of a command run from a script,
+/// function or transaction, and that none of its writes go to a subtree
+/// MIGRATE is sending away.
+/// These hold the write lock, so unlike `route` this cannot look at what
+/// has been migrated already.
+pub(super) fn serves(command: Command, args: &[&str], shared: &Shared) -> Result<(), CommandError> {
+    let keys = key_args(command, args);
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let topology = shared.cluster.topology()?;
+    topology.not_in_flight(command, &keys)?;
+    let Some(ref myself) = shared.cluster.myself else {
+        return Ok(());
+    };
+    for key in keys {
+        let slot = key_slot(key);
+        match topology.owners[slot as usize] {
+            Some(ref owner) if owner == myself => {}
+            Some(ref owner) => return Err(CommandError::Moved(slot, owner.to_string())),
+            None => return Err(CommandError::ClusterDown),
+        }
+    }
+    Ok(())
+}
+
+/// ASKING. Lets the next command into a slot this node is importing.
+pub fn handle_asking(shared: &Shared, session: &mut Session) -> CommandResult {
+    if !shared.cluster.enabled() {
+        return Err(disabled());
+    }
+    session.asking = true;
+    Ok(Reply::ok())
+}
+
+fn disabled() -> CommandError {
+    CommandError::syntax("This instance has cluster support disabled")
+}
+
+//
+// ─── CLUSTER ─────────────────────────────────────────────────────────────────────
+//
+
+/// CLUSTER MYID | INFO | SLOTS | NODES | KEYSLOT key | GETKEYSINSLOT slot count
+/// | SETSLOT slot|first-last NODE node | SETSLOT slot MIGRATING|IMPORTING node
+/// | SETSLOT slot STABLE
+pub fn handle_cluster(args: &[&str], shared: &Shared) -> CommandResult {
+    const USAGE: &str = "Usage: CLUSTER MYID|INFO|SLOTS|NODES|KEYSLOT key|GETKEYSINSLOT slot count|SETSLOT slot NODE|MIGRATING|IMPORTING node|SETSLOT slot STABLE";
+    let usage = || CommandError::syntax(USAGE);
+    let Some(ref myself) = shared.cluster.myself else {
+        return Err(disabled());
+    };
+    let sub = args.get(1).ok_or_else(usage)?.to_ascii_lowercase();
+    let args = &args[2..];
+    let read = || {
+        shared
+            .cluster
+            .topology
+            .read()
+            .map_err(|_| CommandError::Internal("cluster state poisoned".to_string()))
+    };
+    Ok(match (sub.as_str(), args) {
+        ("myid", []) => Reply::Bulk(myself.to_string()),
+        ("keyslot", [key]) => Reply::Integer(key_slot(key) as i64),
+        ("info", []) => {
+            let topology = read()?;
+            let assigned = topology.owners.iter().flatten().count();
+            let mine = topology
+                .owners
+                .iter()
+                .flatten()
+                .filter(|o| *o == myself)
+                .count();
+            let mut out = String::new();
+            let _ = write!(
+                out,
+                "cluster_state:{}\n\
+cluster_slots_assigned:{}\n\
+cluster_my_slots:{}\n\
+cluster_known_nodes:{}\n\
+cluster_migrating_slots:{}\n\
+cluster_importing_slots:{}",
+                if assigned == SLOTS as usize {
+                    "ok"
+                } else {
+                    "fail"
+                },
+                assigned,
+                mine,
+                topology.nodes(myself).len(),
+                topology.migrating.len(),
+                topology.importing.len(),
+            );
+            Reply::Bulk(out)
+        }
+        ("slots", []) => Reply::Array(
+            read()?
+                .ranges()
+                .into_iter()
+                .map(|(first, last, node)| {
+                    Reply::Array(vec![
+                        Reply::Integer(first as i64),
+                        Reply::Integer(last as i64),
+                        Reply::Bulk(node.to_string()),
+                    ])
+                })
+                .collect(),
+        ),
+        ("nodes", []) => {
+            let topology = read()?;
+            let ranges = topology.ranges();
+            let mut out = String::new();
+            for node in topology.nodes(myself) {
+                out.push_str(&node);
+                if node == *myself {
+                    out.push_str(" myself");
+                }
+                for (first, last, _) in ranges.iter().filter(|(_, _, owner)| *owner == node) {
+                    let _ = if first == last {
+                        write!(out, " {first}")
+                    } else {
+                        write!(out, " {first}-{last}")
+                    };
+                }
+                if node == *myself {
+                    let mut moving: Vec<String> = topology
+                        .migrating
+                        .iter()
+                        .map(|(slot, to)| format!(" [{slot}->-{to}]"))
+                        .chain(
+                            topology
+                                .importing
+                                .iter()
+                                .map(|(slot, from)| format!(" [{slot}-<-{from}]")),
+                        )
+                        .collect();
+                    moving.sort();
+                    out.extend(moving);
+                }
+                out.push('\n');
+            }
+            out.pop();
+            Reply::Bulk(out)
+        }
+        ("getkeysinslot", [slot, count]) => {
+            let slot = parse_slot(slot)?;
+            let count: usize = count
+                .parse()
+                .map_err(|_| CommandError::syntax("count is not a number"))?;
+            let mut segments: Vec<String> = shared.database.with_root(|root| {
+                root.c
+                    .iter()
+                    .flat_map(|children| children.keys())
+                    .filter(|segment| key_slot(segment) == slot)
+                    .cloned()
+                    .collect()
+            })?;
+            segments.sort_unstable();
+            segments.truncate(count);
+            Reply::Array(segments.into_iter().map(Reply::Bulk).collect())
+        }
+        ("setslot", [slots, action, rest @ ..]) => {
+            let (first, last) = parse_slot_range(slots)?;
+            let node = rest.first().map(|node| Arc::<str>::from(*node));
+            let mut topology = shared
+                .cluster
+                .topology
+                .write()
+                .map_err(|_| CommandError::Internal("cluster state poisoned".to_string()))?;
+            match (action.to_ascii_lowercase().as_str(), node, rest.len()) {
+                ("node", Some(node), 1) => {
+                    for slot in first..=last {
+                        topology.migrating.remove(&slot);
+                        topology.importing.remove(&slot);
+                        topology.owners[slot as usize] = Some(Arc::clone(&node));
+                    }
+                }
+                ("migrating", Some(node), 1) if first == last => {
+                    if topology.owners[first as usize].as_ref() != Some(myself) {
+                        return Err(CommandError::syntax(format!(
+                            "I'm not the owner of hash slot {first}"
+                        )));
+                    }
+                    topology.migrating.insert(first, node);
+                }
+                ("importing", Some(node), 1) if first == last => {
+                    if topology.owners[first as usize].as_ref() == Some(myself) {
+                        return Err(CommandError::syntax(format!(
+                            "I'm already the owner of hash slot {first}"
+                        )));
+                    }
+                    topology.importing.insert(first, node);
+                }
+                ("stable", None, 0) => {
+                    for slot in first..=last {
+                        topology.migrating.remove(&slot);
+                        topology.importing.remove(&slot);
+                    }
+                }
+                _ => return Err(usage()),
+            }
+            Reply::ok()
+        }
+        _ => return Err(usage()),
+    })
+}
+
+fn parse_slot(arg: &str) -> Result<u16, CommandError> {
+    arg.parse()
+        .ok()
+        .filter(|slot| *slot < SLOTS)
+        .ok_or_else(|| CommandError::syntax(format!("Invalid or out of range slot '{arg}'")))
+}
+
+/// A slot, or a range of them such as `0-8191`.
+fn parse_slot_range(arg: &str) -> Result<(u16, u16), CommandError> {
+    let (first, last) = match arg.split_once('-') {
+        Some((first, last)) => (parse_slot(first)?, parse_slot(last)?),
+        None => (parse_slot(arg)?, parse_slot(arg)?),
+    };
+    if first > last {
+        return Err(CommandError::syntax(format!("Invalid slot range '{arg}'")));
+    }
+    Ok((first, last))
+}
+
+//
+// ─── Moving Subtrees ─────────────────────────────────────────────────────────────
+//
+// DUMP serializes a subtree as hex, since the text protocol splits on spaces;
+// RESTORE puts it back, here or on another node. MIGRATE does both at once
+// for a top-level subtree, which is how a slot is emptied onto another node.
+//
+
+pub(super) fn to_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(out, "{byte:02x}");
+    }
+    out
+}
+
+pub(super) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// DUMP key. The subtree at `key` as a payload for RESTORE.
+pub fn handle_dump(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 2 {
+        return Err(CommandError::syntax("Usage: DUMP key"));
+    }
+    Ok(database
+        .dump(args[1])?
+        .map_or(Reply::Nil, |bytes| Reply::Bulk(to_hex(&bytes))))
+}
+
+/// RESTORE key payload [REPLACE]
+pub fn handle_restore(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    let replace = match args {
+        [_, _, _] => false,
+        [_, _, _, opt] if opt.eq_ignore_ascii_case("replace") => true,
+        _ => return Err(CommandError::syntax("Usage: RESTORE key payload [REPLACE]")),
+    };
+    let subtree = from_hex(args[2])
+        .and_then(|bytes| snapshot::node_from_bytes(&bytes).ok())
+        .ok_or_else(|| CommandError::syntax("DUMP payload is not valid"))?;
+    if !database.restore(args[1], subtree, replace)? {
+        return Err(CommandError::BusyKey);
+    }
+    Ok(Reply::ok())
+}
+
+/// MIGRATE host port segment [REPLACE] [AUTH password]. Move the subtree
+/// under a top-level segment to another node and delete it here, replying
+/// NOKEY if there is none. Writes to it get TRYAGAIN while it is in flight,
+/// so none are lost; the replication log is only held before and after.
+pub fn handle_migrate(args: &[&str], shared: &Shared) -> CommandResult {
+    const USAGE: &str = "Usage: MIGRATE host port segment [REPLACE] [AUTH password]";
+    if args.len() < 4 {
+        return Err(CommandError::syntax(USAGE));
+    }
+    let segment = args[3];
+    if segment.contains(':') {
+        return Err(CommandError::syntax(
+            "MIGRATE moves whole top-level subtrees",
+        ));
+    }
+    let (mut replace, mut auth) = (false, None);
+    let mut options = args[4..].iter();
+    while let Some(opt) = options.next() {
+        match opt.to_ascii_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "AUTH" => auth = Some(*options.next().ok_or_else(|| CommandError::syntax(USAGE))?),
+            _ => return Err(CommandError::syntax(USAGE)),
+        }
+    }
+    let poisoned = || CommandError::Internal("cluster state poisoned".to_string());
+    let in_flight = |on: bool| -> Result<bool, CommandError> {
+        let mut topology = shared.cluster.topology.write().map_err(|_| poisoned())?;
+        Ok(if on {
+            topology.in_flight.insert(segment.to_string())
+        } else {
+            topology.in_flight.remove(segment)
+        })
+    };
+
+    // Writes already past `route` hold the log, so once it is ours they
+    // are all in the dump, and later ones see the segment in flight
+    let log = shared.replication.lock();
+    if !in_flight(true)? {
+        return Err(CommandError::TryAgain);
+    }
+    let payload = match shared.database.dump(segment) {
+        Ok(Some(payload)) => payload,
+        dumped => {
+            in_flight(false)?;
+            return dumped
+                .map(|_| Reply::Status("NOKEY".to_string()))
+                .map_err(Into::into);
+        }
+    };
+    drop(log);
+
+    let addr = format!("{}:{}", args[1], args[2]);
+    let sent = blocking_io(|| send_subtree(&addr, auth, segment, &payload, replace));
+    let mut log = shared.replication.lock();
+    let moved = match sent {
+        Ok(Ok(())) => shared.database.delete(segment).map_err(Into::into),
+        Ok(Err(reply)) => Err(CommandError::Internal(format!(
+            "Target {addr} refused: {reply}"
+        ))),
+        Err(e) => Err(CommandError::Internal(format!(
+            "IOERR migrating to {addr}: {e}"
+        ))),
+    };
+    if moved.is_ok() {
+        log.feed(&["DEL", segment]);
+    }
+    in_flight(false)?;
+    moved.map(|_| Reply::ok())
+}
+
+/// Run blocking work from a command, such as network I/O or waiting on a
+/// script. On a multi-threaded runtime the worker first hands its other
+/// connections to the rest of the pool.
+pub(super) fn blocking_io<T>(io: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(io)
+        }
+        _ => io(),
+    }
+}
+
+/// RESTORE `payload` at `key` on the node at `addr`, sent with ASKING so
+/// it is accepted while the node is still importing the slot. The inner
+/// error is the target's reply when it refused.
+fn send_subtree(
+    addr: &str,
+    auth: Option<&str>,
+    key: &str,
+    payload: &[u8],
+    replace: bool,
+) -> io::Result<Result<(), String>> {
+    let target = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let stream = TcpStream::connect_timeout(&target, MIGRATE_TIMEOUT)?;
+    stream.set_read_timeout(Some(MIGRATE_TIMEOUT))?;
+    stream.set_write_timeout(Some(MIGRATE_TIMEOUT))?;
+    let mut requests = Vec::new();
+    if let Some(password) = auth {
+        requests.push(format!("AUTH {password}\n"));
+    }
+    requests.push("ASKING\n".to_string());
+    let mut restore = format!("RESTORE {key} {}", to_hex(payload));
+    if replace {
+        restore.push_str(" REPLACE");
+    }
+    restore.push('\n');
+    requests.push(restore);
+    (&stream).write_all(requests.concat().as_bytes())?;
+
+    let mut reader = BufReader::new(&stream);
+    for _ in &requests {
+        if let Err(refused) = read_reply(&mut reader)? {
+            return Ok(Err(refused));
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Read one reply in the text protocol: an array is `*N` and its items, a
+/// value that needs it is `$len` and then the value, and anything else is
+/// a single line. The inner error is the first error line found.
+fn read_reply(reader: &mut impl BufRead) -> io::Result<Result<(), String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad reply '{line}'"));
+    if let Some(error) = line.strip_prefix('-') {
+        return Ok(Err(error.to_string()));
+    }
+    if let Some(len) = line.strip_prefix('*') {
+        let len: i64 = len.parse().map_err(|_| bad())?;
+        let mut reply = Ok(());
+        for _ in 0..len.max(0) {
+            let item = read_reply(reader)?;
+            if reply.is_ok() {
+                reply = item;
+            }
+        }
+        return Ok(reply);
+    }
+    if let Some(len) = line.strip_prefix('$') {
+        let len: usize = len.parse().map_err(|_| bad())?;
+        // The value and the newline after it
+        if io::copy(&mut reader.by_ref().take(len as u64 + 1), &mut io::sink())? != len as u64 + 1 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(Ok(()))
+}
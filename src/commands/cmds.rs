@@ -1,150 +1,691 @@
-use crate::db::{Database, Value};
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::tcp::OwnedWriteHalf;
-
-pub struct CommandParts<'a> {
-    parts: Vec<&'a [u8]>,
-}
-
-impl<'a> CommandParts<'a> {
-    pub fn new(buf: &'a [u8]) -> Self {
-        let mut parts = Vec::new();
-        let mut start = 0;
-        for (i, &b) in buf.iter().enumerate() {
-            if b == b' ' {
-                if start < i {
-                    parts.push(&buf[start..i]);
-                }
-                start = i + 1;
-            }
-        }
-        if start < buf.len() {
-            parts.push(&buf[start..]);
+use super::{dispatch_command, Command, CommandError, CommandResult, Reply, Session, Stats};
+use crate::acl::DEFAULT_USER;
+use crate::allocator;
+use crate::db::{self, core, Database, Keyspace, Value};
+use crate::server::Shared;
+use std::fmt::Write as _;
+
+/// SET key value
+pub fn handle_set(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 3 {
+        return Err(CommandError::syntax("Usage: SET key value [NX|XX] [GET]"));
+    }
+    let (mut nx, mut xx, mut get) = (false, false, false);
+    for opt in &args[3..] {
+        match opt.to_ascii_uppercase().as_str() {
+            "NX" if !xx => nx = true,
+            "XX" if !nx => xx = true,
+            "GET" => get = true,
+            _ => return Err(CommandError::syntax("Usage: SET key value [NX|XX] [GET]")),
         }
-        CommandParts { parts }
     }
-    pub fn get(&self, idx: usize) -> Option<&'a [u8]> {
-        self.parts.get(idx).copied()
+    let value = Value::Text(args[2].to_string());
+    let (written, previous) = database.set_if(args[1], value, |current| {
+        let exists = current.is_some();
+        // SET ... GET refuses to replace a list or set
+        let wrong_type = get && current.is_some_and(|(v, _)| !matches!(v, Value::Text(_)));
+        let allowed = if exists { !nx } else { !xx };
+        allowed && !wrong_type
+    })?;
+    if get {
+        return match previous {
+            Some(Value::Text(s)) => Ok(Reply::Bulk(s)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(Reply::Nil),
+        };
+    }
+    Ok(if written { Reply::ok() } else { Reply::Nil })
+}
+
+/// GETSET key value, i.e. SET key value GET
+pub fn handle_getset(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 3 {
+        return Err(CommandError::syntax("Usage: GETSET key value"));
+    }
+    handle_set(&[args[0], args[1], args[2], "GET"], database)
+}
+
+/// GETDEL key. Only the key's own value goes; keys beneath it stay.
+pub fn handle_getdel(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: GETDEL key"));
+    }
+    Ok(database.take_text(args[1])?.map_or(Reply::Nil, Reply::Bulk))
+}
+
+/// GETV key, the value and its version for a later CAS
+pub fn handle_getv(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: GETV key"));
+    }
+    Ok(match database.get_versioned(args[1])? {
+        Some((Value::Text(s), version)) => {
+            Reply::Array(vec![Reply::Bulk(s), Reply::Integer(version as i64)])
+        }
+        Some(_) => return Err(CommandError::WrongType),
+        None => Reply::Nil,
+    })
+}
+
+/// CAS key version value. Sets the key only if it still has the version
+/// GETV returned; 1 if it was set, 0 if the key changed or is gone.
+pub fn handle_cas(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 4 {
+        return Err(CommandError::syntax("Usage: CAS key version value"));
     }
-    pub fn len(&self) -> usize {
-        self.parts.len()
+    let expected: u64 = args[2]
+        .parse()
+        .map_err(|_| CommandError::syntax("version must be a number"))?;
+    let value = Value::Text(args[3].to_string());
+    let (written, _) = database.set_if(args[1], value, |current| {
+        current.is_some_and(|(_, version)| version == expected)
+    })?;
+    Ok(written.into())
+}
+
+/// CHILDREN [key] -> the segments directly beneath key, sorted; the top
+/// level without one. Clients use it to complete key paths.
+pub fn handle_children(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() > 2 {
+        return Err(CommandError::syntax("Usage: CHILDREN [key]"));
     }
+    let names = database.children(args.get(1).copied().unwrap_or(""))?;
+    Ok(Reply::Array(names.into_iter().map(Reply::Bulk).collect()))
 }
 
-pub async fn handle_set(
-    parts: &CommandParts<'_>,
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
-) -> std::io::Result<()> {
-    if parts.len() < 3 {
-        return write_response(writer, b"Usage: SET key value\n").await;
+/// GET key
+pub fn handle_get(args: &[&str], database: &impl Keyspace, stats: &Stats) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: GET key"));
     }
-    let key = bytes_to_str(parts.get(1).unwrap());
-    let value = Value::Text(bytes_to_str(parts.get(2).unwrap()).to_string());
-    match database.set(key, value) {
-        Ok(_) => write_response(writer, b"OK\n").await,
-        Err(_) => write_response(writer, b"Error: SET failed\n").await,
+    let result = database.get_text(args[1]);
+    match result {
+        Ok(Some(_)) | Err(crate::db::Error::WrongType) => stats.hit(),
+        Ok(None) => stats.miss(),
+        Err(_) => {}
     }
+    Ok(match result? {
+        Some(val) => Reply::Bulk(val),
+        None => Reply::Nil,
+    })
 }
 
-pub async fn handle_get(
-    parts: &CommandParts<'_>,
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
-) -> std::io::Result<()> {
-    if parts.len() < 2 {
-        return write_response(writer, b"Usage: GET key\n").await;
+/// DEL key
+pub fn handle_del(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: DEL key"));
     }
-    let key = bytes_to_str(parts.get(1).unwrap());
-    match database.get(key) {
-        Ok(Some(Value::Text(val))) => write_str(writer, &val).await,
-        Ok(Some(_)) => write_response(writer, b"(value)\n").await, // fallback for other types
-        Ok(None) => write_response(writer, b"(nil)\n").await,
-        Err(_) => write_response(writer, b"Error: GET failed\n").await,
+    Ok(database.delete(args[1])?.into())
+}
+
+/// EXPIRE key seconds
+pub fn handle_expire(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 3 {
+        return Err(CommandError::syntax("Usage: EXPIRE key seconds"));
     }
+    let secs: u64 = args[2]
+        .parse()
+        .map_err(|_| CommandError::syntax("seconds must be a number"))?;
+    let at = secs
+        .checked_mul(1000)
+        .and_then(|ms| core::now_ms().checked_add(ms))
+        .ok_or_else(|| CommandError::syntax("invalid expire time"))?;
+    Ok(database.expire_at(args[1], at)?.into())
 }
 
-pub async fn handle_del(
-    parts: &CommandParts<'_>,
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
-) -> std::io::Result<()> {
-    if parts.len() < 2 {
-        return write_response(writer, b"Usage: DEL key\n").await;
+/// PEXPIREAT key unix-time-milliseconds. EXPIRE reaches replicas as this,
+/// so they expire the key when the primary does, however late they apply it.
+pub fn handle_pexpireat(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 3 {
+        return Err(CommandError::syntax(
+            "Usage: PEXPIREAT key unix-time-milliseconds",
+        ));
     }
-    let key = bytes_to_str(parts.get(1).unwrap());
-    match database.delete(key) {
-        Ok(true) => write_response(writer, b"1\n").await,
-        Ok(false) => write_response(writer, b"0\n").await,
-        Err(_) => write_response(writer, b"Error: DEL failed\n").await,
+    let at: u64 = args[2]
+        .parse()
+        .map_err(|_| CommandError::syntax("invalid expire time in 'pexpireat' command"))?;
+    Ok(database.expire_at(args[1], at)?.into())
+}
+
+/// TTL key -> seconds left, -1 without expiry, -2 if missing
+pub fn handle_ttl(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: TTL key"));
     }
+    Ok(match database.expiry(args[1])? {
+        Some(Some(at)) => {
+            let left = at.saturating_sub(core::now_ms()).div_ceil(1000);
+            Reply::Integer(left as i64)
+        }
+        Some(None) => Reply::Integer(-1),
+        None => Reply::Integer(-2),
+    })
 }
 
-pub async fn handle_drop(
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
-) -> std::io::Result<()> {
-    database.drop_all();
-    write_response(writer, b"OK\n").await
+/// PERSIST key -> 1 if an expiry was removed
+pub fn handle_persist(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: PERSIST key"));
+    }
+    let key = args[1];
+    let removed = match database.expiry(key)? {
+        Some(Some(_)) => database.persist(key)?,
+        _ => false,
+    };
+    Ok(removed.into())
 }
 
-pub async fn handle_memory(
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
-) -> std::io::Result<()> {
-    let stats = database.memory();
-    let total_kb = stats.total_bytes as f64 / 1024.0;
-    let total_mb = stats.total_bytes as f64 / (1024.0 * 1024.0);
-    let smallest_kb = stats.smallest_node as f64 / 1024.0;
-    let largest_kb = stats.largest_node as f64 / 1024.0;
+pub fn handle_drop(database: &impl Keyspace) -> CommandResult {
+    database.drop_all()?;
+    Ok(Reply::ok())
+}
 
-    let response = format!(
-        "Nodes: {}\n\
-Total Approx Size: {} bytes | {:.2} KB | {:.4} MB\n\
+/// MEMORY                -> allocator figures and dataset size
+/// MEMORY USAGE key      -> bytes used by one key
+/// MEMORY USAGE prefix:* -> bytes used by a whole subtree
+/// MEMORY NODES          -> per-node walk (node count, smallest/largest)
+pub fn handle_memory(args: &[&str], database: &Database) -> CommandResult {
+    let sub = args.get(1).map(|s| s.to_ascii_lowercase());
+    Ok(match sub.as_deref() {
+        None => {
+            let mut out = String::new();
+            // Allocator figures only mean something when jemalloc serves
+            // the process
+            if let Some(mem) = allocator::stats() {
+                let _ = write!(
+                    out,
+                    "Allocated: {} bytes | {}\n\
+Resident: {} bytes | {}\n\
+Active: {} bytes | {}\n\
+Allocator Metadata: {} bytes | {}\n\
+Fragmentation Ratio: {:.2}\n",
+                    mem.allocated,
+                    allocator::human_bytes(mem.allocated),
+                    mem.resident,
+                    allocator::human_bytes(mem.resident),
+                    mem.active,
+                    allocator::human_bytes(mem.active),
+                    mem.metadata,
+                    allocator::human_bytes(mem.metadata),
+                    mem.fragmentation_ratio(),
+                );
+            }
+            let dataset = database.dataset_bytes()?;
+            let _ = write!(
+                out,
+                "Dataset: {} bytes | {}",
+                dataset,
+                allocator::human_bytes(dataset)
+            );
+            Reply::Bulk(out)
+        }
+        Some("usage") => {
+            if args.len() < 3 {
+                return Err(CommandError::syntax("Usage: MEMORY USAGE key|prefix:*"));
+            }
+            let key = args[2];
+            let usage = match key.strip_suffix('*') {
+                Some(prefix) => database.usage_prefix(prefix.strip_suffix(':').unwrap_or(prefix)),
+                None => database.usage(key),
+            };
+            match usage? {
+                Some(bytes) => Reply::Integer(bytes as i64),
+                None => Reply::Nil,
+            }
+        }
+        Some("nodes") => {
+            let stats = database.memory();
+            let total_kb = stats.total_bytes as f64 / 1024.0;
+            let total_mb = stats.total_bytes as f64 / (1024.0 * 1024.0);
+            let smallest_kb = stats.smallest_node as f64 / 1024.0;
+            let largest_kb = stats.largest_node as f64 / 1024.0;
+            Reply::Bulk(format!(
+                "Nodes: {}\n\
+Total Size: {} bytes | {:.2} KB | {:.4} MB\n\
 Smallest Node: {} bytes | {:.3} KB\n\
-Largest Node: {} bytes | {:.3} KB\n",
-        stats.node_count,
-        stats.total_bytes, total_kb, total_mb,
-        stats.smallest_node, smallest_kb,
-        stats.largest_node, largest_kb,
+Largest Node: {} bytes | {:.3} KB",
+                stats.node_count,
+                stats.total_bytes,
+                total_kb,
+                total_mb,
+                stats.smallest_node,
+                smallest_kb,
+                stats.largest_node,
+                largest_kb,
+            ))
+        }
+        Some(_) => return Err(CommandError::syntax("Usage: MEMORY [USAGE key|NODES]")),
+    })
+}
+
+pub fn handle_size(database: &Database) -> CommandResult {
+    Ok(Reply::Status(format!("Keys: {}", database.size()?)))
+}
+
+/// SHUTDOWN [SAVE|NOSAVE]. Closes the session once shutdown has been triggered.
+pub fn handle_shutdown(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    let save = match args.get(1).map(|m| m.to_ascii_lowercase()) {
+        None => true,
+        Some(m) if m == "save" => {
+            if shared.config.snapshot_path.is_none() {
+                return Err(CommandError::syntax("no snapshot path configured"));
+            }
+            true
+        }
+        Some(m) if m == "nosave" => false,
+        Some(_) => return Err(CommandError::syntax("Usage: SHUTDOWN [SAVE|NOSAVE]")),
+    };
+    shared.shutdown.trigger(save);
+    // Nothing is saved, so a script cut short leaves nothing half-written
+    #[cfg(feature = "lua")]
+    if !save {
+        shared.scripts.abort();
+    }
+    session.quit = true;
+    Ok(Reply::ok())
+}
+
+/// INFO [section]. With no argument every section except commandstats is
+/// returned; `all` includes it too.
+pub fn handle_info(args: &[&str], shared: &Shared) -> CommandResult {
+    const DEFAULT: &[&str] = &[
+        "server",
+        "clients",
+        "memory",
+        "stats",
+        "replication",
+        "cluster",
+        "keyspace",
+    ];
+    const ALL: &[&str] = &[
+        "server",
+        "clients",
+        "memory",
+        "stats",
+        "replication",
+        "cluster",
+        "commandstats",
+        "keyspace",
+    ];
+
+    let section = args.get(1).map(|s| s.to_ascii_lowercase());
+    let sections: Vec<&str> = match section.as_deref() {
+        None | Some("default") => DEFAULT.to_vec(),
+        Some("all") | Some("everything") => ALL.to_vec(),
+        Some(name) => match ALL.iter().find(|s| **s == name) {
+            Some(s) => vec![*s],
+            None => return Err(CommandError::NotFound(format!("INFO section '{name}'"))),
+        },
+    };
+
+    let mut response = String::with_capacity(1024);
+    for (i, section) in sections.iter().enumerate() {
+        if i > 0 {
+            response.push('\n');
+        }
+        match *section {
+            "server" => info_server(&mut response, shared),
+            "clients" => info_clients(&mut response, shared),
+            "memory" => info_memory(&mut response, &shared.database)?,
+            "stats" => info_stats(&mut response, shared),
+            "replication" => info_replication(&mut response, shared),
+            "cluster" => {
+                let enabled = shared.cluster.enabled() as u8;
+                let _ = write!(response, "# Cluster\ncluster_enabled:{}\n", enabled);
+            }
+            "commandstats" => info_commandstats(&mut response, &shared.stats),
+            "keyspace" => info_keyspace(&mut response, &shared.database)?,
+            _ => unreachable!(),
+        }
+    }
+    response.truncate(response.trim_end().len());
+    Ok(Reply::Bulk(response))
+}
+
+fn info_server(out: &mut String, shared: &Shared) {
+    let uptime = shared.started.elapsed().as_secs();
+    let _ = write!(
+        out,
+        "# Server\n\
+flashtree_version:{}\n\
+process_id:{}\n\
+bind:{}\n\
+tls_bind:{}\n\
+unixsocket:{}\n\
+uptime_in_seconds:{}\n\
+uptime_in_days:{}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::process::id(),
+        shared.config.bind.as_deref().unwrap_or("-"),
+        shared.config.tls_bind.as_deref().unwrap_or("-"),
+        shared
+            .config
+            .unixsocket
+            .as_ref()
+            .map_or("-".into(), |path| path.display().to_string()),
+        uptime,
+        uptime / 86400,
     );
+}
 
-    write_response(writer, response.as_bytes()).await
+fn info_clients(out: &mut String, shared: &Shared) {
+    let clients = &shared.clients;
+    let _ = write!(
+        out,
+        "# Clients\n\
+connected_clients:{}\n\
+max_clients:{}\n\
+max_clients_per_ip:{}\n\
+blocked_clients:{}\n",
+        clients.connected(),
+        clients.max(),
+        clients.max_per_ip(),
+        shared.blocking.blocked_clients(),
+    );
 }
 
+fn info_replication(out: &mut String, shared: &Shared) {
+    let status = shared.replication.status();
+    let role = if status.primary.is_some() {
+        "replica"
+    } else {
+        "primary"
+    };
+    let _ = write!(
+        out,
+        "# Replication\nrole:{}\nread_only:{}\n",
+        role, status.read_only as u8
+    );
+    if let Some(ref primary) = status.primary {
+        let _ = write!(
+            out,
+            "primary_host:{}\n\
+primary_link_status:{}\n\
+replica_read_only:{}\n",
+            primary,
+            if status.link_up { "up" } else { "down" },
+            status.replica_read_only as u8,
+        );
+    }
+    let _ = write!(
+        out,
+        "connected_replicas:{}\n\
+repl_id:{}\n\
+repl_offset:{}\n\
+repl_backlog_active:{}\n\
+repl_backlog_size:{}\n\
+repl_backlog_histlen:{}\n",
+        status.replicas,
+        status.replid,
+        status.offset,
+        status.backlog_len.is_some() as u8,
+        status.backlog_size,
+        status.backlog_len.unwrap_or(0),
+    );
+}
 
+fn info_memory(out: &mut String, database: &Database) -> db::Result<()> {
+    // Allocator figures only mean something when jemalloc serves the process
+    let mem = allocator::stats();
+    let dataset = database.dataset_bytes()?;
+    let eviction = database.eviction()?;
+    out.push_str("# Memory\n");
+    if let Some(ref mem) = mem {
+        let _ = write!(
+            out,
+            "used_memory:{}\n\
+used_memory_human:{}\n\
+used_memory_rss:{}\n\
+used_memory_rss_human:{}\n",
+            mem.allocated,
+            allocator::human_bytes(mem.allocated),
+            mem.resident,
+            allocator::human_bytes(mem.resident),
+        );
+    }
+    let _ = write!(
+        out,
+        "used_memory_dataset:{}\n\
+maxmemory:{}\n\
+maxmemory_human:{}\n\
+maxmemory_policy:{}\n\
+maxmemory_subtree_depth:{}\n",
+        dataset,
+        eviction.maxmemory,
+        allocator::human_bytes(eviction.maxmemory),
+        eviction.policy.name(),
+        eviction.subtree_depth,
+    );
+    if let Some(ref mem) = mem {
+        let _ = write!(
+            out,
+            "allocator_active:{}\n\
+allocator_mapped:{}\n\
+allocator_metadata:{}\n\
+allocator_retained:{}\n\
+mem_fragmentation_ratio:{:.2}\n",
+            mem.active,
+            mem.mapped,
+            mem.metadata,
+            mem.retained,
+            mem.fragmentation_ratio(),
+        );
+    }
+    let _ = writeln!(out, "mem_allocator:{}", allocator::name());
+    Ok(())
+}
 
-pub async fn handle_size(
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
-) -> std::io::Result<()> {
-    let count = database.size();    
+fn info_stats(out: &mut String, shared: &Shared) {
+    let [channels, patterns, trees] = shared.pubsub.counts();
+    let _ = write!(
+        out,
+        "# Stats\n\
+total_connections_received:{}\n\
+total_commands_processed:{}\n\
+rejected_connections:{}\n\
+keyspace_hits:{}\n\
+keyspace_misses:{}\n\
+total_error_replies:{}\n\
+evicted_keys:{}\n\
+pubsub_channels:{}\n\
+pubsub_patterns:{}\n\
+watched_trees:{}\n",
+        shared.clients.total_received(),
+        shared.stats.total_commands(),
+        shared.clients.rejected(),
+        shared.stats.hits(),
+        shared.stats.misses(),
+        shared.stats.errors(),
+        shared.database.evicted_keys(),
+        channels,
+        patterns,
+        trees,
+    );
+}
 
-    let response = format!("Keys: {count}\n");
+fn info_commandstats(out: &mut String, stats: &Stats) {
+    out.push_str("# Commandstats\n");
+    for stat in stats.commands() {
+        let _ = writeln!(
+            out,
+            "cmdstat_{}:calls={},usec={},usec_per_call={:.2}",
+            stat.name,
+            stat.calls,
+            stat.usec,
+            stat.usec as f64 / stat.calls as f64,
+        );
+    }
+}
 
-    write_response(writer, response.as_bytes()).await
+fn info_keyspace(out: &mut String, database: &Database) -> db::Result<()> {
+    let _ = write!(
+        out,
+        "# Keyspace\n\
+keys:{}\n\
+nodes:{}\n",
+        database.key_count()?,
+        database.size()?,
+    );
+    Ok(())
 }
 
+/// HELLO [protover]. Protocol 2 switches the connection to RESP replies,
+/// 1 back to plain lines; the greeting is sent in the new protocol.
+pub fn handle_hello(args: &[&str], session: &mut Session) -> CommandResult {
+    match args.get(1).copied() {
+        None => {}
+        Some("1") => session.resp = false,
+        Some("2") => session.resp = true,
+        Some(version) => {
+            return Err(CommandError::syntax(format!(
+                "Unsupported protocol version {version}; use 1 for plain lines or 2 for RESP"
+            )))
+        }
+    }
+    Ok(Reply::Status("Hi there! FlashTree v0.1".to_string()))
+}
 
-//
-// ─── Misc Helpers ──────────────────────────────────────────────────────────────
-//
+/// AUTH password | AUTH username password
+pub fn handle_auth(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    let (name, password) = match args.len() {
+        2 => (DEFAULT_USER, args[1]),
+        3 => (args[1], args[2]),
+        _ => return Err(CommandError::syntax("Usage: AUTH [username] password")),
+    };
+    let user = shared
+        .acl
+        .authenticate(name, password)
+        .ok_or(CommandError::WrongPass)?;
+    session.user = Some(user.name.clone());
+    Ok(Reply::ok())
+}
 
-#[inline]
-pub fn bytes_to_str(bytes: &[u8]) -> &str {
-    std::str::from_utf8(bytes).unwrap_or("")
+/// ACL WHOAMI | LIST | USERS | SETUSER name rules... | DELUSER name...
+pub fn handle_acl(args: &[&str], shared: &Shared, session: &Session) -> CommandResult {
+    const USAGE: &str = "Usage: ACL WHOAMI|LIST|USERS|SETUSER name [rule...]|DELUSER name...";
+    let usage = || CommandError::syntax(USAGE);
+    let sub = args.get(1).ok_or_else(usage)?.to_ascii_lowercase();
+    let args = &args[2..];
+    Ok(match sub.as_str() {
+        "whoami" => match session.user {
+            Some(ref user) => Reply::Bulk(user.clone()),
+            None => Reply::Nil,
+        },
+        "list" => Reply::Array(
+            shared
+                .acl
+                .users()
+                .iter()
+                .map(|u| Reply::Bulk(u.describe()))
+                .collect(),
+        ),
+        "users" => Reply::Array(
+            shared
+                .acl
+                .users()
+                .iter()
+                .map(|u| Reply::Bulk(u.name.clone()))
+                .collect(),
+        ),
+        "setuser" => {
+            let (name, rules) = args.split_first().ok_or_else(usage)?;
+            shared
+                .acl
+                .set_user(name, rules)
+                .map_err(CommandError::Syntax)?;
+            Reply::ok()
+        }
+        "deluser" => {
+            if args.is_empty() {
+                return Err(usage());
+            }
+            if args.contains(&DEFAULT_USER) {
+                return Err(CommandError::syntax("the 'default' user cannot be removed"));
+            }
+            let removed = args.iter().filter(|name| shared.acl.del_user(name)).count();
+            Reply::Integer(removed as i64)
+        }
+        _ => return Err(usage()),
+    })
 }
 
-#[inline(always)]
-pub async fn write_response(
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    data: &[u8],
-) -> std::io::Result<()> {
-    writer.write_all(data).await?;
-    writer.flush().await
+/// Settings CONFIG can read and change at runtime.
+const CONFIG_PARAMS: &[&str] = &["read-only", "replica-read-only"];
+
+/// CONFIG GET parameter | CONFIG SET parameter value
+pub fn handle_config(args: &[&str], shared: &Shared) -> CommandResult {
+    const USAGE: &str = "Usage: CONFIG GET parameter | CONFIG SET parameter value";
+    let sub = args.get(1).ok_or_else(|| CommandError::syntax(USAGE))?;
+    let param = args
+        .get(2)
+        .ok_or_else(|| CommandError::syntax(USAGE))?
+        .to_ascii_lowercase();
+    if !CONFIG_PARAMS.contains(&param.as_str()) {
+        return Err(CommandError::NotFound(format!(
+            "CONFIG parameter '{param}'"
+        )));
+    }
+    let status = shared.replication.status();
+    match (sub.to_ascii_lowercase().as_str(), args.get(3)) {
+        ("get", None) => {
+            let on = match param.as_str() {
+                "read-only" => status.read_only,
+                _ => status.replica_read_only,
+            };
+            let value = if on { "yes" } else { "no" };
+            Ok(Reply::Array(vec![
+                Reply::Bulk(param),
+                Reply::Bulk(value.to_string()),
+            ]))
+        }
+        ("set", Some(value)) if args.len() == 4 => {
+            let on = match value.to_ascii_lowercase().as_str() {
+                "yes" => true,
+                "no" => false,
+                _ => return Err(CommandError::syntax(format!("{param} expects yes or no"))),
+            };
+            match param.as_str() {
+                "read-only" => shared.replication.set_read_only(on),
+                _ => shared.replication.set_replica_read_only(on),
+            }
+            Ok(Reply::ok())
+        }
+        _ => Err(CommandError::syntax(USAGE)),
+    }
 }
 
-#[inline]
-pub async fn write_str(writer: &mut BufWriter<OwnedWriteHalf>, value: &str) -> std::io::Result<()> {
-    write_response(writer, format!("{value}\n").as_bytes()).await
+/// COMMAND [COUNT | INFO name [name ...]]. Lists commands with their
+/// classification flags, e.g. `get read`, so clients and proxies can tell
+/// reads from writes without a table of their own.
+pub fn handle_command_info(args: &[&str]) -> CommandResult {
+    let describe = |command: Command| {
+        let mut items = vec![Reply::Bulk(command.name().to_string())];
+        items.extend(
+            command
+                .flags()
+                .categories()
+                .map(|c| Reply::Bulk(c.to_string())),
+        );
+        Reply::Array(items)
+    };
+    let known = || Command::ALL.into_iter().filter(|c| *c != Command::Unknown);
+    let sub = args.get(1).map(|s| s.to_ascii_lowercase());
+    Ok(match sub.as_deref() {
+        None => Reply::Array(known().map(describe).collect()),
+        Some("count") if args.len() == 2 => Reply::Integer(known().count() as i64),
+        Some("info") if args.len() > 2 => Reply::Array(
+            args[2..]
+                .iter()
+                .map(|name| match dispatch_command(name) {
+                    Command::Unknown => Reply::Nil,
+                    command => describe(command),
+                })
+                .collect(),
+        ),
+        _ => {
+            return Err(CommandError::syntax(
+                "Usage: COMMAND [COUNT | INFO name [name ...]]",
+            ))
+        }
+    })
 }
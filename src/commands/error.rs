@@ -0,0 +1,240 @@
+use crate::db;
+use std::fmt;
+
+/// Why a command failed. Each kind is sent with a stable leading word
+/// (`ERR`, `WRONGTYPE`, `NOAUTH`, ...) so clients can branch on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// Bad or missing arguments; the message says what was expected
+    Syntax(String),
+    UnknownCommand(String),
+    /// The key holds a different kind of value
+    WrongType,
+    /// The named key, user or section does not exist
+    NotFound(String),
+    /// Over `maxmemory` and nothing could be evicted
+    OutOfMemory,
+    NoAuth,
+    WrongPass,
+    NoPerm(&'static str),
+    /// A write was sent to a read-only server
+    ReadOnly,
+    /// EXEC after a command failed to queue
+    ExecAbort,
+    /// The stream or consumer group does not exist
+    NoGroup,
+    /// XGROUP CREATE for a group that already exists
+    BusyGroup,
+    /// RESTORE onto a key that exists, without REPLACE
+    BusyKey,
+    /// The key's slot is served by another cluster node
+    Moved(u16, String),
+    /// The key's slot is being migrated and the key has moved already;
+    /// retry once at that node, after ASKING
+    Ask(u16, String),
+    /// The keys of one command live in different slots
+    CrossSlot,
+    /// Some of the keys have been migrated and some not yet
+    TryAgain,
+    /// No cluster node serves the key's slot
+    ClusterDown,
+    /// A script has run past the time limit and holds the dataset
+    Busy,
+    /// SCRIPT KILL with no script running
+    NotBusy,
+    /// SCRIPT KILL on a script that has written already
+    Unkillable,
+    /// EVALSHA of a script that is not cached
+    NoScript,
+    /// An error raised or returned by a script, sent as it is, leading
+    /// word included
+    Script(String),
+    /// Failure inside the server itself, e.g. a poisoned lock
+    Internal(String),
+}
+
+pub type CommandResult = Result<super::Reply, CommandError>;
+
+impl CommandError {
+    pub fn syntax(msg: impl Into<String>) -> Self {
+        CommandError::Syntax(msg.into())
+    }
+
+    /// The leading word of the error reply.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            CommandError::Syntax(_)
+            | CommandError::UnknownCommand(_)
+            | CommandError::NotFound(_)
+            | CommandError::Script(_)
+            | CommandError::Internal(_) => "ERR",
+            CommandError::WrongType => "WRONGTYPE",
+            CommandError::OutOfMemory => "OOM",
+            CommandError::NoAuth => "NOAUTH",
+            CommandError::WrongPass => "WRONGPASS",
+            CommandError::NoPerm(_) => "NOPERM",
+            CommandError::ReadOnly => "READONLY",
+            CommandError::ExecAbort => "EXECABORT",
+            CommandError::NoGroup => "NOGROUP",
+            CommandError::BusyGroup => "BUSYGROUP",
+            CommandError::BusyKey => "BUSYKEY",
+            CommandError::Moved(..) => "MOVED",
+            CommandError::Ask(..) => "ASK",
+            CommandError::CrossSlot => "CROSSSLOT",
+            CommandError::TryAgain => "TRYAGAIN",
+            CommandError::ClusterDown => "CLUSTERDOWN",
+            CommandError::Busy => "BUSY",
+            CommandError::NotBusy => "NOTBUSY",
+            CommandError::Unkillable => "UNKILLABLE",
+            CommandError::NoScript => "NOSCRIPT",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let CommandError::Script(msg) = self {
+            return f.write_str(msg);
+        }
+        write!(f, "{} ", self.prefix())?;
+        match self {
+            CommandError::Syntax(msg) | CommandError::Internal(msg) => f.write_str(msg),
+            CommandError::UnknownCommand(name) => write!(f, "unknown command '{name}'"),
+            CommandError::WrongType => {
+                f.write_str("Operation against a key holding the wrong kind of value")
+            }
+            CommandError::NotFound(what) => write!(f, "no such {what}"),
+            CommandError::OutOfMemory => {
+                f.write_str("command not allowed when used memory > 'maxmemory'")
+            }
+            CommandError::NoAuth => f.write_str("Authentication required"),
+            CommandError::WrongPass => {
+                f.write_str("invalid username-password pair or user is disabled")
+            }
+            CommandError::NoPerm(what) => {
+                write!(f, "this user has no permissions to {what}")
+            }
+            CommandError::ReadOnly => f.write_str("You can't write against a read only server"),
+            CommandError::ExecAbort => {
+                f.write_str("Transaction discarded because of previous errors.")
+            }
+            CommandError::NoGroup => f.write_str("No such key or consumer group"),
+            CommandError::BusyGroup => f.write_str("Consumer Group name already exists"),
+            CommandError::BusyKey => f.write_str("Target key name already exists."),
+            CommandError::Moved(slot, node) | CommandError::Ask(slot, node) => {
+                write!(f, "{slot} {node}")
+            }
+            CommandError::CrossSlot => {
+                f.write_str("Keys in request don't hash to the same slot")
+            }
+            CommandError::TryAgain => {
+                f.write_str("Multiple keys request during rehashing of slot")
+            }
+            CommandError::ClusterDown => f.write_str("Hash slot not served"),
+            CommandError::Busy => f.write_str(
+                "FlashTree is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.",
+            ),
+            CommandError::NotBusy => f.write_str("No scripts in execution right now."),
+            CommandError::Unkillable => f.write_str(
+                "The script already executed write commands against the dataset, so it can only run to the end.",
+            ),
+            CommandError::NoScript => f.write_str("No matching script. Please use EVAL."),
+            CommandError::Script(_) => unreachable!("written above"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<db::Error> for CommandError {
+    fn from(e: db::Error) -> Self {
+        match e {
+            db::Error::WrongType => CommandError::WrongType,
+            db::Error::OutOfMemory => CommandError::OutOfMemory,
+            db::Error::NoGroup => CommandError::NoGroup,
+            db::Error::GroupExists => CommandError::BusyGroup,
+            db::Error::StreamIdTooSmall => CommandError::Syntax(e.to_string()),
+            db::Error::LockPoisoned => CommandError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommandError;
+    use crate::db;
+
+    #[test]
+    fn every_kind_is_sent_with_its_own_leading_word() {
+        let cases = [
+            (CommandError::syntax("Usage: GET key"), "ERR Usage: GET key"),
+            (
+                CommandError::UnknownCommand("FOO".into()),
+                "ERR unknown command 'FOO'",
+            ),
+            (CommandError::NotFound("user".into()), "ERR no such user"),
+            (
+                CommandError::Internal("Lock poisoned".into()),
+                "ERR Lock poisoned",
+            ),
+            (CommandError::WrongType, "WRONGTYPE "),
+            (CommandError::OutOfMemory, "OOM "),
+            (CommandError::NoAuth, "NOAUTH "),
+            (CommandError::WrongPass, "WRONGPASS "),
+            (CommandError::NoPerm("run this command"), "NOPERM "),
+            (CommandError::ReadOnly, "READONLY "),
+            (CommandError::ExecAbort, "EXECABORT "),
+            (CommandError::NoGroup, "NOGROUP "),
+            (CommandError::BusyGroup, "BUSYGROUP "),
+            (CommandError::BusyKey, "BUSYKEY "),
+            (
+                CommandError::Moved(3999, "10.0.0.2:2002".into()),
+                "MOVED 3999 10.0.0.2:2002",
+            ),
+            (
+                CommandError::Ask(3999, "10.0.0.2:2002".into()),
+                "ASK 3999 10.0.0.2:2002",
+            ),
+            (CommandError::CrossSlot, "CROSSSLOT "),
+            (CommandError::TryAgain, "TRYAGAIN "),
+            (CommandError::ClusterDown, "CLUSTERDOWN "),
+            (CommandError::Busy, "BUSY "),
+            (CommandError::NotBusy, "NOTBUSY "),
+            (CommandError::Unkillable, "UNKILLABLE "),
+            (CommandError::NoScript, "NOSCRIPT "),
+            (
+                CommandError::Script("ERR Error running script: boom".into()),
+                "ERR Error running script: boom",
+            ),
+        ];
+        for (error, start) in cases {
+            let text = error.to_string();
+            assert!(
+                text.starts_with(start),
+                "{text:?} does not start with {start:?}"
+            );
+            assert!(text.starts_with(&format!("{} ", error.prefix())));
+        }
+    }
+
+    #[test]
+    fn db_errors_keep_their_kind() {
+        assert_eq!(
+            CommandError::from(db::Error::WrongType),
+            CommandError::WrongType
+        );
+        assert_eq!(
+            CommandError::from(db::Error::OutOfMemory),
+            CommandError::OutOfMemory
+        );
+        assert_eq!(
+            CommandError::from(db::Error::NoGroup),
+            CommandError::NoGroup
+        );
+        assert_eq!(
+            CommandError::from(db::Error::GroupExists),
+            CommandError::BusyGroup
+        );
+        assert_eq!(CommandError::from(db::Error::LockPoisoned).prefix(), "ERR");
+    }
+}
@@ -0,0 +1,523 @@
+use super::replication::LogGuard;
+use super::sandbox::{split_keys, Calls};
+use super::{cluster, CommandError, CommandResult, Reply, Session};
+use crate::config::Config;
+use crate::db::snapshot;
+use crate::server::Shared;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use wasmi::core::{TrapCode, ValType};
+use wasmi::{
+    Caller, Engine, Extern, ExternType, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+//
+// ─── Host API ────────────────────────────────────────────────────────────────────
+//
+// A library is a WASM module. Every export that takes no parameters and
+// returns an i64 is a function FCALL can run; the i64 is its reply unless
+// it called `reply` or `reply_error`. The module reaches the dataset only
+// through these imports from the `flashtree` namespace:
+//
+//   arg_count() -> i32                    keys and arguments passed to FCALL
+//   key_count() -> i32                    how many of them, from the start, are keys
+//   arg(index, ptr, cap) -> i32           copy one into memory; its length, or -1
+//   get(key, key_len, ptr, cap) -> i32    copy a value into memory; its length, or -1 if nil
+//   set(key, key_len, value, value_len)
+//   del(key, key_len) -> i32              1 if something was removed
+//   reply(ptr, len)                       reply with this string
+//   reply_error(ptr, len)                 reply with this error, e.g. "ERR bad input"
+//
+// Values longer than `cap` are cut short, so a caller can retry with a
+// bigger buffer. Reads and writes run as GET, SET and DEL with the caller's
+// permissions; one that fails ends the function with its error.
+//
+
+const NAMESPACE: &str = "flashtree";
+
+/// Name, parameters and results of each import a module may use.
+const HOST_API: [(&str, &[ValType], &[ValType]); 8] = [
+    ("arg_count", &[], &[ValType::I32]),
+    ("key_count", &[], &[ValType::I32]),
+    ("arg", &[ValType::I32; 3], &[ValType::I32]),
+    ("get", &[ValType::I32; 4], &[ValType::I32]),
+    ("set", &[ValType::I32; 4], &[]),
+    ("del", &[ValType::I32; 2], &[ValType::I32]),
+    ("reply", &[ValType::I32; 2], &[]),
+    ("reply_error", &[ValType::I32; 2], &[]),
+];
+
+/// What the host functions see during one FCALL.
+struct Host<'a, 'tx> {
+    calls: &'a Calls<'a, 'tx>,
+    /// Keys, then the other arguments
+    args: &'a [&'a str],
+    keys: usize,
+    reply: Option<Reply>,
+    /// Error of the host call that ended the function
+    failed: Option<CommandError>,
+    limits: StoreLimits,
+}
+
+type HostCaller<'c, 'a, 'tx> = Caller<'c, Host<'a, 'tx>>;
+
+fn host_arg_count(caller: HostCaller) -> i32 {
+    caller.data().args.len() as i32
+}
+
+fn host_key_count(caller: HostCaller) -> i32 {
+    caller.data().keys as i32
+}
+
+fn host_arg(mut caller: HostCaller, index: i32, ptr: i32, cap: i32) -> Result<i32, wasmi::Error> {
+    let arg = usize::try_from(index)
+        .ok()
+        .and_then(|i| caller.data().args.get(i))
+        .map(|arg| arg.to_string());
+    match arg {
+        Some(arg) => write(&mut caller, ptr, cap, arg.as_bytes()),
+        None => Ok(-1),
+    }
+}
+
+fn host_get(
+    mut caller: HostCaller,
+    key: i32,
+    key_len: i32,
+    ptr: i32,
+    cap: i32,
+) -> Result<i32, wasmi::Error> {
+    let key = read(&caller, key, key_len)?;
+    match call(&mut caller, &["GET", &key])? {
+        Reply::Bulk(value) => write(&mut caller, ptr, cap, value.as_bytes()),
+        _ => Ok(-1),
+    }
+}
+
+fn host_set(
+    mut caller: HostCaller,
+    key: i32,
+    key_len: i32,
+    value: i32,
+    value_len: i32,
+) -> Result<(), wasmi::Error> {
+    let key = read(&caller, key, key_len)?;
+    let value = read(&caller, value, value_len)?;
+    call(&mut caller, &["SET", &key, &value]).map(drop)
+}
+
+fn host_del(mut caller: HostCaller, key: i32, key_len: i32) -> Result<i32, wasmi::Error> {
+    let key = read(&caller, key, key_len)?;
+    match call(&mut caller, &["DEL", &key])? {
+        Reply::Integer(n) => Ok(n.min(1) as i32),
+        _ => Ok(0),
+    }
+}
+
+fn host_reply(mut caller: HostCaller, ptr: i32, len: i32) -> Result<(), wasmi::Error> {
+    let reply = read(&caller, ptr, len)?;
+    caller.data_mut().reply = Some(Reply::Bulk(reply));
+    Ok(())
+}
+
+fn host_reply_error(mut caller: HostCaller, ptr: i32, len: i32) -> Result<(), wasmi::Error> {
+    let error = read(&caller, ptr, len)?;
+    caller.data_mut().reply = Some(Reply::Error(CommandError::Script(error)));
+    Ok(())
+}
+
+/// Run a command for the module, trapping with its error if it fails.
+fn call(caller: &mut HostCaller, args: &[&str]) -> Result<Reply, wasmi::Error> {
+    caller.data().calls.call(args).map_err(|e| {
+        let trap = wasmi::Error::new(e.to_string());
+        caller.data_mut().failed = Some(e);
+        trap
+    })
+}
+
+fn memory(caller: &HostCaller) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("module exports no memory"))
+}
+
+/// The UTF-8 string at `ptr`.
+fn read(caller: &HostCaller, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    let memory = memory(caller)?;
+    let bytes = usize::try_from(ptr)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(ptr, len)| memory.data(caller).get(ptr..ptr.checked_add(len)?))
+        .ok_or_else(|| wasmi::Error::new("out of bounds memory access"))?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| wasmi::Error::new("string is not UTF-8"))
+}
+
+/// Copy as much of `bytes` as fits in `cap` to `ptr`; returns the full length.
+fn write(caller: &mut HostCaller, ptr: i32, cap: i32, bytes: &[u8]) -> Result<i32, wasmi::Error> {
+    let memory = memory(caller)?;
+    let (Ok(ptr), Ok(cap)) = (usize::try_from(ptr), usize::try_from(cap)) else {
+        return Err(wasmi::Error::new("out of bounds memory access"));
+    };
+    memory
+        .write(caller, ptr, &bytes[..bytes.len().min(cap)])
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(bytes.len() as i32)
+}
+
+//
+// ─── Library Registry ────────────────────────────────────────────────────────────
+//
+
+/// Loaded libraries by name. Each FCALL instantiates its library afresh,
+/// so nothing carries over from one call to the next.
+pub struct Functions {
+    engine: Engine,
+    libraries: RwLock<HashMap<String, Arc<Library>>>,
+    /// Fuel for one call; roughly one unit per instruction
+    fuel: u64,
+    /// Bytes of linear memory one call may use
+    max_memory: usize,
+}
+
+struct Library {
+    module: Module,
+    /// Starts at 1 and goes up with each FUNCTION LOAD REPLACE
+    version: u64,
+    /// SHA-256 of the module
+    digest: String,
+    functions: Vec<String>,
+    /// The module as loaded, to save and to send to replicas
+    wasm: Vec<u8>,
+}
+
+impl std::fmt::Debug for Functions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let libraries = self.libraries.read().map_or(0, |libraries| libraries.len());
+        f.debug_struct("Functions")
+            .field("libraries", &libraries)
+            .field("fuel", &self.fuel)
+            .field("max_memory", &self.max_memory)
+            .finish()
+    }
+}
+
+impl Functions {
+    pub fn new(config: &Config) -> Self {
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        Functions {
+            engine: Engine::new(&engine_config),
+            libraries: RwLock::new(HashMap::new()),
+            fuel: config.function_fuel,
+            max_memory: config.function_max_memory,
+        }
+    }
+
+    /// Validate a module and register it as library `name`.
+    fn load(&self, name: &str, wasm: &[u8], replace: bool) -> Result<(), CommandError> {
+        let module = Module::new(&self.engine, wasm)
+            .map_err(|e| CommandError::syntax(format!("Invalid WASM module: {e}")))?;
+        let mut imports = false;
+        for import in module.imports() {
+            let known = HOST_API.iter().find(|(api, ..)| *api == import.name());
+            let matches = match (known, import.ty()) {
+                (Some((_, params, results)), ExternType::Func(ty)) => {
+                    import.module() == NAMESPACE
+                        && ty.params() == *params
+                        && ty.results() == *results
+                }
+                _ => false,
+            };
+            if !matches {
+                return Err(CommandError::syntax(format!(
+                    "Unknown import '{}.{}': only the flashtree host API is available",
+                    import.module(),
+                    import.name()
+                )));
+            }
+            imports = true;
+        }
+        let exports: Vec<_> = module.exports().collect();
+        if imports
+            && !exports
+                .iter()
+                .any(|e| e.name() == "memory" && e.ty().memory().is_some())
+        {
+            return Err(CommandError::syntax(
+                "Module must export its memory as 'memory'",
+            ));
+        }
+        let mut functions: Vec<String> = exports
+            .iter()
+            .filter(|export| {
+                export
+                    .ty()
+                    .func()
+                    .is_some_and(|ty| ty.params().is_empty() && ty.results() == [ValType::I64])
+            })
+            .map(|export| export.name().to_string())
+            .collect();
+        functions.sort();
+        if functions.is_empty() {
+            return Err(CommandError::syntax(
+                "Library exports no functions; a function takes no parameters and returns i64",
+            ));
+        }
+
+        let mut libraries = self.libraries.write().map_err(|_| poisoned())?;
+        let previous = libraries.get(name);
+        if previous.is_some() && !replace {
+            return Err(CommandError::syntax(format!(
+                "Library '{name}' already exists"
+            )));
+        }
+        for (other, library) in libraries.iter().filter(|(other, _)| *other != name) {
+            if let Some(function) = functions.iter().find(|f| library.functions.contains(f)) {
+                return Err(CommandError::syntax(format!(
+                    "Function {function} already exists in library '{other}'"
+                )));
+            }
+        }
+        let library = Library {
+            module,
+            version: previous.map_or(1, |library| library.version + 1),
+            digest: cluster::to_hex(&Sha256::digest(wasm)),
+            functions,
+            wasm: wasm.to_vec(),
+        };
+        libraries.insert(name.to_string(), Arc::new(library));
+        Ok(())
+    }
+
+    /// Every library as a `name hex` line, sorted by name. This is what is
+    /// saved next to the snapshot and sent to replicas with theirs.
+    pub fn dump(&self) -> Result<Vec<u8>, CommandError> {
+        let libraries = self.libraries.read().map_err(|_| poisoned())?;
+        let mut names: Vec<&String> = libraries.keys().collect();
+        names.sort();
+        let mut out = Vec::new();
+        for name in names {
+            let line = format!("{name} {}\n", cluster::to_hex(&libraries[name].wasm));
+            out.extend(line.into_bytes());
+        }
+        Ok(out)
+    }
+
+    /// Replace every library with the ones in `dump`, from `Functions::dump`.
+    pub fn restore(&self, dump: &[u8]) -> Result<(), CommandError> {
+        let invalid = || CommandError::syntax("Function dump is not valid");
+        let dump = std::str::from_utf8(dump).map_err(|_| invalid())?;
+        let mut loaded = Vec::new();
+        for line in dump.lines() {
+            let (name, hex) = line.split_once(' ').ok_or_else(invalid)?;
+            loaded.push((name, cluster::from_hex(hex).ok_or_else(invalid)?));
+        }
+        self.libraries.write().map_err(|_| poisoned())?.clear();
+        for (name, wasm) in loaded {
+            self.load(name, &wasm, false)?;
+        }
+        Ok(())
+    }
+
+    /// Save the libraries next to the snapshot at `snapshot`.
+    pub fn save(&self, snapshot: &Path) -> io::Result<()> {
+        let dump = self.dump().map_err(io::Error::other)?;
+        snapshot::write_atomic(&saved_at(snapshot), &dump)
+    }
+
+    /// Load the libraries saved next to the snapshot at `snapshot`, if any.
+    pub fn load_saved(&self, snapshot: &Path) -> io::Result<()> {
+        let path = saved_at(snapshot);
+        if !path.exists() {
+            return Ok(());
+        }
+        self.restore(&std::fs::read(&path)?)
+            .map_err(|e| io::Error::other(format!("{}: {e}", path.display())))
+    }
+
+    /// The library exporting `function`.
+    fn find(&self, function: &str) -> Result<Option<Arc<Library>>, CommandError> {
+        let libraries = self.libraries.read().map_err(|_| poisoned())?;
+        Ok(libraries
+            .values()
+            .find(|library| library.functions.iter().any(|f| f == function))
+            .cloned())
+    }
+
+    /// Run `function` from `library` with fuel and memory limits.
+    fn call(
+        &self,
+        library: &Library,
+        function: &str,
+        calls: &Calls,
+        args: &[&str],
+        keys: usize,
+    ) -> CommandResult {
+        let host = Host {
+            calls,
+            args,
+            keys,
+            reply: None,
+            failed: None,
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.max_memory)
+                .instances(1)
+                .build(),
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store
+            .set_fuel(self.fuel)
+            .map_err(|e| CommandError::Internal(e.to_string()))?;
+        let mut linker = Linker::new(&self.engine);
+        link(&mut linker).map_err(|e| CommandError::Internal(e.to_string()))?;
+
+        let result = linker
+            .instantiate(&mut store, &library.module)
+            .and_then(|pre| pre.start(&mut store))
+            .and_then(|instance| instance.get_typed_func::<(), i64>(&store, function))
+            .and_then(|func| func.call(&mut store, ()));
+        let host = store.data_mut();
+        match result {
+            Ok(n) => match host.reply.take() {
+                Some(Reply::Error(e)) => Err(e),
+                Some(reply) => Ok(reply),
+                None => Ok(Reply::Integer(n)),
+            },
+            Err(_) if host.failed.is_some() => Err(host.failed.take().unwrap()),
+            Err(e) if e.as_trap_code() == Some(TrapCode::OutOfFuel) => Err(CommandError::Script(
+                "ERR Function ran out of fuel".to_string(),
+            )),
+            Err(e) => {
+                let message = e.to_string();
+                let message = message.lines().next().unwrap_or_default();
+                Err(CommandError::Script(format!(
+                    "ERR Error running function: {message}"
+                )))
+            }
+        }
+    }
+}
+
+fn link(linker: &mut Linker<Host>) -> Result<(), wasmi::errors::LinkerError> {
+    linker
+        .func_wrap(NAMESPACE, "arg_count", host_arg_count)?
+        .func_wrap(NAMESPACE, "key_count", host_key_count)?
+        .func_wrap(NAMESPACE, "arg", host_arg)?
+        .func_wrap(NAMESPACE, "get", host_get)?
+        .func_wrap(NAMESPACE, "set", host_set)?
+        .func_wrap(NAMESPACE, "del", host_del)?
+        .func_wrap(NAMESPACE, "reply", host_reply)?
+        .func_wrap(NAMESPACE, "reply_error", host_reply_error)?;
+    Ok(())
+}
+
+fn poisoned() -> CommandError {
+    CommandError::Internal("function registry poisoned".to_string())
+}
+
+//
+// ─── FCALL / FUNCTION ────────────────────────────────────────────────────────────
+//
+
+/// FCALL function numkeys [key ...] [arg ...]. Runs under the database
+/// write lock, so a function is atomic like a script.
+pub fn handle_fcall(
+    args: &[&str],
+    shared: &Shared,
+    session: &Session,
+    log: Option<&mut LogGuard>,
+) -> CommandResult {
+    let (Some(function), Some(_)) = (args.get(1), args.get(2)) else {
+        return Err(CommandError::syntax(
+            "Usage: FCALL function numkeys [key ...] [arg ...]",
+        ));
+    };
+    let (keys, _) = split_keys(args)?;
+    let library = shared
+        .functions
+        .find(function)?
+        .ok_or_else(|| CommandError::NotFound(format!("function '{function}'")))?;
+    let wrote = AtomicBool::new(false);
+    let (result, effects) = shared.database.transaction(|tx| {
+        let calls = Calls::new(shared, session, tx, &wrote);
+        let result = shared
+            .functions
+            .call(&library, function, &calls, &args[3..], keys.len());
+        (result, calls.into_effects())
+    })?;
+    effects.publish(shared, log);
+    result
+}
+
+/// FUNCTION LOAD [REPLACE] library hex | LIST | DELETE library | FLUSH.
+/// The module is sent hex-encoded, like a DUMP payload.
+pub fn handle_function(args: &[&str], shared: &Shared) -> CommandResult {
+    const USAGE: &str =
+        "Usage: FUNCTION LOAD [REPLACE] library hex | LIST | DELETE library | FLUSH";
+    let functions = &shared.functions;
+    let sub = args.get(1).map(|s| s.to_ascii_lowercase());
+    match (sub.as_deref(), &args[2.min(args.len())..]) {
+        (Some("load"), [name, hex]) => load(functions, name, hex, false),
+        (Some("load"), [opt, name, hex]) if opt.eq_ignore_ascii_case("replace") => {
+            load(functions, name, hex, true)
+        }
+        (Some("list"), []) => {
+            let libraries = functions.libraries.read().map_err(|_| poisoned())?;
+            let mut names: Vec<&String> = libraries.keys().collect();
+            names.sort();
+            Ok(Reply::Array(
+                names
+                    .into_iter()
+                    .map(|name| {
+                        let library = &libraries[name];
+                        let functions = library.functions.iter().cloned().map(Reply::Bulk);
+                        Reply::Array(vec![
+                            Reply::Bulk("library_name".to_string()),
+                            Reply::Bulk(name.clone()),
+                            Reply::Bulk("version".to_string()),
+                            Reply::Integer(library.version as i64),
+                            Reply::Bulk("sha256".to_string()),
+                            Reply::Bulk(library.digest.clone()),
+                            Reply::Bulk("functions".to_string()),
+                            Reply::Array(functions.collect()),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+        (Some("delete"), [name]) => {
+            let mut libraries = functions.libraries.write().map_err(|_| poisoned())?;
+            match libraries.remove(*name) {
+                Some(_) => Ok(Reply::ok()),
+                None => Err(CommandError::NotFound(format!("library '{name}'"))),
+            }
+        }
+        (Some("flush"), []) => {
+            functions.libraries.write().map_err(|_| poisoned())?.clear();
+            Ok(Reply::ok())
+        }
+        _ => Err(CommandError::syntax(USAGE)),
+    }
+}
+
+/// Where the libraries go for the snapshot at `snapshot`: `dump.json`
+/// keeps them in `dump.json.functions`.
+fn saved_at(snapshot: &Path) -> PathBuf {
+    let mut path = snapshot.as_os_str().to_owned();
+    path.push(".functions");
+    PathBuf::from(path)
+}
+
+fn load(functions: &Functions, name: &str, hex: &str, replace: bool) -> CommandResult {
+    let wasm =
+        cluster::from_hex(hex).ok_or_else(|| CommandError::syntax("Module must be hex-encoded"))?;
+    functions.load(name, &wasm, replace)?;
+    Ok(Reply::Bulk(name.to_string()))
+}
@@ -0,0 +1,67 @@
+use super::{CommandError, CommandResult, Reply};
+use crate::db::{End, Keyspace};
+
+/// LPUSH / RPUSH key element [element ...]
+pub fn handle_push(args: &[&str], database: &impl Keyspace, end: End) -> CommandResult {
+    if args.len() < 3 {
+        return Err(CommandError::syntax(format!(
+            "Usage: {} key element [element ...]",
+            args[0].to_ascii_uppercase()
+        )));
+    }
+    Ok(Reply::Integer(
+        database.push(args[1], end, &args[2..])? as i64
+    ))
+}
+
+/// LPOP / RPOP key
+pub fn handle_pop(args: &[&str], database: &impl Keyspace, end: End) -> CommandResult {
+    if args.len() != 2 {
+        return Err(CommandError::syntax(format!(
+            "Usage: {} key",
+            args[0].to_ascii_uppercase()
+        )));
+    }
+    Ok(database.pop(args[1], end)?.map_or(Reply::Nil, Reply::Bulk))
+}
+
+/// LLEN key
+pub fn handle_llen(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 2 {
+        return Err(CommandError::syntax("Usage: LLEN key"));
+    }
+    Ok(Reply::Integer(database.list_len(args[1])? as i64))
+}
+
+/// LRANGE key start stop
+pub fn handle_lrange(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 4 {
+        return Err(CommandError::syntax("Usage: LRANGE key start stop"));
+    }
+    let index = |s: &str| {
+        s.parse::<i64>()
+            .map_err(|_| CommandError::syntax("start and stop must be numbers"))
+    };
+    let items = database.list_range(args[1], index(args[2])?, index(args[3])?)?;
+    Ok(Reply::Array(items.into_iter().map(Reply::Bulk).collect()))
+}
+
+/// LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+pub fn handle_lmove(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 5 {
+        return Err(CommandError::syntax(
+            "Usage: LMOVE source destination LEFT|RIGHT LEFT|RIGHT",
+        ));
+    }
+    let (from, to) = parse_ends(args[3], args[4])?;
+    Ok(database
+        .pop_push(args[1], args[2], from, to)?
+        .map_or(Reply::Nil, Reply::Bulk))
+}
+
+pub(super) fn parse_ends(from: &str, to: &str) -> Result<(End, End), CommandError> {
+    match (End::parse(from), End::parse(to)) {
+        (Some(from), Some(to)) => Ok((from, to)),
+        _ => Err(CommandError::syntax("direction must be LEFT or RIGHT")),
+    }
+}
@@ -1,41 +1,375 @@
-use crate::db::Database;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::tcp::OwnedWriteHalf;
+use crate::db::{End, Keyspace};
+use crate::server::Shared;
+use std::time::Instant;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+mod blocking;
+mod cluster;
 mod cmds;
+mod error;
+#[cfg(feature = "wasm")]
+mod functions;
+mod lists;
+mod multi;
+mod pubsub;
+mod replication;
+mod reply;
+#[cfg(any(feature = "lua", feature = "wasm"))]
+mod sandbox;
+#[cfg(feature = "lua")]
+mod scripting;
+mod stats;
+mod streams;
+pub use blocking::{Blocked, Blocking};
+pub use cluster::{key_slot, Cluster, SLOTS};
+pub use error::{CommandError, CommandResult};
+#[cfg(feature = "wasm")]
+pub use functions::Functions;
+use pubsub::Kind;
+pub use pubsub::{PubSub, PUSH_BACKLOG};
+use replication::LogGuard;
+pub use replication::{apply as apply_replicated, Chunk, Feed, Replication};
+pub use reply::Reply;
+#[cfg(feature = "lua")]
+pub use scripting::Scripts;
+pub use stats::{Stats, LATENCY_BUCKETS_USEC};
 
 //
 // ─── Utility Functions ───────────────────────────────────────────────────────────
 
+/// Split a request line into arguments, dropping the line ending.
 #[inline]
-fn normalize_command(input: &[u8]) -> &[u8] {
-    let mut end = input.len();
-    while end > 0 && (input[end - 1] == b'\r' || input[end - 1] == b'\n') {
-        end -= 1;
-    }
-    &input[..end]
+pub fn parse_args(line: &str) -> Vec<&str> {
+    line.trim_end_matches(['\r', '\n'])
+        .split(' ')
+        .filter(|arg| !arg.is_empty())
+        .collect()
 }
 
 //
 // ─── Command Enum and Matching ───────────────────────────────────────────────────
 //
 
-#[derive(Debug)]
-enum Command {
-    Ping,
-    Hello,
-    Exit,
-    Set,
-    Get,
-    Del,
-    Drop,
-    Memory,
-    Size,
-    Unknown,
-
-}
-
-fn dispatch_command(cmd: &[u8]) -> Command {
-    let s = std::str::from_utf8(cmd).unwrap_or("").to_ascii_lowercase();
+/// What a command does, from the classification table in `Command::flags`.
+/// Read-only mode refuses `write` commands, and ACL rules such as `+@read`
+/// or `-@admin` select commands by these categories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    /// Reads the dataset
+    pub read: bool,
+    /// Changes the dataset
+    pub write: bool,
+    /// Administers the server
+    pub admin: bool,
+}
+
+impl Flags {
+    pub const CATEGORIES: [&'static str; 3] = ["read", "write", "admin"];
+
+    const fn new(read: bool, write: bool, admin: bool) -> Self {
+        Flags { read, write, admin }
+    }
+
+    /// The categories these flags put a command in.
+    pub fn categories(self) -> impl Iterator<Item = &'static str> {
+        Self::CATEGORIES
+            .into_iter()
+            .zip([self.read, self.write, self.admin])
+            .filter_map(|(category, on)| on.then_some(category))
+    }
+}
+
+/// The flags of the command called `name`, or `None` if there is no such
+/// command. Aliases such as QUIT resolve to the command they stand for.
+pub fn command_flags(name: &str) -> Option<Flags> {
+    match dispatch_command(name) {
+        Command::Unknown => None,
+        command => Some(command.flags()),
+    }
+}
+
+/// Declare the command enum together with its table of every command and
+/// the name each one reports, so a new command can't be left out of
+/// COMMAND or INFO commandstats.
+macro_rules! commands {
+    ($($variant:ident => $name:literal,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Command {
+            $($variant,)*
+        }
+
+        impl Command {
+            const COUNT: usize = [$(stringify!($variant),)*].len();
+            const ALL: [Command; Command::COUNT] = [$(Command::$variant,)*];
+
+            fn name(self) -> &'static str {
+                match self {
+                    $(Command::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+commands! {
+    Ping => "ping",
+    Hello => "hello",
+    Exit => "exit",
+    Set => "set",
+    Get => "get",
+    Del => "del",
+    Drop => "drop",
+    Memory => "memory",
+    Size => "size",
+    Shutdown => "shutdown",
+    Info => "info",
+    Expire => "expire",
+    Pexpireat => "pexpireat",
+    Ttl => "ttl",
+    Persist => "persist",
+    Auth => "auth",
+    Acl => "acl",
+    Multi => "multi",
+    Exec => "exec",
+    Discard => "discard",
+    Watch => "watch",
+    Unwatch => "unwatch",
+    Getset => "getset",
+    Getdel => "getdel",
+    Getv => "getv",
+    Cas => "cas",
+    Children => "children",
+    Subscribe => "subscribe",
+    Unsubscribe => "unsubscribe",
+    Psubscribe => "psubscribe",
+    Punsubscribe => "punsubscribe",
+    Publish => "publish",
+    Watchtree => "watchtree",
+    Unwatchtree => "unwatchtree",
+    Lpush => "lpush",
+    Rpush => "rpush",
+    Lpop => "lpop",
+    Rpop => "rpop",
+    Llen => "llen",
+    Lrange => "lrange",
+    Lmove => "lmove",
+    Blpop => "blpop",
+    Brpop => "brpop",
+    Blmove => "blmove",
+    Xadd => "xadd",
+    Xrange => "xrange",
+    Xlen => "xlen",
+    Xtrim => "xtrim",
+    Xread => "xread",
+    Xreadgroup => "xreadgroup",
+    Xgroup => "xgroup",
+    Xack => "xack",
+    Xpending => "xpending",
+    Xclaim => "xclaim",
+    Replicaof => "replicaof",
+    Psync => "psync",
+    Config => "config",
+    Commands => "command",
+    Cluster => "cluster",
+    Asking => "asking",
+    Dump => "dump",
+    Restore => "restore",
+    Migrate => "migrate",
+    Eval => "eval",
+    Evalsha => "evalsha",
+    Script => "script",
+    Function => "function",
+    Fcall => "fcall",
+    Unknown => "unknown",
+}
+
+impl Command {
+    /// Commands that only touch keys, and so can run inside a transaction.
+    fn is_keyspace(self) -> bool {
+        matches!(
+            self,
+            Command::Ping
+                | Command::Set
+                | Command::Get
+                | Command::Del
+                | Command::Drop
+                | Command::Expire
+                | Command::Pexpireat
+                | Command::Ttl
+                | Command::Persist
+                | Command::Getset
+                | Command::Getdel
+                | Command::Getv
+                | Command::Cas
+                | Command::Children
+                | Command::Lpush
+                | Command::Rpush
+                | Command::Lpop
+                | Command::Rpop
+                | Command::Llen
+                | Command::Lrange
+                | Command::Lmove
+                | Command::Xadd
+                | Command::Xrange
+                | Command::Xlen
+                | Command::Xtrim
+                | Command::Xgroup
+                | Command::Xack
+                | Command::Xpending
+                | Command::Xclaim
+                | Command::Dump
+                | Command::Restore
+        )
+    }
+
+    /// The classification table: what each command does to the dataset.
+    fn flags(self) -> Flags {
+        const NONE: Flags = Flags::new(false, false, false);
+        const READ: Flags = Flags::new(true, false, false);
+        const WRITE: Flags = Flags::new(false, true, false);
+        const ADMIN: Flags = Flags::new(false, false, true);
+        match self {
+            Command::Get
+            | Command::Ttl
+            | Command::Getv
+            | Command::Children
+            | Command::Memory
+            | Command::Size
+            | Command::Watch
+            | Command::Llen
+            | Command::Lrange
+            | Command::Xrange
+            | Command::Xlen
+            | Command::Xread
+            | Command::Xpending
+            | Command::Dump => READ,
+            Command::Set
+            | Command::Del
+            | Command::Drop
+            | Command::Expire
+            | Command::Pexpireat
+            | Command::Persist
+            | Command::Getset
+            | Command::Getdel
+            | Command::Cas
+            | Command::Lpush
+            | Command::Rpush
+            | Command::Lpop
+            | Command::Rpop
+            | Command::Lmove
+            | Command::Blpop
+            | Command::Brpop
+            | Command::Blmove
+            | Command::Xadd
+            | Command::Xtrim
+            | Command::Xgroup
+            | Command::Xreadgroup
+            | Command::Xack
+            | Command::Xclaim
+            | Command::Restore => WRITE,
+            Command::Migrate => Flags::new(false, true, true),
+            Command::Shutdown
+            | Command::Acl
+            | Command::Config
+            | Command::Cluster
+            | Command::Replicaof
+            | Command::Psync
+            | Command::Script => ADMIN,
+            // LOAD, DELETE and FLUSH change what is saved and replicated
+            Command::Function => Flags::new(false, true, true),
+            Command::Ping
+            | Command::Hello
+            | Command::Exit
+            | Command::Info
+            | Command::Auth
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Unwatch
+            | Command::Subscribe
+            | Command::Unsubscribe
+            | Command::Psubscribe
+            | Command::Punsubscribe
+            | Command::Publish
+            | Command::Watchtree
+            | Command::Unwatchtree
+            | Command::Commands
+            | Command::Asking
+            // Scripts and functions are checked command by command as they
+            // call them
+            | Command::Eval
+            | Command::Evalsha
+            | Command::Fcall
+            | Command::Unknown => NONE,
+        }
+    }
+
+    /// The list a successful command pushed onto, or the stream it appended
+    /// to, whose blocked clients may now be served.
+    fn pushed_key<'a>(self, args: &[&'a str]) -> Option<&'a str> {
+        match self {
+            Command::Lpush | Command::Rpush | Command::Xadd => args.get(1).copied(),
+            Command::Lmove | Command::Blmove => args.get(2).copied(),
+            _ => None,
+        }
+    }
+
+    /// EXEC, scripts and functions, which run other commands and record
+    /// their writes to the replication log themselves.
+    fn runs_many(self) -> bool {
+        matches!(
+            self,
+            Command::Exec | Command::Eval | Command::Evalsha | Command::Fcall
+        )
+    }
+
+    /// Commands that run holding the replication log: writes, and the ones
+    /// that run other commands. MIGRATE takes it itself before and after
+    /// the transfer, but not while the subtree is on the wire.
+    fn holds_log(self, args: &[&str]) -> bool {
+        (self.writes(args) && self != Command::Migrate) || self.runs_many()
+    }
+
+    /// Whether this call may change the dataset or the function libraries:
+    /// the `write` flag, except for FUNCTION LIST, which only reads.
+    fn writes(self, args: &[&str]) -> bool {
+        match self {
+            Command::Function => !args.get(1).is_some_and(|s| s.eq_ignore_ascii_case("list")),
+            _ => self.flags().write,
+        }
+    }
+
+    /// Commands a connection may still send while it is subscribed.
+    fn allowed_when_subscribed(self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe
+                | Command::Unsubscribe
+                | Command::Psubscribe
+                | Command::Punsubscribe
+                | Command::Watchtree
+                | Command::Unwatchtree
+                | Command::Ping
+                | Command::Exit
+        )
+    }
+
+    /// Commands run straight away even between MULTI and EXEC.
+    fn bypasses_multi(self) -> bool {
+        matches!(
+            self,
+            Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch
+                | Command::Unwatch
+                | Command::Exit
+        )
+    }
+}
+
+fn dispatch_command(cmd: &str) -> Command {
+    let s = cmd.to_ascii_lowercase();
     match s.as_str() {
         // basic
         "ping" => Command::Ping,
@@ -44,12 +378,62 @@ fn dispatch_command(cmd: &[u8]) -> Command {
         "quit" => Command::Exit,
         "memory" => Command::Memory,
         "size" => Command::Size,
+        "shutdown" => Command::Shutdown,
+        "info" => Command::Info,
+        "auth" => Command::Auth,
+        "acl" => Command::Acl,
+        "config" => Command::Config,
+        "command" => Command::Commands,
+
+        // replication
+        "replicaof" => Command::Replicaof,
+        "slaveof" => Command::Replicaof,
+        "psync" => Command::Psync,
+
+        // cluster
+        "cluster" => Command::Cluster,
+        "asking" => Command::Asking,
+        "dump" => Command::Dump,
+        "restore" => Command::Restore,
+        "migrate" => Command::Migrate,
+
+        // scripting and functions
+        "eval" => Command::Eval,
+        "evalsha" => Command::Evalsha,
+        "script" => Command::Script,
+        "function" => Command::Function,
+        "fcall" => Command::Fcall,
+
+        // transactions
+        "multi" => Command::Multi,
+        "exec" => Command::Exec,
+        "discard" => Command::Discard,
+        "watch" => Command::Watch,
+        "unwatch" => Command::Unwatch,
+
+        // pub/sub
+        "subscribe" => Command::Subscribe,
+        "unsubscribe" => Command::Unsubscribe,
+        "psubscribe" => Command::Psubscribe,
+        "punsubscribe" => Command::Punsubscribe,
+        "publish" => Command::Publish,
+        "watchtree" => Command::Watchtree,
+        "unwatchtree" => Command::Unwatchtree,
 
         // core commands
         "set" => Command::Set,
         "get" => Command::Get,
         "del" => Command::Del,
         "drop" => Command::Drop,
+        "expire" => Command::Expire,
+        "pexpireat" => Command::Pexpireat,
+        "ttl" => Command::Ttl,
+        "persist" => Command::Persist,
+        "getset" => Command::Getset,
+        "getdel" => Command::Getdel,
+        "getv" => Command::Getv,
+        "cas" => Command::Cas,
+        "children" => Command::Children,
         "incr" => Command::Unknown,
         "decr" => Command::Unknown,
 
@@ -67,8 +451,16 @@ fn dispatch_command(cmd: &[u8]) -> Command {
         "regidecr" => Command::Unknown,
 
         // list based operations
-        "lpush" => Command::Unknown,
-        "rpush" => Command::Unknown,
+        "lpush" => Command::Lpush,
+        "rpush" => Command::Rpush,
+        "lpop" => Command::Lpop,
+        "rpop" => Command::Rpop,
+        "llen" => Command::Llen,
+        "lrange" => Command::Lrange,
+        "lmove" => Command::Lmove,
+        "blpop" => Command::Blpop,
+        "brpop" => Command::Brpop,
+        "blmove" => Command::Blmove,
         "lset" => Command::Unknown,
         "linsert" => Command::Unknown,
         "lremove" => Command::Unknown,
@@ -81,6 +473,18 @@ fn dispatch_command(cmd: &[u8]) -> Command {
         "lmset" => Command::Unknown,
         "lmindex" => Command::Unknown,
 
+        // streams
+        "xadd" => Command::Xadd,
+        "xrange" => Command::Xrange,
+        "xlen" => Command::Xlen,
+        "xtrim" => Command::Xtrim,
+        "xread" => Command::Xread,
+        "xreadgroup" => Command::Xreadgroup,
+        "xgroup" => Command::Xgroup,
+        "xack" => Command::Xack,
+        "xpending" => Command::Xpending,
+        "xclaim" => Command::Xclaim,
+
         // set based operations
         "sadd" => Command::Unknown, // sadd path wdwjndw
         "srem" => Command::Unknown,
@@ -102,37 +506,442 @@ fn dispatch_command(cmd: &[u8]) -> Command {
     }
 }
 
+/// Arguments that name keys, for ACL key-pattern checks.
+fn key_args<'a>(command: Command, args: &[&'a str]) -> Vec<&'a str> {
+    let arg = |i: usize| args.get(i).copied();
+    match command {
+        Command::Watch | Command::Watchtree => return args.iter().skip(1).copied().collect(),
+        Command::Lmove | Command::Blmove => return args.iter().skip(1).take(2).copied().collect(),
+        Command::Blpop | Command::Brpop if args.len() > 2 => {
+            return args[1..args.len() - 1].to_vec();
+        }
+        Command::Eval | Command::Evalsha | Command::Fcall => {
+            // EVAL script numkeys key [key ...] arg [arg ...]
+            let numkeys = arg(2).and_then(|n| n.parse::<usize>().ok()).unwrap_or(0);
+            return args.iter().skip(3).take(numkeys).copied().collect();
+        }
+        Command::Xread | Command::Xreadgroup => {
+            // Keys are the first half of everything after STREAMS
+            let Some(at) = args.iter().position(|s| s.eq_ignore_ascii_case("streams")) else {
+                return Vec::new();
+            };
+            let rest = &args[at + 1..];
+            return rest[..rest.len() / 2].to_vec();
+        }
+        _ => {}
+    }
+    let key = match command {
+        Command::Set
+        | Command::Get
+        | Command::Del
+        | Command::Expire
+        | Command::Pexpireat
+        | Command::Ttl
+        | Command::Persist
+        | Command::Getset
+        | Command::Getdel
+        | Command::Getv
+        | Command::Cas
+        | Command::Children
+        | Command::Lpush
+        | Command::Rpush
+        | Command::Lpop
+        | Command::Rpop
+        | Command::Llen
+        | Command::Lrange
+        | Command::Xadd
+        | Command::Xrange
+        | Command::Xlen
+        | Command::Xtrim
+        | Command::Xack
+        | Command::Xpending
+        | Command::Xclaim
+        | Command::Dump
+        | Command::Restore => arg(1),
+        Command::Xgroup => arg(2),
+        Command::Migrate => arg(3),
+        Command::Memory if arg(1).is_some_and(|s| s.eq_ignore_ascii_case("usage")) => arg(2),
+        _ => None,
+    };
+    key.into_iter().collect()
+}
+
+/// Commands that act on the whole keyspace rather than named keys, such as
+/// DROP, a bare MEMORY or a bare CHILDREN listing every top-level segment,
+/// and so need access to every key.
+fn spans_keyspace(command: Command, args: &[&str]) -> bool {
+    match command {
+        Command::Drop | Command::Size => true,
+        Command::Children => args.len() == 1,
+        Command::Memory => !args.get(1).is_some_and(|s| s.eq_ignore_ascii_case("usage")),
+        _ => false,
+    }
+}
+
+//
+// ─── Sessions and Access Control ────────────────────────────────────────────────
+//
+
+/// Per-connection state.
+#[derive(Debug)]
+pub struct Session {
+    /// Logged-in ACL user, `None` until AUTH succeeds
+    pub user: Option<String>,
+    /// Set by EXIT and SHUTDOWN; the connection closes after the reply
+    pub quit: bool,
+    /// Commands queued since MULTI
+    pub multi: Option<multi::Multi>,
+    /// Keys from WATCH with the version each had at the time
+    pub watched: Vec<(String, u64)>,
+    /// Pub/Sub channels and patterns this session listens on
+    pub subscriptions: pubsub::Subscriptions,
+    /// Set by a blocking command that has to wait for an element
+    pub blocked: Option<Blocked>,
+    /// Set by PSYNC; the connection then carries the command stream
+    pub replica: Option<Feed>,
+    /// Set by ASKING, for the next command only
+    pub asking: bool,
+    /// Set by `HELLO 2`: replies are sent as RESP instead of plain lines
+    pub resp: bool,
+}
+
+impl Session {
+    /// A session with nowhere to push messages, so it cannot SUBSCRIBE.
+    pub fn new(shared: &Shared) -> Self {
+        Session::build(shared, None)
+    }
+
+    /// A session whose Pub/Sub messages are sent to `push`.
+    pub fn with_push(shared: &Shared, push: mpsc::Sender<Reply>) -> Self {
+        Session::build(shared, Some(push))
+    }
+
+    fn build(shared: &Shared, push: Option<mpsc::Sender<Reply>>) -> Self {
+        Session {
+            user: shared.acl.implicit_user().map(str::to_string),
+            quit: false,
+            multi: None,
+            watched: Vec::new(),
+            subscriptions: pubsub::Subscriptions::new(&shared.pubsub, push),
+            blocked: None,
+            replica: None,
+            asking: false,
+            resp: false,
+        }
+    }
+
+    /// Encode `reply` in the protocol this session speaks.
+    pub fn encode(&self, reply: &Reply, out: &mut Vec<u8>) {
+        if self.resp {
+            reply.write_resp(out);
+        } else {
+            reply.write_text(out);
+        }
+    }
+}
+
+/// Enforce authentication and the user's command and key permissions.
+fn check_access(
+    command: Command,
+    args: &[&str],
+    session: &Session,
+    shared: &Shared,
+) -> Result<(), CommandError> {
+    if matches!(command, Command::Auth | Command::Hello | Command::Exit) {
+        return Ok(());
+    }
+    let user = match session
+        .user
+        .as_deref()
+        .and_then(|name| shared.acl.get(name))
+    {
+        Some(user) if user.enabled => user,
+        _ => return Err(CommandError::NoAuth),
+    };
+    let whoami = command == Command::Acl
+        && args
+            .get(1)
+            .is_some_and(|s| s.eq_ignore_ascii_case("whoami"));
+    if command == Command::Unknown || whoami {
+        return Ok(());
+    }
+    if !user.can_run(command.name(), command.flags().categories()) {
+        return Err(CommandError::NoPerm("run this command"));
+    }
+    if spans_keyspace(command, args) && !user.can_access_all() {
+        return Err(CommandError::NoPerm("access all keys"));
+    }
+    if key_args(command, args)
+        .iter()
+        .any(|key| !user.can_access(key))
+    {
+        return Err(CommandError::NoPerm(
+            "access one of the keys used as arguments",
+        ));
+    }
+    Ok(())
+}
+
+//
+// ─── Command Engine ─────────────────────────────────────────────────────────────
+//
+
+/// Run one command, e.g. `execute(&["SET", "users:42", "bob"], shared, session)`,
+/// and return its reply. Nothing is written anywhere, so this serves
+/// connections, embedding and tests alike.
+pub fn execute(args: &[&str], shared: &Shared, session: &mut Session) -> Reply {
+    let Some(&cmd) = args.first() else {
+        return CommandError::syntax("Empty command").into();
+    };
+    let command = dispatch_command(cmd);
+    if let Err(denied) = check_access(command, args, session, shared) {
+        if let Some(ref mut multi) = session.multi {
+            multi.abort();
+        }
+        shared.stats.error();
+        return denied.into();
+    }
+    #[cfg(feature = "lua")]
+    if shared.scripts.busy() && !matches!(command, Command::Script | Command::Shutdown) {
+        shared.stats.error();
+        return CommandError::Busy.into();
+    }
+    // Writes hold the replication log from start to finish, so they reach
+    // replicas in the order they were applied here, and where their keys
+    // live cannot change under them.
+    let mut log = match command.holds_log(args) {
+        true => match lock_log(shared) {
+            Ok(log) => Some(log),
+            Err(busy) => {
+                shared.stats.error();
+                return busy.into();
+            }
+        },
+        false => None,
+    };
+    if let Err(redirect) = cluster::route(command, args, shared, session, &mut log) {
+        if let Some(ref mut multi) = session.multi {
+            multi.abort();
+        }
+        shared.stats.error();
+        return redirect.into();
+    }
+    if shared.replication.read_only() {
+        if command.writes(args) {
+            if let Some(ref mut multi) = session.multi {
+                multi.abort();
+            }
+            shared.stats.error();
+            return CommandError::ReadOnly.into();
+        }
+        // Read-only mode may have been switched on since the writes queued
+        if let (Command::Exec, Some(multi)) = (command, session.multi.as_mut()) {
+            if multi.has_writes() {
+                multi.abort();
+            }
+        }
+    }
+    if session.subscriptions.active() && !command.allowed_when_subscribed() {
+        shared.stats.error();
+        return CommandError::syntax(format!(
+            "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / (UN)WATCHTREE / PING / QUIT are allowed in this context",
+            cmd.to_ascii_lowercase()
+        ))
+        .into();
+    }
+    run(command, args, shared, session, log.as_mut())
+}
+
+/// How often a command waiting for the log looks at the script holding it.
+#[cfg(feature = "lua")]
+const SCRIPT_POLL: std::time::Duration = std::time::Duration::from_millis(1);
+
+/// Take the replication log for a command. A script holds it while it
+/// runs, so rather than waiting out one that has run past its time limit,
+/// answer BUSY as if the command had come in after that. The wait happens
+/// off the runtime, so this worker's other connections are still served.
+fn lock_log(shared: &Shared) -> Result<LogGuard<'_>, CommandError> {
+    #[cfg(feature = "lua")]
+    if shared.scripts.running() {
+        return cluster::blocking_io(|| loop {
+            if let Some(log) = shared.replication.try_lock() {
+                return Ok(log);
+            }
+            if !shared.scripts.running() {
+                return Ok(shared.replication.lock());
+            }
+            if shared.scripts.busy() {
+                return Err(CommandError::Busy);
+            }
+            std::thread::sleep(SCRIPT_POLL);
+        });
+    }
+    Ok(shared.replication.lock())
+}
+
+/// The part of `execute` after the checks, also used to apply a primary's
+/// command stream. Successful writes are fed to `log`.
+fn run(
+    command: Command,
+    args: &[&str],
+    shared: &Shared,
+    session: &mut Session,
+    mut log: Option<&mut LogGuard>,
+) -> Reply {
+    let database = &*shared.database;
+    if session.multi.is_some() && !command.bypasses_multi() {
+        return multi::queue(command, args, session).unwrap_or_else(|e| {
+            shared.stats.error();
+            e.into()
+        });
+    }
+    let started = Instant::now();
+    let result = match command {
+        Command::Hello => cmds::handle_hello(args, session),
+        Command::Exit => {
+            session.quit = true;
+            Ok(Reply::Status("Bye!".to_string()))
+        }
+        _ if command.is_keyspace() => execute_keyspace(command, args, database, &shared.stats),
+        Command::Memory => cmds::handle_memory(args, database),
+        Command::Size => cmds::handle_size(database),
+        Command::Shutdown => cmds::handle_shutdown(args, shared, session),
+        Command::Info => cmds::handle_info(args, shared),
+        Command::Auth => cmds::handle_auth(args, shared, session),
+        Command::Acl => cmds::handle_acl(args, shared, session),
+        Command::Multi => multi::handle_multi(session),
+        Command::Exec => multi::handle_exec(shared, session, log.as_deref_mut()),
+        Command::Discard => multi::handle_discard(session),
+        Command::Watch => multi::handle_watch(args, shared, session),
+        Command::Unwatch => multi::handle_unwatch(session),
+        Command::Subscribe => pubsub::handle_subscribe(args, shared, session, Kind::Channel),
+        Command::Unsubscribe => pubsub::handle_unsubscribe(args, shared, session, Kind::Channel),
+        Command::Psubscribe => pubsub::handle_subscribe(args, shared, session, Kind::Pattern),
+        Command::Punsubscribe => pubsub::handle_unsubscribe(args, shared, session, Kind::Pattern),
+        Command::Publish => pubsub::handle_publish(args, shared),
+        Command::Watchtree => pubsub::handle_subscribe(args, shared, session, Kind::Tree),
+        Command::Unwatchtree => pubsub::handle_unsubscribe(args, shared, session, Kind::Tree),
+        Command::Blpop => blocking::handle_bpop(args, shared, session, End::Left),
+        Command::Brpop => blocking::handle_bpop(args, shared, session, End::Right),
+        Command::Blmove => blocking::handle_blmove(args, shared, session),
+        Command::Xread => streams::handle_xread(args, shared, session),
+        Command::Xreadgroup => streams::handle_xreadgroup(args, shared, session),
+        Command::Replicaof => replication::handle_replicaof(args, shared),
+        Command::Psync => replication::handle_psync(args, shared, session),
+        Command::Config => cmds::handle_config(args, shared),
+        Command::Commands => cmds::handle_command_info(args),
+        Command::Cluster => cluster::handle_cluster(args, shared),
+        Command::Asking => cluster::handle_asking(shared, session),
+        Command::Migrate => cluster::handle_migrate(args, shared),
+        #[cfg(feature = "lua")]
+        Command::Eval | Command::Evalsha => {
+            scripting::handle_eval(args, shared, session, log.as_deref_mut())
+        }
+        #[cfg(feature = "lua")]
+        Command::Script => scripting::handle_script(args, shared),
+        #[cfg(not(feature = "lua"))]
+        Command::Eval | Command::Evalsha | Command::Script => Err(CommandError::syntax(
+            "This server was built without Lua scripting",
+        )),
+        #[cfg(feature = "wasm")]
+        Command::Fcall => functions::handle_fcall(args, shared, session, log.as_deref_mut()),
+        #[cfg(feature = "wasm")]
+        Command::Function => functions::handle_function(args, shared),
+        #[cfg(not(feature = "wasm"))]
+        Command::Fcall | Command::Function => Err(CommandError::syntax(
+            "This server was built without WASM functions",
+        )),
+        Command::Unknown => Err(CommandError::UnknownCommand(args[0].to_string())),
+        _ => unreachable!("keyspace commands are handled above"),
+    };
+    shared.stats.record(command, started.elapsed());
+    if let Ok(ref reply) = result {
+        if command.writes(args) && !command.runs_many() {
+            if let (Some(log), None) = (log.as_deref_mut(), &session.blocked) {
+                replication::record(log, command, args, reply);
+            }
+            if let Some(key) = command.pushed_key(args) {
+                shared.blocking.serve(key, database, log);
+            }
+        }
+    }
+    result.unwrap_or_else(|e| {
+        shared.stats.error();
+        e.into()
+    })
+}
+
+/// Run a keyspace command against `database`, which is either the database
+/// itself or a transaction holding its write lock.
+fn execute_keyspace(
+    command: Command,
+    args: &[&str],
+    database: &impl Keyspace,
+    stats: &Stats,
+) -> CommandResult {
+    match command {
+        Command::Ping => Ok(Reply::Status("PONG".to_string())),
+        Command::Set => cmds::handle_set(args, database),
+        Command::Get => cmds::handle_get(args, database, stats),
+        Command::Del => cmds::handle_del(args, database),
+        Command::Drop => cmds::handle_drop(database),
+        Command::Expire => cmds::handle_expire(args, database),
+        Command::Pexpireat => cmds::handle_pexpireat(args, database),
+        Command::Ttl => cmds::handle_ttl(args, database),
+        Command::Persist => cmds::handle_persist(args, database),
+        Command::Getset => cmds::handle_getset(args, database),
+        Command::Getdel => cmds::handle_getdel(args, database),
+        Command::Getv => cmds::handle_getv(args, database),
+        Command::Cas => cmds::handle_cas(args, database),
+        Command::Children => cmds::handle_children(args, database),
+        Command::Lpush => lists::handle_push(args, database, End::Left),
+        Command::Rpush => lists::handle_push(args, database, End::Right),
+        Command::Lpop => lists::handle_pop(args, database, End::Left),
+        Command::Rpop => lists::handle_pop(args, database, End::Right),
+        Command::Llen => lists::handle_llen(args, database),
+        Command::Lrange => lists::handle_lrange(args, database),
+        Command::Lmove => lists::handle_lmove(args, database),
+        Command::Xadd => streams::handle_xadd(args, database),
+        Command::Xrange => streams::handle_xrange(args, database),
+        Command::Xlen => streams::handle_xlen(args, database),
+        Command::Xtrim => streams::handle_xtrim(args, database),
+        Command::Xgroup => streams::handle_xgroup(args, database),
+        Command::Xack => streams::handle_xack(args, database),
+        Command::Xpending => streams::handle_xpending(args, database),
+        Command::Xclaim => streams::handle_xclaim(args, database),
+        Command::Dump => cluster::handle_dump(args, database),
+        Command::Restore => cluster::handle_restore(args, database),
+        _ => Err(CommandError::Internal(format!(
+            "{} is not a keyspace command",
+            command.name()
+        ))),
+    }
+}
+
 //
 // ─── Main Entry Point: Command Handler ──────────────────────────────────────────
 //
 
+/// Execute one request line and write the reply in the session's protocol.
+/// The reply is not flushed, so pipelined requests can share one write.
+/// Returns true when the connection should close.
 pub async fn handle_command(
     line: &str,
-    writer: &mut BufWriter<OwnedWriteHalf>,
-    database: &Database,
+    writer: &mut (impl AsyncWrite + Unpin),
+    shared: &Shared,
+    session: &mut Session,
 ) -> std::io::Result<bool> {
-    let raw = normalize_command(line.as_bytes());
-    let parts = cmds::CommandParts::new(raw);
-    if parts.len() == 0 {
-        return cmds::write_response(writer, b"Empty command\n")
-            .await
-            .map(|_| false);
-    }
-    let cmd = parts.get(0).unwrap();
-    match dispatch_command(cmd) {
-        Command::Ping => cmds::write_response(writer, b"PONG\n").await?,
-        Command::Hello => cmds::write_response(writer, b"Hi there! FlashTree v0.1\n").await?,
-        Command::Exit => {
-            cmds::write_response(writer, b"Bye!\n").await?;
-            return Ok(true);
-        }
-        Command::Set => cmds::handle_set(&parts, writer, database).await?,
-        Command::Get => cmds::handle_get(&parts, writer, database).await?,
-        Command::Del => cmds::handle_del(&parts, writer, database).await?,
-        Command::Drop => cmds::handle_drop(writer, database).await?,
-        Command::Memory => cmds::handle_memory(writer, database).await?,
-        Command::Size => cmds::handle_size(writer, database).await?,
-        Command::Unknown => cmds::write_response(writer, b"Unknown command\n").await?,
-    }
-    Ok(false)
+    let args = parse_args(line);
+    #[cfg(feature = "lua")]
+    shared.scripts.wait().await;
+    let reply = execute(&args, shared, session);
+    if session.blocked.is_some() || session.replica.is_some() {
+        // The connection sends the reply once the wait is over, or starts
+        // the command stream instead
+        return Ok(false);
+    }
+    let mut out = Vec::with_capacity(64);
+    session.encode(&reply, &mut out);
+    writer.write_all(&out).await?;
+    Ok(session.quit)
 }
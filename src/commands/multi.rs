@@ -0,0 +1,150 @@
+use super::replication::{self, LogGuard};
+use super::{
+    cluster, dispatch_command, execute_keyspace, Command, CommandError, CommandResult, Reply,
+    Session,
+};
+use crate::db::Keyspace;
+use crate::server::Shared;
+use std::time::Instant;
+
+/// Commands queued between MULTI and EXEC.
+#[derive(Debug, Default)]
+pub struct Multi {
+    queued: Vec<Vec<String>>,
+    /// A command failed to queue; EXEC will refuse to run the rest
+    aborted: bool,
+}
+
+impl Multi {
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Whether any queued command changes the dataset.
+    pub fn has_writes(&self) -> bool {
+        self.queued
+            .iter()
+            .any(|args| dispatch_command(&args[0]).flags().write)
+    }
+}
+
+/// Queue a command inside MULTI. Only keyspace commands can be queued, since
+/// EXEC runs them while holding the database write lock.
+pub fn queue(command: Command, args: &[&str], session: &mut Session) -> CommandResult {
+    let Some(multi) = session.multi.as_mut() else {
+        return Err(CommandError::Internal("not inside MULTI".to_string()));
+    };
+    if command == Command::Unknown {
+        multi.abort();
+        return Err(CommandError::UnknownCommand(args[0].to_string()));
+    }
+    if !command.is_keyspace() {
+        multi.abort();
+        return Err(CommandError::syntax(format!(
+            "{} is not allowed inside MULTI",
+            command.name().to_ascii_uppercase()
+        )));
+    }
+    multi
+        .queued
+        .push(args.iter().map(|s| s.to_string()).collect());
+    Ok(Reply::Status("QUEUED".to_string()))
+}
+
+/// MULTI
+pub fn handle_multi(session: &mut Session) -> CommandResult {
+    if session.multi.is_some() {
+        return Err(CommandError::syntax("MULTI calls can not be nested"));
+    }
+    session.multi = Some(Multi::default());
+    Ok(Reply::ok())
+}
+
+/// DISCARD
+pub fn handle_discard(session: &mut Session) -> CommandResult {
+    if session.multi.take().is_none() {
+        return Err(CommandError::syntax("DISCARD without MULTI"));
+    }
+    session.watched.clear();
+    Ok(Reply::ok())
+}
+
+/// WATCH key [key ...]. Watching a key covers its whole subtree.
+pub fn handle_watch(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    if session.multi.is_some() {
+        return Err(CommandError::syntax("WATCH inside MULTI is not allowed"));
+    }
+    if args.len() < 2 {
+        return Err(CommandError::syntax("Usage: WATCH key [key ...]"));
+    }
+    for key in &args[1..] {
+        let version = shared.database.watch_version(key)?;
+        session.watched.push((key.to_string(), version));
+    }
+    Ok(Reply::ok())
+}
+
+/// UNWATCH
+pub fn handle_unwatch(session: &mut Session) -> CommandResult {
+    session.watched.clear();
+    Ok(Reply::ok())
+}
+
+/// EXEC. Runs every queued command under one hold of the write lock and
+/// returns their replies, or nil if a watched key changed since WATCH.
+pub fn handle_exec(
+    shared: &Shared,
+    session: &mut Session,
+    mut log: Option<&mut LogGuard>,
+) -> CommandResult {
+    let multi = session
+        .multi
+        .take()
+        .ok_or_else(|| CommandError::syntax("EXEC without MULTI"))?;
+    let watched = std::mem::take(&mut session.watched);
+    if multi.aborted {
+        return Err(CommandError::ExecAbort);
+    }
+    // The slots may have moved, or MIGRATE started, since the commands queued
+    for args in &multi.queued {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        cluster::serves(dispatch_command(args[0]), &args, shared)?;
+    }
+    let replies = shared.database.transaction(|tx| {
+        for (key, version) in &watched {
+            if tx.watch_version(key)? != *version {
+                return Ok(None);
+            }
+        }
+        let replies: Vec<Reply> = multi
+            .queued
+            .iter()
+            .map(|args| {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let command = dispatch_command(args[0]);
+                let started = Instant::now();
+                let result = execute_keyspace(command, &args, tx, &shared.stats);
+                shared.stats.record(command, started.elapsed());
+                result.unwrap_or_else(|e| {
+                    shared.stats.error();
+                    e.into()
+                })
+            })
+            .collect();
+        Ok::<_, CommandError>(Some(replies))
+    })??;
+    if let Some(ref replies) = replies {
+        if let Some(log) = log.as_deref_mut() {
+            replication::record_exec(log, &multi.queued, replies);
+        }
+        for args in &multi.queued {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            if let Some(key) = dispatch_command(args[0]).pushed_key(&args) {
+                shared
+                    .blocking
+                    .serve(key, &shared.database, log.as_deref_mut());
+            }
+        }
+    }
+    Ok(replies.map_or(Reply::Nil, Reply::Array))
+}
@@ -0,0 +1,346 @@
+use super::{CommandError, CommandResult, Reply, Session};
+use crate::db::{core, Event};
+use crate::server::Shared;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Messages queued for one subscriber before it is disconnected, so a
+/// stalled reader cannot grow the server's memory without bound.
+pub const PUSH_BACKLOG: usize = 1024;
+
+type Subscribers = HashMap<u64, Subscriber>;
+
+/// Where one connection's pushes go.
+#[derive(Debug, Clone)]
+struct Subscriber {
+    tx: mpsc::Sender<Reply>,
+    /// Set once a push did not fit, so the connection knows it missed some
+    overflowed: Arc<AtomicBool>,
+}
+
+/// What a subscription listens to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// One channel, by exact name
+    Channel,
+    /// Every channel matching a pattern such as `orders:eu:*`
+    Pattern,
+    /// Changes to a key and everything beneath it
+    Tree,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Channel, Kind::Pattern, Kind::Tree];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn subscribe_name(self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+            Kind::Tree => "watchtree",
+        }
+    }
+
+    fn unsubscribe_name(self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::Tree => "unwatchtree",
+        }
+    }
+
+    fn usage(self) -> &'static str {
+        match self {
+            Kind::Channel => "Usage: SUBSCRIBE channel [channel ...]",
+            Kind::Pattern => "Usage: PSUBSCRIBE pattern [pattern ...]",
+            Kind::Tree => "Usage: WATCHTREE prefix [prefix ...]",
+        }
+    }
+}
+
+//
+// ─── Registry ────────────────────────────────────────────────────────────────────
+//
+
+/// Every channel, pattern and tree subscription on the server.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    next_id: AtomicU64,
+    /// Subscribers by name, one map per `Kind`
+    maps: [RwLock<HashMap<String, Subscribers>>; 3],
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub::default()
+    }
+
+    fn map(&self, kind: Kind) -> &RwLock<HashMap<String, Subscribers>> {
+        &self.maps[kind.index()]
+    }
+
+    /// Deliver `message` to subscribers of `channel` and of every pattern
+    /// that matches it. Returns how many subscribers it was queued for.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+        if let Ok(channels) = self.map(Kind::Channel).read() {
+            for subscriber in channels.get(channel).into_iter().flat_map(HashMap::values) {
+                let push = Reply::Array(vec![
+                    Reply::Bulk("message".to_string()),
+                    Reply::Bulk(channel.to_string()),
+                    Reply::Bulk(message.to_string()),
+                ]);
+                receivers += deliver(subscriber, push) as usize;
+            }
+        }
+        if let Ok(patterns) = self.map(Kind::Pattern).read() {
+            for (pattern, subscribers) in patterns.iter() {
+                if !core::matches_pattern(pattern, channel) {
+                    continue;
+                }
+                for subscriber in subscribers.values() {
+                    let push = Reply::Array(vec![
+                        Reply::Bulk("pmessage".to_string()),
+                        Reply::Bulk(pattern.clone()),
+                        Reply::Bulk(channel.to_string()),
+                        Reply::Bulk(message.to_string()),
+                    ]);
+                    receivers += deliver(subscriber, push) as usize;
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Push a keyspace change to everyone watching a tree it touches: the
+    /// key itself or any prefix of it, and for removals anything beneath it.
+    /// Installed as the database listener.
+    pub fn notify_tree(&self, event: Event, key: &str) {
+        let Ok(trees) = self.map(Kind::Tree).read() else {
+            return;
+        };
+        if trees.is_empty() {
+            return;
+        }
+        let send = |prefix: &str, subscribers: &Subscribers| {
+            for subscriber in subscribers.values() {
+                let push = Reply::Array(vec![
+                    Reply::Bulk("tree".to_string()),
+                    Reply::Bulk(prefix.to_string()),
+                    Reply::Bulk(event.name().to_string()),
+                    Reply::Bulk(key.to_string()),
+                ]);
+                deliver(subscriber, push);
+            }
+        };
+        // The key and its ancestors, down from the root: `a:b` checks "", "a", "a:b".
+        let ancestors = std::iter::once(0)
+            .chain(key.match_indices(':').map(|(i, _)| i))
+            .chain((!key.is_empty()).then_some(key.len()));
+        for end in ancestors {
+            if let Some((prefix, subscribers)) = trees.get_key_value(&key[..end]) {
+                send(prefix, subscribers);
+            }
+        }
+        if event.removes_subtree() {
+            for (prefix, subscribers) in trees.iter() {
+                let beneath = match prefix.strip_prefix(key) {
+                    Some(rest) => key.is_empty() || rest.starts_with(':'),
+                    None => false,
+                };
+                if beneath && prefix != key {
+                    send(prefix, subscribers);
+                }
+            }
+        }
+    }
+
+    /// Number of channels, patterns and trees with at least one subscriber.
+    pub fn counts(&self) -> [usize; 3] {
+        Kind::ALL.map(|kind| self.map(kind).read().map_or(0, |m| m.len()))
+    }
+
+    /// Drop every subscription held by `session`, e.g. when it disconnects.
+    pub fn unsubscribe_all(&self, session: &mut Session) {
+        let subs = &mut session.subscriptions;
+        for kind in Kind::ALL {
+            for name in std::mem::take(&mut subs.names[kind.index()]) {
+                remove(self.map(kind), &name, subs.id);
+            }
+        }
+    }
+}
+
+/// Queue a push for one subscriber; false if it is gone or too far behind.
+/// One that is too far behind gets disconnected rather than quietly missing
+/// messages.
+fn deliver(subscriber: &Subscriber, push: Reply) -> bool {
+    match subscriber.tx.try_send(push) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            subscriber.overflowed.store(true, Ordering::Relaxed);
+            false
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+fn insert(
+    map: &RwLock<HashMap<String, Subscribers>>,
+    name: &str,
+    id: u64,
+    subscriber: &Subscriber,
+) {
+    if let Ok(mut map) = map.write() {
+        map.entry(name.to_string())
+            .or_default()
+            .insert(id, subscriber.clone());
+    }
+}
+
+fn remove(map: &RwLock<HashMap<String, Subscribers>>, name: &str, id: u64) {
+    if let Ok(mut map) = map.write() {
+        if let Some(subscribers) = map.get_mut(name) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                map.remove(name);
+            }
+        }
+    }
+}
+
+//
+// ─── Per-Connection State ────────────────────────────────────────────────────────
+//
+
+/// What one connection is subscribed to, and where its messages go.
+#[derive(Debug)]
+pub struct Subscriptions {
+    id: u64,
+    /// `None` for sessions with no connection to push to, e.g. when embedding
+    push: Option<Subscriber>,
+    /// Subscribed names, one set per `Kind`
+    names: [BTreeSet<String>; 3],
+}
+
+impl Subscriptions {
+    pub fn new(pubsub: &PubSub, push: Option<mpsc::Sender<Reply>>) -> Self {
+        Subscriptions {
+            id: pubsub.next_id.fetch_add(1, Ordering::Relaxed),
+            push: push.map(|tx| Subscriber {
+                tx,
+                overflowed: Arc::default(),
+            }),
+            names: Default::default(),
+        }
+    }
+
+    /// While subscribed, a connection only accepts (un)subscribe commands and PING.
+    pub fn active(&self) -> bool {
+        self.names.iter().any(|names| !names.is_empty())
+    }
+
+    /// Whether a push was dropped because `PUSH_BACKLOG` of them were already
+    /// waiting, after which the connection should be closed.
+    pub fn overflowed(&self) -> bool {
+        self.push
+            .as_ref()
+            .is_some_and(|p| p.overflowed.load(Ordering::Relaxed))
+    }
+
+    /// Whether a connection stands behind this session to receive pushes.
+    pub fn can_push(&self) -> bool {
+        self.push.is_some()
+    }
+
+    fn count(&self) -> usize {
+        self.names.iter().map(BTreeSet::len).sum()
+    }
+}
+
+//
+// ─── Commands ────────────────────────────────────────────────────────────────────
+//
+
+/// One `kind name count` confirmation, as sent for each (un)subscribed name.
+fn confirmation(kind: &str, name: Option<&str>, count: i64) -> Reply {
+    Reply::Array(vec![
+        Reply::Bulk(kind.to_string()),
+        name.map_or(Reply::Nil, |n| Reply::Bulk(n.to_string())),
+        Reply::Integer(count),
+    ])
+}
+
+/// SUBSCRIBE channel [channel ...], PSUBSCRIBE pattern [pattern ...] and
+/// WATCHTREE prefix [prefix ...]
+pub fn handle_subscribe(
+    args: &[&str],
+    shared: &Shared,
+    session: &mut Session,
+    kind: Kind,
+) -> CommandResult {
+    if args.len() < 2 {
+        return Err(CommandError::syntax(kind.usage()));
+    }
+    let subs = &mut session.subscriptions;
+    let Some(ref subscriber) = subs.push else {
+        return Err(CommandError::syntax("this session cannot receive messages"));
+    };
+    let others = subs.count() - subs.names[kind.index()].len();
+    let names = &mut subs.names[kind.index()];
+    let mut replies = Vec::with_capacity(args.len() - 1);
+    for name in &args[1..] {
+        if names.insert(name.to_string()) {
+            insert(shared.pubsub.map(kind), name, subs.id, subscriber);
+        }
+        let count = (others + names.len()) as i64;
+        replies.push(confirmation(kind.subscribe_name(), Some(name), count));
+    }
+    Ok(Reply::Array(replies))
+}
+
+/// UNSUBSCRIBE, PUNSUBSCRIBE and UNWATCHTREE, each with optional names.
+/// With none, drops every subscription of that kind.
+pub fn handle_unsubscribe(
+    args: &[&str],
+    shared: &Shared,
+    session: &mut Session,
+    kind: Kind,
+) -> CommandResult {
+    let subs = &mut session.subscriptions;
+    let others = subs.count() - subs.names[kind.index()].len();
+    let names = &mut subs.names[kind.index()];
+    let targets: Vec<String> = if args.len() > 1 {
+        args[1..].iter().map(|s| s.to_string()).collect()
+    } else {
+        names.iter().cloned().collect()
+    };
+    if targets.is_empty() {
+        return Ok(confirmation(kind.unsubscribe_name(), None, others as i64));
+    }
+    let mut replies = Vec::with_capacity(targets.len());
+    for name in &targets {
+        if names.remove(name) {
+            remove(shared.pubsub.map(kind), name, subs.id);
+        }
+        let count = (others + names.len()) as i64;
+        replies.push(confirmation(kind.unsubscribe_name(), Some(name), count));
+    }
+    Ok(Reply::Array(replies))
+}
+
+/// PUBLISH channel message
+pub fn handle_publish(args: &[&str], shared: &Shared) -> CommandResult {
+    if args.len() != 3 {
+        return Err(CommandError::syntax("Usage: PUBLISH channel message"));
+    }
+    Ok(Reply::Integer(
+        shared.pubsub.publish(args[1], args[2]) as i64
+    ))
+}
@@ -0,0 +1,532 @@
+use super::{dispatch_command, streams, Command, CommandError, CommandResult, Reply, Session};
+use crate::config::Config;
+use crate::db::{self, core, snapshot, Database, Eviction};
+use crate::server::Shared;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use tokio::sync::{mpsc, watch};
+
+/// Chunks of command stream queued for one replica before it counts as too
+/// far behind and is cut off. It then reconnects and catches up from the
+/// backlog, or with a full sync if it fell out of that too.
+const REPLICA_QUEUE: usize = 4096;
+
+/// A piece of the command stream: whole request lines in the text protocol.
+pub type Chunk = Arc<[u8]>;
+
+fn new_replid() -> String {
+    let mut rng = rand::thread_rng();
+    format!("{:032x}{:08x}", rng.gen::<u128>(), rng.gen::<u32>())
+}
+
+//
+// ─── Command Log ─────────────────────────────────────────────────────────────────
+//
+
+/// The stream of writes this server sends to its replicas. A position in it
+/// is a replication ID, naming the history, and a byte offset into it.
+#[derive(Debug)]
+struct Log {
+    replid: String,
+    offset: u64,
+    /// The newest bytes of the stream, created when the first replica connects
+    backlog: Option<VecDeque<u8>>,
+    capacity: usize,
+    replicas: Vec<mpsc::Sender<Chunk>>,
+    /// While following a primary the stream is relayed as received, never
+    /// produced here
+    following: bool,
+}
+
+impl Log {
+    fn append(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        if let Some(ref mut backlog) = self.backlog {
+            backlog.extend(bytes);
+            let excess = backlog.len().saturating_sub(self.capacity);
+            backlog.drain(..excess);
+        }
+        if !self.replicas.is_empty() {
+            let chunk: Chunk = Arc::from(bytes);
+            self.replicas
+                .retain(|tx| tx.try_send(Arc::clone(&chunk)).is_ok());
+        }
+    }
+
+    /// The stream from `offset` on, if it is still in the backlog.
+    fn since(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let backlog = self.backlog.as_ref()?;
+        let start = self.offset - backlog.len() as u64;
+        if replid != self.replid || offset < start || offset > self.offset {
+            return None;
+        }
+        Some(
+            backlog
+                .range((offset - start) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+/// The log, locked. Writes hold it from before they run until they are fed,
+/// so replicas apply them in the order they were applied here.
+pub struct LogGuard<'a> {
+    log: MutexGuard<'a, Log>,
+    evicted: &'a Mutex<Vec<String>>,
+}
+
+impl LogGuard<'_> {
+    /// Append a command that ran here. Keys evicted since the last one go
+    /// first, as DEL. Does nothing while following a primary.
+    pub fn feed(&mut self, args: &[&str]) {
+        if self.log.following {
+            return;
+        }
+        if let Ok(mut evicted) = self.evicted.lock() {
+            for key in evicted.drain(..) {
+                self.log.append(format!("DEL {key}\n").as_bytes());
+            }
+        }
+        let mut line = args.join(" ");
+        line.push('\n');
+        self.log.append(line.as_bytes());
+    }
+
+    /// Pass on a line of the primary's stream unchanged, so this server's
+    /// offset keeps matching the primary's and its own replicas can follow.
+    fn relay(&mut self, line: &str) {
+        self.log.append(line.as_bytes());
+    }
+}
+
+//
+// ─── Registry ────────────────────────────────────────────────────────────────────
+//
+
+/// Replication state: this server's role, whether it takes writes, its
+/// command log and the replicas streaming from it.
+#[derive(Debug)]
+pub struct Replication {
+    log: Mutex<Log>,
+    /// Keys evicted since the last write was fed. Filled from the database
+    /// listener, which may run while the log is held.
+    evicted: Mutex<Vec<String>>,
+    following: AtomicBool,
+    /// Read-only mode, refusing writes whatever the role
+    read_only: AtomicBool,
+    /// Refuse writes while following a primary
+    replica_read_only: AtomicBool,
+    link_up: AtomicBool,
+    /// The primary to follow, watched by the replica task
+    primary: watch::Sender<Option<String>>,
+}
+
+/// A snapshot of the replication state, for INFO.
+#[derive(Debug)]
+pub struct Status {
+    pub primary: Option<String>,
+    pub link_up: bool,
+    pub read_only: bool,
+    pub replica_read_only: bool,
+    pub replid: String,
+    pub offset: u64,
+    pub replicas: usize,
+    pub backlog_size: usize,
+    pub backlog_len: Option<usize>,
+}
+
+impl Replication {
+    pub fn new(config: &Config) -> Self {
+        Replication {
+            log: Mutex::new(Log {
+                replid: new_replid(),
+                offset: 0,
+                backlog: None,
+                capacity: config.repl_backlog_size,
+                replicas: Vec::new(),
+                following: false,
+            }),
+            evicted: Mutex::new(Vec::new()),
+            following: AtomicBool::new(false),
+            read_only: AtomicBool::new(config.read_only),
+            replica_read_only: AtomicBool::new(config.replica_read_only),
+            link_up: AtomicBool::new(false),
+            primary: watch::Sender::new(None),
+        }
+    }
+
+    pub fn lock(&self) -> LogGuard<'_> {
+        LogGuard {
+            log: self.log.lock().unwrap_or_else(|e| e.into_inner()),
+            evicted: &self.evicted,
+        }
+    }
+
+    /// The log if no one holds it right now.
+    pub fn try_lock(&self) -> Option<LogGuard<'_>> {
+        let log = match self.log.try_lock() {
+            Ok(log) => log,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some(LogGuard {
+            log,
+            evicted: &self.evicted,
+        })
+    }
+
+    /// Note a key evicted or expired on a primary, to be deleted on its
+    /// replicas too. Replicas don't evict on their own.
+    pub fn evicted(&self, key: &str) {
+        if self.following.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Ok(mut evicted) = self.evicted.lock() {
+            evicted.push(key.to_string());
+        }
+    }
+
+    /// Whether writes from clients are refused, in read-only mode or as a
+    /// read-only replica.
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+            || (self.following.load(Ordering::Relaxed)
+                && self.replica_read_only.load(Ordering::Relaxed))
+    }
+
+    pub fn set_read_only(&self, on: bool) {
+        self.read_only.store(on, Ordering::Relaxed);
+    }
+
+    pub fn set_replica_read_only(&self, on: bool) {
+        self.replica_read_only.store(on, Ordering::Relaxed);
+    }
+
+    /// The primary to follow, `None` while this server is one.
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.primary.subscribe()
+    }
+
+    /// Where this server is in the stream, to resume from after a reconnect.
+    pub fn position(&self) -> (String, u64) {
+        let log = self.lock();
+        (log.log.replid.clone(), log.log.offset)
+    }
+
+    pub fn set_link(&self, up: bool) {
+        self.link_up.store(up, Ordering::Relaxed);
+    }
+
+    /// Replace the dataset with a primary's snapshot and take over its
+    /// position. Replicas of this server have to sync again from scratch.
+    pub fn full_resync(
+        &self,
+        database: &Database,
+        replid: &str,
+        offset: u64,
+        snapshot: &[u8],
+    ) -> io::Result<()> {
+        let mut guard = self.lock();
+        snapshot::load_bytes(database.get_root(), snapshot)?;
+        let log = &mut guard.log;
+        log.replid = replid.to_string();
+        log.offset = offset;
+        log.backlog = None;
+        log.replicas.clear();
+        Ok(())
+    }
+
+    /// Follow the primary at `addr`, or with `None` stop following and
+    /// become a primary under a new replication ID.
+    pub fn follow(&self, shared: &Shared, addr: Option<String>) {
+        let mut guard = self.lock();
+        let following = addr.is_some();
+        if guard.log.following && !following {
+            // Promoted: what happens from here on is a new history
+            guard.log.replid = new_replid();
+        }
+        guard.log.following = following;
+        self.following.store(following, Ordering::Relaxed);
+        self.link_up.store(false, Ordering::Relaxed);
+        if let Ok(mut evicted) = self.evicted.lock() {
+            evicted.clear();
+        }
+        // A replica keeps whatever its primary keeps; the primary's DELs
+        // take care of eviction and of expired keys that reads come across.
+        // The eviction lock is only poisoned by a panic, which has been
+        // reported already.
+        let _ = shared.database.set_eviction(if following {
+            Eviction::default()
+        } else {
+            shared.config.eviction()
+        });
+        shared.database.set_expire_on_read(!following);
+        self.primary.send_replace(addr);
+    }
+
+    pub fn status(&self) -> Status {
+        let guard = self.lock();
+        let log = &guard.log;
+        Status {
+            primary: self.primary.borrow().clone(),
+            link_up: self.link_up.load(Ordering::Relaxed),
+            read_only: self.read_only.load(Ordering::Relaxed),
+            replica_read_only: self.replica_read_only.load(Ordering::Relaxed),
+            replid: log.replid.clone(),
+            offset: log.offset,
+            replicas: log.replicas.iter().filter(|tx| !tx.is_closed()).count(),
+            backlog_size: log.capacity,
+            backlog_len: log.backlog.as_ref().map(VecDeque::len),
+        }
+    }
+}
+
+/// The stream handed to a connection that sent PSYNC: what it missed, then
+/// everything from now on.
+#[derive(Debug)]
+pub struct Feed {
+    preamble: Vec<u8>,
+    rx: mpsc::Receiver<Chunk>,
+}
+
+impl Feed {
+    /// The sync reply, followed by a snapshot or the missed part of the stream.
+    pub fn preamble(&self) -> &[u8] {
+        &self.preamble
+    }
+
+    /// The next chunk; `None` once the replica has been cut off.
+    pub async fn next(&mut self) -> Option<Chunk> {
+        self.rx.recv().await
+    }
+
+    /// A chunk that is already waiting, if any.
+    pub fn try_next(&mut self) -> Option<Chunk> {
+        self.rx.try_recv().ok()
+    }
+}
+
+//
+// ─── Recording Writes ────────────────────────────────────────────────────────────
+//
+
+/// Feed a successful write to the log, as a replica should run it.
+pub(super) fn record(log: &mut LogGuard, command: Command, args: &[&str], reply: &Reply) {
+    if let Some(args) = rewrite(command, args, reply) {
+        log.feed(&args.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+}
+
+/// Feed a transaction that ran: MULTI, its queued writes that succeeded,
+/// then EXEC, so replicas apply them atomically too.
+pub(super) fn record_exec(log: &mut LogGuard, queued: &[Vec<String>], replies: &[Reply]) {
+    let writes: Vec<Vec<String>> = queued
+        .iter()
+        .zip(replies)
+        .filter(|(_, reply)| !matches!(reply, Reply::Error(_)))
+        .filter_map(|(args, reply)| {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let command = dispatch_command(args[0]);
+            if !command.flags().write {
+                return None;
+            }
+            rewrite(command, &args, reply)
+        })
+        .collect();
+    if writes.is_empty() {
+        return;
+    }
+    log.feed(&["MULTI"]);
+    for args in writes {
+        log.feed(&args.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+    log.feed(&["EXEC"]);
+}
+
+/// The command as a replica should run it, or `None` if it changed nothing.
+/// XADD gets the ID generated here, and blocking commands that were served
+/// right away become their plain forms, so a replica never waits. Nothing a
+/// replica runs depends on its own state or clock: conditional sets that
+/// wrote become plain SETs, EXPIRE becomes PEXPIREAT, and reads and claims
+/// for consumer groups get the time they happened here.
+fn rewrite(command: Command, args: &[&str], reply: &Reply) -> Option<Vec<String>> {
+    let mut args: Vec<&str> = args.to_vec();
+    let now;
+    let deadline;
+    match (command, reply) {
+        (Command::Blpop | Command::Brpop | Command::Blmove, Reply::Nil) => return None,
+        (Command::Blpop | Command::Brpop, Reply::Array(popped)) => {
+            let Some(Reply::Bulk(key)) = popped.first() else {
+                return None;
+            };
+            let pop = if command == Command::Blpop {
+                "LPOP"
+            } else {
+                "RPOP"
+            };
+            args = vec![pop, key.as_str()];
+        }
+        (Command::Blmove, _) => {
+            args[0] = "LMOVE";
+            args.truncate(5);
+        }
+        (Command::Set, _) if !set_wrote(&args[3..], reply) => return None,
+        (Command::Set | Command::Getset, _) => args = vec!["SET", args[1], args[2]],
+        (Command::Cas, Reply::Integer(1)) => args = vec!["SET", args[1], args[3]],
+        (Command::Cas, _) => return None,
+        (Command::Expire, Reply::Integer(1)) => {
+            let secs: u64 = args[2].parse().ok()?;
+            deadline = core::now_ms()
+                .saturating_add(secs.saturating_mul(1000))
+                .to_string();
+            args = vec!["PEXPIREAT", args[1], &deadline];
+        }
+        (Command::Expire, _) => return None,
+        (Command::Xreadgroup, Reply::Nil) => return None,
+        (Command::Xreadgroup, _) => {
+            if let Some(at) = args.iter().position(|s| s.eq_ignore_ascii_case("block")) {
+                args.drain(at..(at + 2).min(args.len()));
+            }
+            if !args.iter().any(|s| s.eq_ignore_ascii_case("time")) {
+                let streams = args
+                    .iter()
+                    .position(|s| s.eq_ignore_ascii_case("streams"))?;
+                now = core::now_ms().to_string();
+                args.splice(streams..streams, ["TIME", now.as_str()]);
+            }
+        }
+        (Command::Xclaim, _) if !args.iter().skip(5).any(|s| s.eq_ignore_ascii_case("time")) => {
+            now = core::now_ms().to_string();
+            args.extend(["TIME", now.as_str()]);
+        }
+        // The subtree is on another node now
+        (Command::Xadd, Reply::Bulk(id)) => {
+            if let Some(at) = streams::xadd_id_index(&args) {
+                args[at] = id;
+            }
+        }
+        _ => {}
+    }
+    Some(args.into_iter().map(str::to_string).collect())
+}
+
+/// Whether a successful SET with `options` wrote, judging by its reply.
+/// With GET the reply is the old value: NX wrote only if there was none,
+/// and XX only if there was one.
+fn set_wrote(options: &[&str], reply: &Reply) -> bool {
+    let has = |name: &str| options.iter().any(|s| s.eq_ignore_ascii_case(name));
+    if !has("GET") {
+        return matches!(reply, Reply::Status(_));
+    }
+    if has("NX") {
+        matches!(reply, Reply::Nil)
+    } else if has("XX") {
+        !matches!(reply, Reply::Nil)
+    } else {
+        true
+    }
+}
+
+/// Apply one line of a primary's command stream: run it without access or
+/// read-only checks, and relay it unchanged to this server's own replicas.
+pub fn apply(line: &str, shared: &Shared, session: &mut Session) -> Result<Reply, CommandError> {
+    let args = super::parse_args(line);
+    let Some(&cmd) = args.first() else {
+        return Ok(Reply::Nil);
+    };
+    let mut log = shared.replication.lock();
+    if !log.log.following {
+        return Err(CommandError::syntax("no longer following a primary"));
+    }
+    let reply = super::run(
+        dispatch_command(cmd),
+        &args,
+        shared,
+        session,
+        Some(&mut log),
+    );
+    log.relay(line);
+    Ok(reply)
+}
+
+//
+// ─── Commands ────────────────────────────────────────────────────────────────────
+//
+
+/// REPLICAOF host port | REPLICAOF NO ONE
+pub fn handle_replicaof(args: &[&str], shared: &Shared) -> CommandResult {
+    if args.len() != 3 {
+        return Err(CommandError::syntax(
+            "Usage: REPLICAOF host port | REPLICAOF NO ONE",
+        ));
+    }
+    if args[1].eq_ignore_ascii_case("no") && args[2].eq_ignore_ascii_case("one") {
+        shared.replication.follow(shared, None);
+        return Ok(Reply::ok());
+    }
+    let port: u16 = args[2]
+        .parse()
+        .map_err(|_| CommandError::syntax("port is not a number"))?;
+    shared
+        .replication
+        .follow(shared, Some(format!("{}:{}", args[1], port)));
+    Ok(Reply::ok())
+}
+
+/// PSYNC replid offset. Sent by a replica, which then receives
+/// `CONTINUE replid` and the part of the stream it missed when that is still
+/// in the backlog, or else `FULLRESYNC replid offset`, then `$<bytes>` and a
+/// snapshot, then `$<bytes>` and the function libraries. Either way the
+/// connection then carries the live stream.
+pub fn handle_psync(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    if args.len() != 3 {
+        return Err(CommandError::syntax("Usage: PSYNC replid offset"));
+    }
+    if !session.subscriptions.can_push() {
+        return Err(CommandError::syntax(
+            "this session cannot stream to a replica",
+        ));
+    }
+    let offset: Option<u64> = args[2].parse().ok();
+    let (tx, rx) = mpsc::channel(REPLICA_QUEUE);
+    let mut guard = shared.replication.lock();
+    let log = &mut guard.log;
+    let capacity = log.capacity;
+    log.backlog
+        .get_or_insert_with(|| VecDeque::with_capacity(capacity));
+    log.replicas.push(tx);
+    if let Some(missed) = offset.and_then(|offset| log.since(args[1], offset)) {
+        let mut preamble = format!("CONTINUE {}\n", log.replid).into_bytes();
+        preamble.extend(missed);
+        session.replica = Some(Feed { preamble, rx });
+        return Ok(Reply::Nil);
+    }
+    // Writes take the log before the dataset, so with the log held the
+    // dataset matches the offset. Holding its read lock from here keeps it
+    // that way while it is serialized, without making every write wait on
+    // the log meanwhile.
+    let root = shared
+        .database
+        .get_root()
+        .read()
+        .map_err(|_| db::Error::LockPoisoned)?;
+    let header = format!("FULLRESYNC {} {}\n", log.replid, log.offset);
+    #[cfg(feature = "wasm")]
+    let libraries = shared.functions.dump()?;
+    #[cfg(not(feature = "wasm"))]
+    let libraries: Vec<u8> = Vec::new();
+    drop(guard);
+    let snapshot = snapshot::to_bytes(&root);
+    drop(root);
+    let mut preamble = header.into_bytes();
+    preamble.extend(format!("${}\n", snapshot.len()).into_bytes());
+    preamble.extend(snapshot);
+    preamble.extend(format!("${}\n", libraries.len()).into_bytes());
+    preamble.extend(libraries);
+    session.replica = Some(Feed { preamble, rx });
+    Ok(Reply::Nil)
+}
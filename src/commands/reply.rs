@@ -0,0 +1,145 @@
+use super::CommandError;
+
+/// Result of executing one command, independent of how it is sent back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Short status line such as `OK` or `PONG`
+    Status(String),
+    Error(CommandError),
+    Integer(i64),
+    /// A value or a block of text
+    Bulk(String),
+    Array(Vec<Reply>),
+    Nil,
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Reply::Status("OK".to_string())
+    }
+
+    /// Encode for the line-based text protocol: one line per reply, so a
+    /// value reads as itself. Arrays start with a `*count` line and nil is
+    /// `(nil)`; a value that could be mistaken for either, for an error, or
+    /// that spans lines is sent as a `$length` line followed by the value, so
+    /// a client can always tell where one reply ends.
+    pub fn write_text(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(s) | Reply::Bulk(s) if needs_length(s) => {
+                out.extend_from_slice(format!("${}\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.push(b'\n');
+            }
+            Reply::Status(s) | Reply::Bulk(s) => {
+                out.extend_from_slice(s.as_bytes());
+                out.push(b'\n');
+            }
+            Reply::Error(e) => {
+                out.push(b'-');
+                out.extend_from_slice(e.to_string().replace('\n', " ").as_bytes());
+                out.push(b'\n');
+            }
+            Reply::Integer(n) => {
+                out.extend_from_slice(n.to_string().as_bytes());
+                out.push(b'\n');
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\n", items.len()).as_bytes());
+                items.iter().for_each(|item| item.write_text(out));
+            }
+            Reply::Nil => out.extend_from_slice(b"(nil)\n"),
+        }
+    }
+
+    /// Encode as RESP, for clients that asked with `HELLO 2`: every reply is
+    /// typed and arrays carry their length, so a client can tell where one
+    /// reply ends and nest arrays.
+    pub fn write_resp(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(s) if !s.contains(['\r', '\n']) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Status(s) | Reply::Bulk(s) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Error(e) => {
+                out.push(b'-');
+                out.extend_from_slice(e.to_string().replace(['\r', '\n'], " ").as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{n}\r\n").as_bytes()),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|item| item.write_resp(out));
+            }
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+        }
+    }
+}
+
+/// Whether a value has to be length-prefixed in the text protocol: it
+/// spans lines or starts like an array count, a length, an error or nil.
+fn needs_length(s: &str) -> bool {
+    s.contains('\n') || s.starts_with(['*', '$', '-', '('])
+}
+
+impl From<bool> for Reply {
+    /// `1` / `0`, as returned by DEL, EXPIRE and friends
+    fn from(b: bool) -> Self {
+        Reply::Integer(b as i64)
+    }
+}
+
+impl From<CommandError> for Reply {
+    fn from(e: CommandError) -> Self {
+        Reply::Error(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandError, Reply};
+
+    fn text(reply: &Reply) -> String {
+        let mut out = Vec::new();
+        reply.write_text(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    fn bulk(s: &str) -> Reply {
+        Reply::Bulk(s.to_string())
+    }
+
+    #[test]
+    fn plain_values_read_as_themselves() {
+        assert_eq!(text(&bulk("bob")), "bob\n");
+        assert_eq!(text(&Reply::ok()), "OK\n");
+        assert_eq!(text(&Reply::Integer(-2)), "-2\n");
+        assert_eq!(text(&Reply::Nil), "(nil)\n");
+    }
+
+    #[test]
+    fn arrays_carry_their_count() {
+        assert_eq!(text(&Reply::Array(Vec::new())), "*0\n");
+        let nested = Reply::Array(vec![bulk("a"), Reply::Array(vec![bulk("b"), Reply::Nil])]);
+        assert_eq!(text(&nested), "*2\na\n*2\nb\n(nil)\n");
+    }
+
+    #[test]
+    fn values_that_look_like_framing_are_length_prefixed() {
+        assert_eq!(text(&bulk("(nil)")), "$5\n(nil)\n");
+        assert_eq!(text(&bulk("*3")), "$2\n*3\n");
+        assert_eq!(text(&bulk("-ERR no")), "$7\n-ERR no\n");
+        assert_eq!(text(&bulk("one\ntwo")), "$7\none\ntwo\n");
+    }
+
+    #[test]
+    fn errors_stay_on_one_line() {
+        let reply = Reply::Error(CommandError::syntax("bad\nthing"));
+        assert_eq!(text(&reply), "-ERR bad thing\n");
+    }
+}
@@ -0,0 +1,149 @@
+use super::replication::{self, LogGuard};
+use super::{
+    check_access, cluster, dispatch_command, execute_keyspace, Command, CommandError,
+    CommandResult, Reply, Session,
+};
+use crate::db::Transaction;
+use crate::server::Shared;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+//
+// ─── Server-Side Code ────────────────────────────────────────────────────────────
+//
+// Lua scripts and WASM functions run under the database write lock, so they
+// are atomic: nothing else reads or writes the dataset until they return.
+// What reaches replicas is the writes they made, wrapped in MULTI/EXEC,
+// never the code itself, so a replica ends up with the same data however
+// the code got there.
+//
+
+/// Split `EVAL script numkeys key [key ...] arg [arg ...]`, or FCALL alike,
+/// into its keys and other arguments.
+pub(super) fn split_keys<'a, 'b>(
+    args: &'a [&'b str],
+) -> Result<(&'a [&'b str], &'a [&'b str]), CommandError> {
+    let numkeys: usize = args
+        .get(2)
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| CommandError::syntax("numkeys must be a non-negative integer"))?;
+    if 3 + numkeys > args.len() {
+        return Err(CommandError::syntax(
+            "Number of keys can't be greater than number of args",
+        ));
+    }
+    Ok(args[3..].split_at(numkeys))
+}
+
+/// Runs the keyspace commands a script or function calls, inside its
+/// transaction, with the permissions of the client that called it.
+pub(super) struct Calls<'a, 'tx> {
+    shared: &'a Shared,
+    session: &'a Session,
+    tx: &'a Transaction<'tx>,
+    /// Set by the first write that succeeds
+    wrote: &'a AtomicBool,
+    effects: RefCell<Effects>,
+}
+
+/// The writes that were made, with their replies, as EXEC records them.
+#[derive(Debug, Default)]
+pub(super) struct Effects {
+    queued: Vec<Vec<String>>,
+    replies: Vec<Reply>,
+}
+
+impl<'a, 'tx> Calls<'a, 'tx> {
+    pub fn new(
+        shared: &'a Shared,
+        session: &'a Session,
+        tx: &'a Transaction<'tx>,
+        wrote: &'a AtomicBool,
+    ) -> Self {
+        Calls {
+            shared,
+            session,
+            tx,
+            wrote,
+            effects: RefCell::default(),
+        }
+    }
+
+    /// Run one keyspace command, e.g. `["SET", "users:42", "bob"]`.
+    /// Arguments must be ones a client could send, or the write could not
+    /// be passed on to replicas.
+    pub fn call(&self, args: &[&str]) -> CommandResult {
+        if args
+            .iter()
+            .any(|arg| arg.is_empty() || arg.contains([' ', '\r', '\n']))
+        {
+            return Err(CommandError::syntax(
+                "Command arguments can't be empty or contain spaces or line breaks",
+            ));
+        }
+        let Some(name) = args.first() else {
+            return Err(CommandError::syntax("Please specify a command to call"));
+        };
+        let command = dispatch_command(name);
+        if command == Command::Unknown {
+            return Err(CommandError::UnknownCommand(name.to_string()));
+        }
+        if !command.is_keyspace() {
+            return Err(CommandError::syntax(format!(
+                "{} is not allowed from scripts or functions",
+                command.name().to_ascii_uppercase()
+            )));
+        }
+        check_access(command, args, self.session, self.shared)?;
+        cluster::serves(command, args, self.shared)?;
+        let write = command.flags().write;
+        if write && self.shared.replication.read_only() {
+            return Err(CommandError::ReadOnly);
+        }
+        let stats = &self.shared.stats;
+        let started = Instant::now();
+        let result = execute_keyspace(command, args, self.tx, stats);
+        stats.record(command, started.elapsed());
+        match result {
+            Ok(reply) => {
+                if write {
+                    self.wrote.store(true, Ordering::Relaxed);
+                    let mut effects = self.effects.borrow_mut();
+                    effects
+                        .queued
+                        .push(args.iter().map(|s| s.to_string()).collect());
+                    effects.replies.push(reply.clone());
+                }
+                Ok(reply)
+            }
+            Err(e) => {
+                stats.error();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn into_effects(self) -> Effects {
+        self.effects.into_inner()
+    }
+}
+
+impl Effects {
+    /// Once the write lock is released: feed the writes to `log` as one
+    /// transaction, and serve clients blocked on the lists and streams
+    /// that were pushed to.
+    pub fn publish(&self, shared: &Shared, mut log: Option<&mut LogGuard>) {
+        if let Some(log) = log.as_deref_mut() {
+            replication::record_exec(log, &self.queued, &self.replies);
+        }
+        for args in &self.queued {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            if let Some(key) = dispatch_command(args[0]).pushed_key(&args) {
+                shared
+                    .blocking
+                    .serve(key, &shared.database, log.as_deref_mut());
+            }
+        }
+    }
+}
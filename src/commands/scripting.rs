@@ -0,0 +1,419 @@
+use super::replication::LogGuard;
+use super::sandbox::{split_keys, Calls};
+use super::{cluster, dispatch_command, Command, CommandError, CommandResult, Reply, Session};
+use crate::config::Config;
+use crate::server::Shared;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Lua instructions between checks for SCRIPT KILL.
+const HOOK_INTERVAL: u32 = 10_000;
+
+//
+// ─── Script Registry ─────────────────────────────────────────────────────────────
+//
+
+/// Scripts cached by the SHA1 of their source, and the one running now.
+#[derive(Debug)]
+pub struct Scripts {
+    cache: RwLock<HashMap<String, Arc<str>>>,
+    running: Mutex<Option<Arc<Running>>>,
+    /// Woken when a script returns
+    done: Notify,
+    time_limit: Duration,
+    /// Memory limit for each script's interpreter
+    max_memory: usize,
+}
+
+/// The script holding the dataset.
+#[derive(Debug)]
+struct Running {
+    started: Instant,
+    /// Set by SCRIPT KILL; the script fails at its next check
+    killed: AtomicBool,
+    /// Once a script has written, killing it would leave half its changes
+    wrote: AtomicBool,
+}
+
+impl Scripts {
+    pub fn new(config: &Config) -> Self {
+        Scripts {
+            cache: RwLock::new(HashMap::new()),
+            running: Mutex::new(None),
+            done: Notify::new(),
+            time_limit: config.lua_time_limit,
+            max_memory: config.lua_max_memory,
+        }
+    }
+
+    /// Whether a script has run past the time limit. Other clients are then
+    /// turned away with BUSY instead of queueing behind it.
+    pub fn busy(&self) -> bool {
+        self.running.lock().is_ok_and(|running| {
+            running
+                .as_ref()
+                .is_some_and(|r| r.started.elapsed() > self.time_limit)
+        })
+    }
+
+    /// Whether a script is running now, within its time limit or not.
+    pub fn running(&self) -> bool {
+        self.running.lock().is_ok_and(|running| running.is_some())
+    }
+
+    /// Wait while a script runs within its time limit, without holding up
+    /// a runtime thread on the database lock.
+    pub async fn wait(&self) {
+        loop {
+            let done = self.done.notified();
+            let remaining = match self.running.lock() {
+                Ok(running) => running
+                    .as_ref()
+                    .and_then(|r| self.time_limit.checked_sub(r.started.elapsed())),
+                Err(_) => None,
+            };
+            let Some(remaining) = remaining else {
+                return;
+            };
+            let _ = tokio::time::timeout(remaining, done).await;
+        }
+    }
+
+    /// Cache `source` and return its SHA1.
+    fn load(&self, source: &str) -> Result<String, CommandError> {
+        let sha = sha1_hex(source);
+        let mut cache = self.cache.write().map_err(|_| poisoned())?;
+        cache
+            .entry(sha.clone())
+            .or_insert_with(|| Arc::from(source));
+        Ok(sha)
+    }
+
+    fn get(&self, sha: &str) -> Result<Option<Arc<str>>, CommandError> {
+        let cache = self.cache.read().map_err(|_| poisoned())?;
+        Ok(cache.get(&sha.to_ascii_lowercase()).cloned())
+    }
+
+    /// Stop the running script, if any, even one that has written.
+    pub fn abort(&self) {
+        if let Ok(running) = self.running.lock() {
+            if let Some(ref running) = *running {
+                running.killed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn set_running(&self, running: Option<Arc<Running>>) {
+        let done = running.is_none();
+        if let Ok(mut slot) = self.running.lock() {
+            *slot = running;
+        }
+        if done {
+            self.done.notify_waiters();
+        }
+    }
+}
+
+fn sha1_hex(source: &str) -> String {
+    cluster::to_hex(&Sha1::digest(source.as_bytes()))
+}
+
+fn poisoned() -> CommandError {
+    CommandError::Internal("script cache poisoned".to_string())
+}
+
+//
+// ─── EVAL / EVALSHA ──────────────────────────────────────────────────────────────
+//
+
+/// EVAL script numkeys [key ...] [arg ...], or EVALSHA sha1 numkeys ...
+/// with a script cached by SCRIPT LOAD. Requests are split on spaces and
+/// there is no quoting, so the script EVAL takes is everything up to the
+/// first space: `return 1` would run as just `return`. Scripts with spaces
+/// go through SCRIPT LOAD, which takes the rest of its line, and EVALSHA.
+pub fn handle_eval(
+    args: &[&str],
+    shared: &Shared,
+    session: &Session,
+    log: Option<&mut LogGuard>,
+) -> CommandResult {
+    let by_sha = dispatch_command(args[0]) == Command::Evalsha;
+    let (Some(body), Some(_)) = (args.get(1), args.get(2)) else {
+        return Err(CommandError::syntax(format!(
+            "Usage: {} {} numkeys [key ...] [arg ...]",
+            args[0].to_ascii_uppercase(),
+            if by_sha { "sha1" } else { "script" }
+        )));
+    };
+    let (keys, argv) = split_keys(args)?;
+    let source = if by_sha {
+        shared.scripts.get(body)?.ok_or(CommandError::NoScript)?
+    } else {
+        shared.scripts.load(body)?;
+        Arc::from(*body)
+    };
+
+    let running = Arc::new(Running {
+        started: Instant::now(),
+        killed: AtomicBool::new(false),
+        wrote: AtomicBool::new(false),
+    });
+    let eval = || {
+        shared.database.transaction(|tx| {
+            shared.scripts.set_running(Some(Arc::clone(&running)));
+            let calls = Calls::new(shared, session, tx, &running.wrote);
+            let result = run(
+                &calls,
+                &running,
+                &source,
+                keys,
+                argv,
+                shared.scripts.max_memory,
+            );
+            shared.scripts.set_running(None);
+            (result, calls.into_effects())
+        })
+    };
+    // Let the runtime move other connections off this thread, so they can
+    // still be told BUSY and send SCRIPT KILL
+    let (result, effects) = cluster::blocking_io(eval)?;
+
+    effects.publish(shared, log);
+    if running.killed.load(Ordering::Relaxed) {
+        return Err(CommandError::Script(
+            "ERR Script killed by user with SCRIPT KILL".to_string(),
+        ));
+    }
+    result
+}
+
+/// Run a script in a fresh interpreter. Only the table, string, math and
+/// utf8 libraries are loaded and the random seed is fixed, so a script sees
+/// nothing but its arguments and the dataset.
+fn run(
+    calls: &Calls,
+    running: &Arc<Running>,
+    source: &str,
+    keys: &[&str],
+    argv: &[&str],
+    max_memory: usize,
+) -> CommandResult {
+    let lua = sandbox(max_memory).map_err(|e| script_error(&e))?;
+    let running = Arc::clone(running);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |_, _| match running.killed.load(Ordering::Relaxed) {
+            true => Err(mlua::Error::RuntimeError("Script killed".to_string())),
+            false => Ok(()),
+        },
+    );
+    let result = lua.scope(|scope| {
+        let globals = lua.globals();
+        globals.set("KEYS", lua.create_sequence_from(keys.iter().copied())?)?;
+        globals.set("ARGV", lua.create_sequence_from(argv.iter().copied())?)?;
+        let flashtree = lua.create_table()?;
+        flashtree.set(
+            "call",
+            scope.create_function(|lua, args: Variadic<Value>| match call(calls, &args) {
+                Ok(reply) => to_lua(lua, reply),
+                Err(e) => Err(mlua::Error::external(e)),
+            })?,
+        )?;
+        flashtree.set(
+            "pcall",
+            scope.create_function(|lua, args: Variadic<Value>| {
+                to_lua(lua, call(calls, &args).unwrap_or_else(Reply::Error))
+            })?,
+        )?;
+        flashtree.set(
+            "status_reply",
+            lua.create_function(|lua, status: String| {
+                let table = lua.create_table()?;
+                table.set("ok", status)?;
+                Ok(table)
+            })?,
+        )?;
+        flashtree.set(
+            "error_reply",
+            lua.create_function(|lua, error: String| {
+                let table = lua.create_table()?;
+                table.set("err", error)?;
+                Ok(table)
+            })?,
+        )?;
+        globals.set("flashtree", flashtree)?;
+        let value: Value = lua.load(source).set_name("script").eval()?;
+        Ok(from_lua(value))
+    });
+    result.map_err(|e| script_error(&e))
+}
+
+/// `flashtree.call("SET", KEYS[1], ARGV[1])`: run a keyspace command inside
+/// the script's transaction.
+fn call(calls: &Calls, args: &[Value]) -> CommandResult {
+    let args = args
+        .iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(s.to_string_lossy().into_owned()),
+            Value::Integer(n) => Ok(n.to_string()),
+            Value::Number(n) => Ok(n.to_string()),
+            _ => Err(CommandError::syntax(
+                "Lua flashtree lib command arguments must be strings or integers",
+            )),
+        })
+        .collect::<Result<Vec<String>, _>>()?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    calls.call(&args)
+}
+
+/// An interpreter that fails allocations past `max_memory` bytes. The base
+/// library is always loaded, so its functions that read files or write to
+/// the server's stdout are removed.
+fn sandbox(max_memory: usize) -> mlua::Result<Lua> {
+    let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+    let lua = Lua::new_with(libs, LuaOptions::default())?;
+    lua.set_memory_limit(max_memory)?;
+    for name in ["dofile", "loadfile", "print"] {
+        lua.globals().set(name, Value::Nil)?;
+    }
+    lua.load("math.randomseed(0)").exec()?;
+    Ok(lua)
+}
+
+/// The error reply for a failed script. Errors from `flashtree.call` come
+/// through unchanged, so the client still sees e.g. WRONGTYPE. Lua's own
+/// messages are cut to their first line, dropping the stack traceback.
+fn script_error(e: &mlua::Error) -> CommandError {
+    let error = |what: &str, message: &str| {
+        let message = message.lines().next().unwrap_or_default();
+        CommandError::Script(format!("ERR Error {what} script: {message}"))
+    };
+    match e {
+        mlua::Error::CallbackError { cause, .. } => script_error(cause),
+        mlua::Error::ExternalError(e) => match e.downcast_ref::<CommandError>() {
+            Some(e) => e.clone(),
+            None => error("running", &e.to_string()),
+        },
+        mlua::Error::SyntaxError { message, .. } => error("compiling", message),
+        mlua::Error::RuntimeError(message) => error("running", message),
+        e => error("running", &e.to_string()),
+    }
+}
+
+//
+// ─── Value Conversion ────────────────────────────────────────────────────────────
+//
+// Replies become Lua values as in Redis: a status is {ok = ...}, an error
+// {err = ...}, nil is false and arrays are sequences. Going back, a table
+// stops at its first nil, and numbers are truncated to integers.
+//
+
+fn to_lua(lua: &Lua, reply: Reply) -> mlua::Result<Value<'_>> {
+    Ok(match reply {
+        Reply::Status(status) => {
+            let table = lua.create_table()?;
+            table.set("ok", status)?;
+            Value::Table(table)
+        }
+        Reply::Error(e) => {
+            let table = lua.create_table()?;
+            table.set("err", e.to_string())?;
+            Value::Table(table)
+        }
+        Reply::Integer(n) => Value::Integer(n),
+        Reply::Bulk(s) => Value::String(lua.create_string(&s)?),
+        Reply::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| to_lua(lua, item))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            Value::Table(lua.create_sequence_from(items)?)
+        }
+        Reply::Nil => Value::Boolean(false),
+    })
+}
+
+fn from_lua(value: Value) -> Reply {
+    match value {
+        Value::Nil | Value::Boolean(false) => Reply::Nil,
+        Value::Boolean(true) => Reply::Integer(1),
+        Value::Integer(n) => Reply::Integer(n),
+        Value::Number(n) => Reply::Integer(n as i64),
+        Value::String(s) => Reply::Bulk(s.to_string_lossy().into_owned()),
+        Value::Table(table) => table_reply(table),
+        _ => Reply::Nil,
+    }
+}
+
+fn table_reply(table: Table) -> Reply {
+    // Replies are one line each
+    let line = |s: mlua::String| s.to_string_lossy().replace(['\r', '\n'], " ");
+    if let Ok(Value::String(err)) = table.raw_get("err") {
+        return Reply::Error(CommandError::Script(line(err)));
+    }
+    if let Ok(Value::String(ok)) = table.raw_get("ok") {
+        return Reply::Status(line(ok));
+    }
+    Reply::Array(
+        table
+            .sequence_values::<Value>()
+            .map_while(Result::ok)
+            .map(from_lua)
+            .collect(),
+    )
+}
+
+//
+// ─── SCRIPT ──────────────────────────────────────────────────────────────────────
+//
+
+/// SCRIPT LOAD script | EXISTS sha1 [sha1 ...] | FLUSH | KILL. LOAD takes
+/// the rest of the line as the script, so it may contain spaces.
+pub fn handle_script(args: &[&str], shared: &Shared) -> CommandResult {
+    const USAGE: &str = "Usage: SCRIPT LOAD script | EXISTS sha1 [sha1 ...] | FLUSH | KILL";
+    let sub = args.get(1).ok_or_else(|| CommandError::syntax(USAGE))?;
+    let scripts = &shared.scripts;
+    match sub.to_ascii_lowercase().as_str() {
+        "load" if args.len() > 2 => {
+            let source = args[2..].join(" ");
+            // Refuse what would not compile, as EVALSHA would fail on it later
+            sandbox(scripts.max_memory)
+                .and_then(|lua| {
+                    lua.load(&source)
+                        .set_name("script")
+                        .into_function()
+                        .map(drop)
+                })
+                .map_err(|e| script_error(&e))?;
+            Ok(Reply::Bulk(scripts.load(&source)?))
+        }
+        "exists" if args.len() > 2 => {
+            let mut found = Vec::with_capacity(args.len() - 2);
+            for sha in &args[2..] {
+                found.push(Reply::from(scripts.get(sha)?.is_some()));
+            }
+            Ok(Reply::Array(found))
+        }
+        "flush" => {
+            scripts.cache.write().map_err(|_| poisoned())?.clear();
+            Ok(Reply::ok())
+        }
+        "kill" if args.len() == 2 => {
+            let running = scripts.running.lock().map_err(|_| poisoned())?;
+            let Some(ref running) = *running else {
+                return Err(CommandError::NotBusy);
+            };
+            if running.wrote.load(Ordering::Relaxed) {
+                return Err(CommandError::Unkillable);
+            }
+            running.killed.store(true, Ordering::Relaxed);
+            Ok(Reply::ok())
+        }
+        _ => Err(CommandError::syntax(USAGE)),
+    }
+}
@@ -0,0 +1,134 @@
+use super::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+/// An implicit +Inf bucket follows the last one.
+pub const LATENCY_BUCKETS_USEC: [u64; 11] = [
+    10, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000,
+];
+const BUCKETS: usize = LATENCY_BUCKETS_USEC.len() + 1;
+
+/// Server-wide command counters, updated lock-free from every connection.
+#[derive(Debug)]
+pub struct Stats {
+    total: AtomicU64,
+    calls: [AtomicU64; Command::COUNT],
+    usec: [AtomicU64; Command::COUNT],
+    latency: [[AtomicU64; BUCKETS]; Command::COUNT],
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Per-command totals, as shown by INFO commandstats and /metrics.
+pub struct CommandStat {
+    pub name: &'static str,
+    pub calls: u64,
+    pub usec: u64,
+    /// Non-cumulative counts per latency bucket, +Inf last
+    pub latency: [u64; BUCKETS],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            total: AtomicU64::new(0),
+            calls: std::array::from_fn(|_| AtomicU64::new(0)),
+            usec: std::array::from_fn(|_| AtomicU64::new(0)),
+            latency: std::array::from_fn(|_| std::array::from_fn(|_| AtomicU64::new(0))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn record(&self, cmd: Command, elapsed: Duration) {
+        let idx = cmd as usize;
+        let usec = elapsed.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_USEC
+            .iter()
+            .position(|&bound| usec <= bound)
+            .unwrap_or(BUCKETS - 1);
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.calls[idx].fetch_add(1, Ordering::Relaxed);
+        self.usec[idx].fetch_add(usec, Ordering::Relaxed);
+        self.latency[idx][bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a command answered with an error reply.
+    #[inline]
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn total_commands(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Commands that have been called at least once.
+    pub fn commands(&self) -> Vec<CommandStat> {
+        Command::ALL
+            .iter()
+            .filter_map(|&cmd| {
+                let idx = cmd as usize;
+                let calls = self.calls[idx].load(Ordering::Relaxed);
+                (calls > 0).then(|| CommandStat {
+                    name: cmd.name(),
+                    calls,
+                    usec: self.usec[idx].load(Ordering::Relaxed),
+                    latency: std::array::from_fn(|b| self.latency[idx][b].load(Ordering::Relaxed)),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Stats};
+    use std::time::Duration;
+
+    #[test]
+    fn commandstats_lists_called_commands_by_name() {
+        let stats = Stats::new();
+        stats.record(Command::Get, Duration::from_micros(5));
+        stats.record(Command::Get, Duration::from_micros(7));
+        stats.record(Command::Set, Duration::from_micros(1));
+        let seen: Vec<_> = stats
+            .commands()
+            .iter()
+            .map(|c| (c.name, c.calls, c.usec))
+            .collect();
+        assert_eq!(seen, [("set", 1, 1), ("get", 2, 12)]);
+        assert_eq!(stats.total_commands(), 3);
+    }
+}
@@ -0,0 +1,585 @@
+use super::blocking::{self, Op};
+use super::{CommandError, CommandResult, Reply, Session};
+use crate::db::{self, core, Fields, Keyspace, NewId, Stream, StreamId, Trim};
+use crate::server::Shared;
+use std::time::Duration;
+
+const NO_STREAM: &str = "The XGROUP subcommand requires the key to exist. \
+    Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+//
+// ─── Replies and Parsing ─────────────────────────────────────────────────────────
+//
+
+/// One entry as `[id, [field, value, ...]]`; nil fields for an entry that was
+/// deleted while still pending.
+fn entry_reply(id: StreamId, fields: Option<Fields>) -> Reply {
+    let fields = fields.map_or(Reply::Nil, |fields| {
+        Reply::Array(
+            fields
+                .into_iter()
+                .flat_map(|(f, v)| [Reply::Bulk(f), Reply::Bulk(v)])
+                .collect(),
+        )
+    });
+    Reply::Array(vec![Reply::Bulk(id.to_string()), fields])
+}
+
+fn entries_reply(entries: Vec<(StreamId, Fields)>) -> Reply {
+    Reply::Array(
+        entries
+            .into_iter()
+            .map(|(id, fields)| entry_reply(id, Some(fields)))
+            .collect(),
+    )
+}
+
+/// `[key, entries]`, one per stream in an XREAD reply.
+fn keyed(key: &str, entries: Reply) -> Reply {
+    Reply::Array(vec![Reply::Bulk(key.to_string()), entries])
+}
+
+fn invalid_id() -> CommandError {
+    CommandError::syntax("Invalid stream ID specified as stream command argument")
+}
+
+fn parse_id(arg: &str) -> Result<StreamId, CommandError> {
+    StreamId::parse(arg, 0).ok_or_else(invalid_id)
+}
+
+/// A group's starting ID: `$` for the stream's last entry, `None` here.
+fn parse_group_id(arg: &str) -> Result<Option<StreamId>, CommandError> {
+    match arg {
+        "$" => Ok(None),
+        id => parse_id(id).map(Some),
+    }
+}
+
+fn parse_count(arg: Option<&&str>) -> Result<usize, CommandError> {
+    arg.and_then(|s| s.parse().ok())
+        .ok_or_else(|| CommandError::syntax("value is not an integer or out of range"))
+}
+
+/// `MAXLEN|MINID [=|~] threshold` starting at `args[0]`. Trimming is always
+/// exact, so `~` is accepted and treated like `=`. Returns the trim and how
+/// many arguments it took.
+fn parse_trim(args: &[&str]) -> Result<Option<(Trim, usize)>, CommandError> {
+    let Some(kind) = args.first().map(|s| s.to_ascii_uppercase()) else {
+        return Ok(None);
+    };
+    if kind != "MAXLEN" && kind != "MINID" {
+        return Ok(None);
+    }
+    let skip = usize::from(args.get(1).is_some_and(|s| *s == "=" || *s == "~"));
+    let threshold = args
+        .get(1 + skip)
+        .ok_or_else(|| CommandError::syntax("syntax error"))?;
+    let trim = if kind == "MAXLEN" {
+        Trim::MaxLen(parse_count(Some(threshold))?)
+    } else {
+        Trim::MinId(parse_id(threshold)?)
+    };
+    Ok(Some((trim, 2 + skip)))
+}
+
+/// Milliseconds to block; 0 blocks indefinitely.
+fn parse_block(arg: Option<&&str>) -> Result<Option<Duration>, CommandError> {
+    let ms: u64 = arg
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| CommandError::syntax("timeout is not an integer or out of range"))?;
+    Ok((ms > 0).then(|| Duration::from_millis(ms)))
+}
+
+/// Options of XREAD and XREADGROUP, up to and including STREAMS.
+#[derive(Default)]
+struct ReadOptions<'a> {
+    count: Option<usize>,
+    block: Option<Option<Duration>>,
+    noack: bool,
+    /// When the read happened, as a unix time in milliseconds; given to
+    /// replicas so they record the deliveries as the primary did
+    time: Option<u64>,
+    keys: Vec<&'a str>,
+    ids: Vec<&'a str>,
+}
+
+fn parse_read<'a>(args: &[&'a str], group: bool) -> Result<ReadOptions<'a>, CommandError> {
+    let mut options = ReadOptions::default();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].to_ascii_uppercase().as_str() {
+            "COUNT" => {
+                options.count = Some(parse_count(args.get(i + 1))?);
+                i += 2;
+            }
+            "BLOCK" => {
+                options.block = Some(parse_block(args.get(i + 1))?);
+                i += 2;
+            }
+            "NOACK" if group => {
+                options.noack = true;
+                i += 1;
+            }
+            "TIME" if group => {
+                options.time = Some(parse_count(args.get(i + 1))? as u64);
+                i += 2;
+            }
+            "STREAMS" => {
+                let rest = &args[i + 1..];
+                if rest.is_empty() || !rest.len().is_multiple_of(2) {
+                    return Err(CommandError::syntax(
+                        "Unbalanced list of streams: for each stream key an ID must be specified",
+                    ));
+                }
+                let (keys, ids) = rest.split_at(rest.len() / 2);
+                options.keys = keys.to_vec();
+                options.ids = ids.to_vec();
+                return Ok(options);
+            }
+            _ => return Err(CommandError::syntax("syntax error")),
+        }
+    }
+    Err(CommandError::syntax("syntax error"))
+}
+
+//
+// ─── Reads Shared With Blocking ──────────────────────────────────────────────────
+//
+
+/// Entries of `key` after `after` as an XREAD reply for that one stream;
+/// `None` if there are none.
+pub(super) fn read_after(
+    database: &impl Keyspace,
+    key: &str,
+    after: StreamId,
+    count: Option<usize>,
+) -> Result<Option<Reply>, CommandError> {
+    let entries = database
+        .read_stream(key, |stream| stream.after(after, count))?
+        .unwrap_or_default();
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Reply::Array(vec![keyed(key, entries_reply(entries))])))
+}
+
+/// Entries of `key` never delivered to `group`, handed to `consumer`, as an
+/// XREADGROUP reply for that one stream; `None` if there are none.
+pub(super) fn read_new(
+    database: &impl Keyspace,
+    key: &str,
+    group: &str,
+    consumer: &str,
+    count: Option<usize>,
+    noack: bool,
+    now_ms: u64,
+) -> Result<Option<Reply>, CommandError> {
+    let entries = read_group(database, key, |stream| {
+        stream.read_group(group, consumer, None, count, noack, now_ms)
+    })?;
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Reply::Array(vec![keyed(key, Reply::Array(entries))])))
+}
+
+/// Run a group read on the stream at `key` and turn what it returned into
+/// entry replies.
+fn read_group(
+    database: &impl Keyspace,
+    key: &str,
+    read: impl FnOnce(&mut Stream) -> db::Result<Vec<(StreamId, Option<Fields>)>>,
+) -> Result<Vec<Reply>, CommandError> {
+    let entries = database
+        .update_stream(key, false, read)?
+        .ok_or(CommandError::NoGroup)?;
+    Ok(entries
+        .into_iter()
+        .map(|(id, fields)| entry_reply(id, fields))
+        .collect())
+}
+
+//
+// ─── Commands ────────────────────────────────────────────────────────────────────
+//
+
+/// Where XADD's ID argument is, past NOMKSTREAM and any trim option.
+fn xadd_id_at(args: &[&str]) -> Result<(usize, bool, Option<Trim>), CommandError> {
+    let mut at = 2;
+    let create = !args
+        .get(at)
+        .is_some_and(|s| s.eq_ignore_ascii_case("NOMKSTREAM"));
+    if !create {
+        at += 1;
+    }
+    let trim = match parse_trim(&args[at.min(args.len())..])? {
+        Some((trim, taken)) => {
+            at += taken;
+            Some(trim)
+        }
+        None => None,
+    };
+    Ok((at, create, trim))
+}
+
+/// The position of XADD's ID argument, so the ID generated for `*` can be
+/// put in its place before the command goes to replicas.
+pub(super) fn xadd_id_index(args: &[&str]) -> Option<usize> {
+    xadd_id_at(args)
+        .ok()
+        .map(|(at, _, _)| at)
+        .filter(|&at| at < args.len())
+}
+
+/// XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold] *|id field value [field value ...]
+pub fn handle_xadd(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    let usage = || {
+        CommandError::syntax(
+            "Usage: XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold] *|id field value [field value ...]",
+        )
+    };
+    let key = args.get(1).ok_or_else(usage)?;
+    let (at, create, trim) = xadd_id_at(args)?;
+    let id = NewId::parse(args.get(at).ok_or_else(usage)?).ok_or_else(invalid_id)?;
+    if id == NewId::Explicit(StreamId::MIN) {
+        return Err(CommandError::syntax(
+            "The ID specified in XADD must be greater than 0-0",
+        ));
+    }
+    let pairs = &args[at + 1..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(usage());
+    }
+    let fields = pairs
+        .chunks(2)
+        .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+        .collect();
+    Ok(database
+        .xadd(key, id, fields, trim, create)?
+        .map_or(Reply::Nil, |id| Reply::Bulk(id.to_string())))
+}
+
+/// XRANGE key start end [COUNT count]
+pub fn handle_xrange(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    let usage = || CommandError::syntax("Usage: XRANGE key start end [COUNT count]");
+    let count = match args.len() {
+        4 => None,
+        6 if args[4].eq_ignore_ascii_case("COUNT") => Some(parse_count(args.get(5))?),
+        _ => return Err(usage()),
+    };
+    let start = StreamId::parse_start(args[2]).ok_or_else(invalid_id)?;
+    let end = StreamId::parse_end(args[3]).ok_or_else(invalid_id)?;
+    let entries = database
+        .read_stream(args[1], |stream| stream.range(start, end, count))?
+        .unwrap_or_default();
+    Ok(entries_reply(entries))
+}
+
+/// XLEN key
+pub fn handle_xlen(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() != 2 {
+        return Err(CommandError::syntax("Usage: XLEN key"));
+    }
+    let len = database.read_stream(args[1], |stream| stream.len())?;
+    Ok(Reply::Integer(len.unwrap_or(0) as i64))
+}
+
+/// XTRIM key MAXLEN|MINID [=|~] threshold
+pub fn handle_xtrim(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    let usage = || CommandError::syntax("Usage: XTRIM key MAXLEN|MINID [=|~] threshold");
+    let key = args.get(1).ok_or_else(usage)?;
+    let trim = match parse_trim(&args[2.min(args.len())..])? {
+        Some((trim, taken)) if 2 + taken == args.len() => trim,
+        _ => return Err(usage()),
+    };
+    Ok(Reply::Integer(database.xtrim(key, trim)? as i64))
+}
+
+/// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+///
+/// `$` as an ID means entries added from now on.
+pub fn handle_xread(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    let options = parse_read(&args[1..], false)?;
+    let database = &*shared.database;
+    let mut after = Vec::with_capacity(options.keys.len());
+    for (key, id) in options.keys.iter().zip(&options.ids) {
+        let id = if *id == "$" {
+            let last = database.read_stream(key, |stream| stream.last_id)?;
+            last.unwrap_or(StreamId::MIN)
+        } else {
+            parse_id(id)?
+        };
+        after.push((key.to_string(), id));
+    }
+    let count = options.count;
+    let read_all = |after: &[(String, StreamId)]| {
+        let mut streams = Vec::new();
+        for (key, id) in after {
+            if let Some(Reply::Array(found)) = read_after(database, key, *id, count)? {
+                streams.extend(found);
+            }
+        }
+        Ok::<_, CommandError>((!streams.is_empty()).then_some(Reply::Array(streams)))
+    };
+    let Some(timeout) = options.block else {
+        return Ok(read_all(&after)?.unwrap_or(Reply::Nil));
+    };
+    let op = Op::Read { after, count };
+    blocking::block(shared, session, &options.keys, timeout, op, |op| match op {
+        Op::Read { after, .. } => read_all(after),
+        _ => Ok(None),
+    })
+}
+
+/// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK]
+/// [TIME unix-time-milliseconds] STREAMS key [key ...] id [id ...]
+///
+/// `>` reads entries never delivered to the group; any other ID re-reads the
+/// consumer's own pending entries after it, and never blocks. TIME stands in
+/// for the clock, so replicas record deliveries at the primary's time.
+pub fn handle_xreadgroup(args: &[&str], shared: &Shared, session: &mut Session) -> CommandResult {
+    if args.len() < 4 || !args[1].eq_ignore_ascii_case("GROUP") {
+        return Err(CommandError::syntax(
+            "Usage: XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] [TIME unix-time-milliseconds] STREAMS key [key ...] id [id ...]",
+        ));
+    }
+    let (group, consumer) = (args[2], args[3]);
+    let options = parse_read(&args[4..], true)?;
+    let database = &*shared.database;
+    let (count, noack) = (options.count, options.noack);
+    let now_ms = options.time.unwrap_or_else(core::now_ms);
+    if options.ids.iter().any(|id| *id != ">") {
+        let mut streams = Vec::with_capacity(options.keys.len());
+        for (key, id) in options.keys.iter().zip(&options.ids) {
+            let after = if *id == ">" {
+                None
+            } else {
+                Some(parse_id(id)?)
+            };
+            let entries = read_group(database, key, |stream| {
+                stream.read_group(group, consumer, after, count, noack, now_ms)
+            })?;
+            streams.push(keyed(key, Reply::Array(entries)));
+        }
+        return Ok(Reply::Array(streams));
+    }
+    let read_all = || {
+        let mut streams = Vec::new();
+        for key in &options.keys {
+            if let Some(Reply::Array(found)) =
+                read_new(database, key, group, consumer, count, noack, now_ms)?
+            {
+                streams.extend(found);
+            }
+        }
+        Ok::<_, CommandError>((!streams.is_empty()).then_some(Reply::Array(streams)))
+    };
+    let Some(timeout) = options.block else {
+        return Ok(read_all()?.unwrap_or(Reply::Nil));
+    };
+    let op = Op::ReadGroup {
+        group: group.to_string(),
+        consumer: consumer.to_string(),
+        count,
+        noack,
+    };
+    blocking::block(shared, session, &options.keys, timeout, op, |_| read_all())
+}
+
+/// XGROUP CREATE key group id|$ [MKSTREAM] | SETID key group id|$ |
+/// DESTROY key group | CREATECONSUMER key group consumer | DELCONSUMER key group consumer
+pub fn handle_xgroup(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    let usage = || {
+        CommandError::syntax(
+            "Usage: XGROUP CREATE key group id|$ [MKSTREAM] | SETID key group id|$ | \
+             DESTROY key group | CREATECONSUMER key group consumer | DELCONSUMER key group consumer",
+        )
+    };
+    if args.len() < 4 {
+        return Err(usage());
+    }
+    let sub = args[1].to_ascii_uppercase();
+    let (key, group) = (args[2], args[3]);
+    let reply = match (sub.as_str(), args.len()) {
+        ("CREATE", 5 | 6) => {
+            let mkstream = match args.get(5) {
+                Some(opt) if opt.eq_ignore_ascii_case("MKSTREAM") => true,
+                Some(_) => return Err(usage()),
+                None => false,
+            };
+            let id = parse_group_id(args[4])?;
+            database
+                .update_stream(key, mkstream, |stream| {
+                    stream.create_group(group, id.unwrap_or(stream.last_id))
+                })?
+                .map(|()| Reply::ok())
+        }
+        ("SETID", 5) => {
+            let id = parse_group_id(args[4])?;
+            database
+                .update_stream(key, false, |stream| {
+                    stream.set_group_id(group, id.unwrap_or(stream.last_id))
+                })?
+                .map(|()| Reply::ok())
+        }
+        ("DESTROY", 4) => database
+            .update_stream(key, false, |stream| Ok(stream.destroy_group(group)))?
+            .map(|destroyed| Reply::Integer(destroyed as i64)),
+        ("CREATECONSUMER", 5) => {
+            let now = core::now_ms();
+            database
+                .update_stream(key, false, |stream| {
+                    stream.create_consumer(group, args[4], now)
+                })?
+                .map(|created| Reply::Integer(created as i64))
+        }
+        ("DELCONSUMER", 5) => database
+            .update_stream(key, false, |stream| stream.delete_consumer(group, args[4]))?
+            .map(|pending| Reply::Integer(pending as i64)),
+        _ => return Err(usage()),
+    };
+    reply.ok_or_else(|| CommandError::syntax(NO_STREAM))
+}
+
+/// XACK key group id [id ...]
+pub fn handle_xack(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 4 {
+        return Err(CommandError::syntax("Usage: XACK key group id [id ...]"));
+    }
+    let ids = args[3..]
+        .iter()
+        .map(|id| parse_id(id))
+        .collect::<Result<Vec<_>, _>>()?;
+    let acked = database.update_stream(args[1], false, |stream| stream.ack(args[2], &ids))?;
+    Ok(Reply::Integer(acked.unwrap_or(0) as i64))
+}
+
+/// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+///
+/// The short form summarises: the pending count, lowest and highest pending
+/// IDs, and how many each consumer holds. The long form lists entries as
+/// `[id, consumer, idle ms, deliveries]`.
+pub fn handle_xpending(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    let usage = || {
+        CommandError::syntax(
+            "Usage: XPENDING key group [[IDLE min-idle-time] start end count [consumer]]",
+        )
+    };
+    if args.len() < 3 {
+        return Err(usage());
+    }
+    let (key, group) = (args[1], args[2]);
+    let now = core::now_ms();
+    if args.len() == 3 {
+        let summary = database
+            .read_stream(key, |stream| {
+                let group = stream.group(group)?;
+                let mut consumers: Vec<(&str, i64)> = Vec::new();
+                for pending in group.pending.values() {
+                    match consumers.iter_mut().find(|(c, _)| *c == pending.consumer) {
+                        Some((_, n)) => *n += 1,
+                        None => consumers.push((&pending.consumer, 1)),
+                    }
+                }
+                consumers.sort_unstable();
+                let (Some(first), Some(last)) = (
+                    group.pending.keys().next(),
+                    group.pending.keys().next_back(),
+                ) else {
+                    return Ok::<_, db::Error>(vec![
+                        Reply::Integer(0),
+                        Reply::Nil,
+                        Reply::Nil,
+                        Reply::Nil,
+                    ]);
+                };
+                let consumers = consumers
+                    .into_iter()
+                    .map(|(c, n)| {
+                        Reply::Array(vec![Reply::Bulk(c.to_string()), Reply::Bulk(n.to_string())])
+                    })
+                    .collect();
+                Ok(vec![
+                    Reply::Integer(group.pending.len() as i64),
+                    Reply::Bulk(first.to_string()),
+                    Reply::Bulk(last.to_string()),
+                    Reply::Array(consumers),
+                ])
+            })?
+            .ok_or(CommandError::NoGroup)??;
+        return Ok(Reply::Array(summary));
+    }
+    let mut rest = &args[3..];
+    let mut min_idle = 0;
+    if rest.first().is_some_and(|s| s.eq_ignore_ascii_case("IDLE")) {
+        min_idle = parse_count(rest.get(1))? as u64;
+        rest = &rest[2..];
+    }
+    if rest.len() != 3 && rest.len() != 4 {
+        return Err(usage());
+    }
+    let start = StreamId::parse_start(rest[0]).ok_or_else(invalid_id)?;
+    let end = StreamId::parse_end(rest[1]).ok_or_else(invalid_id)?;
+    let count = parse_count(rest.get(2))?;
+    let consumer = rest.get(3).copied();
+    let entries = database
+        .read_stream(key, |stream| {
+            let pending = stream.pending(group, start, end, usize::MAX, consumer)?;
+            Ok::<_, db::Error>(
+                pending
+                    .into_iter()
+                    .map(|(id, p)| (id, p, now.saturating_sub(p.delivered_ms)))
+                    .filter(|&(_, _, idle)| idle >= min_idle)
+                    .take(count)
+                    .map(|(id, p, idle)| {
+                        Reply::Array(vec![
+                            Reply::Bulk(id.to_string()),
+                            Reply::Bulk(p.consumer.clone()),
+                            Reply::Integer(idle as i64),
+                            Reply::Integer(p.deliveries as i64),
+                        ])
+                    })
+                    .collect(),
+            )
+        })?
+        .ok_or(CommandError::NoGroup)??;
+    Ok(Reply::Array(entries))
+}
+
+/// XCLAIM key group consumer min-idle-time id [id ...]
+/// [TIME unix-time-milliseconds] [JUSTID]. TIME stands in for the clock, as
+/// for XREADGROUP.
+pub fn handle_xclaim(args: &[&str], database: &impl Keyspace) -> CommandResult {
+    if args.len() < 6 {
+        return Err(CommandError::syntax(
+            "Usage: XCLAIM key group consumer min-idle-time id [id ...] [TIME unix-time-milliseconds] [JUSTID]",
+        ));
+    }
+    let min_idle = parse_count(args.get(4))? as u64;
+    let (mut ids, mut time, mut justid) = (Vec::new(), None, false);
+    let mut rest = args[5..].iter();
+    while let Some(arg) = rest.next() {
+        if arg.eq_ignore_ascii_case("JUSTID") {
+            justid = true;
+        } else if arg.eq_ignore_ascii_case("TIME") {
+            time = Some(parse_count(rest.next())? as u64);
+        } else {
+            ids.push(parse_id(arg)?);
+        }
+    }
+    let now = time.unwrap_or_else(core::now_ms);
+    let claimed = database
+        .update_stream(args[1], false, |stream| {
+            stream.claim(args[2], args[3], min_idle, &ids, now)
+        })?
+        .ok_or(CommandError::NoGroup)?;
+    if justid {
+        return Ok(Reply::Array(
+            claimed
+                .into_iter()
+                .map(|(id, _)| Reply::Bulk(id.to_string()))
+                .collect(),
+        ));
+    }
+    Ok(entries_reply(claimed))
+}
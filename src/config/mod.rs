@@ -0,0 +1,219 @@
+use crate::db::evict::{Eviction, Policy};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Runtime settings, filled from command-line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Plaintext TCP address; `--bind none` leaves only TLS / Unix socket listeners
+    pub bind: Option<String>,
+    pub shutdown_timeout: Duration,
+    pub snapshot_path: Option<PathBuf>,
+    pub max_clients: usize,
+    /// 0 means no per-address limit
+    pub max_clients_per_ip: usize,
+    /// Address for the Prometheus `/metrics` listener; disabled when `None`
+    pub metrics_bind: Option<String>,
+    /// Dataset limit in bytes, 0 for unlimited
+    pub maxmemory: usize,
+    pub maxmemory_policy: Policy,
+    /// Evict whole subtrees this many segments deep; 0 evicts single keys
+    pub maxmemory_subtree_depth: usize,
+    /// Password for the `default` ACL user
+    pub requirepass: Option<String>,
+    /// File with `user <name> <rules...>` lines
+    pub aclfile: Option<PathBuf>,
+    /// Address for a TLS listener, served alongside `bind`
+    pub tls_bind: Option<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// CA bundle; when set, TLS clients must present a certificate signed by it
+    pub tls_ca_cert: Option<PathBuf>,
+    /// Path for a Unix domain socket listener
+    pub unixsocket: Option<PathBuf>,
+    /// Mode bits for the socket file, e.g. 0o700
+    pub unixsocket_perm: u32,
+    /// Refuse writes from clients; switchable with CONFIG SET read-only
+    pub read_only: bool,
+    /// `host:port` of a primary to replicate from at startup
+    pub replicaof: Option<String>,
+    /// Sent as `AUTH` to the primary before syncing
+    pub primary_auth: Option<String>,
+    /// Whether a replica rejects writes from its own clients
+    pub replica_read_only: bool,
+    /// Bytes of recent command stream kept for replicas to catch up from
+    pub repl_backlog_size: usize,
+    /// Largest snapshot or function dump a replica accepts from its primary
+    /// during a full sync
+    pub repl_max_sync_payload: usize,
+    /// Partition keys across nodes by slot, redirecting with MOVED/ASK
+    pub cluster_enabled: bool,
+    /// `host:port` this node is known by in the cluster; defaults to `bind`
+    pub cluster_announce: Option<String>,
+    /// How long a script runs before other clients get BUSY and SCRIPT KILL
+    /// may stop it
+    pub lua_time_limit: Duration,
+    /// Bytes one script's interpreter may allocate, 0 for unlimited
+    pub lua_max_memory: usize,
+    /// Fuel for one FCALL, roughly one unit per WASM instruction
+    pub function_fuel: u64,
+    /// Bytes of linear memory one FCALL may use
+    pub function_max_memory: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: Some("0.0.0.0:2002".to_string()),
+            shutdown_timeout: Duration::from_secs(10),
+            snapshot_path: None,
+            max_clients: 5000,
+            max_clients_per_ip: 0,
+            metrics_bind: None,
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            maxmemory_subtree_depth: 0,
+            requirepass: None,
+            aclfile: None,
+            tls_bind: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca_cert: None,
+            unixsocket: None,
+            unixsocket_perm: 0o700,
+            read_only: false,
+            replicaof: None,
+            primary_auth: None,
+            replica_read_only: true,
+            repl_backlog_size: 1 << 20,
+            repl_max_sync_payload: 1 << 30,
+            cluster_enabled: false,
+            cluster_announce: None,
+            lua_time_limit: Duration::from_secs(5),
+            lua_max_memory: 64 << 20,
+            function_fuel: 10_000_000,
+            function_max_memory: 16 << 20,
+        }
+    }
+}
+
+impl Config {
+    /// Parse `std::env::args()`, e.g. `flashtree --bind 127.0.0.1:2002 --snapshot dump.json`
+    pub fn from_args() -> Result<Self, String> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = Config::default();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {flag}"))
+            };
+            match flag.as_str() {
+                "--bind" => {
+                    let addr = value()?;
+                    config.bind = (addr != "none").then_some(addr);
+                }
+                "--shutdown-timeout" => {
+                    config.shutdown_timeout = Duration::from_secs(parse_number(&flag, value()?)?)
+                }
+                "--snapshot" => config.snapshot_path = Some(PathBuf::from(value()?)),
+                "--max-clients" => config.max_clients = parse_number(&flag, value()?)?,
+                "--max-clients-per-ip" => {
+                    config.max_clients_per_ip = parse_number(&flag, value()?)?
+                }
+                "--metrics-bind" => config.metrics_bind = Some(value()?),
+                "--maxmemory" => config.maxmemory = parse_bytes(&flag, value()?)?,
+                "--maxmemory-policy" => {
+                    let name = value()?;
+                    config.maxmemory_policy = Policy::parse(&name)
+                        .ok_or_else(|| format!("Unknown eviction policy: {name}"))?;
+                }
+                "--maxmemory-subtree-depth" => {
+                    config.maxmemory_subtree_depth = parse_number(&flag, value()?)?
+                }
+                "--requirepass" => config.requirepass = Some(value()?),
+                "--aclfile" => config.aclfile = Some(PathBuf::from(value()?)),
+                "--tls-bind" => config.tls_bind = Some(value()?),
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(value()?)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(value()?)),
+                "--tls-ca-cert" => config.tls_ca_cert = Some(PathBuf::from(value()?)),
+                "--unixsocket" => config.unixsocket = Some(PathBuf::from(value()?)),
+                "--unixsocketperm" => {
+                    let mode = value()?;
+                    config.unixsocket_perm = u32::from_str_radix(&mode, 8)
+                        .map_err(|_| format!("{flag} expects octal permissions, got {mode:?}"))?;
+                }
+                "--read-only" => config.read_only = parse_yes_no(&flag, value()?)?,
+                "--replicaof" => config.replicaof = Some(value()?),
+                "--primaryauth" => config.primary_auth = Some(value()?),
+                "--replica-read-only" => config.replica_read_only = parse_yes_no(&flag, value()?)?,
+                "--repl-backlog-size" => config.repl_backlog_size = parse_bytes(&flag, value()?)?,
+                "--repl-max-sync-payload" => {
+                    config.repl_max_sync_payload = parse_bytes(&flag, value()?)?
+                }
+                "--cluster-enabled" => config.cluster_enabled = parse_yes_no(&flag, value()?)?,
+                "--cluster-announce" => config.cluster_announce = Some(value()?),
+                "--lua-time-limit" => {
+                    config.lua_time_limit = Duration::from_millis(parse_number(&flag, value()?)?)
+                }
+                "--lua-max-memory" => config.lua_max_memory = parse_bytes(&flag, value()?)?,
+                "--function-fuel" => config.function_fuel = parse_number(&flag, value()?)?,
+                "--function-max-memory" => {
+                    config.function_max_memory = parse_bytes(&flag, value()?)?
+                }
+                _ => return Err(format!("Unknown option: {flag}")),
+            }
+        }
+        if config.tls_bind.is_some() && (config.tls_cert.is_none() || config.tls_key.is_none()) {
+            return Err("--tls-bind needs --tls-cert and --tls-key".to_string());
+        }
+        if config.bind.is_none() && config.tls_bind.is_none() && config.unixsocket.is_none() {
+            return Err("No listener left: set --bind, --tls-bind or --unixsocket".to_string());
+        }
+        Ok(config)
+    }
+
+    /// The memory limit and eviction policy to give the database.
+    pub fn eviction(&self) -> Eviction {
+        Eviction {
+            maxmemory: self.maxmemory,
+            policy: self.maxmemory_policy,
+            subtree_depth: self.maxmemory_subtree_depth,
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{flag} expects a number, got {value:?}"))
+}
+
+fn parse_yes_no(flag: &str, value: String) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("{flag} expects yes or no, got {value:?}")),
+    }
+}
+
+/// Accepts plain bytes or a kb/mb/gb suffix, e.g. "512mb".
+fn parse_bytes(flag: &str, value: String) -> Result<usize, String> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => lower.split_at(i),
+        None => (lower.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return Err(format!("{flag} expects a size like 512mb, got {value:?}")),
+    };
+    let n: usize = parse_number(flag, digits.to_string())?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| format!("{flag} is too large: {value:?}"))
+}
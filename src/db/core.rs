@@ -1,390 +1,41 @@
-// use dashmap::DashMap;
-// use std::collections::HashSet;
-// use std::sync::{Arc, RwLock};
-
-// pub fn node_count(node: &Arc<RwLock<Node>>) -> usize {
-//     let guard = node.read().unwrap();
-//     let mut count = 1; // Count self
-
-//     if let Some(ref children) = guard.children {
-//         for entry in children.iter() {
-//             count += node_count(entry.value());
-//         }
-//     }
-
-//     count
-// }
-
-
-// pub fn value_size(val: &Value) -> usize {
-//     use std::mem::size_of_val;
-//     match val {
-//         Value::Text(s) => size_of_val(s) + s.capacity(),
-//         Value::List(vec) => {
-//             // Vec allocates heap for strings:
-//             let mut total = size_of_val(vec) + vec.capacity() * std::mem::size_of::<String>();
-//             // Also count heap for each string element
-//             for s in vec {
-//                 total += std::mem::size_of_val(s) + s.capacity();
-//             }
-//             total
-//         }
-//         Value::Set(set) => {
-//             // HashSet allocates a bucket array for pointers (not accessible directly).
-//             // We'll fudge a bit: struct, plus buckets (16 bytes per elem), plus inner strings.
-//             let mut total = size_of_val(set) + set.capacity() * 16;
-//             for s in set {
-//                 total += std::mem::size_of_val(s) + s.capacity();
-//             }
-//             total
-//         }
-//     }
-// }
-
-// pub fn node_size_bytes(node: &Arc<RwLock<Node>>) -> usize {
-//     use std::mem::size_of_val;
-//     let guard = node.read().unwrap();
-//     let mut size = 0;
-
-//     // Arc<RwLock<Node>> overhead (only if you want this included at every call)
-//     size += std::mem::size_of::<Arc<RwLock<Node>>>();
-//     // RwLock itself
-//     size += std::mem::size_of::<RwLock<Node>>();
-//     // Node struct
-//     size += std::mem::size_of::<Node>();
-
-//     // Value in this node (Option is part of the Node struct already)
-//     if let Some(ref value) = guard.value {
-//         size += value_size(value);
-//     }
-
-//     // Children
-//     if let Some(ref children) = guard.children {
-//         // DashMap struct header
-//         size += size_of_val(children);
-
-//         // DashMap buckets and internal structure (estimate!)
-//         size += 512; // fudge for shards/locks/etc.
-//         size += children.len() * 32; // per-entry overhead
-
-//         // Keys and recursive child nodes
-//         for entry in children.iter() {
-//             let key = entry.key();
-//             size += size_of_val(key) + key.capacity();
-//             size += node_size_bytes(entry.value());
-//         }
-//     }
-
-//     size
-// }
-
-
-
-// #[derive(Debug, Clone)]
-// pub enum Value {
-//     Text(String),
-//     List(Vec<String>),
-//     Set(HashSet<String>),
-// }
-
-// #[derive(Debug)]
-// pub struct Node {
-//     pub value: Option<Value>,
-//     pub ttl: Option<u64>,
-//     pub children: Option<DashMap<String, Arc<RwLock<Node>>>>,
-// }
-
-// impl Node {
-//     pub fn new() -> Self {
-//         Node {
-//             value: None,
-//             ttl: None,
-//             children: None,
-//         }
-//     }
-// }
-
-// #[inline]
-// pub fn delete(root: &Arc<RwLock<Node>>, key: &str) -> Result<bool, String> {
-//     Ok(true)
-// }
-
-// pub fn set(root: &Arc<RwLock<Node>>, key: &str, value: Value) -> Result<(), String> {
-
-//     let path: Vec<&str> = if key.is_empty() {
-//         Vec::new()
-//     } else {
-//         key.split(':').collect()
-//     };
-
-//     if path.is_empty() {
-//         let mut guard = root.write().map_err(|_| "Lock poisoned")?;
-//         guard.value = Some(value);
-//         return Ok(());
-//     }
-
-//     let mut current = root.clone();
-
-//     for (i, part) in path.iter().enumerate() {
-//         {
-//             let needs_children = {
-//                 let guard = current.read().map_err(|_| "Lock poisoned")?;
-//                 guard.children.is_none()
-//             };
-
-//             if needs_children {
-//                 let mut guard = current.write().map_err(|_| "Lock poisoned")?;
-//                 if guard.children.is_none() {
-//                     guard.children = Some(DashMap::new());
-//                 }
-//             }
-//         }
-
-//         let next = {
-//             let next;
-//             {
-//                 let guard = current.read().map_err(|_| "Lock poisoned")?;
-//                 let children = guard.children.as_ref().unwrap();
-//                 next = children
-//                     .entry(part.to_string())
-//                     .or_insert_with(|| Arc::new(RwLock::new(Node::new())))
-//                     .clone();
-//             }
-//             next
-//         };
-//         current = next;
-
-//         if i == path.len() - 1 {
-//             let mut guard = current.write().map_err(|_| "Lock poisoned")?;
-//             guard.value = Some(value);
-//             return Ok(());
-//         }
-//     }
-
-//     Ok(())
-// }
-
-// pub fn get(root: &Arc<RwLock<Node>>, key: &str) -> Result<Option<Value>, String> {
-
-//     let path: Vec<&str> = if key.is_empty() {
-//         Vec::new()
-//     } else {
-//         key.split(':').collect()
-//     };
-
-//     let mut current = root.clone();
-
-//     for &part in &path {
-//         println!("{part}");
-//         let guard = current.read().map_err(|_| "Lock poisoned")?;
-//         let children = match guard.children.as_ref() {
-//             Some(children) => children,
-//             None => return Ok(None),
-//         };
-//         let next = match children.get(part) {
-//             Some(child_arc) => child_arc.clone(),
-//             None => return Ok(None),
-//         };
-
-//         drop(guard);
-//         current = next;
-//     }
-
-//     let guard = current.read().map_err(|_| "Lock poisoned")?;
-//     Ok(guard.value.clone())
-// }
-
-
-// hash map implementation - no dash map
-
-
-// use std::collections::{HashMap, HashSet};
-// use std::sync::{Arc, RwLock};
-
-// #[derive(Debug, Clone)]
-// pub enum Value {
-//     Text(String),
-//     List(Vec<String>),
-//     Set(HashSet<String>),
-// }
-
-// #[derive(Debug)]
-// pub struct Node {
-//     pub v: Option<Value>,
-//     pub t: Option<u64>,
-//     pub c: Option<HashMap<String, Arc<RwLock<Node>>>>,
-// }
-
-// #[derive(Debug)]
-// pub struct MemoryStats {
-//     pub total_bytes: usize,
-//     pub node_count: usize,
-//     pub smallest_node: usize,
-//     pub largest_node: usize,
-// }
-
-
-// impl Node {
-//     pub fn new() -> Self {
-//         Node {
-//             v: None,
-//             t: None,
-//             c: None,
-//         }
-//     }
-// }
-
-// pub fn node_count(node: &Arc<RwLock<Node>>) -> usize {
-//     let guard = node.read().unwrap();
-//     let mut count = 1; // Count self
-
-//     if let Some(ref children) = guard.c {
-//         for child in children.values() {
-//             count += node_count(child);
-//         }
-//     }
-//     count
-// }
-
-// /// Returns (total_bytes, node_count, smallest_node, largest_node)
-// pub fn node_memory_stats(node: &Arc<RwLock<Node>>) -> (usize, usize, usize, usize) {
-//     use std::mem::size_of_val;
-//     let guard = node.read().unwrap();
-//     let mut size = 0;
-//     size += std::mem::size_of::<Arc<RwLock<Node>>>();
-//     size += std::mem::size_of::<RwLock<Node>>();
-//     size += std::mem::size_of::<Node>();
-//     if let Some(ref value) = guard.v {
-//         size += value_size(value);
-//     }
-//     if let Some(ref children) = guard.c {
-//         size += size_of_val(children);
-//         size += children.capacity() * (std::mem::size_of::<String>() + std::mem::size_of::<Arc<RwLock<Node>>>());
-//     }
-
-//     // Initially, the smallest and largest are this node's size.
-//     let mut smallest = size;
-//     let mut largest = size;
-//     let mut total = size;
-//     let mut count = 1;
-
-//     if let Some(ref children) = guard.c {
-//         for (_key, child) in children.iter() {
-//             let (child_total, child_count, child_smallest, child_largest) = node_memory_stats(child);
-//             total += child_total;
-//             count += child_count;
-//             if child_smallest < smallest {
-//                 smallest = child_smallest;
-//             }
-//             if child_largest > largest {
-//                 largest = child_largest;
-//             }
-//         }
-//     }
-//     (total, count, smallest, largest)
-// }
-
-
-// pub fn value_size(val: &Value) -> usize {
-//     use std::mem::size_of_val;
-//     match val {
-//         Value::Text(s) => size_of_val(s) + s.capacity(),
-//         Value::List(vec) => {
-//             let mut total = size_of_val(vec) + vec.capacity() * std::mem::size_of::<String>();
-//             for s in vec {
-//                 total += size_of_val(s) + s.capacity();
-//             }
-//             total
-//         }
-//         Value::Set(set) => {
-//             let mut total = size_of_val(set) + set.capacity() * 16;
-//             for s in set {
-//                 total += size_of_val(s) + s.capacity();
-//             }
-//             total
-//         }
-//     }
-// }
-
-// pub fn set(root: &Arc<RwLock<Node>>, key: &str, value: Value) -> Result<(), String> {
-//     let path: Vec<&str> = if key.is_empty() {
-//         Vec::new()
-//     } else {
-//         key.split(':').collect()
-//     };
-//     if path.is_empty() {
-//         let mut guard = root.write().map_err(|_| "Lock poisoned")?;
-//         guard.v = Some(value);
-//         return Ok(());
-//     }
-//     let mut current = root.clone();
-//     for (i, part) in path.iter().enumerate() {
-//         {
-//             let needs_children = {
-//                 let guard = current.read().map_err(|_| "Lock poisoned")?;
-//                 guard.c.is_none()
-//             };
-//             if needs_children {
-//                 let mut guard = current.write().map_err(|_| "Lock poisoned")?;
-//                 if guard.c.is_none() {
-//                     guard.c = Some(HashMap::new());
-//                 }
-//             }
-//         }
-//         let next = {
-//             let mut guard = current.write().map_err(|_| "Lock poisoned")?;
-//             let children = guard.c.as_mut().unwrap();
-//             children
-//                 .entry(part.to_string())
-//                 .or_insert_with(|| Arc::new(RwLock::new(Node::new())))
-//                 .clone()
-//         };
-//         current = next;
-//         if i == path.len() - 1 {
-//             let mut guard = current.write().map_err(|_| "Lock poisoned")?;
-//             guard.v = Some(value);
-//             return Ok(());
-//         }
-//     }
-//     Ok(())
-// }
-
-// pub fn get(root: &Arc<RwLock<Node>>, key: &str) -> Result<Option<Value>, String> {
-//     let path: Vec<&str> = if key.is_empty() {
-//         Vec::new()
-//     } else {
-//         key.split(':').collect()
-//     };
-//     let mut current = root.clone();
-//     for &part in &path {
-//         let guard = current.read().map_err(|_| "Lock poisoned")?;
-//         let children = match guard.c.as_ref() {
-//             Some(children) => children,
-//             None => return Ok(None),
-//         };
-//         let next = match children.get(part) {
-//             Some(child_arc) => child_arc.clone(),
-//             None => return Ok(None),
-//         };
-//         drop(guard);
-//         current = next;
-//     }
-//     let guard = current.read().map_err(|_| "Lock poisoned")?;
-//     Ok(guard.v.clone())
-// }
-
-// #[inline]
-// pub fn delete(_root: &Arc<RwLock<Node>>, _key: &str) -> Result<bool, String> {
-//     Ok(true)
-// }
-
-use std::collections::{HashMap, HashSet};
+use super::error::{Error, Result};
+use super::stream::Stream;
+use crate::allocator::alloc_size;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
 pub enum Value {
     Text(String),
-    List(Vec<String>),
+    List(VecDeque<String>),
     Set(HashSet<String>),
+    Stream(Box<Stream>),
+}
+
+/// Which end of a list to push to or pop from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "LEFT" => Some(End::Left),
+            "RIGHT" => Some(End::Right),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            End::Left => "LEFT",
+            End::Right => "RIGHT",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -392,6 +43,30 @@ pub struct Node {
     pub v: Option<Value>,
     pub t: Option<u64>,
     pub c: Option<HashMap<String, Box<Node>>>,
+    /// Bytes used by this subtree (node, value, child table, child keys and
+    /// children), kept current by `set`/`delete` so usage reads are O(depth).
+    pub m: usize,
+    /// Nodes in this subtree, itself included.
+    pub n: usize,
+    /// Nodes in this subtree that hold a value. Like `m`, these are kept
+    /// current on every write so counting keys is O(1).
+    pub k: usize,
+    /// Nodes in this subtree with an expiry, so the active expiry cycle only
+    /// walks branches where something can expire.
+    pub x: usize,
+    /// Last access in unix seconds. Writes touch every node along the path,
+    /// so for an inner node it is the last write anywhere beneath it; reads
+    /// touch only the node eviction compares (see `get`).
+    pub a: AtomicU32,
+    /// Logarithmic access counter for LFU eviction, decays while idle.
+    pub f: AtomicU8,
+    /// Version of the last write at or beneath this node. Every write stamps
+    /// its whole path with one more than the root's current version, so the
+    /// root always holds the newest one. Used by WATCH.
+    pub w: u64,
+    /// Version of this node's own value: the stamp of the write that last set
+    /// it. Kept in snapshots along with the counter. Used by GETV and CAS.
+    pub r: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -402,42 +77,101 @@ pub struct MemoryStats {
     pub largest_node: usize,
 }
 
+impl Default for Node {
+    fn default() -> Self {
+        Node::new()
+    }
+}
+
 impl Node {
     pub fn new() -> Self {
         Node {
             v: None,
             t: None,
             c: None,
+            m: node_alloc_size(),
+            n: 1,
+            k: 0,
+            x: 0,
+            a: AtomicU32::new(now_secs()),
+            f: AtomicU8::new(LFU_INIT),
+            w: 0,
+            r: 0,
         }
     }
-}
 
-pub fn node_count(node: &std::sync::RwLock<Node>) -> usize {
-    fn count(node: &Node) -> usize {
-        let mut c = 1;
-        if let Some(children) = node.c.as_ref() {
-            for child in children.values() {
-                c += count(child);
+    /// Record an access for LRU/LFU bookkeeping. Only atomics are touched, so
+    /// this is safe under the read lock.
+    #[inline]
+    pub fn touch(&self, now: u32) {
+        let freq = self.frequency(now);
+        if freq < u8::MAX {
+            let base = freq.saturating_sub(LFU_INIT) as f64;
+            if rand::random::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                self.f.store(freq + 1, Ordering::Relaxed);
+            } else {
+                self.f.store(freq, Ordering::Relaxed);
             }
         }
-        c
+        self.a.store(now, Ordering::Relaxed);
+    }
+
+    /// LFU counter with one point of decay per idle minute applied.
+    #[inline]
+    pub fn frequency(&self, now: u32) -> u8 {
+        let idle = now.saturating_sub(self.a.load(Ordering::Relaxed)) / LFU_DECAY_SECS;
+        let freq = self.f.load(Ordering::Relaxed);
+        freq.saturating_sub(idle.min(u8::MAX as u32) as u8)
+    }
+
+    #[inline]
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.t.is_some_and(|at| at <= now_ms)
+    }
+
+    /// Holds a live value (set and not past its expiry).
+    #[inline]
+    pub fn has_value(&self, now_ms: u64) -> bool {
+        self.v.is_some() && !self.is_expired(now_ms)
+    }
+
+    /// Recompute `m`, `n`, `k` and `x` from this node's fields and its
+    /// children's totals. Used when a subtree is built in bulk, e.g. from a
+    /// snapshot.
+    pub fn refresh_usage(&mut self) {
+        let mut m = node_alloc_size();
+        let mut n = 1;
+        let mut k = self.v.is_some() as usize;
+        let mut x = self.t.is_some() as usize;
+        if let Some(ref v) = self.v {
+            m += value_size(v);
+        }
+        if let Some(ref children) = self.c {
+            m += table_size(children);
+            for (key, child) in children {
+                m += key_size(key) + child.m;
+                n += child.n;
+                k += child.k;
+                x += child.x;
+            }
+        }
+        self.m = m;
+        self.n = n;
+        self.k = k;
+        self.x = x;
     }
-    let guard = node.read().unwrap();
-    count(&*guard)
 }
 
 // Returns (total_bytes, node_count, smallest, largest)
 pub fn node_memory_stats(node: &std::sync::RwLock<Node>) -> (usize, usize, usize, usize) {
-    use std::mem::size_of_val;
     fn stats(node: &Node) -> (usize, usize, usize, usize) {
-        let mut size = size_of_val(node);
+        let mut size = node_alloc_size();
         if let Some(ref v) = node.v {
             size += value_size(v);
         }
         if let Some(ref children) = node.c {
-            size += size_of_val(children);
-            size += children.capacity()
-                * (std::mem::size_of::<String>() + std::mem::size_of::<Box<Node>>());
+            size += table_size(children);
+            size += children.keys().map(|k| key_size(k)).sum::<usize>();
         }
         let mut smallest = size;
         let mut largest = size;
@@ -459,111 +193,767 @@ pub fn node_memory_stats(node: &std::sync::RwLock<Node>) -> (usize, usize, usize
         (total, count, smallest, largest)
     }
     let guard = node.read().unwrap();
-    stats(&*guard)
+    stats(&guard)
+}
+
+//
+// ─── Clocks ──────────────────────────────────────────────────────────────────────
+//
+
+const LFU_INIT: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_SECS: u32 = 60;
+
+#[inline]
+pub fn now_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
 }
 
+#[inline]
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+//
+// ─── Size Accounting ─────────────────────────────────────────────────────────────
+//
+// Sizes are what jemalloc actually hands out (size classes included), so the
+// totals line up with the allocator's own figures. Without the `jemalloc`
+// feature they are estimates.
+//
+
+#[inline]
+fn node_alloc_size() -> usize {
+    alloc_size(size_of::<Node>())
+}
+
+#[inline]
+fn key_size(key: &str) -> usize {
+    alloc_size(key.len())
+}
+
+/// Heap block behind a hashbrown table: one slot plus one control byte per
+/// bucket, plus a trailing group of control bytes.
+fn raw_table_size<T>(capacity: usize) -> usize {
+    const GROUP_WIDTH: usize = 16;
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 8 {
+        capacity + 1
+    } else {
+        capacity / 7 * 8
+    };
+    alloc_size(buckets * size_of::<T>() + buckets + GROUP_WIDTH)
+}
+
+#[inline]
+fn table_size(children: &HashMap<String, Box<Node>>) -> usize {
+    raw_table_size::<(String, Box<Node>)>(children.capacity())
+}
+
+/// Heap bytes owned by a value (the enum itself lives inline in `Node`).
 pub fn value_size(val: &Value) -> usize {
-    use std::mem::size_of_val;
     match val {
-        Value::Text(s) => size_of_val(s) + s.capacity(),
-        Value::List(vec) => {
-            let mut total = size_of_val(vec) + vec.capacity() * std::mem::size_of::<String>();
-            for s in vec {
-                total += size_of_val(s) + s.capacity();
+        Value::Text(s) => alloc_size(s.capacity()),
+        Value::List(list) => {
+            let mut total = alloc_size(list.capacity() * size_of::<String>());
+            for s in list {
+                total += alloc_size(s.capacity());
             }
             total
         }
         Value::Set(set) => {
-            let mut total = size_of_val(set) + set.capacity() * 16;
+            let mut total = raw_table_size::<String>(set.capacity());
             for s in set {
-                total += size_of_val(s) + s.capacity();
+                total += alloc_size(s.capacity());
             }
             total
         }
+        Value::Stream(stream) => alloc_size(size_of::<Stream>()) + stream.bytes(),
     }
 }
 
-pub fn set(root: &std::sync::RwLock<Node>, key: &str, value: Value) -> Result<(), String> {
-    let path: Vec<&str> = if key.is_empty() {
-        Vec::new()
-    } else {
-        key.split(':').collect()
-    };
-    let mut guard = root.write().map_err(|_| "Lock poisoned")?;
-    let mut current = &mut *guard;
-    if path.is_empty() {
-        current.v = Some(value);
-        return Ok(());
+/// A change to a subtree's running totals, handed back up the path so
+/// every node on the way can adjust its `m`, `n`, `k` and `x`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct Delta {
+    bytes: isize,
+    nodes: isize,
+    keys: isize,
+    volatile: isize,
+}
+
+impl Delta {
+    fn bytes(bytes: usize) -> Self {
+        Delta {
+            bytes: bytes as isize,
+            ..Delta::default()
+        }
     }
-    let mut val_opt = Some(value);
-    for (i, part) in path.iter().enumerate() {
-        if current.c.is_none() {
-            current.c = Some(HashMap::new());
+
+    /// Everything in `node`'s subtree, as if it had just been added.
+    fn of(node: &Node) -> Self {
+        Delta {
+            bytes: node.m as isize,
+            nodes: node.n as isize,
+            keys: node.k as isize,
+            volatile: node.x as isize,
         }
-        let children = current.c.as_mut().unwrap();
-        current = children.entry(part.to_string())
-            .or_insert_with(|| Box::new(Node::new()));
-        if i == path.len() - 1 {
-            current.v = val_opt.take();  // Moves the value only once
+    }
+
+    /// What `node`'s own value and expiry add, leaving out the node itself
+    /// and its children. Taken before and after a change to the value, the
+    /// difference is that change.
+    fn own(node: &Node) -> Self {
+        Delta {
+            bytes: node.v.as_ref().map_or(0, value_size) as isize,
+            nodes: 0,
+            keys: node.v.is_some() as isize,
+            volatile: node.t.is_some() as isize,
         }
     }
-    Ok(())
+
+    /// A child table going from `before` to `after` bytes.
+    fn table(before: usize, after: usize) -> Self {
+        Delta {
+            bytes: after as isize - before as isize,
+            ..Delta::default()
+        }
+    }
+}
+
+impl std::ops::Add for Delta {
+    type Output = Delta;
+
+    fn add(self, other: Delta) -> Delta {
+        Delta {
+            bytes: self.bytes + other.bytes,
+            nodes: self.nodes + other.nodes,
+            keys: self.keys + other.keys,
+            volatile: self.volatile + other.volatile,
+        }
+    }
+}
+
+impl std::ops::AddAssign for Delta {
+    fn add_assign(&mut self, other: Delta) {
+        *self = *self + other;
+    }
+}
+
+impl std::ops::Sub for Delta {
+    type Output = Delta;
+
+    fn sub(self, other: Delta) -> Delta {
+        self + -other
+    }
+}
+
+impl std::ops::Neg for Delta {
+    type Output = Delta;
+
+    fn neg(self) -> Delta {
+        Delta {
+            bytes: -self.bytes,
+            nodes: -self.nodes,
+            keys: -self.keys,
+            volatile: -self.volatile,
+        }
+    }
+}
+
+#[inline]
+fn apply_delta(node: &mut Node, delta: Delta) {
+    node.m = (node.m as isize + delta.bytes) as usize;
+    node.n = (node.n as isize + delta.nodes) as usize;
+    node.k = (node.k as isize + delta.keys) as usize;
+    node.x = (node.x as isize + delta.volatile) as usize;
+}
+
+/// Apply `delta` to every node from `root` down to the end of `path`, for a
+/// change made in place at the end of it.
+fn apply_path(root: &mut Node, path: &[&str], delta: Delta) {
+    let mut current = root;
+    apply_delta(current, delta);
+    for part in path {
+        current = match current.c.as_mut().and_then(|c| c.get_mut(*part)) {
+            Some(child) => child,
+            None => return,
+        };
+        apply_delta(current, delta);
+    }
 }
 
-pub fn get(root: &std::sync::RwLock<Node>, key: &str) -> Result<Option<Value>, String> {
-    let path: Vec<&str> = if key.is_empty() {
+//
+// ─── Operations ──────────────────────────────────────────────────────────────────
+//
+
+/// Split a key into its path segments; the empty key is the root.
+#[inline]
+fn split_key(key: &str) -> Vec<&str> {
+    if key.is_empty() {
         Vec::new()
     } else {
         key.split(':').collect()
-    };
-    let guard = root.read().map_err(|_| "Lock poisoned")?;
-    let mut current = &*guard;
+    }
+}
+
+/// Whether `key` falls under `pattern`: `*` matches everything, a trailing `*`
+/// matches by prefix, anything else must be equal. A subtree pattern such as
+/// `a:b:*` also covers `a:b` itself, since that key is the subtree.
+pub fn matches_pattern(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => {
+            key.starts_with(prefix) || prefix.strip_suffix(':').is_some_and(|root| key == root)
+        }
+        None => key == pattern,
+    }
+}
+
+/// The node at `path`, if there is one.
+pub(super) fn find<'a>(root: &'a Node, path: &[&str]) -> Option<&'a Node> {
+    let mut current = root;
     for part in path {
-        let children = match current.c.as_ref() {
-            Some(children) => children,
-            None => return Ok(None),
+        current = current.c.as_ref()?.get(*part)?;
+    }
+    Some(current)
+}
+
+/// The node at `path`, if there is one, for changing in place.
+fn find_mut<'a>(root: &'a mut Node, path: &[&str]) -> Option<&'a mut Node> {
+    let mut current = root;
+    for part in path {
+        current = current.c.as_mut()?.get_mut(*part)?;
+    }
+    Some(current)
+}
+
+/// Mark a write at `path`: every existing node from the root down gets the
+/// next version.
+pub(super) fn stamp_path(root: &mut Node, path: &[&str]) {
+    let stamp = root.w + 1;
+    let mut current = root;
+    current.w = stamp;
+    for part in path {
+        current = match current.c.as_mut().and_then(|c| c.get_mut(*part)) {
+            Some(child) => child,
+            None => return,
+        };
+        current.w = stamp;
+    }
+}
+
+/// `stamp_path`, and also record the stamp as the version of the value at
+/// the end of `path`.
+fn stamp_value(root: &mut Node, path: &[&str]) {
+    stamp_path(root, path);
+    let stamp = root.w;
+    let mut current = root;
+    for part in path {
+        current = match current.c.as_mut().and_then(|c| c.get_mut(*part)) {
+            Some(child) => child,
+            None => return,
         };
-        match children.get(part) {
-            Some(child) => {
-                current = child;
+    }
+    current.r = stamp;
+}
+
+pub fn set(root: &mut Node, key: &str, value: Value) {
+    let path = split_key(key);
+    set_in(root, &path, value, now_secs());
+    stamp_value(root, &path);
+}
+
+/// Store `value` at `path` below `node`. Returns the change in totals so
+/// every node on the way back up can adjust its own.
+fn set_in(node: &mut Node, path: &[&str], value: Value, now: u32) -> Delta {
+    node.touch(now);
+    let delta = match path.split_first() {
+        None => {
+            let before = Delta::own(node);
+            node.v = Some(value);
+            node.t = None;
+            Delta::own(node) - before
+        }
+        Some((part, rest)) => {
+            let children = node.c.get_or_insert_with(HashMap::new);
+            let table_before = table_size(children);
+            let mut delta = Delta::default();
+            let child = children.entry(part.to_string()).or_insert_with(|| {
+                let child = Box::new(Node::new());
+                delta += Delta::bytes(key_size(part)) + Delta::of(&child);
+                child
+            });
+            delta += set_in(child, rest, value, now);
+            delta + Delta::table(table_before, table_size(children))
+        }
+    };
+    apply_delta(node, delta);
+    delta
+}
+
+/// Read `key`. `track` is the depth of the node whose access is recorded
+/// for LRU/LFU eviction: `Some(0)` for the key itself, the subtree's depth
+/// under subtree eviction, or `None` to leave access metadata alone.
+pub fn get(root: &Node, key: &str, track: Option<usize>) -> Option<Value> {
+    let current = find_tracked(root, &split_key(key), track)?;
+    // Expired keys read as missing until the expiry cycle or a write to
+    // them removes them.
+    if current.is_expired(now_ms()) {
+        return None;
+    }
+    current.v.clone()
+}
+
+/// The node at `path`, recording access on the one `track` names.
+fn find_tracked<'a>(root: &'a Node, path: &[&str], track: Option<usize>) -> Option<&'a Node> {
+    let track = match track {
+        Some(0) => Some(path.len()),
+        depth => depth,
+    };
+    let mut current = root;
+    for (depth, part) in path.iter().enumerate() {
+        current = current.c.as_ref()?.get(*part)?;
+        if track == Some(depth + 1) {
+            current.touch(now_secs());
+        }
+    }
+    Some(current)
+}
+
+/// Run `f` on the value slot at `path`, creating the path first when `create`
+/// is set, and account for any change in size. An expired value is cleared
+/// beforehand, so `f` only ever sees a live one. `None` if the path is missing.
+fn modify_in<R>(
+    node: &mut Node,
+    path: &[&str],
+    create: bool,
+    now: u32,
+    f: impl FnOnce(&mut Option<Value>) -> R,
+) -> Option<R> {
+    node.touch(now);
+    let (result, delta) = match path.split_first() {
+        None => {
+            let before = Delta::own(node);
+            if node.is_expired(now_ms()) {
+                node.v = None;
+                node.t = None;
             }
+            let result = f(&mut node.v);
+            if node.v.is_none() {
+                node.t = None;
+            }
+            (result, Delta::own(node) - before)
+        }
+        Some((part, rest)) if create => {
+            let children = node.c.get_or_insert_with(HashMap::new);
+            let table_before = table_size(children);
+            let mut delta = Delta::default();
+            let child = children.entry(part.to_string()).or_insert_with(|| {
+                let child = Box::new(Node::new());
+                delta += Delta::bytes(key_size(part)) + Delta::of(&child);
+                child
+            });
+            let before = Delta::of(child);
+            let result = modify_in(child, rest, create, now, f)?;
+            delta += Delta::of(child) - before;
+            (
+                result,
+                delta + Delta::table(table_before, table_size(children)),
+            )
+        }
+        Some((part, rest)) => {
+            let child = node.c.as_mut()?.get_mut(*part)?;
+            let before = Delta::of(child);
+            let result = modify_in(child, rest, create, now, f)?;
+            (result, Delta::of(child) - before)
+        }
+    };
+    apply_delta(node, delta);
+    Some(result)
+}
+
+/// Push `items` onto the list at `key`, creating it if needed. Returns the
+/// new length; `Err(WrongType)` if the key holds something else.
+pub fn push(root: &mut Node, key: &str, end: End, items: &[&str]) -> Result<usize> {
+    let path = split_key(key);
+    let pushed = modify_in(root, &path, true, now_secs(), |slot| {
+        let list = match slot.get_or_insert_with(|| Value::List(VecDeque::new())) {
+            Value::List(list) => list,
+            _ => return Err(Error::WrongType),
+        };
+        for item in items {
+            match end {
+                End::Left => list.push_front(item.to_string()),
+                End::Right => list.push_back(item.to_string()),
+            }
+        }
+        Ok(list.len())
+    });
+    let len = pushed.expect("push creates the path")?;
+    stamp_value(root, &path);
+    Ok(len)
+}
+
+/// Pop one element from the list at `key`. The key goes away with its last
+/// element; `Err(WrongType)` if it holds something other than a list.
+pub fn pop(root: &mut Node, key: &str, end: End) -> Result<Option<String>> {
+    let path = split_key(key);
+    let popped = modify_in(root, &path, false, now_secs(), |slot| {
+        let list = match slot {
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(Error::WrongType),
             None => return Ok(None),
+        };
+        let item = match end {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        };
+        if list.is_empty() {
+            *slot = None;
         }
+        Ok(item)
+    });
+    let Some(item) = popped.transpose()?.flatten() else {
+        return Ok(None);
+    };
+    if find(root, &path).is_some_and(|node| node.v.is_none()) {
+        evict_key_in(root, &path);
+    }
+    stamp_value(root, &path);
+    Ok(Some(item))
+}
+
+/// The live list at `key`, `None` if there is none; `Err(WrongType)` if the
+/// key holds something else. `track` as for `get`.
+pub fn list<'a>(
+    root: &'a Node,
+    key: &str,
+    track: Option<usize>,
+) -> Result<Option<&'a VecDeque<String>>> {
+    let Some(node) = find_tracked(root, &split_key(key), track) else {
+        return Ok(None);
+    };
+    match node.v {
+        _ if !node.has_value(now_ms()) => Ok(None),
+        Some(Value::List(ref list)) => Ok(Some(list)),
+        _ => Err(Error::WrongType),
     }
-    Ok(current.v.clone())
+}
+
+/// Run `f` on the stream at `key`, creating an empty one first when `create`
+/// is set. `Ok(None)` if there is no stream; `Err(WrongType)` if the key holds
+/// something else. A stream created for an `f` that then fails is removed again.
+pub fn stream_mut<R>(
+    root: &mut Node,
+    key: &str,
+    create: bool,
+    f: impl FnOnce(&mut Stream) -> Result<R>,
+) -> Result<Option<R>> {
+    let path = split_key(key);
+    let result = modify_in(root, &path, create, now_secs(), |slot| {
+        let created = slot.is_none();
+        if created && !create {
+            return Ok(None);
+        }
+        let stream = match slot.get_or_insert_with(|| Value::Stream(Box::default())) {
+            Value::Stream(stream) => stream,
+            _ => return Err(Error::WrongType),
+        };
+        let result = f(stream);
+        if result.is_err() && created {
+            *slot = None;
+        }
+        result.map(Some)
+    });
+    let Some(result) = result else {
+        return Ok(None);
+    };
+    if find(root, &path).is_some_and(|node| node.v.is_none()) {
+        evict_key_in(root, &path);
+    }
+    let result = result?;
+    if result.is_some() {
+        stamp_value(root, &path);
+    }
+    Ok(result)
+}
+
+/// The live stream at `key`, `None` if there is none; `Err(WrongType)` if the
+/// key holds something else. `track` as for `get`.
+pub fn stream<'a>(root: &'a Node, key: &str, track: Option<usize>) -> Result<Option<&'a Stream>> {
+    let Some(node) = find_tracked(root, &split_key(key), track) else {
+        return Ok(None);
+    };
+    match node.v {
+        _ if !node.has_value(now_ms()) => Ok(None),
+        Some(Value::Stream(ref stream)) => Ok(Some(stream)),
+        _ => Err(Error::WrongType),
+    }
+}
+
+/// Live value at `key` together with its version; `track` as for `get`.
+pub fn get_versioned(root: &Node, key: &str, track: Option<usize>) -> Option<(Value, u64)> {
+    let value = get(root, key, track)?;
+    let node = find(root, &split_key(key))?;
+    Some((value, node.r))
+}
+
+/// Remove and return the value at `key`, leaving any children in place.
+pub fn take(root: &mut Node, key: &str) -> Option<Value> {
+    let path = split_key(key);
+    let value = get(root, key, None)?;
+    if path.is_empty() {
+        let freed = drop_value(root);
+        apply_delta(root, freed);
+    } else {
+        evict_key_in(root, &path);
+    }
+    stamp_path(root, &path);
+    Some(value)
 }
 
 #[inline]
-pub fn delete(root: &std::sync::RwLock<Node>, key: &str) -> Result<bool, String> {
-    let path: Vec<&str> = if key.is_empty() {
-        Vec::new()
+pub fn delete(root: &mut Node, key: &str) -> bool {
+    let path = split_key(key);
+    if path.is_empty() {
+        clear(root);
+        return true;
+    }
+    let deleted = delete_in(root, &path).is_some();
+    if deleted {
+        stamp_path(root, &path);
+    }
+    deleted
+}
+
+/// Put `subtree` at `key`, replacing whatever was there, e.g. a subtree
+/// restored from a dump. The empty key replaces the whole trie. Everything
+/// in it counts as written now, whatever versions it came with.
+pub fn graft(root: &mut Node, key: &str, subtree: Node) {
+    let path = split_key(key);
+    if path.is_empty() {
+        let stamp = root.w + 1;
+        *root = subtree;
+        restamp(root, stamp, true);
+        return;
+    }
+    delete_in(root, &path);
+    graft_in(root, &path, subtree);
+    stamp_path(root, &path);
+    let stamp = root.w;
+    if let Some(node) = find_mut(root, &path) {
+        restamp(node, stamp, true);
+    }
+}
+
+/// Give every node of `node`'s subtree the write version `stamp`, and with
+/// `values` set every value in it too.
+pub(super) fn restamp(node: &mut Node, stamp: u64, values: bool) {
+    node.w = stamp;
+    if values && node.v.is_some() {
+        node.r = stamp;
+    }
+    for child in node.c.iter_mut().flat_map(|c| c.values_mut()) {
+        restamp(child, stamp, values);
+    }
+}
+
+fn graft_in(node: &mut Node, path: &[&str], subtree: Node) -> Delta {
+    let Some((part, rest)) = path.split_first() else {
+        return Delta::default();
+    };
+    let children = node.c.get_or_insert_with(HashMap::new);
+    let table_before = table_size(children);
+    let mut delta = Delta::default();
+    if rest.is_empty() {
+        delta += Delta::bytes(key_size(part)) + Delta::of(&subtree);
+        children.insert(part.to_string(), Box::new(subtree));
     } else {
-        key.split(':').collect()
+        let child = children.entry(part.to_string()).or_insert_with(|| {
+            let child = Box::new(Node::new());
+            delta += Delta::bytes(key_size(part)) + Delta::of(&child);
+            child
+        });
+        delta += graft_in(child, rest, subtree);
+    }
+    delta += Delta::table(table_before, table_size(children));
+    apply_delta(node, delta);
+    delta
+}
+
+/// The node at `key`, if there is one.
+pub fn subtree<'a>(root: &'a Node, key: &str) -> Option<&'a Node> {
+    find(root, &split_key(key))
+}
+
+/// Empty the whole trie, keeping the version counter moving forward.
+pub fn clear(root: &mut Node) {
+    let stamp = root.w + 1;
+    *root = Node::new();
+    root.w = stamp;
+}
+
+/// Remove the subtree at `path` below `node`. Returns the (negative) change in
+/// totals, or `None` if nothing was there.
+pub(super) fn delete_in(node: &mut Node, path: &[&str]) -> Option<Delta> {
+    let (part, rest) = path.split_first()?;
+    let children = node.c.as_mut()?;
+    let delta = if rest.is_empty() {
+        // Removal can leave a tombstone that lowers the reported capacity, so
+        // the table is re-measured here as well as on insert.
+        let table_before = table_size(children);
+        let (key, child) = children.remove_entry(*part)?;
+        Delta::table(table_before, table_size(children))
+            - (Delta::bytes(key_size(&key)) + Delta::of(&child))
+    } else {
+        delete_in(children.get_mut(*part)?, rest)?
+    };
+    apply_delta(node, delta);
+    Some(delta)
+}
+
+/// Clear `node`'s own value and expiry. Returns the change, for the caller
+/// to apply to the node and its ancestors.
+fn drop_value(node: &mut Node) -> Delta {
+    let before = Delta::own(node);
+    node.v = None;
+    node.t = None;
+    Delta::own(node) - before
+}
+
+/// Drop the value at `path`. The node itself goes too unless it still has
+/// children. Returns the (negative) change in totals.
+pub(super) fn evict_key_in(node: &mut Node, path: &[&str]) -> Option<Delta> {
+    let (part, rest) = path.split_first()?;
+    let delta = if rest.is_empty() {
+        let child = node.c.as_mut()?.get_mut(*part)?;
+        if child.c.as_ref().is_none_or(|c| c.is_empty()) {
+            return delete_in(node, path);
+        }
+        let freed = drop_value(child);
+        apply_delta(child, freed);
+        freed
+    } else {
+        evict_key_in(node.c.as_mut()?.get_mut(*part)?, rest)?
     };
-    let mut guard = root.write().map_err(|_| "Lock poisoned")?;
+    apply_delta(node, delta);
+    Some(delta)
+}
+
+/// Whether `key` holds a value that has expired by `now_ms` but is still
+/// there.
+pub(super) fn has_expired(root: &Node, key: &str, now_ms: u64) -> bool {
+    find(root, &split_key(key)).is_some_and(|node| node.v.is_some() && node.is_expired(now_ms))
+}
+
+/// Remove the value at `key` if it has expired by `now_ms`, as the active
+/// expiry cycle does. The node goes too unless it still has children.
+pub(super) fn remove_expired(root: &mut Node, key: &str, now_ms: u64) -> bool {
+    if !has_expired(root, key, now_ms) {
+        return false;
+    }
+    let path = split_key(key);
     if path.is_empty() {
-        guard.v = None;
-        guard.t = None;
-        guard.c = None;
-        return Ok(true);
-    }
-    let mut current = &mut *guard;
-    let last = path.last().copied();
-    for part in &path[..path.len().saturating_sub(1)] {
-        let children = match current.c.as_mut() {
-            Some(c) => c,
-            None => return Ok(false),
-        };
-        current = match children.get_mut(*part) {
+        let freed = drop_value(root);
+        apply_delta(root, freed);
+    } else {
+        evict_key_in(root, &path);
+    }
+    true
+}
+
+/// Set (or with `None` clear) the expiry of `key`, in unix milliseconds.
+/// Returns false if the key holds no live value.
+pub fn set_expiry(root: &mut Node, key: &str, at: Option<u64>) -> bool {
+    let path = split_key(key);
+    let mut current = &mut *root;
+    for part in &path {
+        current = match current.c.as_mut().and_then(|c| c.get_mut(*part)) {
             Some(child) => child,
-            None => return Ok(false),
+            None => return false,
         };
     }
-    if let Some(last_part) = last {
-        if let Some(children) = current.c.as_mut() {
-            children.remove(last_part);
-            return Ok(true);
+    if !current.has_value(now_ms()) {
+        return false;
+    }
+    let before = Delta::own(current);
+    current.t = at;
+    let delta = Delta::own(current) - before;
+    apply_path(root, &path, delta);
+    stamp_path(root, &path);
+    true
+}
+
+/// Expiry of `key`: `None` if the key has no live value, `Some(None)` if it
+/// never expires.
+pub fn expiry(root: &Node, key: &str) -> Option<Option<u64>> {
+    let node = find(root, &split_key(key))?;
+    node.has_value(now_ms()).then_some(node.t)
+}
+
+/// Version of the last write at or beneath `key`; `None` if there is no node.
+pub fn version(root: &Node, key: &str) -> Option<u64> {
+    find(root, &split_key(key)).map(|node| node.w)
+}
+
+/// The version of the deepest node along `key`'s path: the key's own when it
+/// exists, otherwise that of its nearest ancestor. Creating the key, or
+/// removing it, stamps that node too, so unlike `version` this also tells
+/// whether a key that was missing has come and gone since.
+pub fn path_version(root: &Node, key: &str) -> u64 {
+    let mut current = root;
+    for part in split_key(key) {
+        match current.c.as_ref().and_then(|c| c.get(part)) {
+            Some(child) => current = child,
+            None => break,
         }
     }
-    Ok(false)
+    current.w
+}
+
+/// Bytes used at `key`: the key alone, or with `subtree` everything beneath it.
+/// `None` when there is no such key (or, for a single key, no value).
+pub fn usage(root: &Node, key: &str, subtree: bool) -> Option<usize> {
+    let path = split_key(key);
+    let node = find(root, &path)?;
+    let key_bytes = path.last().map_or(0, |k| key_size(k));
+    if subtree {
+        return Some(node.m + key_bytes);
+    }
+    node.v
+        .as_ref()
+        .map(|v| node_alloc_size() + key_bytes + value_size(v))
+}
+
+/// Call `f` with every live key at or beneath `prefix`, e.g. `users` visits
+/// `users`, `users:42` and `users:42:name`.
+pub fn for_each_prefix(root: &Node, prefix: &str, mut f: impl FnMut(&str, &Value)) {
+    fn walk(node: &Node, key: &mut String, now_ms: u64, f: &mut impl FnMut(&str, &Value)) {
+        if let Some(ref v) = node.v {
+            if !node.is_expired(now_ms) {
+                f(key, v);
+            }
+        }
+        if let Some(ref children) = node.c {
+            for (part, child) in children {
+                let len = key.len();
+                if len > 0 {
+                    key.push(':');
+                }
+                key.push_str(part);
+                walk(child, key, now_ms, f);
+                key.truncate(len);
+            }
+        }
+    }
+    if let Some(node) = find(root, &split_key(prefix)) {
+        let mut key = prefix.to_string();
+        walk(node, &mut key, now_ms(), &mut f);
+    }
 }
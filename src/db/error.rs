@@ -0,0 +1,45 @@
+use std::fmt;
+
+/// Why a database operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A thread panicked while holding the trie lock
+    LockPoisoned,
+    /// Over `maxmemory` and the eviction policy could not make room
+    OutOfMemory,
+    /// The key holds a different kind of value than the operation expects
+    WrongType,
+    /// The stream or consumer group does not exist
+    NoGroup,
+    /// A consumer group by that name already exists
+    GroupExists,
+    /// XADD was given an ID not above the stream's last one
+    StreamIdTooSmall,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LockPoisoned => f.write_str("Lock poisoned"),
+            Error::OutOfMemory => f.write_str(super::evict::OOM),
+            Error::WrongType => {
+                f.write_str("Operation against a key holding the wrong kind of value")
+            }
+            Error::NoGroup => f.write_str("No such key or consumer group"),
+            Error::GroupExists => f.write_str("Consumer Group name already exists"),
+            Error::StreamIdTooSmall => f.write_str(
+                "The ID specified in XADD is equal or smaller than the target stream top item",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::other(e)
+    }
+}
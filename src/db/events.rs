@@ -0,0 +1,109 @@
+use super::core::End;
+use std::fmt;
+use std::sync::RwLock;
+
+/// A change to the keyspace, reported to the listener set with
+/// `Database::set_listener`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A value was written
+    Set,
+    /// A key, or with it its whole subtree, was removed
+    Del,
+    /// An expiry was set
+    Expire,
+    /// An expiry was removed
+    Persist,
+    /// An expired key was reclaimed, by the expiry cycle, by a read that
+    /// came across it or by eviction; that can be some time after it expired.
+    Expired,
+    /// A key or subtree was evicted to stay under maxmemory
+    Evicted,
+    /// Elements were pushed onto the head of a list
+    Lpush,
+    /// Elements were pushed onto the tail of a list
+    Rpush,
+    /// An element was popped from the head of a list
+    Lpop,
+    /// An element was popped from the tail of a list
+    Rpop,
+    /// An entry was appended to a stream
+    Xadd,
+    /// Old entries were trimmed from a stream
+    Xtrim,
+    /// A subtree was restored from a dump, replacing what was there
+    Restore,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Set => "set",
+            Event::Del => "del",
+            Event::Expire => "expire",
+            Event::Persist => "persist",
+            Event::Expired => "expired",
+            Event::Evicted => "evicted",
+            Event::Lpush => "lpush",
+            Event::Rpush => "rpush",
+            Event::Lpop => "lpop",
+            Event::Rpop => "rpop",
+            Event::Xadd => "xadd",
+            Event::Xtrim => "xtrim",
+            Event::Restore => "restore",
+        }
+    }
+
+    pub fn push(end: End) -> Self {
+        match end {
+            End::Left => Event::Lpush,
+            End::Right => Event::Rpush,
+        }
+    }
+
+    pub fn pop(end: End) -> Self {
+        match end {
+            End::Left => Event::Lpop,
+            End::Right => Event::Rpop,
+        }
+    }
+
+    /// Whether everything beneath the key is gone too, not just its value.
+    pub fn removes_subtree(self) -> bool {
+        matches!(self, Event::Del | Event::Evicted | Event::Restore)
+    }
+}
+
+/// Called with each event and the key it happened to; the empty key means
+/// the whole database. It runs while the write lock is held, right after the
+/// change, so events arrive in the order the changes were made; it should
+/// be quick and must not call back into the database.
+pub type Listener = Box<dyn Fn(Event, &str) + Send + Sync>;
+
+/// Holder for the optional listener.
+#[derive(Default)]
+pub(super) struct Notifier(RwLock<Option<Listener>>);
+
+impl Notifier {
+    pub fn set(&self, listener: Option<Listener>) {
+        if let Ok(mut slot) = self.0.write() {
+            *slot = listener;
+        }
+    }
+
+    #[inline]
+    pub fn notify(&self, event: Event, key: &str) {
+        if let Ok(slot) = self.0.read() {
+            if let Some(ref listener) = *slot {
+                listener(event, key);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let set = self.0.read().is_ok_and(|slot| slot.is_some());
+        f.debug_tuple("Notifier").field(&set).finish()
+    }
+}
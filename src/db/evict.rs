@@ -0,0 +1,241 @@
+use super::core::{self, Node};
+use super::error::{Error, Result};
+use super::events::Event;
+use super::expire;
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+pub const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'";
+
+/// Candidates compared per eviction, as in Redis' approximated LRU.
+const SAMPLES: usize = 5;
+/// Random walks allowed per eviction before giving up.
+const MAX_WALKS: usize = SAMPLES * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileTtl,
+}
+
+impl Policy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "noeviction" => Some(Policy::NoEviction),
+            "allkeys-lru" => Some(Policy::AllKeysLru),
+            "allkeys-lfu" => Some(Policy::AllKeysLfu),
+            "allkeys-random" | "random" => Some(Policy::AllKeysRandom),
+            "volatile-ttl" => Some(Policy::VolatileTtl),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::AllKeysRandom => "allkeys-random",
+            Policy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+/// Memory limit settings. `maxmemory == 0` means unlimited.
+///
+/// With `subtree_depth == 0` single keys are evicted. Otherwise whole subtrees
+/// rooted `subtree_depth` segments down are evicted, e.g. depth 2 drops an
+/// entire `cache:user42` at once.
+#[derive(Debug, Clone, Copy)]
+pub struct Eviction {
+    pub maxmemory: usize,
+    pub policy: Policy,
+    pub subtree_depth: usize,
+}
+
+impl Eviction {
+    /// Depth of the node a read records access on, for `core::get`: the key
+    /// itself or its subtree. `None` unless the policy looks at access.
+    pub fn access_depth(&self) -> Option<usize> {
+        matches!(self.policy, Policy::AllKeysLru | Policy::AllKeysLfu).then_some(self.subtree_depth)
+    }
+}
+
+impl Default for Eviction {
+    fn default() -> Self {
+        Eviction {
+            maxmemory: 0,
+            policy: Policy::NoEviction,
+            subtree_depth: 0,
+        }
+    }
+}
+
+/// Evict until the dataset fits under `maxmemory`. Fails with `OutOfMemory` if the
+/// policy forbids eviction or nothing evictable is left. `notify` hears
+/// about each key or subtree that goes, while the write lock is still held.
+///
+/// Under noeviction only expired keys may go: one expiry cycle runs, the
+/// same bounded one the server runs in the background, and the write is
+/// refused unless that brought usage under the limit.
+pub fn make_room(
+    root: &RwLock<Node>,
+    cfg: &Eviction,
+    evicted: &AtomicU64,
+    notify: impl FnMut(Event, &str),
+) -> Result<()> {
+    if cfg.maxmemory == 0 {
+        return Ok(());
+    }
+    {
+        let guard = root.read().map_err(|_| Error::LockPoisoned)?;
+        if guard.m <= cfg.maxmemory {
+            return Ok(());
+        }
+        if cfg.policy == Policy::NoEviction && guard.x == 0 {
+            return Err(Error::OutOfMemory);
+        }
+    }
+    let mut guard = root.write().map_err(|_| Error::LockPoisoned)?;
+    make_room_in(&mut guard, cfg, evicted, notify)
+}
+
+/// `make_room` for a caller that already holds the write lock.
+pub fn make_room_in(
+    root: &mut Node,
+    cfg: &Eviction,
+    evicted: &AtomicU64,
+    mut notify: impl FnMut(Event, &str),
+) -> Result<()> {
+    if cfg.maxmemory == 0 || root.m <= cfg.maxmemory {
+        return Ok(());
+    }
+    if cfg.policy == Policy::NoEviction {
+        // Expired keys are the one thing noeviction may still reclaim
+        for key in expire::cycle(root) {
+            notify(Event::Expired, &key);
+        }
+        if root.m <= cfg.maxmemory {
+            return Ok(());
+        }
+        return Err(Error::OutOfMemory);
+    }
+    let mut rng = rand::thread_rng();
+    while root.m > cfg.maxmemory {
+        let before = root.m;
+        let path = match pick(root, cfg, &mut rng) {
+            Some(path) => path,
+            None => return Err(Error::OutOfMemory),
+        };
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        // Empty leftovers go silently; nobody could have seen them.
+        let event = core::find(root, &path).and_then(|node| {
+            let empty = node.v.is_none() && node.c.as_ref().is_none_or(|c| c.is_empty());
+            if empty {
+                None
+            } else if node.is_expired(core::now_ms()) {
+                Some(Event::Expired)
+            } else {
+                Some(Event::Evicted)
+            }
+        });
+        if cfg.subtree_depth > 0 {
+            core::delete_in(root, &path);
+        } else {
+            core::evict_key_in(root, &path);
+        }
+        if root.m >= before {
+            return Err(Error::OutOfMemory);
+        }
+        core::stamp_path(root, &path);
+        evicted.fetch_add(1, Ordering::Relaxed);
+        if let Some(event) = event {
+            notify(event, &path.join(":"));
+        }
+    }
+    Ok(())
+}
+
+/// Sample a few random paths and return the best one to evict.
+fn pick(root: &Node, cfg: &Eviction, rng: &mut impl Rng) -> Option<Vec<String>> {
+    let now = core::now_secs();
+    let now_ms = core::now_ms();
+    let mut best: Option<(u64, Vec<String>)> = None;
+    let mut found = 0;
+
+    for _ in 0..MAX_WALKS {
+        let sample = if cfg.subtree_depth > 0 {
+            walk_to_depth(root, cfg.subtree_depth, rng)
+        } else {
+            walk_to_key(root, rng)
+        };
+        let Some((path, node)) = sample else {
+            continue;
+        };
+        // Empty leftovers and expired keys are always fair game.
+        let empty = node.v.is_none() && node.c.as_ref().is_none_or(|c| c.is_empty());
+        if empty || node.is_expired(now_ms) {
+            return Some(path);
+        }
+        let score = match cfg.policy {
+            Policy::AllKeysLru => node.a.load(Ordering::Relaxed) as u64,
+            Policy::AllKeysLfu => {
+                ((node.frequency(now) as u64) << 32) | node.a.load(Ordering::Relaxed) as u64
+            }
+            Policy::VolatileTtl => match node.t {
+                Some(at) => at,
+                None => continue,
+            },
+            Policy::AllKeysRandom => return Some(path),
+            Policy::NoEviction => return None,
+        };
+        if best.as_ref().is_none_or(|(s, _)| score < *s) {
+            best = Some((score, path));
+        }
+        found += 1;
+        if found == SAMPLES {
+            break;
+        }
+    }
+    best.map(|(_, path)| path)
+}
+
+/// Random walk ending at a node holding a value (or an empty leftover leaf).
+fn walk_to_key<'a>(root: &'a Node, rng: &mut impl Rng) -> Option<(Vec<String>, &'a Node)> {
+    let mut path = Vec::new();
+    let mut node = root;
+    loop {
+        let children = match node.c.as_ref().filter(|c| !c.is_empty()) {
+            Some(children) => children,
+            None => return (!path.is_empty()).then_some((path, node)),
+        };
+        // Stop at an inner key with the same odds as each of its children.
+        if !path.is_empty() && node.v.is_some() && rng.gen_range(0..=children.len()) == 0 {
+            return Some((path, node));
+        }
+        let (key, child) = children.iter().nth(rng.gen_range(0..children.len()))?;
+        path.push(key.clone());
+        node = child;
+    }
+}
+
+/// Random walk exactly `depth` segments down.
+fn walk_to_depth<'a>(
+    root: &'a Node,
+    depth: usize,
+    rng: &mut impl Rng,
+) -> Option<(Vec<String>, &'a Node)> {
+    let mut path = Vec::with_capacity(depth);
+    let mut node = root;
+    while path.len() < depth {
+        let children = node.c.as_ref().filter(|c| !c.is_empty())?;
+        let (key, child) = children.iter().nth(rng.gen_range(0..children.len()))?;
+        path.push(key.clone());
+        node = child;
+    }
+    Some((path, node))
+}
@@ -0,0 +1,61 @@
+use super::core::{self, Node};
+use rand::Rng;
+
+/// Keys with an expiry looked at per round, as in Redis' active expiry.
+const SAMPLES: usize = 20;
+/// Another round follows while more than this many of a sample had expired.
+const REPEAT_ABOVE: usize = SAMPLES / 4;
+/// Rounds per cycle at most, so one cycle holds the write lock only briefly.
+const MAX_ROUNDS: usize = 16;
+
+/// One active expiry cycle: sample keys that have an expiry, remove those
+/// past it, and go again while a good share of the sample was. Returns the
+/// keys removed.
+pub fn cycle(root: &mut Node) -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    let mut removed = Vec::new();
+    for _ in 0..MAX_ROUNDS {
+        let now_ms = core::now_ms();
+        let mut expired = 0;
+        for _ in 0..SAMPLES {
+            let Some(key) = sample(root, &mut rng) else {
+                return removed;
+            };
+            if core::remove_expired(root, &key, now_ms) {
+                expired += 1;
+                removed.push(key);
+            }
+        }
+        if expired <= REPEAT_ABOVE {
+            break;
+        }
+    }
+    removed
+}
+
+/// Random walk to a key with an expiry, following only branches that have
+/// one. Each step stops at the current key with the same odds as going on
+/// into any one of those branches.
+fn sample(root: &Node, rng: &mut impl Rng) -> Option<String> {
+    if root.x == 0 {
+        return None;
+    }
+    let mut path: Vec<&str> = Vec::new();
+    let mut node = root;
+    loop {
+        let branches: Vec<_> = node
+            .c
+            .iter()
+            .flatten()
+            .filter(|(_, child)| child.x > 0)
+            .collect();
+        let here = node.t.is_some() as usize;
+        let pick = rng.gen_range(0..here + branches.len());
+        if pick < here {
+            return Some(path.join(":"));
+        }
+        let (part, child) = branches[pick - here];
+        path.push(part);
+        node = child;
+    }
+}
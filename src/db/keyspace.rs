@@ -0,0 +1,500 @@
+use super::core::{self, End, Node, Value};
+use super::error::{Error, Result};
+use super::events::Event;
+use super::stream::{Fields, NewId, Stream, StreamId, Trim};
+use super::{evict, snapshot, Database};
+use std::cell::RefCell;
+use std::sync::atomic::Ordering;
+use std::sync::RwLockWriteGuard;
+
+/// Iterator over `(key, value)` pairs returned by `Keyspace::scan_prefix`.
+pub type Entries = std::vec::IntoIter<(String, Value)>;
+/// Iterator over keys returned by `Keyspace::keys_prefix`.
+pub type Keys = std::vec::IntoIter<String>;
+
+/// Key operations, shared by `Database` (which locks per call) and
+/// `Transaction` (which already holds the write lock).
+pub trait Keyspace {
+    /// Run `f` with shared access to the root node.
+    fn with_root<R>(&self, f: impl FnOnce(&Node) -> R) -> Result<R>;
+
+    /// Run `f` with exclusive access to the root node.
+    fn with_root_mut<R>(&self, f: impl FnOnce(&mut Node) -> R) -> Result<R>;
+
+    /// Evict as needed before a write; `Err(OutOfMemory)` if the write must be refused.
+    fn make_room(&self) -> Result<()>;
+
+    /// Depth of the node a read records access on, as `Eviction::access_depth`.
+    fn access_depth(&self) -> Result<Option<usize>>;
+
+    /// Report a change to the database's listener, if it has one. Operations
+    /// report from inside `with_root_mut`, before the lock is released, so the
+    /// listener hears about changes in the order they were made.
+    fn notify(&self, event: Event, key: &str);
+
+    /// Remove `key` if it holds an expired value, reporting `Event::Expired`.
+    /// Reads that find nothing call it, so an expired key goes the first
+    /// time it is looked at.
+    fn reap(&self, key: &str) -> Result<()>;
+
+    /// Set a value, e.g. db.set("foo:bar", Value::Text("abc".to_string()))
+    fn set(&self, key: &str, value: Value) -> Result<()> {
+        self.make_room()?;
+        self.with_root_mut(|root| {
+            core::set(root, key, value);
+            self.notify(Event::Set, key);
+        })
+    }
+
+    /// Get a value by key
+    fn get(&self, key: &str) -> Result<Option<Value>> {
+        let track = self.access_depth()?;
+        let value = self.with_root(|root| core::get(root, key, track))?;
+        if value.is_none() {
+            self.reap(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Set `key` only if `cond` accepts its current value and version (`None`
+    /// when absent), e.g. db.set_if("lock", v, |cur| cur.is_none()) for
+    /// set-if-not-exists. The check and the write happen under one lock.
+    /// Returns whether the value was written, and the previous value.
+    fn set_if(
+        &self,
+        key: &str,
+        value: Value,
+        cond: impl FnOnce(Option<(&Value, u64)>) -> bool,
+    ) -> Result<(bool, Option<Value>)> {
+        self.make_room()?;
+        self.with_root_mut(|root| {
+            let previous = core::get_versioned(root, key, None);
+            let written = cond(previous.as_ref().map(|(v, r)| (v, *r)));
+            if written {
+                core::set(root, key, value);
+                self.notify(Event::Set, key);
+            }
+            (written, previous.map(|(v, _)| v))
+        })
+    }
+
+    /// Get a value and its version, which changes on every write to the key
+    fn get_versioned(&self, key: &str) -> Result<Option<(Value, u64)>> {
+        let track = self.access_depth()?;
+        let value = self.with_root(|root| core::get_versioned(root, key, track))?;
+        if value.is_none() {
+            self.reap(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Get a text value; `Err(WrongType)` if the key holds a list or set
+    fn get_text(&self, key: &str) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(Value::Text(s)) => Ok(Some(s)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete a key (or subtree)
+    fn delete(&self, key: &str) -> Result<bool> {
+        self.with_root_mut(|root| {
+            let deleted = core::delete(root, key);
+            if deleted {
+                self.notify(Event::Del, key);
+            }
+            deleted
+        })
+    }
+
+    /// Remove and return a text value, leaving keys beneath it alone.
+    /// `Err(WrongType)`, with nothing removed, if it holds a list or set.
+    fn take_text(&self, key: &str) -> Result<Option<String>> {
+        self.with_root_mut(|root| match core::get(root, key, None) {
+            Some(Value::Text(_)) => match core::take(root, key) {
+                Some(Value::Text(s)) => {
+                    self.notify(Event::Del, key);
+                    Ok(Some(s))
+                }
+                _ => Ok(None),
+            },
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        })?
+    }
+
+    /// Push onto a list, creating it, e.g. db.push("jobs:tenantA", End::Right, &["job1"]).
+    /// Returns the new length; `Err(WrongType)` if the key is not a list.
+    fn push(&self, key: &str, end: End, items: &[&str]) -> Result<usize> {
+        self.make_room()?;
+        self.with_root_mut(|root| {
+            let len = core::push(root, key, end, items)?;
+            self.notify(Event::push(end), key);
+            Ok(len)
+        })?
+    }
+
+    /// Pop from a list; the key is removed along with its last element
+    fn pop(&self, key: &str, end: End) -> Result<Option<String>> {
+        self.with_root_mut(|root| {
+            let item = core::pop(root, key, end)?;
+            if item.is_some() {
+                self.notify(Event::pop(end), key);
+            }
+            Ok(item)
+        })?
+    }
+
+    /// Pop from `src` and push onto `dst` in one step, e.g. moving a job from
+    /// a queue to a processing list. `dst` is type-checked before anything moves.
+    fn pop_push(&self, src: &str, dst: &str, from: End, to: End) -> Result<Option<String>> {
+        self.make_room()?;
+        self.with_root_mut(|root| {
+            core::list(root, dst, None)?;
+            let Some(item) = core::pop(root, src, from)? else {
+                return Ok(None);
+            };
+            self.notify(Event::pop(from), src);
+            core::push(root, dst, to, &[&item])?;
+            self.notify(Event::push(to), dst);
+            Ok(Some(item))
+        })?
+    }
+
+    /// Length of a list, 0 if the key doesn't exist
+    fn list_len(&self, key: &str) -> Result<usize> {
+        let track = self.access_depth()?;
+        let len =
+            self.with_root(|root| core::list(root, key, track).map(|l| l.map(|l| l.len())))??;
+        if len.is_none() {
+            self.reap(key)?;
+        }
+        Ok(len.unwrap_or(0))
+    }
+
+    /// Elements `start..=stop` of a list. Negative indexes count from the
+    /// end, so `0, -1` is the whole list.
+    fn list_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>> {
+        let track = self.access_depth()?;
+        let range = self.with_root(|root| {
+            let Some(list) = core::list(root, key, track)? else {
+                return Ok(None);
+            };
+            let len = list.len() as i64;
+            let start = if start < 0 {
+                (len + start).max(0)
+            } else {
+                start
+            };
+            let stop = if stop < 0 {
+                len + stop
+            } else {
+                stop.min(len - 1)
+            };
+            if start > stop {
+                return Ok(Some(Vec::new()));
+            }
+            Ok(Some(
+                list.range(start as usize..=stop as usize)
+                    .cloned()
+                    .collect(),
+            ))
+        })??;
+        if range.is_none() {
+            self.reap(key)?;
+        }
+        Ok(range.unwrap_or_default())
+    }
+
+    /// Append an entry to a stream, creating the stream unless `create` is
+    /// unset, then trim it if asked, e.g.
+    /// db.xadd("events", NewId::Auto, fields, Some(Trim::MaxLen(1000)), true).
+    /// Returns the new entry's ID, `None` if there was no stream to add to.
+    fn xadd(
+        &self,
+        key: &str,
+        id: NewId,
+        fields: Fields,
+        trim: Option<Trim>,
+        create: bool,
+    ) -> Result<Option<StreamId>> {
+        self.make_room()?;
+        self.with_root_mut(|root| {
+            let added = core::stream_mut(root, key, create, |stream| {
+                let id = stream.add(id, fields, core::now_ms())?;
+                let trimmed = trim.map_or(0, |trim| stream.trim(trim));
+                Ok((id, trimmed))
+            })?;
+            let Some((id, trimmed)) = added else {
+                return Ok(None);
+            };
+            self.notify(Event::Xadd, key);
+            if trimmed > 0 {
+                self.notify(Event::Xtrim, key);
+            }
+            Ok(Some(id))
+        })?
+    }
+
+    /// Trim a stream. Returns how many entries were removed.
+    fn xtrim(&self, key: &str, trim: Trim) -> Result<usize> {
+        self.with_root_mut(|root| {
+            let trimmed = core::stream_mut(root, key, false, |s| Ok(s.trim(trim)))?.unwrap_or(0);
+            if trimmed > 0 {
+                self.notify(Event::Xtrim, key);
+            }
+            Ok(trimmed)
+        })?
+    }
+
+    /// Run `f` on a stream, e.g. to read its entries. `None` if there is no
+    /// stream at `key`; `Err(WrongType)` if it holds something else.
+    fn read_stream<R>(&self, key: &str, f: impl FnOnce(&Stream) -> R) -> Result<Option<R>> {
+        let track = self.access_depth()?;
+        let read = self.with_root(|root| Ok(core::stream(root, key, track)?.map(f)))??;
+        if read.is_none() {
+            self.reap(key)?;
+        }
+        Ok(read)
+    }
+
+    /// Change a stream's consumer groups through `f`, creating an empty stream
+    /// first when `create` is set. For group bookkeeping only: nothing is
+    /// reported to the listener. `None` if there is no stream at `key`.
+    fn update_stream<R>(
+        &self,
+        key: &str,
+        create: bool,
+        f: impl FnOnce(&mut Stream) -> Result<R>,
+    ) -> Result<Option<R>> {
+        self.make_room()?;
+        self.with_root_mut(|root| core::stream_mut(root, key, create, f))?
+    }
+
+    /// Serialize the subtree at `key`, to be put back with `restore`,
+    /// possibly on another server. `None` if nothing is there.
+    fn dump(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.with_root(|root| core::subtree(root, key).map(snapshot::to_bytes))
+    }
+
+    /// Put a subtree from `dump` (parsed with `snapshot::node_from_bytes`)
+    /// at `key`. Anything already there is replaced only with `replace`;
+    /// returns whether the subtree was written.
+    fn restore(&self, key: &str, subtree: Node, replace: bool) -> Result<bool> {
+        self.make_room()?;
+        self.with_root_mut(|root| {
+            let taken = core::subtree(root, key).is_some();
+            if taken && !replace {
+                return false;
+            }
+            core::graft(root, key, subtree);
+            self.notify(Event::Restore, key);
+            true
+        })
+    }
+
+    /// Empty the whole database
+    fn drop_all(&self) -> Result<()> {
+        self.with_root_mut(|root| {
+            core::clear(root);
+            self.notify(Event::Del, "");
+        })
+    }
+
+    /// Expire a key at a unix time in milliseconds. False if the key doesn't exist.
+    fn expire_at(&self, key: &str, at_ms: u64) -> Result<bool> {
+        self.with_root_mut(|root| {
+            let found = core::set_expiry(root, key, Some(at_ms));
+            if found {
+                self.notify(Event::Expire, key);
+            }
+            found
+        })
+    }
+
+    /// Remove a key's expiry. False if the key doesn't exist.
+    fn persist(&self, key: &str) -> Result<bool> {
+        self.with_root_mut(|root| {
+            let found = core::set_expiry(root, key, None);
+            if found {
+                self.notify(Event::Persist, key);
+            }
+            found
+        })
+    }
+
+    /// `None` if the key doesn't exist, `Some(None)` if it has no expiry,
+    /// otherwise the expiry as a unix time in milliseconds.
+    fn expiry(&self, key: &str) -> Result<Option<Option<u64>>> {
+        let expiry = self.with_root(|root| core::expiry(root, key))?;
+        if expiry.is_none() {
+            self.reap(key)?;
+        }
+        Ok(expiry)
+    }
+
+    /// Version of the last write at or beneath `key`, `None` if nothing is
+    /// there. Any change to the key or its subtree yields a different answer.
+    fn version(&self, key: &str) -> Result<Option<u64>> {
+        self.with_root(|root| core::version(root, key))
+    }
+
+    /// What WATCH records for `key`: any write at or beneath it since, or
+    /// the key coming and going, yields a different answer.
+    fn watch_version(&self, key: &str) -> Result<u64> {
+        self.with_root(|root| core::path_version(root, key))
+    }
+
+    /// Bytes used by the key alone, e.g. db.usage("users:42")
+    fn usage(&self, key: &str) -> Result<Option<usize>> {
+        self.with_root(|root| core::usage(root, key, false))
+    }
+
+    /// Bytes used by everything under a prefix, including the prefix's own value
+    fn usage_prefix(&self, prefix: &str) -> Result<Option<usize>> {
+        self.with_root(|root| core::usage(root, prefix, true))
+    }
+
+    /// Segments directly beneath `key`, sorted, e.g. db.children("users")
+    /// yields `41`, `42`. The empty key lists the top level.
+    fn children(&self, key: &str) -> Result<Vec<String>> {
+        let mut names = self.with_root(|root| {
+            core::subtree(root, key)
+                .and_then(|node| node.c.as_ref())
+                .map(|c| c.keys().cloned().collect::<Vec<_>>())
+                .unwrap_or_default()
+        })?;
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Live entries at or beneath `prefix`, sorted by key, e.g.
+    /// db.scan_prefix("users:42") yields `users:42`, `users:42:name`, ...
+    /// An empty prefix covers the whole database. The entries are gathered
+    /// under one read lock, so iterating sees a consistent snapshot and never
+    /// blocks writers.
+    fn scan_prefix(&self, prefix: &str) -> Result<Entries> {
+        let mut entries = Vec::new();
+        self.with_root(|root| {
+            core::for_each_prefix(root, prefix, |key, value| {
+                entries.push((key.to_string(), value.clone()))
+            })
+        })?;
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries.into_iter())
+    }
+
+    /// Keys at or beneath `prefix`, sorted; like `scan_prefix` without the values.
+    fn keys_prefix(&self, prefix: &str) -> Result<Keys> {
+        let mut keys = Vec::new();
+        self.with_root(|root| {
+            core::for_each_prefix(root, prefix, |key, _| keys.push(key.to_string()))
+        })?;
+        keys.sort_unstable();
+        Ok(keys.into_iter())
+    }
+}
+
+impl Keyspace for Database {
+    fn with_root<R>(&self, f: impl FnOnce(&Node) -> R) -> Result<R> {
+        let guard = self.root.read().map_err(|_| Error::LockPoisoned)?;
+        Ok(f(&guard))
+    }
+
+    fn with_root_mut<R>(&self, f: impl FnOnce(&mut Node) -> R) -> Result<R> {
+        let mut guard = self.root.write().map_err(|_| Error::LockPoisoned)?;
+        Ok(f(&mut guard))
+    }
+
+    fn make_room(&self) -> Result<()> {
+        let notify = |event, key: &str| self.listener.notify(event, key);
+        evict::make_room(&self.root, &self.eviction()?, &self.evicted, notify)
+    }
+
+    fn access_depth(&self) -> Result<Option<usize>> {
+        Ok(self.eviction()?.access_depth())
+    }
+
+    fn notify(&self, event: Event, key: &str) {
+        self.listener.notify(event, key);
+    }
+
+    fn reap(&self, key: &str) -> Result<()> {
+        if !self.expire_on_read.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let now_ms = core::now_ms();
+        if !self.with_root(|root| core::has_expired(root, key, now_ms))? {
+            return Ok(());
+        }
+        self.with_root_mut(|root| {
+            if core::remove_expired(root, key, now_ms) {
+                self.notify(Event::Expired, key);
+            }
+        })
+    }
+}
+
+//
+// ─── Transactions ────────────────────────────────────────────────────────────────
+//
+
+/// Every operation of one `Database::transaction` call, run under a single
+/// hold of the write lock so no other reader or writer can interleave.
+pub struct Transaction<'a> {
+    database: &'a Database,
+    root: RefCell<RwLockWriteGuard<'a, Node>>,
+}
+
+impl Database {
+    /// Run `f` with the write lock held throughout, e.g.
+    /// db.transaction(|tx| { tx.set("a", v1)?; tx.set("b", v2) })
+    pub fn transaction<R>(&self, f: impl FnOnce(&Transaction<'_>) -> R) -> Result<R> {
+        let guard = self.root.write().map_err(|_| Error::LockPoisoned)?;
+        let tx = Transaction {
+            database: self,
+            root: RefCell::new(guard),
+        };
+        Ok(f(&tx))
+    }
+}
+
+impl Keyspace for Transaction<'_> {
+    fn with_root<R>(&self, f: impl FnOnce(&Node) -> R) -> Result<R> {
+        Ok(f(&self.root.borrow()))
+    }
+
+    fn with_root_mut<R>(&self, f: impl FnOnce(&mut Node) -> R) -> Result<R> {
+        Ok(f(&mut self.root.borrow_mut()))
+    }
+
+    fn make_room(&self) -> Result<()> {
+        let eviction = self.database.eviction()?;
+        let notify = |event, key: &str| self.database.listener.notify(event, key);
+        evict::make_room_in(
+            &mut self.root.borrow_mut(),
+            &eviction,
+            &self.database.evicted,
+            notify,
+        )
+    }
+
+    fn access_depth(&self) -> Result<Option<usize>> {
+        Ok(self.database.eviction()?.access_depth())
+    }
+
+    fn notify(&self, event: Event, key: &str) {
+        self.database.listener.notify(event, key);
+    }
+
+    fn reap(&self, key: &str) -> Result<()> {
+        if !self.database.expire_on_read.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if core::remove_expired(&mut self.root.borrow_mut(), key, core::now_ms()) {
+            self.notify(Event::Expired, key);
+        }
+        Ok(())
+    }
+}
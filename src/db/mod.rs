@@ -1,72 +1,36 @@
-// use dashmap::DashMap;
-// use std::sync::{Arc, RwLock};
-// // use serde_json::Error;
-// // use std::collections::HashSet;
-// pub mod core;
-// pub use core::Value; 
-
-
-// #[derive(Debug, Clone)]
-// pub struct Database {
-//     root: Arc<RwLock<core::Node>>,
-// }
-
-// impl Database {
-//     pub fn new() -> Self {
-//         Database {
-//             root: Arc::new(RwLock::new(core::Node::new())),
-//         }
-//     }
-
-//     #[inline]
-//     pub fn get_root(&self) -> &Arc<RwLock<core::Node>> {
-//         &self.root
-//     }
-
-//     pub fn set(&self, key: &str, value: Value) -> Result<(), String> {
-//         core::set(&self.root, key, value)
-//     }
-
-//     pub fn get(&self, key: &str) -> Result<Option<core::Value>, String> {
-//         core::get(&self.root, key)
-//     }
-
-//     pub fn delete(&self, key: &str) -> Result<bool, String> {
-//         core::delete(&self.root, key)
-//     }
-
-//     pub fn drop_all(&self) {
-//         let mut root = self.root.write().unwrap();
-//         root.v = None;
-//         root.t = None;
-//         root.c = None;
-//     }
-
-//     pub fn memory(&self) -> core::MemoryStats {
-//         let (total, count, smallest, largest) = core::node_memory_stats(&self.root);
-//         core::MemoryStats {
-//             total_bytes: total,
-//             node_count: count,
-//             smallest_node: smallest,
-//             largest_node: largest,
-//         }
-//     }
-
-//     pub fn size(&self)->usize {
-//         core::node_count(&self.root)
-//     }
-
-// }
-
-
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::RwLock;
 pub mod core;
-pub use core::Value;
+mod error;
+mod events;
+pub mod evict;
+pub mod expire;
+mod keyspace;
+pub mod snapshot;
+pub mod stream;
+pub use core::{End, Value};
+pub use error::{Error, Result};
+pub use events::{Event, Listener};
+pub use evict::{Eviction, Policy};
+pub use keyspace::{Entries, Keys, Keyspace, Transaction};
+pub use stream::{Fields, NewId, Stream, StreamId, Trim};
 
-/// The main handle to your in-memory database
+/// The main handle to your in-memory database. Key operations come from the
+/// `Keyspace` trait.
 #[derive(Debug)]
 pub struct Database {
     root: RwLock<core::Node>,
+    eviction: RwLock<Eviction>,
+    evicted: AtomicU64,
+    /// Whether reads remove the expired keys they come across
+    expire_on_read: AtomicBool,
+    listener: events::Notifier,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database::new()
+    }
 }
 
 impl Database {
@@ -74,38 +38,65 @@ impl Database {
     pub fn new() -> Self {
         Database {
             root: RwLock::new(core::Node::new()),
+            eviction: RwLock::new(Eviction::default()),
+            evicted: AtomicU64::new(0),
+            expire_on_read: AtomicBool::new(true),
+            listener: events::Notifier::default(),
         }
     }
 
-    #[inline]
-    pub fn get_root(&self) -> &RwLock<core::Node> {
-        &self.root
+    /// Set the memory limit and eviction policy, e.g.
+    /// database.set_eviction(Eviction { maxmemory: 1 << 30, policy: Policy::AllKeysLru, subtree_depth: 0 })
+    pub fn set_eviction(&self, eviction: Eviction) -> Result<()> {
+        *self.eviction.write().map_err(|_| Error::LockPoisoned)? = eviction;
+        Ok(())
+    }
+
+    pub fn eviction(&self) -> Result<Eviction> {
+        Ok(*self.eviction.read().map_err(|_| Error::LockPoisoned)?)
+    }
+
+    /// Whether reads remove expired keys they come across (the default), or
+    /// only report them missing, e.g. on a replica, which leaves that to
+    /// its primary.
+    pub fn set_expire_on_read(&self, on: bool) {
+        self.expire_on_read.store(on, Ordering::Relaxed);
+    }
+
+    /// Run one active expiry cycle. Returns how many keys it removed.
+    pub fn expire_cycle(&self) -> Result<usize> {
+        let mut root = self.root.write().map_err(|_| Error::LockPoisoned)?;
+        let removed = expire::cycle(&mut root);
+        for key in &removed {
+            self.listener.notify(Event::Expired, key);
+        }
+        Ok(removed.len())
     }
 
-    /// Set a value, e.g. database.set("foo:bar", Value::Text("abc".to_string()))
-    pub fn set(&self, key: &str, value: Value) -> Result<(), String> {
-        core::set(&self.root, key, value)
+    /// Be told about every change to the keyspace, e.g.
+    /// database.set_listener(|event, key| println!("{} {}", event.name(), key))
+    pub fn set_listener(&self, listener: impl Fn(Event, &str) + Send + Sync + 'static) {
+        self.listener.set(Some(Box::new(listener)));
     }
 
-    /// Get a value by key
-    pub fn get(&self, key: &str) -> Result<Option<core::Value>, String> {
-        core::get(&self.root, key)
+    /// Stop reporting changes.
+    pub fn clear_listener(&self) {
+        self.listener.set(None);
     }
 
-    /// Delete a key (or subtree)
-    pub fn delete(&self, key: &str) -> Result<bool, String> {
-        core::delete(&self.root, key)
+    /// Keys (or subtrees) evicted so far
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
     }
 
-    /// Empty the whole database
-    pub fn drop_all(&self) {
-        let mut root = self.root.write().unwrap();
-        root.v = None;
-        root.t = None;
-        root.c = None;
+    #[inline]
+    pub fn get_root(&self) -> &RwLock<core::Node> {
+        &self.root
     }
 
-    /// Memory statistics (total bytes, node count, min/max node size)
+    /// Memory statistics (total bytes, node count, min/max node size). This
+    /// walks the whole trie under the read lock; prefer `dataset_bytes`,
+    /// `size` and `key_count` where a running total will do.
     pub fn memory(&self) -> core::MemoryStats {
         let (total, count, smallest, largest) = core::node_memory_stats(&self.root);
         core::MemoryStats {
@@ -116,8 +107,258 @@ impl Database {
         }
     }
 
-    /// Total number of nodes
-    pub fn size(&self) -> usize {
-        core::node_count(&self.root)
+    /// Bytes used by the whole dataset, read from the root's running total
+    pub fn dataset_bytes(&self) -> Result<usize> {
+        Ok(self.root.read().map_err(|_| Error::LockPoisoned)?.m)
+    }
+
+    /// Total number of nodes, read from the root's running total
+    pub fn size(&self) -> Result<usize> {
+        Ok(self.root.read().map_err(|_| Error::LockPoisoned)?.n)
+    }
+
+    /// Number of nodes holding a value, read from the root's running total
+    pub fn key_count(&self) -> Result<usize> {
+        Ok(self.root.read().map_err(|_| Error::LockPoisoned)?.k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::evict::{Eviction, Policy};
+    use super::{Database, Event, Keyspace, Value};
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    /// The running totals must agree with a full walk of the trie.
+    fn assert_totals(db: &Database) {
+        let stats = db.memory();
+        assert_eq!(db.dataset_bytes().unwrap(), stats.total_bytes);
+        assert_eq!(db.size().unwrap(), stats.node_count);
+    }
+
+    #[test]
+    fn counters_follow_sets_and_deletes() {
+        let db = Database::new();
+        assert_eq!((db.size().unwrap(), db.key_count().unwrap()), (1, 0));
+        db.set("a:b:c", text("1")).unwrap();
+        db.set("a:b", text("2")).unwrap();
+        db.set("a:b", text("22")).unwrap();
+        db.set("x", text("3")).unwrap();
+        db.set("l", Value::List(["p".to_string(), "q".to_string()].into()))
+            .unwrap();
+        assert_eq!((db.size().unwrap(), db.key_count().unwrap()), (6, 4));
+        assert_totals(&db);
+
+        assert!(db.delete("l").unwrap());
+        assert!(!db.delete("l").unwrap());
+        assert_eq!((db.size().unwrap(), db.key_count().unwrap()), (5, 3));
+        assert!(db.delete("a").unwrap());
+        assert_eq!((db.size().unwrap(), db.key_count().unwrap()), (2, 1));
+        assert_totals(&db);
+
+        db.drop_all().unwrap();
+        assert_eq!((db.size().unwrap(), db.key_count().unwrap()), (1, 0));
+        assert_totals(&db);
+    }
+
+    #[test]
+    fn usage_covers_a_key_or_everything_under_a_prefix() {
+        let db = Database::new();
+        db.set("users:1", text("alice")).unwrap();
+        db.set("users:2", text("bob")).unwrap();
+        db.set("users", text("all")).unwrap();
+        let one = db.usage("users:1").unwrap().unwrap();
+        let two = db.usage("users:2").unwrap().unwrap();
+        let prefix = db.usage_prefix("users").unwrap().unwrap();
+        assert!(one > "alice".len());
+        assert!(prefix > one + two, "{prefix} <= {one} + {two}");
+        assert_eq!(
+            db.usage_prefix("").unwrap(),
+            Some(db.dataset_bytes().unwrap())
+        );
+        assert_eq!(db.usage("missing").unwrap(), None);
+
+        assert!(db.delete("users:1").unwrap());
+        assert_eq!(db.usage("users:1").unwrap(), None);
+        let after = db.usage_prefix("users").unwrap().unwrap();
+        assert!(after < prefix, "{after} >= {prefix}");
+        assert_totals(&db);
+
+        // A node with children but no value has no usage of its own
+        db.set("orders:1", text("pen")).unwrap();
+        assert_eq!(db.usage("orders").unwrap(), None);
+        assert!(db.usage_prefix("orders").unwrap().is_some());
+    }
+
+    #[test]
+    fn expire_cycle_removes_expired_keys_and_keeps_the_rest() {
+        let db = Database::new();
+        for i in 0..10 {
+            db.set(&format!("old:{i}"), text("x")).unwrap();
+            assert!(db.expire_at(&format!("old:{i}"), 1).unwrap());
+        }
+        db.set("old", text("parent")).unwrap();
+        db.set("new", text("y")).unwrap();
+        assert!(db.expire_at("new", u64::MAX).unwrap());
+        assert_eq!(db.get_root().read().unwrap().x, 11);
+
+        while db.expire_cycle().unwrap() > 0 {}
+        let root = db.get_root().read().unwrap();
+        assert_eq!((root.k, root.x), (2, 1));
+        drop(root);
+        assert_eq!(db.expiry("new").unwrap(), Some(Some(u64::MAX)));
+        assert!(db.persist("new").unwrap());
+        assert_eq!(db.get_root().read().unwrap().x, 0);
+        assert_eq!(db.expire_cycle().unwrap(), 0);
+        assert_totals(&db);
+    }
+
+    #[test]
+    fn noeviction_reclaims_only_expired_keys_before_refusing_a_write() {
+        let db = Database::new();
+        db.set("a", text("x".repeat(1000).as_str())).unwrap();
+        db.set_eviction(Eviction {
+            maxmemory: db.dataset_bytes().unwrap(),
+            policy: Policy::NoEviction,
+            subtree_depth: 0,
+        })
+        .unwrap();
+        db.set("b", text("y")).unwrap();
+        // Nothing has an expiry, so the write is refused straight away
+        assert!(db.set("c", text("z")).is_err());
+
+        assert!(db.expire_at("a", 1).unwrap());
+        db.set("c", text("z")).unwrap();
+        assert!(db.get("a").unwrap().is_none());
+        assert_eq!(db.key_count().unwrap(), 2);
+        assert_eq!(db.evicted_keys(), 0);
+    }
+
+    #[test]
+    fn reads_record_access_on_the_key_only_under_lru_or_lfu() {
+        let db = Database::new();
+        db.set("a:b", text("x")).unwrap();
+        let last_access = |key: &str| {
+            let root = db.get_root().read().unwrap();
+            let mut node = &*root;
+            for part in key.split(':') {
+                node = &node.c.as_ref().unwrap()[part];
+            }
+            node.a.load(Ordering::Relaxed)
+        };
+        let reset = || {
+            let root = db.get_root().read().unwrap();
+            let a = &root.c.as_ref().unwrap()["a"];
+            a.a.store(0, Ordering::Relaxed);
+            a.c.as_ref().unwrap()["b"].a.store(0, Ordering::Relaxed);
+        };
+
+        reset();
+        db.get("a:b").unwrap();
+        assert_eq!((last_access("a"), last_access("a:b")), (0, 0));
+
+        let lru = Eviction {
+            maxmemory: 0,
+            policy: Policy::AllKeysLru,
+            subtree_depth: 0,
+        };
+        db.set_eviction(lru).unwrap();
+        db.get("a:b").unwrap();
+        assert_eq!(last_access("a"), 0);
+        assert!(last_access("a:b") > 0);
+
+        // Subtree eviction compares the subtree's node instead
+        reset();
+        db.set_eviction(Eviction {
+            subtree_depth: 1,
+            ..lru
+        })
+        .unwrap();
+        db.get("a:b").unwrap();
+        assert!(last_access("a") > 0);
+        assert_eq!(last_access("a:b"), 0);
+    }
+
+    #[test]
+    fn the_listener_hears_changes_in_the_order_they_were_made() {
+        // Writers race to set and delete one key. Deletes are only reported
+        // when something was removed, so heard in order the events alternate
+        // and end where the key does.
+        let db = Arc::new(Database::new());
+        let heard = Arc::new(Mutex::new((false, Vec::new())));
+        db.set_listener({
+            let heard = Arc::clone(&heard);
+            move |event, key| {
+                let mut heard = heard.lock().unwrap();
+                let exists = heard.0;
+                if event == Event::Del && !exists {
+                    heard
+                        .1
+                        .push(format!("{} {key} with exists={exists}", event.name()));
+                }
+                heard.0 = event == Event::Set;
+            }
+        });
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    for _ in 0..2000 {
+                        db.set("k", text("v")).unwrap();
+                        db.delete("k").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let heard = heard.lock().unwrap();
+        assert_eq!(heard.1, Vec::<String>::new());
+        assert_eq!(heard.0, db.get("k").unwrap().is_some());
+    }
+
+    /// A database whose listener records every event as `name key`.
+    fn recording() -> (Database, Arc<Mutex<Vec<String>>>) {
+        let db = Database::new();
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&heard);
+        db.set_listener(move |event, key| {
+            log.lock().unwrap().push(format!("{} {key}", event.name()))
+        });
+        (db, heard)
+    }
+
+    #[test]
+    fn reads_reclaim_expired_keys_and_report_them_once() {
+        let (db, heard) = recording();
+        db.set("k", text("v")).unwrap();
+        assert!(db.expire_at("k", 1).unwrap());
+        heard.lock().unwrap().clear();
+
+        assert!(db.get("k").unwrap().is_none());
+        assert!(db.get("k").unwrap().is_none());
+        assert_eq!(db.key_count().unwrap(), 0);
+        assert_eq!(*heard.lock().unwrap(), ["expired k"]);
+        assert_totals(&db);
+    }
+
+    #[test]
+    fn reads_leave_expired_keys_alone_when_told_to() {
+        let (db, heard) = recording();
+        db.set_expire_on_read(false);
+        db.set("k", text("v")).unwrap();
+        assert!(db.expire_at("k", 1).unwrap());
+        heard.lock().unwrap().clear();
+
+        assert!(db.get("k").unwrap().is_none());
+        assert_eq!(db.expiry("k").unwrap(), None);
+        assert_eq!(db.key_count().unwrap(), 1);
+        assert!(heard.lock().unwrap().is_empty());
     }
 }
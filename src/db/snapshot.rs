@@ -0,0 +1,405 @@
+use super::core::{self, Node, Value};
+use super::stream::{Fields, Group, Pending, Stream, StreamId};
+use super::Error;
+use serde_json::{json, Map, Value as Json};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+//
+// ─── Snapshot Format ─────────────────────────────────────────────────────────────
+//
+// The trie is written as nested JSON objects mirroring `Node`:
+// { "v": {"text": "abc"}, "t": 123, "c": { "users": { ... } } }
+// Empty fields are omitted to keep dumps small.
+//
+
+fn value_to_json(val: &Value) -> Json {
+    match val {
+        Value::Text(s) => json!({ "text": s }),
+        Value::List(list) => json!({ "list": list }),
+        Value::Set(set) => json!({ "set": set.iter().collect::<Vec<_>>() }),
+        Value::Stream(stream) => json!({ "stream": stream_to_json(stream) }),
+    }
+}
+
+// A stream is { "last": "ms-seq", "entries": [["ms-seq", ["f", "v", ...]], ...],
+//   "groups": { name: { "last": id, "consumers": { name: seen_ms },
+//     "pending": [[id, consumer, delivered_ms, deliveries], ...] } } }
+
+fn stream_to_json(stream: &Stream) -> Json {
+    let entries: Vec<Json> = stream
+        .entries
+        .iter()
+        .map(|(id, fields)| {
+            let flat: Vec<&str> = fields
+                .iter()
+                .flat_map(|(f, v)| [f.as_str(), v.as_str()])
+                .collect();
+            json!([id.to_string(), flat])
+        })
+        .collect();
+    let groups: Map<String, Json> = stream
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            let pending: Vec<Json> = group
+                .pending
+                .iter()
+                .map(|(id, p)| json!([id.to_string(), p.consumer, p.delivered_ms, p.deliveries]))
+                .collect();
+            let group = json!({
+                "last": group.last_delivered.to_string(),
+                "consumers": group.consumers,
+                "pending": pending,
+            });
+            (name.clone(), group)
+        })
+        .collect();
+    json!({ "last": stream.last_id.to_string(), "entries": entries, "groups": groups })
+}
+
+fn stream_from_json(json: &Json) -> io::Result<Stream> {
+    let id = |json: &Json| {
+        json.as_str()
+            .and_then(|s| StreamId::parse(s, 0))
+            .ok_or_else(|| invalid("expected stream id"))
+    };
+    let string = |json: &Json| {
+        json.as_str()
+            .map(str::to_string)
+            .ok_or_else(|| invalid("expected string"))
+    };
+    let number = |json: &Json| json.as_u64().ok_or_else(|| invalid("expected number"));
+    let mut stream = Stream::new();
+    stream.last_id = id(&json["last"])?;
+    for entry in array(&json["entries"])? {
+        let flat = array(&entry[1])?;
+        let mut fields: Fields = flat
+            .chunks(2)
+            .map(|pair| {
+                Ok((
+                    string(&pair[0])?,
+                    string(pair.get(1).unwrap_or(&Json::Null))?,
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+        // Collecting through `Result` over-allocates
+        fields.shrink_to_fit();
+        stream.entries.insert(id(&entry[0])?, fields);
+    }
+    if let Some(groups) = json.get("groups").and_then(Json::as_object) {
+        for (name, json) in groups {
+            let mut group = Group {
+                last_delivered: id(&json["last"])?,
+                ..Group::default()
+            };
+            if let Some(consumers) = json.get("consumers").and_then(Json::as_object) {
+                for (consumer, seen) in consumers {
+                    group.consumers.insert(consumer.clone(), number(seen)?);
+                }
+            }
+            for p in array(&json["pending"])? {
+                let pending = Pending {
+                    consumer: string(&p[1])?,
+                    delivered_ms: number(&p[2])?,
+                    deliveries: number(&p[3])?,
+                };
+                group.pending.insert(id(&p[0])?, pending);
+            }
+            stream.groups.insert(name.clone(), group);
+        }
+    }
+    stream.refresh_bytes();
+    Ok(stream)
+}
+
+fn array(json: &Json) -> io::Result<&Vec<Json>> {
+    json.as_array().ok_or_else(|| invalid("expected array"))
+}
+
+fn value_from_json(json: &Json) -> io::Result<Value> {
+    let strings = |items: &Json| -> io::Result<Vec<String>> {
+        items
+            .as_array()
+            .ok_or_else(|| invalid("expected array"))?
+            .iter()
+            .map(|s| {
+                s.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| invalid("expected string"))
+            })
+            .collect()
+    };
+    if let Some(s) = json.get("text") {
+        let s = s.as_str().ok_or_else(|| invalid("expected string"))?;
+        return Ok(Value::Text(s.to_string()));
+    }
+    if let Some(items) = json.get("list") {
+        return Ok(Value::List(strings(items)?.into()));
+    }
+    if let Some(items) = json.get("set") {
+        return Ok(Value::Set(
+            strings(items)?.into_iter().collect::<HashSet<_>>(),
+        ));
+    }
+    if let Some(stream) = json.get("stream") {
+        return Ok(Value::Stream(Box::new(stream_from_json(stream)?)));
+    }
+    Err(invalid("unknown value type"))
+}
+
+/// Keys already expired at `now_ms` are left out, keeping their children.
+fn node_to_json(node: &Node, now_ms: u64) -> Json {
+    let mut obj = Map::new();
+    if let (Some(ref v), false) = (&node.v, node.is_expired(now_ms)) {
+        obj.insert("v".to_string(), value_to_json(v));
+        if node.r != 0 {
+            obj.insert("r".to_string(), json!(node.r));
+        }
+        if let Some(t) = node.t {
+            obj.insert("t".to_string(), json!(t));
+        }
+    }
+    if let Some(ref children) = node.c {
+        let c: Map<String, Json> = children
+            .iter()
+            .map(|(k, child)| (k.clone(), node_to_json(child, now_ms)))
+            .collect();
+        obj.insert("c".to_string(), Json::Object(c));
+    }
+    Json::Object(obj)
+}
+
+fn node_from_json(json: &Json) -> io::Result<Node> {
+    let mut node = Node::new();
+    if let Some(v) = json.get("v") {
+        node.v = Some(value_from_json(v)?);
+    }
+    if let Some(r) = json.get("r") {
+        node.r = r.as_u64().ok_or_else(|| invalid("expected version"))?;
+    }
+    if let Some(t) = json.get("t") {
+        node.t = Some(t.as_u64().ok_or_else(|| invalid("expected ttl"))?);
+    }
+    if let Some(c) = json.get("c") {
+        let c = c.as_object().ok_or_else(|| invalid("expected children"))?;
+        let mut children = HashMap::with_capacity(c.len());
+        for (k, child) in c {
+            children.insert(k.clone(), Box::new(node_from_json(child)?));
+        }
+        node.c = Some(children);
+    }
+    node.refresh_usage();
+    Ok(node)
+}
+
+#[inline]
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Bad snapshot: {msg}"))
+}
+
+//
+// ─── Save / Load ─────────────────────────────────────────────────────────────────
+//
+
+/// Serialize a trie or subtree, e.g. to ship it to a replica. Value
+/// versions go along, and so does the version counter (the top node's
+/// write version), so versions handed out before a restart are never handed
+/// out again for a different value.
+pub fn to_bytes(root: &Node) -> Vec<u8> {
+    let mut json = node_to_json(root, core::now_ms());
+    json["w"] = json!(root.w);
+    json.to_string().into_bytes()
+}
+
+/// Parse a trie or subtree serialized by `to_bytes`.
+pub fn node_from_bytes(bytes: &[u8]) -> io::Result<Node> {
+    node_from_json(&serde_json::from_slice(bytes)?)
+}
+
+/// Write the whole trie to `path`. Goes through a temp file + rename so a crash
+/// mid-write never leaves a truncated snapshot behind.
+pub fn save(root: &RwLock<Node>, path: &Path) -> io::Result<()> {
+    let bytes = {
+        let guard = root.read().map_err(|_| Error::LockPoisoned)?;
+        to_bytes(&guard)
+    };
+    write_atomic(path, &bytes)
+}
+
+/// Replace `path` with `bytes` durably: write `<path>.tmp`, fsync it, rename
+/// it over `path`, then fsync the directory so the rename survives a crash.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    // Appended rather than swapped in for the extension, so `dump.a` and
+    // `dump.b` never share a temp file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Replace the trie with the contents of `path`. The version counter carries
+/// on from the saved one, or from the current one if that is higher.
+pub fn load(root: &RwLock<Node>, path: &Path) -> io::Result<()> {
+    load_bytes(root, &std::fs::read(path)?)
+}
+
+/// Replace the trie with a serialized one, as produced by `to_bytes`, the
+/// version counter carrying on as for `load`.
+pub fn load_bytes(root: &RwLock<Node>, bytes: &[u8]) -> io::Result<()> {
+    let json: Json = serde_json::from_slice(bytes)?;
+    let saved = match json.get("w") {
+        Some(w) => w.as_u64().ok_or_else(|| invalid("expected version"))?,
+        None => 0,
+    };
+    let mut node = node_from_json(&json)?;
+    let mut guard = root.write().map_err(|_| Error::LockPoisoned)?;
+    // Everything changed, as far as WATCH is concerned.
+    core::restamp(&mut node, saved.max(guard.w) + 1, false);
+    *guard = node;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, node_from_bytes, save};
+    use crate::db::{Database, Keyspace, Value};
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flashtree-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    fn get_text(db: &Database, key: &str) -> Option<String> {
+        match db.get(key).unwrap() {
+            Some(Value::Text(s)) => Some(s),
+            other => panic!("{key}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn snapshots_with_the_same_stem_do_not_share_a_temp_file() {
+        let dir = scratch_dir("stem");
+        let (a, b) = (Database::new(), Database::new());
+        a.set("k", text("from-a")).unwrap();
+        b.set("k", text("from-b")).unwrap();
+        save(a.get_root(), &dir.join("dump.a")).unwrap();
+        save(b.get_root(), &dir.join("dump.b")).unwrap();
+
+        let loaded = Database::new();
+        load(loaded.get_root(), &dir.join("dump.a")).unwrap();
+        assert_eq!(get_text(&loaded, "k").as_deref(), Some("from-a"));
+        load(loaded.get_root(), &dir.join("dump.b")).unwrap();
+        assert_eq!(get_text(&loaded, "k").as_deref(), Some("from-b"));
+
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["dump.a", "dump.b"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_round_trips_values_and_nesting() {
+        let dir = scratch_dir("round-trip");
+        let path = dir.join("dump.json");
+        let db = Database::new();
+        db.set("users:42:name", text("bob")).unwrap();
+        db.set("users:43", text("alice")).unwrap();
+        let queue = vec!["a".to_string(), "b".to_string()];
+        db.set("queue", Value::List(queue.clone().into())).unwrap();
+        save(db.get_root(), &path).unwrap();
+
+        let loaded = Database::new();
+        load(loaded.get_root(), &path).unwrap();
+        assert_eq!(get_text(&loaded, "users:42:name").as_deref(), Some("bob"));
+        assert_eq!(get_text(&loaded, "users:43").as_deref(), Some("alice"));
+        assert!(loaded.get("users:42").unwrap().is_none());
+        match loaded.get("queue").unwrap() {
+            Some(Value::List(items)) => assert_eq!(items, queue),
+            other => panic!("queue: {other:?}"),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expired_keys_are_left_out_but_their_children_are_kept() {
+        let dir = scratch_dir("expired");
+        let path = dir.join("dump.json");
+        let db = Database::new();
+        db.set("a", text("gone")).unwrap();
+        db.set("a:b", text("kept")).unwrap();
+        db.set("c", text("later")).unwrap();
+        assert!(db.expire_at("a", 1).unwrap());
+        assert!(db.expire_at("c", u64::MAX).unwrap());
+        save(db.get_root(), &path).unwrap();
+
+        let loaded = Database::new();
+        load(loaded.get_root(), &path).unwrap();
+        assert!(loaded.get("a").unwrap().is_none());
+        assert_eq!(loaded.get_root().read().unwrap().k, 2);
+        assert_eq!(get_text(&loaded, "a:b").as_deref(), Some("kept"));
+        assert_eq!(loaded.expiry("c").unwrap(), Some(Some(u64::MAX)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn versions_survive_a_reload_and_are_not_handed_out_again() {
+        let dir = scratch_dir("versions");
+        let path = dir.join("dump.json");
+        let db = Database::new();
+        db.set("a", text("1")).unwrap();
+        db.set("b", text("2")).unwrap();
+        let (_, a) = db.get_versioned("a").unwrap().unwrap();
+        let (_, b) = db.get_versioned("b").unwrap().unwrap();
+        save(db.get_root(), &path).unwrap();
+
+        let loaded = Database::new();
+        load(loaded.get_root(), &path).unwrap();
+        assert_eq!(loaded.get_versioned("a").unwrap().unwrap().1, a);
+        loaded.set("c", text("3")).unwrap();
+        let (_, c) = loaded.get_versioned("c").unwrap().unwrap();
+        assert!(c > a.max(b));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restored_values_count_as_new_writes() {
+        let source = Database::new();
+        for i in 0..50 {
+            source.set("src:k", text(&i.to_string())).unwrap();
+        }
+        let (_, old) = source.get_versioned("src:k").unwrap().unwrap();
+        let dump = source.dump("src").unwrap().unwrap();
+
+        let db = Database::new();
+        db.set("other", text("x")).unwrap();
+        let subtree = node_from_bytes(&dump).unwrap();
+        assert!(db.restore("dst", subtree, false).unwrap());
+        let (_, restored) = db.get_versioned("dst:k").unwrap().unwrap();
+        assert!(restored < old);
+        db.set("dst:k", text("y")).unwrap();
+        assert!(db.get_versioned("dst:k").unwrap().unwrap().1 > restored);
+    }
+}
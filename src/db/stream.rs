@@ -0,0 +1,466 @@
+use super::error::{Error, Result};
+use crate::allocator::alloc_size;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem::size_of;
+use std::ops::Bound;
+
+/// `field value` pairs of one entry, in the order they were added.
+pub type Fields = Vec<(String, String)>;
+
+/// Entry ID: milliseconds, then a sequence number within the millisecond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// `ms-seq`, or just `ms` with the sequence taken as `default_seq`.
+    pub fn parse(s: &str, default_seq: u64) -> Option<Self> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId {
+                ms: ms.parse().ok()?,
+                seq: seq.parse().ok()?,
+            }),
+            None => Some(StreamId {
+                ms: s.parse().ok()?,
+                seq: default_seq,
+            }),
+        }
+    }
+
+    /// Start of a range. `-` and `+` are the first and last possible IDs.
+    pub fn parse_start(s: &str) -> Option<Self> {
+        StreamId::parse_bound(s, 0)
+    }
+
+    /// End of a range, as `parse_start`; a bare `ms` covers the whole
+    /// millisecond.
+    pub fn parse_end(s: &str) -> Option<Self> {
+        StreamId::parse_bound(s, u64::MAX)
+    }
+
+    fn parse_bound(s: &str, default_seq: u64) -> Option<Self> {
+        match s {
+            "-" => Some(StreamId::MIN),
+            "+" => Some(StreamId::MAX),
+            _ => StreamId::parse(s, default_seq),
+        }
+    }
+
+    fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// ID requested by XADD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewId {
+    /// `*`: the current time, or just past the last ID if the clock lags
+    Auto,
+    /// `ms-*`: a given millisecond with the next free sequence number
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewId {
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "*" {
+            return Some(NewId::Auto);
+        }
+        if let Some(ms) = s.strip_suffix("-*") {
+            return ms.parse().ok().map(NewId::AutoSeq);
+        }
+        StreamId::parse(s, 0).map(NewId::Explicit)
+    }
+}
+
+/// How XADD and XTRIM cut a stream down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trim {
+    /// Keep at most this many of the newest entries
+    MaxLen(usize),
+    /// Drop entries with IDs below this one
+    MinId(StreamId),
+}
+
+/// An entry delivered to a group consumer and not yet acknowledged.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub consumer: String,
+    /// Last delivery, unix milliseconds
+    pub delivered_ms: u64,
+    pub deliveries: u64,
+}
+
+/// A consumer group: how far it has read, and what is still unacknowledged.
+#[derive(Debug, Clone, Default)]
+pub struct Group {
+    pub last_delivered: StreamId,
+    pub pending: BTreeMap<StreamId, Pending>,
+    /// Consumer names with the last time each read, unix milliseconds
+    pub consumers: BTreeMap<String, u64>,
+}
+
+/// Append-only log of entries with ever-increasing IDs.
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    pub last_id: StreamId,
+    pub groups: BTreeMap<String, Group>,
+    /// Heap bytes owned by the stream, kept current on every change so memory
+    /// accounting stays O(1) however long the stream grows.
+    bytes: usize,
+}
+
+//
+// ─── Memory ──────────────────────────────────────────────────────────────────────
+//
+
+fn entry_size(fields: &Fields) -> usize {
+    let mut total = size_of::<(StreamId, Fields)>()
+        + alloc_size(fields.capacity() * size_of::<(String, String)>());
+    for (field, value) in fields {
+        total += alloc_size(field.capacity()) + alloc_size(value.capacity());
+    }
+    total
+}
+
+fn pending_size(pending: &Pending) -> usize {
+    size_of::<(StreamId, Pending)>() + alloc_size(pending.consumer.capacity())
+}
+
+fn name_size(name: &str) -> usize {
+    size_of::<(String, u64)>() + alloc_size(name.len())
+}
+
+fn group_size(name: &str, group: &Group) -> usize {
+    let mut total = size_of::<(String, Group)>() + alloc_size(name.len());
+    total += group.pending.values().map(pending_size).sum::<usize>();
+    total += group.consumers.keys().map(|c| name_size(c)).sum::<usize>();
+    total
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Stream::default()
+    }
+
+    /// Heap bytes owned by the stream.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Recompute `bytes` from scratch, e.g. after loading a snapshot.
+    pub fn refresh_bytes(&mut self) {
+        self.bytes = self.entries.values().map(entry_size).sum::<usize>()
+            + self
+                .groups
+                .iter()
+                .map(|(name, group)| group_size(name, group))
+                .sum::<usize>();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    //
+    // ─── Entries ─────────────────────────────────────────────────────────────────
+    //
+
+    /// Append an entry. `Err(StreamIdTooSmall)` unless the ID is above every
+    /// ID the stream has ever had.
+    pub fn add(&mut self, id: NewId, fields: Fields, now_ms: u64) -> Result<StreamId> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now_ms > last.ms => StreamId { ms: now_ms, seq: 0 },
+            NewId::Auto => last.next().ok_or(Error::StreamIdTooSmall)?,
+            NewId::AutoSeq(ms) if ms > last.ms => StreamId { ms, seq: 0 },
+            NewId::AutoSeq(ms) if ms == last.ms => last.next().ok_or(Error::StreamIdTooSmall)?,
+            NewId::AutoSeq(_) => return Err(Error::StreamIdTooSmall),
+            NewId::Explicit(id) => id,
+        };
+        if id <= last {
+            return Err(Error::StreamIdTooSmall);
+        }
+        self.bytes += entry_size(&fields);
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Entries from `start` to `end` inclusive, at most `count`.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, Fields)> {
+        if start > end {
+            return Vec::new();
+        }
+        self.entries
+            .range(start..=end)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect()
+    }
+
+    /// Entries after `id`, at most `count`.
+    pub fn after(&self, id: StreamId, count: Option<usize>) -> Vec<(StreamId, Fields)> {
+        self.entries
+            .range((Bound::Excluded(id), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect()
+    }
+
+    /// Drop the oldest entries as `trim` says. Returns how many went.
+    pub fn trim(&mut self, trim: Trim) -> usize {
+        match trim {
+            Trim::MaxLen(max) => self.trim_maxlen(max),
+            Trim::MinId(min) => self.trim_minid(min),
+        }
+    }
+
+    /// Drop the oldest entries until at most `max` remain. Returns how many went.
+    pub fn trim_maxlen(&mut self, max: usize) -> usize {
+        let mut removed = 0;
+        while self.entries.len() > max {
+            if let Some((_, fields)) = self.entries.pop_first() {
+                self.bytes -= entry_size(&fields);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Drop entries with IDs below `min`. Returns how many went.
+    pub fn trim_minid(&mut self, min: StreamId) -> usize {
+        let kept = self.entries.split_off(&min);
+        let removed = std::mem::replace(&mut self.entries, kept);
+        self.bytes -= removed.values().map(entry_size).sum::<usize>();
+        removed.len()
+    }
+
+    //
+    // ─── Consumer Groups ─────────────────────────────────────────────────────────
+    //
+
+    fn group_mut(&mut self, name: &str) -> Result<&mut Group> {
+        self.groups.get_mut(name).ok_or(Error::NoGroup)
+    }
+
+    pub fn group(&self, name: &str) -> Result<&Group> {
+        self.groups.get(name).ok_or(Error::NoGroup)
+    }
+
+    /// Create a group that will deliver entries after `last_delivered`.
+    pub fn create_group(&mut self, name: &str, last_delivered: StreamId) -> Result<()> {
+        if self.groups.contains_key(name) {
+            return Err(Error::GroupExists);
+        }
+        let group = Group {
+            last_delivered,
+            ..Group::default()
+        };
+        self.bytes += group_size(name, &group);
+        self.groups.insert(name.to_string(), group);
+        Ok(())
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        match self.groups.remove(name) {
+            Some(group) => {
+                self.bytes -= group_size(name, &group);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_group_id(&mut self, name: &str, last_delivered: StreamId) -> Result<()> {
+        self.group_mut(name)?.last_delivered = last_delivered;
+        Ok(())
+    }
+
+    /// Register `consumer` in `group`; false if it already existed.
+    pub fn create_consumer(&mut self, group: &str, consumer: &str, now_ms: u64) -> Result<bool> {
+        let group = self.groups.get_mut(group).ok_or(Error::NoGroup)?;
+        if group.consumers.contains_key(consumer) {
+            return Ok(false);
+        }
+        group.consumers.insert(consumer.to_string(), now_ms);
+        self.bytes += name_size(consumer);
+        Ok(true)
+    }
+
+    /// Remove `consumer` and its pending entries. Returns how many were pending.
+    pub fn delete_consumer(&mut self, group: &str, consumer: &str) -> Result<usize> {
+        let group = self.groups.get_mut(group).ok_or(Error::NoGroup)?;
+        if group.consumers.remove(consumer).is_none() {
+            return Ok(0);
+        }
+        let mut freed = name_size(consumer);
+        let mut dropped = 0;
+        group.pending.retain(|_, p| {
+            if p.consumer != consumer {
+                return true;
+            }
+            freed += pending_size(p);
+            dropped += 1;
+            false
+        });
+        self.bytes -= freed;
+        Ok(dropped)
+    }
+
+    /// Read for `consumer` in `group`. With `after == None`, deliver entries
+    /// never delivered to the group (`>`) and, unless `noack`, record them as
+    /// pending. Otherwise re-read the consumer's own pending entries after
+    /// that ID; those deleted from the stream since come back as `None`.
+    pub fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: Option<StreamId>,
+        count: Option<usize>,
+        noack: bool,
+        now_ms: u64,
+    ) -> Result<Vec<(StreamId, Option<Fields>)>> {
+        self.create_consumer(group, consumer, now_ms)?;
+        let entries = &self.entries;
+        let group = self.groups.get_mut(group).ok_or(Error::NoGroup)?;
+        group.consumers.insert(consumer.to_string(), now_ms);
+        let count = count.unwrap_or(usize::MAX);
+        let Some(after) = after else {
+            let delivered: Vec<(StreamId, Option<Fields>)> = entries
+                .range((Bound::Excluded(group.last_delivered), Bound::Unbounded))
+                .take(count)
+                .map(|(id, fields)| (*id, Some(fields.clone())))
+                .collect();
+            if let Some((last, _)) = delivered.last() {
+                group.last_delivered = *last;
+            }
+            if !noack {
+                for (id, _) in &delivered {
+                    let pending = Pending {
+                        consumer: consumer.to_string(),
+                        delivered_ms: now_ms,
+                        deliveries: 1,
+                    };
+                    self.bytes += pending_size(&pending);
+                    if let Some(old) = group.pending.insert(*id, pending) {
+                        self.bytes -= pending_size(&old);
+                    }
+                }
+            }
+            return Ok(delivered);
+        };
+        let history = group
+            .pending
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, p)| p.consumer == consumer)
+            .take(count)
+            .map(|(id, _)| (*id, entries.get(id).cloned()))
+            .collect();
+        Ok(history)
+    }
+
+    /// Acknowledge entries for `group`. Returns how many were pending.
+    pub fn ack(&mut self, group: &str, ids: &[StreamId]) -> Result<usize> {
+        let group = self.groups.get_mut(group).ok_or(Error::NoGroup)?;
+        let mut acked = 0;
+        for id in ids {
+            if let Some(pending) = group.pending.remove(id) {
+                self.bytes -= pending_size(&pending);
+                acked += 1;
+            }
+        }
+        Ok(acked)
+    }
+
+    /// Pending entries of `group` from `start` to `end`, at most `count`,
+    /// optionally for one consumer only.
+    pub fn pending(
+        &self,
+        group: &str,
+        start: StreamId,
+        end: StreamId,
+        count: usize,
+        consumer: Option<&str>,
+    ) -> Result<Vec<(StreamId, &Pending)>> {
+        let group = self.group(group)?;
+        if start > end {
+            return Ok(Vec::new());
+        }
+        Ok(group
+            .pending
+            .range(start..=end)
+            .filter(|(_, p)| consumer.is_none_or(|c| p.consumer == c))
+            .take(count)
+            .map(|(id, p)| (*id, p))
+            .collect())
+    }
+
+    /// Take over pending entries idle for at least `min_idle_ms`, e.g. from a
+    /// crashed consumer. Entries deleted from the stream meanwhile are dropped
+    /// from the pending list instead. Returns the claimed entries.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        min_idle_ms: u64,
+        ids: &[StreamId],
+        now_ms: u64,
+    ) -> Result<Vec<(StreamId, Fields)>> {
+        self.create_consumer(group, consumer, now_ms)?;
+        let group = self.groups.get_mut(group).ok_or(Error::NoGroup)?;
+        let mut claimed = Vec::new();
+        for id in ids {
+            let Some(pending) = group.pending.get_mut(id) else {
+                continue;
+            };
+            if now_ms.saturating_sub(pending.delivered_ms) < min_idle_ms {
+                continue;
+            }
+            let Some(fields) = self.entries.get(id) else {
+                if let Some(gone) = group.pending.remove(id) {
+                    self.bytes -= pending_size(&gone);
+                }
+                continue;
+            };
+            self.bytes -= pending_size(pending);
+            pending.consumer = consumer.to_string();
+            pending.delivered_ms = now_ms;
+            pending.deliveries += 1;
+            self.bytes += pending_size(pending);
+            claimed.push((*id, fields.clone()));
+        }
+        Ok(claimed)
+    }
+}
@@ -0,0 +1,30 @@
+//! FlashTree: an in-memory key-value store where keys are `:`-separated
+//! paths in a trie.
+//!
+//! The trie can be embedded directly:
+//!
+//! ```
+//! use word_trie::{Database, Keyspace, Value};
+//!
+//! let db = Database::new();
+//! db.set("users:42:name", Value::Text("bob".to_string())).unwrap();
+//! let keys: Vec<String> = db.keys_prefix("users").unwrap().collect();
+//! assert_eq!(keys, ["users:42:name"]);
+//! ```
+//!
+//! The network server, ACLs and command engine live behind the `server`
+//! feature (on by default).
+
+pub mod allocator;
+pub mod db;
+
+#[cfg(feature = "server")]
+pub mod acl;
+#[cfg(feature = "server")]
+pub mod commands;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(feature = "server")]
+pub mod server;
+
+pub use db::{Database, End, Error, Event, Eviction, Keyspace, Policy, Result, Transaction, Value};
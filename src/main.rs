@@ -1,12 +1,28 @@
-mod db;
-mod commands;
-mod server;
-
-use crate::db::Database;
 use std::sync::Arc;
+use word_trie::config::Config;
+use word_trie::db::{self, Database};
+use word_trie::server;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
     let db = Arc::new(Database::new());
-    server::start("0.0.0.0:2002", db).await
-}
\ No newline at end of file
+    db.set_eviction(config.eviction())?;
+    if let Some(ref path) = config.snapshot_path {
+        if path.exists() {
+            db::snapshot::load(db.get_root(), path)?;
+            println!("Loaded snapshot from {}", path.display());
+        }
+    }
+    server::start(config, db).await
+}
@@ -0,0 +1,179 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Why a new connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    MaxClients,
+    MaxClientsPerIp,
+}
+
+impl Rejection {
+    pub fn message(self) -> &'static [u8] {
+        match self {
+            Rejection::MaxClients => b"-ERR max clients reached\n",
+            Rejection::MaxClientsPerIp => b"-ERR max clients per IP reached\n",
+        }
+    }
+}
+
+/// Connection admission control and live client counters.
+#[derive(Debug)]
+pub struct Clients {
+    max: usize,
+    max_per_ip: usize,
+    active: AtomicUsize,
+    total: AtomicU64,
+    rejected: AtomicU64,
+    per_ip: DashMap<IpAddr, usize>,
+}
+
+impl Clients {
+    /// `max_per_ip == 0` disables the per-address limit.
+    pub fn new(max: usize, max_per_ip: usize) -> Self {
+        Clients {
+            max,
+            max_per_ip,
+            active: AtomicUsize::new(0),
+            total: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            per_ip: DashMap::new(),
+        }
+    }
+
+    /// Reserve a slot for a client from `ip`. The slot is released when the
+    /// returned guard is dropped. Local clients (Unix socket) have no `ip` and
+    /// only count towards the global limit.
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<ClientGuard<'_>, Rejection> {
+        if self.active.fetch_add(1, Ordering::AcqRel) >= self.max {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejection::MaxClients);
+        }
+        if let Some(ip) = ip.filter(|_| self.max_per_ip > 0) {
+            let mut count = self.per_ip.entry(ip).or_insert(0);
+            if *count >= self.max_per_ip {
+                drop(count);
+                self.active.fetch_sub(1, Ordering::AcqRel);
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(Rejection::MaxClientsPerIp);
+            }
+            *count += 1;
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        Ok(ClientGuard { clients: self, ip })
+    }
+
+    pub fn connected(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    pub fn total_received(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip.filter(|_| self.max_per_ip > 0) {
+            // Drop the entry at zero so the map doesn't grow with every address seen.
+            self.per_ip.remove_if_mut(&ip, |_, count| {
+                *count -= 1;
+                *count == 0
+            });
+        }
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Held for the lifetime of an admitted connection.
+#[derive(Debug)]
+pub struct ClientGuard<'a> {
+    clients: &'a Clients,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.clients.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clients, Rejection};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn per_ip_slots_are_admitted_and_released() {
+        let clients = Clients::new(10, 2);
+        let a = clients.admit(ip("10.0.0.1")).unwrap();
+        let b = clients.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            clients.admit(ip("10.0.0.1")).unwrap_err(),
+            Rejection::MaxClientsPerIp
+        );
+        let other = clients.admit(ip("10.0.0.2")).unwrap();
+        assert_eq!(clients.connected(), 3);
+
+        drop(a);
+        let c = clients.admit(ip("10.0.0.1")).unwrap();
+        drop((b, c, other));
+        assert_eq!(clients.connected(), 0);
+        assert!(clients.per_ip.is_empty());
+        assert_eq!((clients.total_received(), clients.rejected()), (4, 1));
+    }
+
+    #[test]
+    fn the_global_limit_applies_across_addresses() {
+        let clients = Clients::new(1, 0);
+        let first = clients.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            clients.admit(ip("10.0.0.2")).unwrap_err(),
+            Rejection::MaxClients
+        );
+        assert_eq!(clients.connected(), 1);
+        drop(first);
+        assert!(clients.admit(ip("10.0.0.2")).is_ok());
+    }
+
+    #[test]
+    fn local_clients_only_count_towards_the_global_limit() {
+        let clients = Clients::new(3, 1);
+        let _remote = clients.admit(ip("10.0.0.1")).unwrap();
+        let _a = clients.admit(None).unwrap();
+        let _b = clients.admit(None).unwrap();
+        assert_eq!(clients.admit(None).unwrap_err(), Rejection::MaxClients);
+        assert!(clients
+            .per_ip
+            .get(&ip("10.0.0.1").unwrap())
+            .is_some_and(|n| *n == 1));
+    }
+
+    #[test]
+    fn rejections_tell_the_client_why() {
+        assert_eq!(
+            Rejection::MaxClients.message(),
+            b"-ERR max clients reached\n"
+        );
+        assert_eq!(
+            Rejection::MaxClientsPerIp.message(),
+            b"-ERR max clients per IP reached\n"
+        );
+    }
+}
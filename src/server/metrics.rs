@@ -0,0 +1,366 @@
+use super::{shutdown, Shared};
+use crate::allocator;
+use crate::commands::LATENCY_BUCKETS_USEC;
+use crate::db;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8192;
+
+/// Serve `GET /metrics` in Prometheus text format until shutdown.
+pub async fn serve(addr: String, shared: Arc<Shared>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Metrics endpoint listening on http://{}/metrics", addr);
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown::wait(&mut shutdown_rx) => return Ok(()),
+        };
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(stream, &shared).await {
+                eprintln!("Metrics error for {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_scrape(mut stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    // Only the request line matters; read until the end of headers.
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST {
+        let n = match timeout(REQUEST_TIMEOUT, stream.read(&mut chunk)).await {
+            Ok(res) => res?,
+            Err(_) => return Ok(()),
+        };
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request_line = buf.split(|&b| b == b'\n').next().unwrap_or(&[]);
+    let mut fields = std::str::from_utf8(request_line)
+        .unwrap_or("")
+        .split_whitespace();
+    let (method, path) = (fields.next(), fields.next());
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => match render(shared) {
+            Ok(body) => ("200 OK", body),
+            Err(e) => ("500 Internal Server Error", format!("{e}\n")),
+        },
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+Content-Length: {}\r\n\
+Connection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+//
+// ─── Exposition ──────────────────────────────────────────────────────────────────
+//
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn render(shared: &Shared) -> db::Result<String> {
+    let mut out = String::with_capacity(8192);
+    let stats = &shared.stats;
+    let clients = &shared.clients;
+    let database = &shared.database;
+
+    let simple: [(&str, &str, &str, f64); 14] = [
+        (
+            "flashtree_uptime_seconds",
+            "gauge",
+            "Seconds since the server started.",
+            shared.started.elapsed().as_secs() as f64,
+        ),
+        // Connections
+        (
+            "flashtree_connected_clients",
+            "gauge",
+            "Currently connected clients.",
+            clients.connected() as f64,
+        ),
+        (
+            "flashtree_max_clients",
+            "gauge",
+            "Configured client limit.",
+            clients.max() as f64,
+        ),
+        (
+            "flashtree_connections_received_total",
+            "counter",
+            "Connections admitted since start.",
+            clients.total_received() as f64,
+        ),
+        (
+            "flashtree_connections_rejected_total",
+            "counter",
+            "Connections rejected by admission control.",
+            clients.rejected() as f64,
+        ),
+        // Keyspace
+        (
+            "flashtree_keyspace_hits_total",
+            "counter",
+            "GETs that found a value.",
+            stats.hits() as f64,
+        ),
+        (
+            "flashtree_keyspace_misses_total",
+            "counter",
+            "GETs that found nothing.",
+            stats.misses() as f64,
+        ),
+        (
+            "flashtree_keys",
+            "gauge",
+            "Nodes holding a value.",
+            database.key_count()? as f64,
+        ),
+        (
+            "flashtree_nodes",
+            "gauge",
+            "Nodes in the trie.",
+            database.size()? as f64,
+        ),
+        (
+            "flashtree_maxmemory_bytes",
+            "gauge",
+            "Configured dataset limit, 0 for none.",
+            database.eviction()?.maxmemory as f64,
+        ),
+        (
+            "flashtree_evicted_keys_total",
+            "counter",
+            "Keys or subtrees evicted to stay under maxmemory.",
+            database.evicted_keys() as f64,
+        ),
+        (
+            "flashtree_dataset_bytes",
+            "gauge",
+            "Size of the trie from the running total.",
+            database.dataset_bytes()? as f64,
+        ),
+        (
+            "flashtree_error_replies_total",
+            "counter",
+            "Commands answered with an error.",
+            stats.errors() as f64,
+        ),
+        (
+            "flashtree_commands_processed_total",
+            "counter",
+            "Commands processed, all commands.",
+            stats.total_commands() as f64,
+        ),
+    ];
+    for (name, kind, help, value) in simple {
+        header(&mut out, name, kind, help);
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    // Allocator figures only mean something when jemalloc serves the process
+    if let Some(mem) = allocator::stats() {
+        let allocator: [(&str, &str, &str, f64); 7] = [
+            (
+                "flashtree_allocator_allocated_bytes",
+                "gauge",
+                "Bytes allocated by the application.",
+                mem.allocated as f64,
+            ),
+            (
+                "flashtree_allocator_active_bytes",
+                "gauge",
+                "Bytes in active pages.",
+                mem.active as f64,
+            ),
+            (
+                "flashtree_allocator_resident_bytes",
+                "gauge",
+                "Bytes physically resident.",
+                mem.resident as f64,
+            ),
+            (
+                "flashtree_allocator_mapped_bytes",
+                "gauge",
+                "Bytes in mapped extents.",
+                mem.mapped as f64,
+            ),
+            (
+                "flashtree_allocator_metadata_bytes",
+                "gauge",
+                "Bytes used for allocator metadata.",
+                mem.metadata as f64,
+            ),
+            (
+                "flashtree_allocator_retained_bytes",
+                "gauge",
+                "Bytes retained but not mapped.",
+                mem.retained as f64,
+            ),
+            (
+                "flashtree_allocator_fragmentation_ratio",
+                "gauge",
+                "Resident over allocated bytes.",
+                mem.fragmentation_ratio(),
+            ),
+        ];
+        for (name, kind, help, value) in allocator {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+    }
+
+    // Per-command counters and latency histograms
+    let commands = stats.commands();
+    header(
+        &mut out,
+        "flashtree_commands_total",
+        "counter",
+        "Commands processed, by command.",
+    );
+    for cmd in &commands {
+        let _ = writeln!(
+            out,
+            "flashtree_commands_total{{command=\"{}\"}} {}",
+            cmd.name, cmd.calls
+        );
+    }
+    header(
+        &mut out,
+        "flashtree_command_duration_seconds",
+        "histogram",
+        "Command latency, by command.",
+    );
+    for cmd in &commands {
+        let mut cumulative = 0;
+        for (i, count) in cmd.latency.iter().enumerate() {
+            cumulative += count;
+            let le = match LATENCY_BUCKETS_USEC.get(i) {
+                Some(usec) => format!("{}", *usec as f64 / 1_000_000.0),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "flashtree_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                cmd.name, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "flashtree_command_duration_seconds_sum{{command=\"{}\"}} {}",
+            cmd.name,
+            cmd.usec as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "flashtree_command_duration_seconds_count{{command=\"{}\"}} {}",
+            cmd.name, cmd.calls
+        );
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{send, shared};
+    use super::handle_scrape;
+    use super::Shared;
+    use crate::db::Keyspace;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Send `request` to a scrape handler and return the whole response.
+    async fn scrape(shared: &Shared, request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        handle_scrape(stream, shared).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_in_the_text_exposition_format() {
+        let shared = shared();
+        shared
+            .database
+            .set("k", crate::db::Value::Text("v".to_string()))
+            .unwrap();
+        let response = scrape(&shared, "GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+
+        // Every sample belongs to the family declared just above it
+        let mut family = "";
+        for line in body.lines() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                family = rest.split(' ').next().unwrap();
+            } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert_eq!(name, family);
+                assert!(["gauge", "counter", "histogram"].contains(&kind), "{line}");
+            } else {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                assert!(name.starts_with(family), "{line} outside {family}");
+                value.parse::<f64>().unwrap();
+            }
+        }
+        assert!(body.contains("\nflashtree_keys 1\n"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn latency_buckets_are_cumulative_and_end_at_inf() {
+        let shared = Arc::new(shared());
+        let response = scrape(&shared, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(!response.contains("flashtree_command_duration_seconds_bucket"));
+
+        send(&shared, "GET k\nGET k\nEXIT\n").await;
+        let response = scrape(&shared, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        let buckets: Vec<u64> = response
+            .lines()
+            .filter(|l| l.starts_with("flashtree_command_duration_seconds_bucket{command=\"get\""))
+            .map(|l| l.rsplit_once(' ').unwrap().1.parse().unwrap())
+            .collect();
+        assert!(buckets.windows(2).all(|w| w[0] <= w[1]), "{buckets:?}");
+        assert_eq!(buckets.last(), Some(&2));
+        assert!(response.contains("le=\"+Inf\"} 2\n"));
+        assert!(response.contains("flashtree_command_duration_seconds_count{command=\"get\"} 2\n"));
+    }
+
+    #[tokio::test]
+    async fn other_paths_and_methods_are_refused() {
+        let shared = shared();
+        let response = scrape(&shared, "GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = scrape(&shared, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -1,130 +1,660 @@
-// use std::sync::atomic::{AtomicUsize, Ordering};
-// use std::sync::Arc;
-// use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-// use tokio::net::{TcpListener, TcpStream};
-// use tokio::sync::Semaphore;
-// use tokio::time::{timeout, Duration};
-// use crate::db::Database;
-
-// pub async fn start(addr: &str, database: Database) -> std::io::Result<()> {
-//     let listener = TcpListener::bind(addr).await?;
-//     let max_connections = 5000;
-//     let semaphore = Arc::new(Semaphore::new(max_connections));
-//     let active_connections = Arc::new(AtomicUsize::new(0));
-    
-//     println!("FlashTree server started on {}", addr);
-
-//     loop {
-//         let (stream, addr) = listener.accept().await?;
-//         let semaphore = Arc::clone(&semaphore);
-//         let active_connections = Arc::clone(&active_connections);
-//         let database = database.clone(); 
-//         // let database = Arc::new(Database::new());
-
-        
-//         active_connections.fetch_add(1, Ordering::Relaxed);
-//         tokio::spawn(async move {
-//             let _permit = semaphore.acquire().await.unwrap();
-//             if let Err(e) = handle_client(stream, database).await {
-//                 eprintln!("Connection error for {}: {}", addr, e);
-//             }
-//             active_connections.fetch_sub(1, Ordering::Relaxed);
-//         });
-//     }
-// }
-
-// async fn handle_client(stream: TcpStream, database: Database) -> std::io::Result<()> {
-//     let (reader, writer) = stream.into_split();
-//     let mut reader = BufReader::new(reader);
-//     let mut writer = BufWriter::new(writer);
-//     let mut line = String::with_capacity(128);
-//     const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
-
-//     loop {
-//         line.clear();
-//         let bytes = match timeout(IDLE_TIMEOUT, reader.read_line(&mut line)).await {
-//             Ok(Ok(bytes)) => bytes,
-//             Ok(Err(e)) => return Err(e),
-//             Err(_) => {
-//                 writer.write_all(b"Timeout\n").await?;
-//                 writer.flush().await?;
-//                 break;
-//             }
-//         };
-//         if bytes == 0 {
-//             break;
-//         }
-
-//         if crate::commands::handle_command(&line, &mut writer, &database).await? {
-//             break;
-//         }
-//     }
-//     Ok(())
-// }
-
-
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 use tokio::time::{timeout, Duration};
-use crate::db::Database;
+use tokio_rustls::TlsAcceptor;
+use crate::acl::Acl;
+use crate::commands::{
+    Blocked, Blocking, Cluster, Feed, PubSub, Replication, Reply, Session, Stats, PUSH_BACKLOG,
+};
+#[cfg(feature = "wasm")]
+use crate::commands::Functions;
+#[cfg(feature = "lua")]
+use crate::commands::Scripts;
+use crate::config::Config;
+use crate::db::{snapshot, Database, Event};
+mod clients;
+mod metrics;
+mod replica;
+mod shutdown;
+mod tls;
+#[cfg(unix)]
+mod unix;
+pub use clients::Clients;
+pub use shutdown::Shutdown;
+
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the active expiry cycle runs, ten times a second as in Redis.
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept (e.g. out of file descriptors) before retrying.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Replies buffered per connection before they are written out, even while
+/// more pipelined requests are waiting.
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// State shared by the accept loops and every connection.
+#[derive(Debug)]
+pub struct Shared {
+    pub database: Arc<Database>,
+    pub config: Config,
+    pub shutdown: Shutdown,
+    pub clients: Clients,
+    pub stats: Stats,
+    pub started: Instant,
+    pub acl: Acl,
+    pub pubsub: Arc<PubSub>,
+    pub blocking: Blocking,
+    pub replication: Arc<Replication>,
+    pub cluster: Cluster,
+    #[cfg(feature = "lua")]
+    pub scripts: Scripts,
+    #[cfg(feature = "wasm")]
+    pub functions: Functions,
+}
+
+impl Shared {
+    /// Everything a server needs besides its listeners, e.g. to run
+    /// commands with `execute` in-process. Loads the ACL file and the saved
+    /// function libraries if configured, and listens to `database` for
+    /// WATCHTREE and replication.
+    pub fn new(config: Config, database: Arc<Database>) -> std::io::Result<Self> {
+        let acl = Acl::new(config.requirepass.as_deref());
+        if let Some(ref path) = config.aclfile {
+            acl.load_file(path).map_err(std::io::Error::other)?;
+        }
+        #[cfg(feature = "wasm")]
+        let functions = Functions::new(&config);
+        #[cfg(feature = "wasm")]
+        if let Some(ref path) = config.snapshot_path {
+            functions.load_saved(path)?;
+        }
+        let replication = Arc::new(Replication::new(&config));
+        let pubsub = Arc::new(PubSub::new());
+        // Feeds WATCHTREE, and replicas with the keys that vanish on their own.
+        // The registries are held on their own rather than through `Shared`,
+        // which owns the database and would make a cycle.
+        let listener_pubsub = Arc::clone(&pubsub);
+        let listener_replication = Arc::clone(&replication);
+        database.set_listener(move |event, key| {
+            if matches!(event, Event::Evicted | Event::Expired) {
+                listener_replication.evicted(key);
+            }
+            listener_pubsub.notify_tree(event, key)
+        });
+        Ok(Shared {
+            clients: Clients::new(config.max_clients, config.max_clients_per_ip),
+            shutdown: Shutdown::new(),
+            stats: Stats::new(),
+            started: Instant::now(),
+            acl,
+            pubsub,
+            blocking: Blocking::new(),
+            replication,
+            cluster: Cluster::new(&config),
+            #[cfg(feature = "lua")]
+            scripts: Scripts::new(&config),
+            #[cfg(feature = "wasm")]
+            functions,
+            database,
+            config,
+        })
+    }
+}
 
 /// Launch the server. Pass in `Arc::new(Database::new())` as `database`.
-pub async fn start(addr: &str, database: Arc<Database>) -> std::io::Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-    let max_connections = 5000;
-    let semaphore = Arc::new(Semaphore::new(max_connections));
-    let active_connections = Arc::new(AtomicUsize::new(0));
+/// Runs until SIGINT/SIGTERM or the SHUTDOWN command, then drains connections
+/// and writes the final snapshot (if configured) before returning.
+pub async fn start(config: Config, database: Arc<Database>) -> std::io::Result<()> {
+    let listener = match config.bind {
+        Some(ref addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let tls_listener = match (&config.tls_bind, &config.tls_cert, &config.tls_key) {
+        (Some(addr), Some(cert), Some(key)) => {
+            let acceptor = tls::acceptor(cert, key, config.tls_ca_cert.as_deref())?;
+            Some((TcpListener::bind(addr).await?, acceptor))
+        }
+        _ => None,
+    };
+    #[cfg(unix)]
+    let unix_listener = match config.unixsocket {
+        Some(ref path) => Some(unix::bind(path, config.unixsocket_perm)?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if config.unixsocket.is_some() {
+        return Err(std::io::Error::other(
+            "Unix sockets are not supported on this platform",
+        ));
+    }
+    let shared = Arc::new(Shared::new(config, database)?);
+    if let Some(addr) = shared.config.replicaof.clone() {
+        shared.replication.follow(&shared, Some(addr));
+    }
+    tokio::spawn(replica::supervise(Arc::clone(&shared)));
+    // Every connection task holds a clone; recv() yields None once all are gone.
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+    if let Some(listener) = listener {
+        if let Some(ref addr) = shared.config.bind {
+            println!("FlashTree server started on {}", addr);
+        }
+        tokio::spawn(accept_loop(
+            listener,
+            None,
+            Arc::clone(&shared),
+            done_tx.clone(),
+        ));
+    }
+    if let Some((listener, acceptor)) = tls_listener {
+        if let Some(ref addr) = shared.config.tls_bind {
+            println!("TLS listening on {}", addr);
+        }
+        tokio::spawn(accept_loop(
+            listener,
+            Some(acceptor),
+            Arc::clone(&shared),
+            done_tx.clone(),
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(listener) = unix_listener {
+        if let Some(ref path) = shared.config.unixsocket {
+            println!("Unix socket listening on {}", path.display());
+        }
+        tokio::spawn(unix_accept_loop(
+            listener,
+            Arc::clone(&shared),
+            done_tx.clone(),
+        ));
+    }
+
+    let signals = Arc::clone(&shared);
+    tokio::spawn(async move { shutdown::listen_for_signals(&signals.shutdown).await });
+
+    tokio::spawn(expire_keys(Arc::clone(&shared)));
+
+    if let Some(addr) = shared.config.metrics_bind.clone() {
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, shared).await {
+                eprintln!("Metrics endpoint stopped: {}", e);
+            }
+        });
+    }
 
-    println!("FlashTree server started on {}", addr);
+    // The accept loops stop on their own; then give in-flight connections
+    // until the deadline.
+    shutdown::wait(&mut shared.shutdown.subscribe()).await;
+    drop(done_tx);
+    #[cfg(unix)]
+    if let Some(ref path) = shared.config.unixsocket {
+        unix::remove(path);
+    }
+    println!(
+        "Shutting down, waiting for {} connection(s)",
+        shared.clients.connected()
+    );
+    if timeout(shared.config.shutdown_timeout, done_rx.recv())
+        .await
+        .is_err()
+    {
+        eprintln!(
+            "Shutdown deadline reached, {} connection(s) still open",
+            shared.clients.connected()
+        );
+    }
+    shared.database.clear_listener();
 
+    if shared.shutdown.should_save() {
+        if let Some(ref path) = shared.config.snapshot_path {
+            if let Err(e) = snapshot::save(shared.database.get_root(), path) {
+                eprintln!("Final snapshot to {} failed: {}", path.display(), e);
+                return Err(e);
+            }
+            #[cfg(feature = "wasm")]
+            if let Err(e) = shared.functions.save(path) {
+                eprintln!("Saving functions next to {} failed: {}", path.display(), e);
+                return Err(e);
+            }
+            println!("Snapshot saved to {}", path.display());
+        }
+    }
+    println!("FlashTree server stopped");
+    Ok(())
+}
+
+/// Remove expired keys in the background until shutdown, so keys nobody
+/// reads again don't hold on to memory.
+async fn expire_keys(shared: Arc<Shared>) {
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    let mut ticks = tokio::time::interval(EXPIRE_INTERVAL);
     loop {
-        let (stream, addr) = listener.accept().await?;
-        let semaphore = Arc::clone(&semaphore);
-        let active_connections = Arc::clone(&active_connections);
-        let database = Arc::clone(&database); // <-- clone the Arc, not Database itself
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown::wait(&mut shutdown_rx) => return,
+        }
+        let _ = shared.database.expire_cycle();
+    }
+}
+
+/// Accept connections on `listener` until shutdown. With `tls` set, each
+/// connection completes a TLS handshake before anything else is sent.
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    shared: Arc<Shared>,
+    done_tx: mpsc::Sender<()>,
+) {
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Accept error: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = shutdown::wait(&mut shutdown_rx) => break,
+        };
+        let shared = Arc::clone(&shared);
+        let done = done_tx.clone();
+        let tls = tls.clone();
 
-        active_connections.fetch_add(1, Ordering::Relaxed);
         tokio::spawn(async move {
-            let _permit = semaphore.acquire().await.unwrap();
-            if let Err(e) = handle_client(stream, database).await {
+            let result = match tls {
+                // Admitted before the handshake, so clients over the limit
+                // cost no TLS work. They couldn't read a plain-text error,
+                // so the socket is just closed.
+                Some(acceptor) => match shared.clients.admit(Some(addr.ip())) {
+                    Ok(_slot) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => handle_client(stream, &shared).await,
+                        Ok(Err(e)) => Err(e),
+                        Err(_) => Ok(()),
+                    },
+                    Err(_) => Ok(()),
+                },
+                None => serve_connection(stream, Some(addr.ip()), &shared).await,
+            };
+            if let Err(e) = result {
                 eprintln!("Connection error for {}: {}", addr, e);
             }
-            active_connections.fetch_sub(1, Ordering::Relaxed);
+            drop(done);
         });
     }
 }
 
-async fn handle_client(
-    stream: TcpStream,
-    database: Arc<Database>
-) -> std::io::Result<()> {
-    let (reader, writer) = stream.into_split();
+/// Accept local clients on a Unix socket until shutdown.
+#[cfg(unix)]
+async fn unix_accept_loop(
+    listener: tokio::net::UnixListener,
+    shared: Arc<Shared>,
+    done_tx: mpsc::Sender<()>,
+) {
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    loop {
+        let stream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Accept error: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = shutdown::wait(&mut shutdown_rx) => break,
+        };
+        let shared = Arc::clone(&shared);
+        let done = done_tx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, None, &shared).await {
+                eprintln!("Connection error for local client: {}", e);
+            }
+            drop(done);
+        });
+    }
+}
+
+/// `ip` is `None` for Unix socket clients.
+async fn serve_connection<S>(
+    mut stream: S,
+    ip: Option<IpAddr>,
+    shared: &Shared,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Admission is decided right away: a client over the limit gets an
+    // error and a closed socket instead of a connection that hangs.
+    let _slot = match shared.clients.admit(ip) {
+        Ok(slot) => slot,
+        Err(rejection) => {
+            let _ = timeout(REJECT_TIMEOUT, async {
+                stream.write_all(rejection.message()).await?;
+                stream.shutdown().await
+            })
+            .await;
+            return Ok(());
+        }
+    };
+    handle_client(stream, shared).await
+}
+
+async fn handle_client<S>(stream: S, shared: &Shared) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
-    let mut writer = BufWriter::new(writer);
-    let mut line = String::with_capacity(128);
+    let mut writer = BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    let mut line = Vec::with_capacity(128);
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    let (push_tx, mut push_rx) = mpsc::channel(PUSH_BACKLOG);
+    let mut session = Session::with_push(shared, push_tx);
     const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
 
+    let result = async {
+        loop {
+            // Subscribers wait for messages by design, so they never idle out.
+            let idle = if session.subscriptions.active() {
+                Duration::MAX
+            } else {
+                IDLE_TIMEOUT
+            };
+            // Only the read is raced against shutdown and pushes, so a command
+            // that has already been received always runs to completion. Reads
+            // go first so pipelined requests already buffered are answered
+            // before closing. read_until keeps a partial line across the other
+            // branches firing, which read_line would not.
+            let read = tokio::select! {
+                biased;
+                read = timeout(idle, reader.read_until(b'\n', &mut line)) => read,
+                _ = shutdown::wait(&mut shutdown_rx) => {
+                    writer.write_all(&notice(&session, "Server shutting down")).await?;
+                    writer.flush().await?;
+                    break;
+                }
+                Some(message) = push_rx.recv() => {
+                    if session.subscriptions.overflowed() {
+                        writer.write_all(b"Too far behind, disconnecting\n").await?;
+                        writer.flush().await?;
+                        break;
+                    }
+                    let mut out = Vec::with_capacity(64);
+                    session.encode(&message, &mut out);
+                    writer.write_all(&out).await?;
+                    if !reader.buffer().contains(&b'\n') {
+                        writer.flush().await?;
+                    }
+                    continue;
+                }
+            };
+            let bytes = match read {
+                Ok(Ok(bytes)) => bytes,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    writer.write_all(&notice(&session, "Timeout")).await?;
+                    writer.flush().await?;
+                    break;
+                }
+            };
+            if bytes == 0 {
+                break;
+            }
+
+            let request = std::str::from_utf8(&line)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            if crate::commands::handle_command(request, &mut writer, shared, &mut session).await? {
+                break;
+            }
+            line.clear();
+            if let Some(feed) = session.replica.take() {
+                writer.flush().await?;
+                return stream_to_replica(feed, &mut reader, &mut writer, &mut shutdown_rx).await;
+            }
+            if let Some(blocked) = session.blocked.take() {
+                writer.flush().await?;
+                let Some(reply) =
+                    wait_blocked(blocked, &mut reader, shared, &mut shutdown_rx).await
+                else {
+                    break;
+                };
+                let mut out = Vec::with_capacity(64);
+                session.encode(&reply, &mut out);
+                writer.write_all(&out).await?;
+            }
+            // Pipelining: while another complete request is already buffered, run
+            // it before flushing, so a batch of requests costs one write. The
+            // BufWriter writes out on its own once OUTPUT_BUFFER fills up.
+            if !reader.buffer().contains(&b'\n') {
+                writer.flush().await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    shared.pubsub.unsubscribe_all(&mut session);
+    // Lets TLS clients see a clean close_notify rather than a bare EOF.
+    let _ = writer.shutdown().await;
+    result
+}
+
+/// A line the server sends on its own before closing a connection.
+fn notice(session: &Session, text: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(32);
+    session.encode(&Reply::Status(text.to_string()), &mut out);
+    out
+}
+
+/// Send a replica that sent PSYNC its sync payload, then the command stream
+/// as it is produced, until it hangs up, falls too far behind or the server
+/// shuts down.
+async fn stream_to_replica<R, W>(
+    mut feed: Feed,
+    reader: &mut BufReader<R>,
+    writer: &mut W,
+    shutdown_rx: &mut tokio::sync::watch::Receiver<bool>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(feed.preamble()).await?;
+    writer.flush().await?;
+    let mut discard = Vec::new();
     loop {
-        line.clear();
-        let bytes = match timeout(IDLE_TIMEOUT, reader.read_line(&mut line)).await {
-            Ok(Ok(bytes)) => bytes,
-            Ok(Err(e)) => return Err(e),
-            Err(_) => {
-                writer.write_all(b"Timeout\n").await?;
+        tokio::select! {
+            chunk = feed.next() => {
+                let Some(chunk) = chunk else {
+                    return Ok(());
+                };
+                writer.write_all(&chunk).await?;
+                while let Some(chunk) = feed.try_next() {
+                    writer.write_all(&chunk).await?;
+                }
                 writer.flush().await?;
-                break;
             }
-        };
-        if bytes == 0 {
-            break;
+            // Replicas send nothing once streaming; only EOF matters
+            read = reader.read_until(b'\n', &mut discard) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+                discard.clear();
+            }
+            _ = shutdown::wait(shutdown_rx) => return Ok(()),
         }
+    }
+}
 
-        if crate::commands::handle_command(&line, &mut writer, &database).await? {
-            break;
+/// Park a connection on a blocking command until it is served, times out or
+/// the server shuts down. `None` if the client hung up meanwhile.
+async fn wait_blocked<R>(
+    mut blocked: Blocked,
+    reader: &mut BufReader<R>,
+    shared: &Shared,
+    shutdown_rx: &mut tokio::sync::watch::Receiver<bool>,
+) -> Option<Reply>
+where
+    R: AsyncRead + Unpin,
+{
+    // Requests pipelined behind the blocking one wait their turn; only EOF
+    // ends the wait early.
+    let hung_up = async {
+        if reader.buffer().is_empty() {
+            match reader.fill_buf().await {
+                Ok(buf) if !buf.is_empty() => {}
+                _ => return,
+            }
+        }
+        std::future::pending::<()>().await
+    };
+    let timeout = blocked.timeout();
+    let expired = async move {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
         }
+    };
+    let mut gone = false;
+    let served = tokio::select! {
+        _ = expired => None,
+        served = blocked.served() => served,
+        _ = shutdown::wait(shutdown_rx) => None,
+        _ = hung_up => {
+            gone = true;
+            None
+        }
+    };
+    let reply = blocked.finish(&shared.blocking, served);
+    (!gone).then_some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_client, Shared};
+    use crate::config::Config;
+    use crate::db::{Database, Keyspace};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A server with the default configuration and an empty database.
+    pub fn shared() -> Shared {
+        Shared::new(Config::default(), Arc::new(Database::new())).unwrap()
+    }
+
+    /// Run `requests` on a client connection until it closes, and return
+    /// everything the server wrote back.
+    pub async fn send(shared: &Arc<Shared>, requests: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn({
+            let shared = Arc::clone(shared);
+            async move { handle_client(stream, &shared).await }
+        });
+        client.write_all(requests.as_bytes()).await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        server.await.unwrap().unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn memory_usage_reports_a_key_or_a_prefix() {
+        let shared = Arc::new(shared());
+        let replies = send(
+            &shared,
+            "SET users:1 alice\nSET users:2 bob\nMEMORY USAGE users:1\n\
+MEMORY USAGE users:*\nMEMORY USAGE users\nMEMORY USAGE nobody\nEXIT\n",
+        )
+        .await;
+        let lines: Vec<&str> = replies.lines().collect();
+        let one: usize = lines[2].parse().unwrap();
+        let prefix: usize = lines[3].parse().unwrap();
+        assert_eq!(Some(one), shared.database.usage("users:1").unwrap());
+        assert_eq!(Some(prefix), shared.database.usage_prefix("users").unwrap());
+        assert!(prefix > one);
+        assert_eq!(lines[4..6], ["(nil)", "(nil)"]);
+
+        let replies = send(
+            &shared,
+            "DEL users:1\nMEMORY USAGE users:1\nMEMORY USAGE users:*\nEXIT\n",
+        )
+        .await;
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(lines[1], "(nil)");
+        assert!(lines[2].parse::<usize>().unwrap() < prefix);
+    }
+
+    #[tokio::test]
+    async fn expire_rejects_times_that_overflow() {
+        let shared = Arc::new(shared());
+        let replies = send(
+            &shared,
+            "SET k v\nEXPIRE k 18446744073709551615\nEXPIRE k 100\nEXIT\n",
+        )
+        .await;
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(lines[1..3], ["-ERR invalid expire time", "1"]);
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        // Enough replies to spill past OUTPUT_BUFFER mid-batch
+        let shared = Arc::new(shared());
+        let mut requests = String::new();
+        for i in 0..5000 {
+            requests.push_str(&format!("SET k{i} value-{i}\nGET k{i}\nGET missing\n"));
+        }
+        requests.push_str("EXIT\n");
+        let replies = send(&shared, &requests).await;
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(lines.len(), 5000 * 3 + 1);
+        for (i, chunk) in lines.chunks(3).take(5000).enumerate() {
+            assert_eq!(chunk, ["OK", format!("value-{i}").as_str(), "(nil)"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn key_patterns_limit_named_keys_and_the_whole_keyspace() {
+        let shared = Arc::new(shared());
+        send(
+            &shared,
+            "ACL SETUSER alice on nopass ~users:* +@all\nSET users:1 bob\nSET orders:1 pen\nEXIT\n",
+        )
+        .await;
+        let replies = send(
+            &shared,
+            "AUTH alice any\nGET users:1\nGET orders:1\nMEMORY USAGE users:1\n\
+DROP\nSIZE\nMEMORY\nCHILDREN\nCHILDREN users\nCHILDREN orders\nEXIT\n",
+        )
+        .await;
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(lines[1], "bob");
+        assert!(lines[2].starts_with("-NOPERM"), "{}", lines[2]);
+        assert!(lines[3].parse::<usize>().is_ok(), "{}", lines[3]);
+        for line in &lines[4..8] {
+            assert_eq!(
+                *line,
+                "-NOPERM this user has no permissions to access all keys"
+            );
+        }
+        assert_eq!(lines[8..10], ["*1", "1"]);
+        assert!(lines[10].starts_with("-NOPERM"), "{}", lines[10]);
+        assert!(shared.database.get("orders:1").unwrap().is_some());
+
+        send(&shared, "ACL SETUSER alice allkeys\nEXIT\n").await;
+        send(&shared, "AUTH alice any\nDROP\nEXIT\n").await;
+        assert!(shared.database.get("orders:1").unwrap().is_none());
     }
-    Ok(())
 }
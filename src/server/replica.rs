@@ -0,0 +1,203 @@
+use super::{shutdown, Shared};
+use crate::commands::{apply_replicated, Session};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pause before reconnecting after the link to the primary broke.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Follow whichever primary REPLICAOF or `--replicaof` names, switching
+/// when it changes, until shutdown.
+pub async fn supervise(shared: Arc<Shared>) {
+    let mut primary = shared.replication.subscribe();
+    let mut shutdown_rx = shared.shutdown.subscribe();
+    loop {
+        let addr = primary.borrow_and_update().clone();
+        let following = async {
+            match addr {
+                Some(ref addr) => follow(&shared, addr).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = following => {}
+            changed = primary.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = shutdown::wait(&mut shutdown_rx) => break,
+        }
+        shared.replication.set_link(false);
+    }
+}
+
+/// Keep a link to the primary at `addr` up, reconnecting as needed.
+async fn follow(shared: &Shared, addr: &str) {
+    loop {
+        if let Err(e) = sync(shared, addr).await {
+            eprintln!("Replication link to {} lost: {}", addr, e);
+        }
+        shared.replication.set_link(false);
+        sleep(RETRY_DELAY).await;
+    }
+}
+
+/// One link to the primary: PSYNC from where this server left off, load a
+/// snapshot if the primary can't continue from there, then apply the
+/// command stream until the connection ends.
+async fn sync(shared: &Shared, addr: &str) -> Result<()> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "connect timed out"))??;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    if let Some(ref password) = shared.config.primary_auth {
+        writer
+            .write_all(format!("AUTH {}\n", password).as_bytes())
+            .await?;
+        read_line(&mut reader, &mut line).await?;
+        if line.starts_with('-') {
+            return Err(Error::other(format!("AUTH refused: {}", line.trim_end())));
+        }
+    }
+    let (replid, offset) = shared.replication.position();
+    writer
+        .write_all(format!("PSYNC {} {}\n", replid, offset).as_bytes())
+        .await?;
+    read_line(&mut reader, &mut line).await?;
+    let reply: Vec<&str> = line.split_whitespace().collect();
+    match reply.as_slice() {
+        ["FULLRESYNC", replid, offset] => {
+            let offset: u64 = offset.parse().map_err(|_| invalid("bad offset"))?;
+            let replid = replid.to_string();
+            let snapshot = read_payload(shared, &mut reader, &mut line, "snapshot").await?;
+            let libraries = read_payload(shared, &mut reader, &mut line, "function dump").await?;
+            shared
+                .replication
+                .full_resync(&shared.database, &replid, offset, &snapshot)?;
+            #[cfg(feature = "wasm")]
+            shared
+                .functions
+                .restore(&libraries)
+                .map_err(|e| Error::other(e.to_string()))?;
+            #[cfg(not(feature = "wasm"))]
+            drop(libraries);
+            println!(
+                "Full sync with primary {} done ({} bytes)",
+                addr,
+                snapshot.len()
+            );
+        }
+        ["CONTINUE", _] => println!(
+            "Partial resync with primary {} from offset {}",
+            addr, offset
+        ),
+        _ => return Err(Error::other(format!("PSYNC refused: {}", line.trim_end()))),
+    }
+    shared.replication.set_link(true);
+
+    let mut session = Session::new(shared);
+    loop {
+        read_line(&mut reader, &mut line).await?;
+        if let Err(e) = apply_replicated(&line, shared, &mut session) {
+            return Err(Error::other(e.to_string()));
+        }
+    }
+}
+
+/// Read one whole line into `line`; a partial one at EOF means the link broke.
+async fn read_line(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+    line: &mut String,
+) -> Result<()> {
+    line.clear();
+    reader.read_line(line).await?;
+    if !line.ends_with('\n') {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "primary closed the connection",
+        ));
+    }
+    Ok(())
+}
+
+/// Read a `$<len>` header and that many bytes. The length comes from the
+/// primary, so it is checked against `--repl-max-sync-payload` and the bytes
+/// are read as they arrive rather than into a buffer sized up front.
+async fn read_payload(
+    shared: &Shared,
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+    line: &mut String,
+    what: &str,
+) -> Result<Vec<u8>> {
+    read_line(reader, line).await?;
+    let len: usize = line
+        .trim_end()
+        .strip_prefix('$')
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| invalid(&format!("bad {what} length")))?;
+    if len > shared.config.repl_max_sync_payload {
+        return Err(invalid(&format!(
+            "{what} of {len} bytes exceeds repl-max-sync-payload"
+        )));
+    }
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload).await?;
+    if payload.len() < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "primary closed the connection",
+        ));
+    }
+    Ok(payload)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sync;
+    use crate::config::Config;
+    use crate::db::Database;
+    use crate::server::Shared;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn oversized_snapshots_are_refused_before_reading() {
+        let config = Config {
+            repl_max_sync_payload: 1024,
+            ..Config::default()
+        };
+        let shared = Shared::new(config, Arc::new(Database::new())).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let primary = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut psync = String::new();
+            stream.read_line(&mut psync).await.unwrap();
+            stream
+                .write_all(b"FULLRESYNC 0123456789abcdef 0\n$1099511627776\n")
+                .await
+                .unwrap();
+            stream
+        });
+
+        let err = sync(&shared, &addr).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("repl-max-sync-payload"));
+        drop(primary.await.unwrap());
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::watch;
+
+/// Shared shutdown switch. Flipped once by a signal or the SHUTDOWN command;
+/// the accept loop and every connection watch it.
+#[derive(Debug)]
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+    save: AtomicBool,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Shutdown {
+            tx,
+            save: AtomicBool::new(true),
+        }
+    }
+
+    /// Begin shutting down. `save` decides whether the final snapshot is written;
+    /// a later trigger can turn it off but never back on, so a signal after
+    /// SHUTDOWN NOSAVE still skips the snapshot.
+    pub fn trigger(&self, save: bool) {
+        self.save.fetch_and(save, Ordering::Relaxed);
+        self.tx.send_replace(true);
+    }
+
+    pub fn should_save(&self) -> bool {
+        self.save.load(Ordering::Relaxed)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+}
+
+/// Resolves once `rx` sees shutdown, immediately if it already happened.
+pub async fn wait(rx: &mut watch::Receiver<bool>) {
+    let _ = rx.wait_for(|down| *down).await;
+}
+
+/// Trigger shutdown on SIGINT / SIGTERM.
+pub async fn listen_for_signals(shutdown: &Shutdown) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = match signal(SignalKind::terminate()) {
+            Ok(term) => term,
+            Err(e) => {
+                eprintln!("Cannot install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                shutdown.trigger(true);
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    println!("Signal received, shutting down");
+    shutdown.trigger(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+
+    #[test]
+    fn a_later_trigger_cannot_turn_saving_back_on() {
+        let shutdown = Shutdown::new();
+        shutdown.trigger(false);
+        shutdown.trigger(true);
+        assert!(!shutdown.should_save());
+
+        let shutdown = Shutdown::new();
+        shutdown.trigger(true);
+        assert!(shutdown.should_save());
+        shutdown.trigger(false);
+        assert!(!shutdown.should_save());
+    }
+}
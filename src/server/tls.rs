@@ -0,0 +1,161 @@
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Build the acceptor for the TLS listener. With `ca` set, clients must
+/// present a certificate signed by it.
+pub fn acceptor(cert: &Path, key: &Path, ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let builder = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert).map_err(io::Error::other)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn open(path: &Path) -> io::Result<std::fs::File> {
+    std::fs::File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::accept_loop;
+    use super::super::tests::shared;
+    use super::{acceptor, load_certs, load_key};
+    use crate::db::Keyspace;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    /// Test certificates: `ca` signs the server and `client`, while `rogue`
+    /// is signed by a CA the server doesn't know.
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    /// A client trusting the test CA, presenting `identity` if given.
+    fn connector(identity: Option<&str>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&fixture("ca.crt")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match identity {
+            Some(name) => {
+                let certs = load_certs(&fixture(&format!("{name}.crt"))).unwrap();
+                let key = load_key(&fixture(&format!("{name}.key"))).unwrap();
+                builder.with_client_auth_cert(certs, key).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Run `requests` over TLS against a server requiring client
+    /// certificates. Returns what came back (or the error that ended it)
+    /// and whether the server ended up with key `k`.
+    async fn send(identity: Option<&str>, requests: &str) -> (std::io::Result<String>, bool) {
+        let acceptor = acceptor(
+            &fixture("server.crt"),
+            &fixture("server.key"),
+            Some(&fixture("ca.crt")),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(shared());
+        let (done_tx, mut done_rx) = mpsc::channel(1);
+        tokio::spawn(accept_loop(
+            listener,
+            Some(acceptor),
+            Arc::clone(&shared),
+            done_tx,
+        ));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let result = async {
+            let mut stream = connector(identity).connect(name, stream).await?;
+            stream.write_all(requests.as_bytes()).await?;
+            let mut replies = String::new();
+            stream.read_to_string(&mut replies).await?;
+            Ok(replies)
+        }
+        .await;
+        shared.shutdown.trigger(false);
+        done_rx.recv().await;
+        (result, shared.database.get("k").unwrap().is_some())
+    }
+
+    #[tokio::test]
+    async fn a_verified_client_is_served_over_tls() {
+        let (replies, written) = send(Some("client"), "SET k v\nGET k\nEXIT\n").await;
+        assert_eq!(
+            replies.unwrap().lines().collect::<Vec<_>>(),
+            ["OK", "v", "Bye!"]
+        );
+        assert!(written);
+    }
+
+    #[tokio::test]
+    async fn clients_without_a_trusted_certificate_are_refused() {
+        for identity in [Some("rogue"), None] {
+            let (replies, written) = send(identity, "SET k v\nEXIT\n").await;
+            assert!(replies.is_err(), "{identity:?} got {replies:?}");
+            assert!(!written, "{identity:?} could write");
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::ffi::OsString;
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Bind a Unix socket at `path` with permissions `mode`. The socket is
+/// created in a private (0700) directory beside `path`, given its mode there
+/// and only then renamed into place, so it is never reachable with the
+/// looser permissions the umask would give it. A socket file left behind by
+/// an unclean exit is replaced; any other file is an error.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+    }
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+    })?;
+    let mut private = OsString::from(".");
+    private.push(name);
+    private.push(format!(".{}", std::process::id()));
+    let private = path.with_file_name(private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("s");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&private)?;
+    bound
+}
+
+/// Remove the socket file on shutdown.
+pub fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        eprintln!("Cannot remove Unix socket {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bind;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flashtree-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn mode(path: &PathBuf) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn the_socket_gets_the_configured_permissions() {
+        let dir = scratch_dir("unix-perm");
+        let path = dir.join("flashtree.sock");
+        let listener = bind(&path, 0o600).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        drop(listener);
+
+        // A stale socket from an unclean exit is replaced
+        let _listener = bind(&path, 0o660).unwrap();
+        assert_eq!(mode(&path), 0o660);
+        let names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["flashtree.sock"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_files_are_never_replaced() {
+        let dir = scratch_dir("unix-file");
+        let path = dir.join("flashtree.sock");
+        std::fs::write(&path, "keep").unwrap();
+        assert!(bind(&path, 0o600).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
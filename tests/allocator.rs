@@ -0,0 +1,18 @@
+#![cfg(feature = "jemalloc")]
+
+//! jemalloc is the global allocator here, as in the server binary; the
+//! library's own unit tests run on the system allocator instead.
+
+use word_trie::allocator;
+
+#[global_allocator]
+static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+#[test]
+fn jemalloc_figures_are_reported_when_it_is_the_global_allocator() {
+    assert!(allocator::in_use());
+    assert_eq!(allocator::name(), "jemalloc");
+    let stats = allocator::stats().unwrap();
+    assert!(stats.allocated > 0);
+    assert!(stats.fragmentation_ratio() > 0.0);
+}
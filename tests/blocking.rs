@@ -0,0 +1,74 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use common::{error_starts, run, shared};
+use tokio::sync::mpsc;
+use word_trie::commands::{Reply, Session, PUSH_BACKLOG};
+use word_trie::server::Shared;
+
+/// A session with a connection behind it, so it can block.
+fn connected(shared: &Shared) -> Session {
+    Session::with_push(shared, mpsc::channel(PUSH_BACKLOG).0)
+}
+
+/// The reply a blocked session was served, nil if it is still waiting.
+fn served(shared: &Shared, session: &mut Session) -> Reply {
+    let blocked = session.blocked.take().expect("session is not blocked");
+    blocked.finish(&shared.blocking, None)
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(s.to_string())
+}
+
+#[test]
+fn blmove_woken_by_a_push_wakes_the_destination_waiters() {
+    let shared = shared();
+    let (mut mover, mut popper) = (connected(&shared), connected(&shared));
+    let mut pusher = Session::new(&shared);
+    assert_eq!(
+        run(&shared, &mut mover, "BLMOVE src mid LEFT RIGHT 0"),
+        Reply::Nil
+    );
+    assert_eq!(run(&shared, &mut popper, "BLPOP mid 0"), Reply::Nil);
+
+    run(&shared, &mut pusher, "RPUSH src job");
+    assert_eq!(served(&shared, &mut mover), bulk("job"));
+    assert_eq!(
+        served(&shared, &mut popper),
+        Reply::Array(vec![bulk("mid"), bulk("job")])
+    );
+    assert_eq!(run(&shared, &mut pusher, "LLEN mid"), Reply::Integer(0));
+}
+
+#[test]
+fn blmove_that_does_not_block_wakes_the_destination_waiters() {
+    let shared = shared();
+    let mut popper = connected(&shared);
+    let mut mover = Session::new(&shared);
+    run(&shared, &mut mover, "RPUSH src job");
+    assert_eq!(run(&shared, &mut popper, "BLPOP dst 0"), Reply::Nil);
+
+    assert_eq!(
+        run(&shared, &mut mover, "BLMOVE src dst LEFT RIGHT 0"),
+        bulk("job")
+    );
+    assert_eq!(
+        served(&shared, &mut popper),
+        Reply::Array(vec![bulk("dst"), bulk("job")])
+    );
+}
+
+#[test]
+fn blocking_timeouts_out_of_range_are_rejected() {
+    let shared = shared();
+    let mut session = connected(&shared);
+    for timeout in ["1e20", "inf", "NaN", "-1", "soon"] {
+        let reply = run(&shared, &mut session, &format!("BLPOP k {timeout}"));
+        assert!(error_starts(&reply, "ERR timeout"), "{timeout}: {reply:?}");
+        assert!(session.blocked.is_none());
+    }
+    assert_eq!(run(&shared, &mut session, "BLPOP k 0.5"), Reply::Nil);
+    assert!(session.blocked.is_some());
+}
@@ -0,0 +1,122 @@
+#![cfg(all(feature = "server", feature = "cli"))]
+
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use word_trie::config::Config;
+use word_trie::Database;
+
+/// Start a server on a free port in the background and return the port
+/// once it accepts connections.
+fn server() -> u16 {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = Config {
+        bind: Some(format!("127.0.0.1:{port}")),
+        ..Config::default()
+    };
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(word_trie::server::start(config, Arc::new(Database::new())))
+            .unwrap();
+    });
+    for _ in 0..200 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server did not start on port {port}");
+}
+
+/// Run flashtree-cli against `port` with `args`, feeding it `stdin`.
+fn cli(port: u16, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_flashtree-cli"))
+        .args(["--port", &port.to_string(), "--raw"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn one_shot_prints_the_reply_and_exits_zero() {
+    let port = server();
+    let set = cli(port, &["SET", "users:1", "alice"], "");
+    assert!(set.status.success(), "{}", stderr(&set));
+    let get = cli(port, &["GET", "users:1"], "");
+    assert!(get.status.success(), "{}", stderr(&get));
+    assert_eq!(stdout(&get), "alice\n");
+}
+
+#[test]
+fn one_shot_errors_go_to_stderr_and_the_exit_status() {
+    let port = server();
+    let output = cli(port, &["NOSUCHCOMMAND"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(
+        stderr(&output).starts_with("(error) "),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn bad_options_exit_with_status_two() {
+    let output = cli(0, &["--pipe", "GET", "users:1"], "");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn pipe_sends_every_line_and_counts_the_replies() {
+    let port = server();
+    let mut input = String::new();
+    for i in 0..1000 {
+        input.push_str(&format!("SET users:{i} user-{i}\n"));
+    }
+    input.push_str("\nSIZE\r\n");
+    let output = cli(port, &["--pipe"], &input);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "errors: 0, replies: 1001\n");
+
+    let get = cli(port, &["GET", "users:999"], "");
+    assert_eq!(stdout(&get), "user-999\n");
+}
+
+#[test]
+fn pipe_fails_if_any_request_fails() {
+    let port = server();
+    let output = cli(
+        port,
+        &["--pipe"],
+        "SET users:1 alice\nNOSUCHCOMMAND\nGET users:1\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "errors: 1, replies: 3\n");
+    assert_eq!(stderr(&output).lines().count(), 1, "{}", stderr(&output));
+}
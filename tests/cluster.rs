@@ -0,0 +1,145 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use common::{error_starts, run, shared};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+use word_trie::commands::{Reply, Session, PUSH_BACKLOG};
+use word_trie::config::Config;
+use word_trie::server::Shared;
+use word_trie::Database;
+
+/// A cluster node at `127.0.0.1:7000` that owns every slot.
+fn node() -> Shared {
+    let config = Config {
+        cluster_enabled: true,
+        bind: Some("127.0.0.1:7000".to_string()),
+        ..Config::default()
+    };
+    let shared = Shared::new(config, Arc::new(Database::new())).unwrap();
+    let mut session = Session::new(&shared);
+    let reply = run(
+        &shared,
+        &mut session,
+        "CLUSTER SETSLOT 0-16383 NODE 127.0.0.1:7000",
+    );
+    assert_eq!(reply, Reply::ok());
+    shared
+}
+
+/// Accept one MIGRATE connection and answer its ASKING and RESTORE with
+/// `replies`, running `during` first, while the subtree is in flight.
+/// Returns the RESTORE line and what `during` returned.
+fn target<T: Send + 'static>(
+    replies: &'static str,
+    during: impl FnOnce() -> T + Send + 'static,
+) -> (u16, thread::JoinHandle<(String, T)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut asking = String::new();
+        reader.read_line(&mut asking).unwrap();
+        assert_eq!(asking, "ASKING\n");
+        let mut restore = String::new();
+        reader.read_line(&mut restore).unwrap();
+        let seen = during();
+        (&stream).write_all(replies.as_bytes()).unwrap();
+        (restore, seen)
+    });
+    (port, handle)
+}
+
+#[test]
+fn keyless_commands_over_every_slot_are_refused() {
+    let shared = node();
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "SET tenant:1 a");
+    for line in ["DROP", "SIZE", "CHILDREN"] {
+        let reply = run(&shared, &mut session, line);
+        assert!(error_starts(&reply, "ERR"), "{line}: {reply:?}");
+    }
+    assert_eq!(
+        run(&shared, &mut session, "CHILDREN tenant"),
+        Reply::Array(vec![Reply::Bulk("1".to_string())])
+    );
+    assert_eq!(
+        run(&shared, &mut session, "PING"),
+        Reply::Status("PONG".to_string())
+    );
+}
+
+#[test]
+fn migrate_sends_the_subtree_without_holding_writes_up() {
+    let source = Arc::new(shared());
+    let mut session = Session::with_push(&source, mpsc::channel(PUSH_BACKLOG).0);
+    run(&source, &mut session, "PSYNC ? -1");
+    let mut feed = session.replica.take().expect("no feed");
+    let mut session = Session::new(&source);
+    run(&source, &mut session, "SET tenant:1 a");
+    run(&source, &mut session, "SET other b");
+
+    // The first reply is a value that looks like an error line
+    let (port, handle) = target("$7\n-dashed\nOK\n", {
+        let source = Arc::clone(&source);
+        move || {
+            let mut session = Session::new(&source);
+            (
+                run(&source, &mut session, "SET tenant:2 b"),
+                run(&source, &mut session, "SET other c"),
+                run(&source, &mut session, "GET tenant:1"),
+            )
+        }
+    });
+    let reply = run(
+        &source,
+        &mut session,
+        &format!("MIGRATE 127.0.0.1 {port} tenant"),
+    );
+    assert_eq!(reply, Reply::ok());
+    let (restore, (write, other, read)) = handle.join().unwrap();
+    assert!(error_starts(&write, "TRYAGAIN"), "{write:?}");
+    assert_eq!(other, Reply::ok());
+    assert_eq!(read, Reply::Bulk("a".to_string()));
+
+    assert_eq!(run(&source, &mut session, "GET tenant:1"), Reply::Nil);
+    assert_eq!(run(&source, &mut session, "SET tenant:2 b"), Reply::ok());
+    let mut sent = Vec::new();
+    while let Some(chunk) = feed.try_next() {
+        sent.extend_from_slice(&chunk);
+    }
+    assert!(String::from_utf8(sent).unwrap().contains("\nDEL tenant\n"));
+
+    let copy = shared();
+    let mut session = Session::new(&copy);
+    assert_eq!(run(&copy, &mut session, restore.trim_end()), Reply::ok());
+    assert_eq!(
+        run(&copy, &mut session, "GET tenant:1"),
+        Reply::Bulk("a".to_string())
+    );
+}
+
+#[test]
+fn refused_migrate_keeps_the_subtree() {
+    let source = shared();
+    let mut session = Session::new(&source);
+    run(&source, &mut session, "SET tenant:1 a");
+    let (port, handle) = target("OK\n-BUSYKEY Target key name already exists.\n", || ());
+    let reply = run(
+        &source,
+        &mut session,
+        &format!("MIGRATE 127.0.0.1 {port} tenant"),
+    );
+    assert!(error_starts(&reply, "ERR Target"), "{reply:?}");
+    handle.join().unwrap();
+    assert_eq!(
+        run(&source, &mut session, "GET tenant:1"),
+        Reply::Bulk("a".to_string())
+    );
+    assert_eq!(run(&source, &mut session, "SET tenant:2 b"), Reply::ok());
+}
@@ -0,0 +1,26 @@
+//! Helpers for running commands in-process, without a listener.
+
+// Each test file uses only some of them
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use word_trie::commands::{execute, Reply, Session};
+use word_trie::config::Config;
+use word_trie::server::Shared;
+use word_trie::Database;
+
+/// A server with the default configuration and an empty database.
+pub fn shared() -> Shared {
+    Shared::new(Config::default(), Arc::new(Database::new())).unwrap()
+}
+
+/// Run one request line, split on spaces the way the server splits it.
+pub fn run(shared: &Shared, session: &mut Session, line: &str) -> Reply {
+    let args: Vec<&str> = line.split(' ').collect();
+    execute(&args, shared, session)
+}
+
+/// Whether `reply` is an error whose code or message starts with `prefix`.
+pub fn error_starts(reply: &Reply, prefix: &str) -> bool {
+    matches!(reply, Reply::Error(e) if e.to_string().starts_with(prefix))
+}
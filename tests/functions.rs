@@ -0,0 +1,142 @@
+#![cfg(all(feature = "server", feature = "wasm"))]
+
+mod common;
+
+use common::{error_starts, run, shared};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+use word_trie::commands::{Reply, Session, PUSH_BACKLOG};
+use word_trie::config::Config;
+use word_trie::server::Shared;
+use word_trie::Database;
+
+/// A library exporting `one`, which returns 1 and imports nothing.
+const ONE: &str = concat!(
+    "0061736d01000000",
+    "0105016000017e",
+    "03020100",
+    "070701036f6e650000",
+    "0a0601040042010b",
+);
+
+/// A library exporting `twice`, which sets `k` to 1, counts down from
+/// 100000 and then sets `k` to 2.
+const TWICE: &str = concat!(
+    "0061736d01000000",
+    "010c0260047f7f7f7f006000017e",
+    "02110109666c61736874726565037365740000",
+    "03020101",
+    "0503010001",
+    "071202066d656d6f727902000574776963650001",
+    "0a3601340101",
+    "7f4100410141014101100041a08d06210002400340200045",
+    "0d01200041016b21000c000b0b41004101410241011000",
+    "42010b",
+    "0b09010041000b036b3132",
+);
+
+#[test]
+fn library_changes_are_writes() {
+    let shared = shared();
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "CONFIG SET read-only yes");
+    for line in [
+        format!("FUNCTION LOAD lib {ONE}"),
+        "FUNCTION DELETE lib".to_string(),
+        "FUNCTION FLUSH".to_string(),
+    ] {
+        let reply = run(&shared, &mut session, &line);
+        assert!(error_starts(&reply, "READONLY"), "{line}: {reply:?}");
+    }
+    assert_eq!(
+        run(&shared, &mut session, "FUNCTION LIST"),
+        Reply::Array(vec![])
+    );
+
+    run(&shared, &mut session, "CONFIG SET read-only no");
+    let mut replica = Session::with_push(&shared, mpsc::channel(PUSH_BACKLOG).0);
+    run(&shared, &mut replica, "PSYNC ? -1");
+    let mut feed = replica.replica.take().expect("no feed");
+    let load = format!("FUNCTION LOAD lib {ONE}");
+    assert_eq!(
+        run(&shared, &mut session, &load),
+        Reply::Bulk("lib".to_string())
+    );
+    run(&shared, &mut session, "FUNCTION LIST");
+    run(&shared, &mut session, "FUNCTION DELETE lib");
+    let mut sent = Vec::new();
+    while let Some(chunk) = feed.try_next() {
+        sent.extend_from_slice(&chunk);
+    }
+    let sent = String::from_utf8(sent).unwrap();
+    assert_eq!(sent, format!("{load}\nFUNCTION DELETE lib\n"));
+}
+
+#[test]
+fn full_syncs_carry_the_libraries() {
+    let shared = shared();
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, &format!("FUNCTION LOAD lib {ONE}"));
+    let mut replica = Session::with_push(&shared, mpsc::channel(PUSH_BACKLOG).0);
+    run(&shared, &mut replica, "PSYNC ? -1");
+    let feed = replica.replica.take().expect("no feed");
+    let libraries = format!("lib {ONE}\n");
+    let preamble = String::from_utf8(feed.preamble().to_vec()).unwrap();
+    assert!(
+        preamble.ends_with(&format!("${}\n{libraries}", libraries.len())),
+        "{preamble}"
+    );
+}
+
+#[test]
+fn libraries_are_saved_next_to_the_snapshot() {
+    let dir = std::env::temp_dir().join(format!("flashtree-functions-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = Config {
+        snapshot_path: Some(dir.join("dump.json")),
+        ..Config::default()
+    };
+    let shared = Shared::new(config.clone(), Arc::new(Database::new())).unwrap();
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, &format!("FUNCTION LOAD lib {ONE}"));
+    shared.functions.save(&dir.join("dump.json")).unwrap();
+    assert!(dir.join("dump.json.functions").exists());
+
+    let restarted = Shared::new(config, Arc::new(Database::new())).unwrap();
+    let mut session = Session::new(&restarted);
+    assert_eq!(
+        run(&restarted, &mut session, "FCALL one 0"),
+        Reply::Integer(1)
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn functions_run_atomically() {
+    let shared = Arc::new(shared());
+    let mut session = Session::new(&shared);
+    let reply = run(&shared, &mut session, &format!("FUNCTION LOAD lib {TWICE}"));
+    assert_eq!(reply, Reply::Bulk("lib".to_string()));
+    let calls = thread::spawn({
+        let shared = Arc::clone(&shared);
+        move || {
+            let mut session = Session::new(&shared);
+            for _ in 0..20 {
+                assert_eq!(
+                    run(&shared, &mut session, "FCALL twice 0"),
+                    Reply::Integer(1)
+                );
+            }
+        }
+    });
+    while !calls.is_finished() {
+        let reply = run(&shared, &mut session, "GET k");
+        assert_ne!(reply, Reply::Bulk("1".to_string()), "saw half a call");
+    }
+    calls.join().unwrap();
+    assert_eq!(
+        run(&shared, &mut session, "GET k"),
+        Reply::Bulk("2".to_string())
+    );
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use common::{run, shared};
+use word_trie::commands::{Reply, Session};
+use word_trie::server::Shared;
+
+/// WATCH `key` in a new session and queue a SET under MULTI.
+fn watching(shared: &Shared, key: &str) -> Session {
+    let mut session = Session::new(shared);
+    assert_eq!(
+        run(shared, &mut session, &format!("WATCH {key}")),
+        Reply::ok()
+    );
+    assert_eq!(run(shared, &mut session, "MULTI"), Reply::ok());
+    run(shared, &mut session, "SET tx:ran yes");
+    session
+}
+
+#[test]
+fn exec_runs_when_nothing_changed() {
+    let shared = shared();
+    let mut other = Session::new(&shared);
+    run(&shared, &mut other, "SET users:1 bob");
+    let mut session = watching(&shared, "users:1");
+    run(&shared, &mut other, "SET orders:1 pen");
+    assert_eq!(
+        run(&shared, &mut session, "EXEC"),
+        Reply::Array(vec![Reply::ok()])
+    );
+}
+
+#[test]
+fn exec_aborts_when_a_watched_key_changed() {
+    let shared = shared();
+    let mut other = Session::new(&shared);
+    run(&shared, &mut other, "SET users:1 bob");
+    let mut session = watching(&shared, "users:1");
+    run(&shared, &mut other, "SET users:1 alice");
+    assert_eq!(run(&shared, &mut session, "EXEC"), Reply::Nil);
+    assert_eq!(run(&shared, &mut other, "GET tx:ran"), Reply::Nil);
+}
+
+#[test]
+fn exec_aborts_when_its_subtree_changed() {
+    let shared = shared();
+    let mut other = Session::new(&shared);
+    run(&shared, &mut other, "SET users:1 bob");
+    let mut session = watching(&shared, "users");
+    run(&shared, &mut other, "SET users:2 alice");
+    assert_eq!(run(&shared, &mut session, "EXEC"), Reply::Nil);
+}
+
+#[test]
+fn exec_aborts_when_a_missing_key_came_and_went() {
+    let shared = shared();
+    let mut other = Session::new(&shared);
+    run(&shared, &mut other, "SET users:1 bob");
+    let mut session = watching(&shared, "users:2:name");
+    run(&shared, &mut other, "SET users:2:name alice");
+    run(&shared, &mut other, "DEL users:2");
+    assert_eq!(run(&shared, &mut other, "GET users:2:name"), Reply::Nil);
+    assert_eq!(run(&shared, &mut session, "EXEC"), Reply::Nil);
+}
+
+#[test]
+fn exec_aborts_when_a_watched_key_was_deleted() {
+    let shared = shared();
+    let mut other = Session::new(&shared);
+    run(&shared, &mut other, "SET users:1 bob");
+    let mut session = watching(&shared, "users:1");
+    run(&shared, &mut other, "DEL users:1");
+    assert_eq!(run(&shared, &mut session, "EXEC"), Reply::Nil);
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use common::{run, shared};
+use tokio::sync::mpsc;
+use word_trie::commands::{Reply, Session, PUSH_BACKLOG};
+
+#[test]
+fn a_subscriber_that_falls_behind_is_marked_for_disconnect() {
+    let shared = shared();
+    let (tx, mut rx) = mpsc::channel(PUSH_BACKLOG);
+    let mut subscriber = Session::with_push(&shared, tx);
+    let mut publisher = Session::new(&shared);
+    run(&shared, &mut subscriber, "SUBSCRIBE news");
+
+    for _ in 0..PUSH_BACKLOG {
+        assert_eq!(
+            run(&shared, &mut publisher, "PUBLISH news hi"),
+            Reply::Integer(1)
+        );
+    }
+    assert!(!subscriber.subscriptions.overflowed());
+    assert_eq!(
+        run(&shared, &mut publisher, "PUBLISH news hi"),
+        Reply::Integer(0)
+    );
+    assert!(subscriber.subscriptions.overflowed());
+
+    // Draining the backlog afterwards does not hide the lost message.
+    while rx.try_recv().is_ok() {}
+    assert!(subscriber.subscriptions.overflowed());
+}
+
+#[test]
+fn tree_notifications_count_against_the_backlog_too() {
+    let shared = shared();
+    let (tx, _rx) = mpsc::channel(PUSH_BACKLOG);
+    let mut watcher = Session::with_push(&shared, tx);
+    let mut writer = Session::new(&shared);
+    run(&shared, &mut watcher, "WATCHTREE app");
+
+    for i in 0..=PUSH_BACKLOG {
+        run(&shared, &mut writer, &format!("SET app:{i} x"));
+    }
+    assert!(watcher.subscriptions.overflowed());
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use common::{error_starts, run, shared};
+use word_trie::commands::{Reply, Session};
+use word_trie::server::Shared;
+
+/// The names COMMAND lists with the given category.
+fn commands_in(shared: &Shared, category: &str) -> Vec<String> {
+    let mut session = Session::new(shared);
+    let Reply::Array(commands) = run(shared, &mut session, "COMMAND") else {
+        panic!("COMMAND should list the commands");
+    };
+    commands
+        .into_iter()
+        .filter_map(|command| match command {
+            Reply::Array(items) if items.contains(&Reply::Bulk(category.to_string())) => {
+                match &items[0] {
+                    Reply::Bulk(name) => Some(name.clone()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn every_write_command_is_refused() {
+    let shared = shared();
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "SET users:1 alice");
+    run(&shared, &mut session, "CONFIG SET read-only yes");
+
+    let writes = commands_in(&shared, "write");
+    assert!(writes.contains(&"set".to_string()));
+    assert!(writes.contains(&"xadd".to_string()));
+    for name in writes {
+        let reply = run(&shared, &mut session, &format!("{name} users:1 bob 0"));
+        assert!(error_starts(&reply, "READONLY"), "{name} gave {reply:?}");
+    }
+    assert_eq!(
+        run(&shared, &mut session, "GET users:1"),
+        Reply::Bulk("alice".to_string())
+    );
+}
+
+#[test]
+fn reads_are_still_served() {
+    let shared = shared();
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "CONFIG SET read-only yes");
+
+    for name in commands_in(&shared, "read") {
+        let reply = run(&shared, &mut session, &format!("{name} users:1"));
+        assert!(!error_starts(&reply, "READONLY"), "{name} gave {reply:?}");
+    }
+}
+
+#[test]
+fn exec_aborts_if_read_only_mode_began_after_writes_queued() {
+    let shared = shared();
+    let mut session = Session::new(&shared);
+    let mut admin = Session::new(&shared);
+    run(&shared, &mut session, "MULTI");
+    run(&shared, &mut session, "SET users:1 alice");
+    run(&shared, &mut admin, "CONFIG SET read-only yes");
+
+    assert!(error_starts(
+        &run(&shared, &mut session, "EXEC"),
+        "EXECABORT"
+    ));
+    run(&shared, &mut admin, "CONFIG SET read-only no");
+    assert_eq!(run(&shared, &mut admin, "GET users:1"), Reply::Nil);
+}
@@ -0,0 +1,147 @@
+#![cfg(feature = "server")]
+
+mod common;
+
+use common::{run, shared};
+use tokio::sync::mpsc;
+use word_trie::commands::{Feed, Reply, Session, PUSH_BACKLOG};
+use word_trie::db::core;
+use word_trie::server::Shared;
+
+/// Attach a replica with a full sync, returning its feed.
+fn attach(shared: &Shared) -> Feed {
+    let mut session = Session::with_push(shared, mpsc::channel(PUSH_BACKLOG).0);
+    assert_eq!(run(shared, &mut session, "PSYNC ? -1"), Reply::Nil);
+    let feed = session.replica.take().expect("no feed");
+    assert!(feed.preamble().starts_with(b"FULLRESYNC "));
+    feed
+}
+
+/// A server that ran `lines`, as a replica would, each without error.
+fn replay(lines: &[String]) -> Shared {
+    let replica = shared();
+    let mut session = Session::new(&replica);
+    for line in lines {
+        let reply = run(&replica, &mut session, line);
+        assert!(!matches!(reply, Reply::Error(_)), "{line}: {reply:?}");
+    }
+    replica
+}
+
+/// The lines sent to replicas since the last call.
+fn sent(feed: &mut Feed) -> Vec<String> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = feed.try_next() {
+        bytes.extend_from_slice(&chunk);
+    }
+    String::from_utf8(bytes)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn conditional_sets_replicate_as_plain_sets_or_not_at_all() {
+    let shared = shared();
+    let mut feed = attach(&shared);
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "SET k a");
+    run(&shared, &mut session, "SET k b NX");
+    run(&shared, &mut session, "SET k c XX GET");
+    run(&shared, &mut session, "SET new d XX");
+    run(&shared, &mut session, "GETSET k e");
+    assert_eq!(sent(&mut feed), ["SET k a", "SET k c", "SET k e"]);
+
+    let Reply::Array(getv) = run(&shared, &mut session, "GETV k") else {
+        panic!("GETV k");
+    };
+    let Reply::Integer(version) = getv[1] else {
+        panic!("no version");
+    };
+    run(
+        &shared,
+        &mut session,
+        &format!("CAS k {} stale", version + 1),
+    );
+    run(&shared, &mut session, &format!("CAS k {version} f"));
+    assert_eq!(sent(&mut feed), ["SET k f"]);
+}
+
+#[test]
+fn writes_in_transactions_are_rewritten_too() {
+    let shared = shared();
+    let mut feed = attach(&shared);
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "SET k a");
+    sent(&mut feed);
+    run(&shared, &mut session, "MULTI");
+    run(&shared, &mut session, "SET k b NX");
+    run(&shared, &mut session, "GETSET k c");
+    run(&shared, &mut session, "EXEC");
+    assert_eq!(sent(&mut feed), ["MULTI", "SET k c", "EXEC"]);
+}
+
+#[test]
+fn expire_replicates_as_an_absolute_time() {
+    let shared = shared();
+    let mut feed = attach(&shared);
+    let mut session = Session::new(&shared);
+    run(&shared, &mut session, "SET k v");
+    run(&shared, &mut session, "EXPIRE missing 10");
+    let before = core::now_ms();
+    run(&shared, &mut session, "EXPIRE k 10");
+    let after = core::now_ms();
+
+    let lines = sent(&mut feed);
+    assert_eq!(lines.len(), 2, "{lines:?}");
+    let args: Vec<&str> = lines[1].split(' ').collect();
+    assert_eq!(args[..2], ["PEXPIREAT", "k"]);
+    let at: u64 = args[2].parse().unwrap();
+    assert!((before + 10_000..=after + 10_000).contains(&at));
+
+    // Applied late, it still expires the key at the same moment
+    let replica = replay(&lines);
+    let ttl = run(&replica, &mut Session::new(&replica), "TTL k");
+    assert_eq!(ttl, Reply::Integer(10));
+}
+
+#[test]
+fn group_reads_and_claims_carry_the_time() {
+    let shared = shared();
+    let mut session = Session::new(&shared);
+    let base = ["XGROUP CREATE s g $ MKSTREAM", "XADD s 1-1 f v"].map(String::from);
+    for line in &base {
+        run(&shared, &mut session, line);
+    }
+    let mut feed = attach(&shared);
+    run(
+        &shared,
+        &mut session,
+        "XREADGROUP GROUP g alice COUNT 1 STREAMS s >",
+    );
+    run(&shared, &mut session, "XCLAIM s g bob 0 1-1 JUSTID");
+
+    let lines = sent(&mut feed);
+    assert_eq!(lines.len(), 2, "{lines:?}");
+    let read: Vec<&str> = lines[0].split(' ').collect();
+    assert_eq!(
+        read[..6],
+        ["XREADGROUP", "GROUP", "g", "alice", "COUNT", "1"]
+    );
+    assert_eq!(read[6], "TIME");
+    assert_eq!(read[8..], ["STREAMS", "s", ">"]);
+    let claim: Vec<&str> = lines[1].split(' ').collect();
+    assert_eq!(
+        claim[..7],
+        ["XCLAIM", "s", "g", "bob", "0", "1-1", "JUSTID"]
+    );
+    assert_eq!(claim[7], "TIME");
+
+    let replica = replay(&[&base[..], &lines[..]].concat());
+    let pending = run(&replica, &mut Session::new(&replica), "XPENDING s g");
+    let Reply::Array(summary) = pending else {
+        panic!("{pending:?}");
+    };
+    assert_eq!(summary[0], Reply::Integer(1));
+}
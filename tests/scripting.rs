@@ -0,0 +1,161 @@
+#![cfg(all(feature = "server", feature = "lua"))]
+
+mod common;
+
+use common::{error_starts, run};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use word_trie::commands::{Reply, Session};
+use word_trie::config::Config;
+use word_trie::server::Shared;
+use word_trie::Database;
+
+fn with_config(config: Config) -> Shared {
+    Shared::new(config, Arc::new(Database::new())).unwrap()
+}
+
+/// Load `source` with SCRIPT LOAD, which keeps its spaces, and return its SHA1.
+fn load(shared: &Shared, session: &mut Session, source: &str) -> String {
+    match run(shared, session, &format!("SCRIPT LOAD {source}")) {
+        Reply::Bulk(sha) => sha,
+        reply => panic!("SCRIPT LOAD: {reply:?}"),
+    }
+}
+
+#[test]
+fn scripts_cannot_allocate_past_the_memory_limit() {
+    let shared = with_config(Config {
+        lua_max_memory: 1 << 20,
+        ..Config::default()
+    });
+    let mut session = Session::new(&shared);
+    let sha = load(&shared, &mut session, "return #string.rep('x', 1 << 24)");
+    let reply = run(&shared, &mut session, &format!("EVALSHA {sha} 0"));
+    assert!(
+        error_starts(&reply, "ERR Error running script"),
+        "{reply:?}"
+    );
+
+    let sha = load(&shared, &mut session, "return #string.rep('x', 1 << 10)");
+    let reply = run(&shared, &mut session, &format!("EVALSHA {sha} 0"));
+    assert_eq!(reply, Reply::Integer(1 << 10));
+}
+
+#[test]
+fn writes_waiting_behind_a_slow_script_get_busy() {
+    let shared = Arc::new(with_config(Config {
+        lua_time_limit: Duration::from_millis(100),
+        ..Config::default()
+    }));
+    let mut session = Session::new(&shared);
+    let sha = load(&shared, &mut session, "while true do end");
+    let script = thread::spawn({
+        let shared = Arc::clone(&shared);
+        move || {
+            let mut session = Session::new(&shared);
+            run(&shared, &mut session, &format!("EVALSHA {sha} 0"))
+        }
+    });
+    while !shared.scripts.running() {
+        thread::yield_now();
+    }
+
+    // Within the time limit the write is let through, to wait for the log
+    let reply = run(&shared, &mut session, "SET k v");
+    assert!(error_starts(&reply, "BUSY"), "{reply:?}");
+    assert_eq!(run(&shared, &mut session, "SCRIPT KILL"), Reply::ok());
+    let reply = script.join().unwrap();
+    assert!(error_starts(&reply, "ERR Script killed"), "{reply:?}");
+    assert_eq!(run(&shared, &mut session, "SET k v"), Reply::ok());
+}
+
+#[test]
+fn scripts_run_atomically() {
+    let shared = Arc::new(with_config(Config::default()));
+    let mut session = Session::new(&shared);
+    let sha = load(
+        &shared,
+        &mut session,
+        "flashtree.call('SET', 'k', '1') for i = 1, 100000 do end flashtree.call('SET', 'k', '2')",
+    );
+    let calls = thread::spawn({
+        let shared = Arc::clone(&shared);
+        move || {
+            let mut session = Session::new(&shared);
+            for _ in 0..20 {
+                let reply = run(&shared, &mut session, &format!("EVALSHA {sha} 0"));
+                assert_eq!(reply, Reply::Nil);
+            }
+        }
+    });
+    while !calls.is_finished() {
+        let reply = run(&shared, &mut session, "GET k");
+        assert_ne!(reply, Reply::Bulk("1".to_string()), "saw half a script");
+    }
+    calls.join().unwrap();
+    assert_eq!(
+        run(&shared, &mut session, "GET k"),
+        Reply::Bulk("2".to_string())
+    );
+}
+
+#[test]
+fn scripts_cannot_reach_files_or_stdout() {
+    let shared = with_config(Config::default());
+    let mut session = Session::new(&shared);
+    for name in ["dofile", "loadfile", "print", "io", "os", "require"] {
+        let reply = run(
+            &shared,
+            &mut session,
+            &format!("EVAL return(type({name})) 0"),
+        );
+        assert_eq!(reply, Reply::Bulk("nil".to_string()), "{name}");
+    }
+    let sha = load(&shared, &mut session, "return dofile('/etc/passwd')");
+    let reply = run(&shared, &mut session, &format!("EVALSHA {sha} 0"));
+    assert!(
+        error_starts(&reply, "ERR Error running script"),
+        "{reply:?}"
+    );
+}
+
+#[test]
+fn eval_takes_the_script_up_to_the_first_space() {
+    let shared = with_config(Config::default());
+    let mut session = Session::new(&shared);
+    assert_eq!(
+        run(&shared, &mut session, "EVAL return(KEYS[1]..ARGV[1]) 1 a b"),
+        Reply::Bulk("ab".to_string())
+    );
+    // Runs as `return`, with `2` as a key
+    assert_eq!(run(&shared, &mut session, "EVAL return 1 2"), Reply::Nil);
+
+    let sha = load(&shared, &mut session, "return KEYS[1] .. ' ' .. ARGV[1]");
+    assert_eq!(
+        run(&shared, &mut session, &format!("EVALSHA {sha} 1 a b")),
+        Reply::Bulk("a b".to_string())
+    );
+}
+
+#[test]
+fn script_management_is_an_admin_command() {
+    let shared = with_config(Config::default());
+    let mut admin = Session::new(&shared);
+    let reply = run(
+        &shared,
+        &mut admin,
+        "ACL SETUSER alice on nopass allkeys +@all -@admin",
+    );
+    assert_eq!(reply, Reply::ok());
+    let mut alice = Session::new(&shared);
+    assert_eq!(run(&shared, &mut alice, "AUTH alice any"), Reply::ok());
+    assert_eq!(
+        run(&shared, &mut alice, "EVAL return(1) 0"),
+        Reply::Integer(1)
+    );
+    for line in ["SCRIPT FLUSH", "SCRIPT KILL", "SCRIPT LOAD return 2"] {
+        let reply = run(&shared, &mut alice, line);
+        assert!(error_starts(&reply, "NOPERM"), "{line}: {reply:?}");
+    }
+}
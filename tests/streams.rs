@@ -0,0 +1,475 @@
+use word_trie::db::{snapshot, NewId, Stream, StreamId, Trim};
+use word_trie::{Database, Error, Keyspace};
+
+fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(f, v)| (f.to_string(), v.to_string()))
+        .collect()
+}
+
+fn id(ms: u64, seq: u64) -> StreamId {
+    StreamId { ms, seq }
+}
+
+/// The running byte count must agree with one computed from scratch.
+fn assert_bytes(stream: &mut Stream) {
+    let kept = stream.bytes();
+    stream.refresh_bytes();
+    assert_eq!(kept, stream.bytes());
+}
+
+#[test]
+fn generated_ids_only_ever_go_up() {
+    let mut stream = Stream::new();
+    assert_eq!(
+        stream.add(NewId::Auto, fields(&[("a", "1")]), 1000),
+        Ok(id(1000, 0))
+    );
+    // A clock that stalls or goes back still yields a higher ID
+    assert_eq!(
+        stream.add(NewId::Auto, fields(&[("a", "2")]), 1000),
+        Ok(id(1000, 1))
+    );
+    assert_eq!(
+        stream.add(NewId::Auto, fields(&[("a", "3")]), 900),
+        Ok(id(1000, 2))
+    );
+    assert_eq!(
+        stream.add(NewId::Auto, fields(&[("a", "4")]), 1001),
+        Ok(id(1001, 0))
+    );
+
+    assert_eq!(
+        stream.add(NewId::AutoSeq(1001), fields(&[("b", "1")]), 0),
+        Ok(id(1001, 1))
+    );
+    assert_eq!(
+        stream.add(NewId::AutoSeq(2000), fields(&[("b", "2")]), 0),
+        Ok(id(2000, 0))
+    );
+    assert_eq!(
+        stream.add(NewId::AutoSeq(1999), fields(&[("b", "3")]), 0),
+        Err(Error::StreamIdTooSmall)
+    );
+    assert_eq!(stream.len(), 6);
+    assert_eq!(stream.last_id, id(2000, 0));
+}
+
+#[test]
+fn ids_at_or_below_the_last_one_are_too_small() {
+    let mut stream = Stream::new();
+    stream
+        .add(NewId::Explicit(id(5, 5)), fields(&[("a", "1")]), 0)
+        .unwrap();
+    for small in [id(5, 5), id(5, 4), id(4, 9)] {
+        assert_eq!(
+            stream.add(NewId::Explicit(small), fields(&[("a", "2")]), 0),
+            Err(Error::StreamIdTooSmall)
+        );
+    }
+    // Trimming everything away doesn't let old IDs back in
+    stream.trim(Trim::MaxLen(0));
+    assert!(stream.is_empty());
+    assert_eq!(
+        stream.add(NewId::Explicit(id(5, 5)), fields(&[("a", "3")]), 0),
+        Err(Error::StreamIdTooSmall)
+    );
+    assert_bytes(&mut stream);
+}
+
+#[test]
+fn nothing_can_follow_the_highest_id() {
+    let mut stream = Stream::new();
+    stream
+        .add(NewId::Explicit(StreamId::MAX), fields(&[("a", "1")]), 0)
+        .unwrap();
+    assert_eq!(
+        stream.add(NewId::Auto, fields(&[("a", "2")]), u64::MAX),
+        Err(Error::StreamIdTooSmall)
+    );
+    assert_eq!(
+        stream.add(NewId::AutoSeq(u64::MAX), fields(&[("a", "3")]), 0),
+        Err(Error::StreamIdTooSmall)
+    );
+    assert_eq!(
+        stream.add(NewId::Explicit(StreamId::MAX), fields(&[("a", "4")]), 0),
+        Err(Error::StreamIdTooSmall)
+    );
+    assert_eq!(stream.len(), 1);
+
+    let mut stream = Stream::new();
+    stream
+        .add(NewId::Explicit(id(7, u64::MAX)), fields(&[("a", "1")]), 0)
+        .unwrap();
+    assert_eq!(
+        stream.add(NewId::Auto, fields(&[("a", "2")]), 0),
+        Ok(id(8, 0))
+    );
+}
+
+#[test]
+fn trimming_keeps_the_newest_entries() {
+    let mut stream = Stream::new();
+    for seq in 1..=10 {
+        stream
+            .add(NewId::Explicit(id(1, seq)), fields(&[("n", "x")]), 0)
+            .unwrap();
+    }
+    assert_eq!(stream.trim(Trim::MaxLen(7)), 3);
+    assert_eq!(
+        stream.range(StreamId::MIN, StreamId::MAX, Some(1))[0].0,
+        id(1, 4)
+    );
+    assert_eq!(stream.trim(Trim::MaxLen(7)), 0);
+    assert_bytes(&mut stream);
+
+    assert_eq!(stream.trim(Trim::MinId(id(1, 8))), 4);
+    assert_eq!(stream.len(), 3);
+    assert_eq!(
+        stream.range(StreamId::MIN, StreamId::MAX, Some(1))[0].0,
+        id(1, 8)
+    );
+    assert_eq!(stream.trim(Trim::MinId(id(1, 8))), 0);
+    assert_bytes(&mut stream);
+}
+
+#[test]
+fn group_reads_hand_out_new_entries_or_replay_pending_ones() {
+    let mut stream = Stream::new();
+    for seq in 1..=3 {
+        stream
+            .add(NewId::Explicit(id(1, seq)), fields(&[("n", "x")]), 0)
+            .unwrap();
+    }
+    stream.create_group("g", StreamId::MIN).unwrap();
+
+    let new = stream
+        .read_group("g", "alice", None, Some(2), false, 100)
+        .unwrap();
+    let ids: Vec<_> = new.iter().map(|(id, _)| *id).collect();
+    assert_eq!(ids, [id(1, 1), id(1, 2)]);
+    // `>` moves on; the history only holds what alice was handed
+    let new = stream
+        .read_group("g", "bob", None, None, false, 100)
+        .unwrap();
+    assert_eq!(new.len(), 1);
+    assert_eq!(new[0].0, id(1, 3));
+    let history = stream
+        .read_group("g", "alice", Some(StreamId::MIN), None, false, 100)
+        .unwrap();
+    let ids: Vec<_> = history.iter().map(|(id, _)| *id).collect();
+    assert_eq!(ids, [id(1, 1), id(1, 2)]);
+    let history = stream
+        .read_group("g", "alice", Some(id(1, 1)), None, false, 100)
+        .unwrap();
+    assert_eq!(history.len(), 1);
+    assert!(stream
+        .read_group("g", "alice", None, None, false, 100)
+        .unwrap()
+        .is_empty());
+
+    // A pending entry trimmed away replays without its fields
+    stream.trim(Trim::MinId(id(1, 2)));
+    let history = stream
+        .read_group("g", "alice", Some(StreamId::MIN), None, false, 100)
+        .unwrap();
+    assert_eq!(history[0], (id(1, 1), None));
+    assert!(history[1].1.is_some());
+
+    assert_eq!(
+        stream.read_group("nope", "alice", None, None, false, 100),
+        Err(Error::NoGroup)
+    );
+    assert_bytes(&mut stream);
+}
+
+#[test]
+fn acks_and_claims_move_pending_entries() {
+    let mut stream = Stream::new();
+    for seq in 1..=4 {
+        stream
+            .add(NewId::Explicit(id(1, seq)), fields(&[("n", "x")]), 0)
+            .unwrap();
+    }
+    stream.create_group("g", StreamId::MIN).unwrap();
+    stream
+        .read_group("g", "alice", None, None, false, 1000)
+        .unwrap();
+    assert_bytes(&mut stream);
+
+    assert_eq!(stream.ack("g", &[id(1, 1), id(1, 1), id(9, 9)]), Ok(1));
+    assert_eq!(
+        stream
+            .pending("g", StreamId::MIN, StreamId::MAX, 10, None)
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_bytes(&mut stream);
+
+    // Not idle long enough yet
+    let claimed = stream.claim("g", "bob", 500, &[id(1, 2)], 1200).unwrap();
+    assert!(claimed.is_empty());
+    let claimed = stream
+        .claim("g", "bob", 500, &[id(1, 2), id(1, 3)], 1500)
+        .unwrap();
+    assert_eq!(claimed.len(), 2);
+    let bob = stream
+        .pending("g", StreamId::MIN, StreamId::MAX, 10, Some("bob"))
+        .unwrap();
+    assert_eq!(bob.len(), 2);
+    assert_eq!((bob[0].1.delivered_ms, bob[0].1.deliveries), (1500, 2));
+    // Claiming resets the idle time
+    assert!(stream
+        .claim("g", "carol", 500, &[id(1, 2)], 1700)
+        .unwrap()
+        .is_empty());
+    assert_bytes(&mut stream);
+
+    // Entries deleted meanwhile are dropped from the pending list instead
+    stream.trim(Trim::MinId(id(1, 4)));
+    assert!(stream
+        .claim("g", "carol", 0, &[id(1, 2)], 2000)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        stream
+            .pending("g", StreamId::MIN, StreamId::MAX, 10, None)
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_bytes(&mut stream);
+
+    assert_eq!(stream.delete_consumer("g", "bob"), Ok(1));
+    assert_eq!(stream.delete_consumer("g", "bob"), Ok(0));
+    assert_bytes(&mut stream);
+    assert!(stream.destroy_group("g"));
+    assert_bytes(&mut stream);
+}
+
+#[test]
+fn groups_survive_a_snapshot() {
+    let dir = std::env::temp_dir().join(format!("word_trie-streams-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("dump.json");
+
+    let db = Database::new();
+    for seq in 1..=3 {
+        db.xadd(
+            "s",
+            NewId::Explicit(id(1, seq)),
+            fields(&[("n", "x")]),
+            None,
+            true,
+        )
+        .unwrap();
+    }
+    db.update_stream("s", false, |stream| {
+        stream.create_group("g", StreamId::MIN)?;
+        stream.read_group("g", "alice", None, Some(2), false, 1000)
+    })
+    .unwrap();
+    snapshot::save(db.get_root(), &path).unwrap();
+
+    let loaded = Database::new();
+    snapshot::load(loaded.get_root(), &path).unwrap();
+    let (pending, last_delivered, bytes) = loaded
+        .read_stream("s", |stream| {
+            let group = stream.group("g").unwrap();
+            let pending: Vec<_> = group
+                .pending
+                .iter()
+                .map(|(id, p)| (*id, p.consumer.clone(), p.deliveries))
+                .collect();
+            (pending, group.last_delivered, stream.bytes())
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        pending,
+        [
+            (id(1, 1), "alice".to_string(), 1),
+            (id(1, 2), "alice".to_string(), 1)
+        ]
+    );
+    assert_eq!(last_delivered, id(1, 2));
+    assert_eq!(
+        Some(bytes),
+        db.read_stream("s", |stream| stream.bytes()).unwrap()
+    );
+
+    let next = loaded
+        .update_stream("s", false, |stream| {
+            stream.read_group("g", "bob", None, None, false, 2000)
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.len(), 1);
+    assert_eq!(next[0].0, id(1, 3));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "server")]
+mod commands {
+    use super::common::{error_starts, run, shared};
+    use tokio::sync::mpsc;
+    use word_trie::commands::{Reply, Session, PUSH_BACKLOG};
+
+    fn bulk(s: &str) -> Reply {
+        Reply::Bulk(s.to_string())
+    }
+
+    /// `[id, [field, value, ...]]`
+    fn entry(id: &str, pairs: &[&str]) -> Reply {
+        Reply::Array(vec![
+            bulk(id),
+            Reply::Array(pairs.iter().map(|s| bulk(s)).collect()),
+        ])
+    }
+
+    #[test]
+    fn ranges_the_wrong_way_round_are_empty() {
+        let shared = shared();
+        let mut session = Session::new(&shared);
+        run(&shared, &mut session, "XADD s 1-1 f v");
+        run(&shared, &mut session, "XADD s 2-1 f v");
+        assert_eq!(
+            run(&shared, &mut session, "XRANGE s + -"),
+            Reply::Array(vec![])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XRANGE s 2 1"),
+            Reply::Array(vec![])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XRANGE s - +"),
+            Reply::Array(vec![entry("1-1", &["f", "v"]), entry("2-1", &["f", "v"])])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XRANGE s 2 +"),
+            Reply::Array(vec![entry("2-1", &["f", "v"])])
+        );
+    }
+
+    #[test]
+    fn xadd_refuses_the_zero_id() {
+        let shared = shared();
+        let mut session = Session::new(&shared);
+        let reply = run(&shared, &mut session, "XADD s 0-0 f v");
+        assert!(
+            error_starts(
+                &reply,
+                "ERR The ID specified in XADD must be greater than 0-0"
+            ),
+            "{reply:?}"
+        );
+        assert_eq!(run(&shared, &mut session, "XLEN s"), Reply::Integer(0));
+        assert_eq!(run(&shared, &mut session, "XADD s 0-* f v"), bulk("0-1"));
+        let reply = run(&shared, &mut session, "XADD s 0-1 f v");
+        assert!(
+            error_starts(&reply, "ERR The ID specified in XADD is equal or smaller"),
+            "{reply:?}"
+        );
+    }
+
+    #[test]
+    fn xtrim_by_length_and_by_id() {
+        let shared = shared();
+        let mut session = Session::new(&shared);
+        for seq in 1..=5 {
+            run(&shared, &mut session, &format!("XADD s 1-{seq} f v"));
+        }
+        assert_eq!(
+            run(&shared, &mut session, "XTRIM s MAXLEN = 3"),
+            Reply::Integer(2)
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XTRIM s MINID ~ 1-5"),
+            Reply::Integer(2)
+        );
+        assert_eq!(run(&shared, &mut session, "XLEN s"), Reply::Integer(1));
+        assert_eq!(
+            run(&shared, &mut session, "XTRIM missing MAXLEN 0"),
+            Reply::Integer(0)
+        );
+        assert!(matches!(
+            run(&shared, &mut session, "XADD s MAXLEN 0 * f v"),
+            Reply::Bulk(_)
+        ));
+        assert_eq!(run(&shared, &mut session, "XLEN s"), Reply::Integer(0));
+    }
+
+    #[test]
+    fn xpending_and_xclaim_go_by_idle_time() {
+        let shared = shared();
+        let mut session = Session::new(&shared);
+        run(&shared, &mut session, "XADD s 1-1 f v");
+        run(&shared, &mut session, "XGROUP CREATE s g 0");
+        run(
+            &shared,
+            &mut session,
+            "XREADGROUP GROUP g alice STREAMS s >",
+        );
+
+        let Reply::Array(listed) = run(&shared, &mut session, "XPENDING s g - + 10") else {
+            panic!("XPENDING did not list entries");
+        };
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            run(&shared, &mut session, "XPENDING s g IDLE 3600000 - + 10"),
+            Reply::Array(vec![])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XCLAIM s g bob 3600000 1-1"),
+            Reply::Array(vec![])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XCLAIM s g bob 0 1-1 JUSTID"),
+            Reply::Array(vec![bulk("1-1")])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XPENDING s g"),
+            Reply::Array(vec![
+                Reply::Integer(1),
+                bulk("1-1"),
+                bulk("1-1"),
+                Reply::Array(vec![Reply::Array(vec![bulk("bob"), bulk("1")])]),
+            ])
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XACK s g 1-1"),
+            Reply::Integer(1)
+        );
+        assert_eq!(
+            run(&shared, &mut session, "XPENDING s g - + 10"),
+            Reply::Array(vec![])
+        );
+    }
+
+    #[test]
+    fn xread_block_is_woken_by_xadd() {
+        let shared = shared();
+        let mut reader = Session::with_push(&shared, mpsc::channel(PUSH_BACKLOG).0);
+        let mut writer = Session::new(&shared);
+        run(&shared, &mut writer, "XADD s 1-1 old v");
+        assert_eq!(
+            run(&shared, &mut reader, "XREAD BLOCK 0 STREAMS s $"),
+            Reply::Nil
+        );
+        let blocked = reader.blocked.take().expect("XREAD did not block");
+
+        run(&shared, &mut writer, "XADD s 1-2 new v");
+        assert_eq!(
+            blocked.finish(&shared.blocking, None),
+            Reply::Array(vec![Reply::Array(vec![
+                bulk("s"),
+                Reply::Array(vec![entry("1-2", &["new", "v"])]),
+            ])])
+        );
+    }
+}
+
+#[cfg(feature = "server")]
+mod common;